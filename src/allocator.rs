@@ -0,0 +1,514 @@
+use crate::bitmap::{Bitmap, bitmap_bytes};
+use crate::cache::ImageDevice;
+use crate::check::with_scratch;
+use crate::cli::{print, print_no_ln, print_number};
+use crate::device::BlockDevice;
+use crate::helpers::parse_u64;
+use crate::volume::{FsInfo, Volume, VolumeError};
+
+/* Clusters whose FAT entries are read together, one 512 byte FAT32 sector */
+const GROUP: u32 = 128;
+
+/* Where an allocation looks for a free cluster */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /* The lowest free cluster */
+    First,
+    /* The first free cluster from the hint, past the last allocation */
+    Next,
+    /* The free cluster closest to the given one, its parent directory or
+     * the cluster it extends */
+    Near(u32),
+}
+
+/* Bytes of storage a `ClusterAllocator` needs for `clusters_count` clusters */
+pub const fn allocator_scratch_size(clusters_count: u32) -> usize {
+    let bits = clusters_count as usize + 2;
+    bitmap_bytes(bits) + bitmap_bytes(bits.div_ceil(GROUP as usize))
+}
+
+/* Free cluster map of a FAT volume over caller provided storage. The FAT
+ * is read one group of clusters at a time as searches reach it, starting
+ * from the FSInfo hint, or whole by `load_all`. Allocations made through
+ * it keep the map, the hint and the free count up to date */
+pub struct ClusterAllocator<'a> {
+    /* Free clusters by number, only meaningful in loaded groups */
+    free: Bitmap<'a>,
+    /* Groups whose FAT entries were read */
+    loaded: Bitmap<'a>,
+    groups: u32,
+    loaded_groups: u32,
+    clusters_count: u32,
+    /* Free clusters in the loaded groups */
+    loaded_free: u32,
+    /* Free clusters on the volume: the FSInfo count until every group is
+     * loaded, then the map's own. None when neither is known */
+    free_count: Option<u32>,
+    hint: u32,
+}
+
+impl<'a> ClusterAllocator<'a> {
+    /* Nothing loaded yet, None when `storage` is smaller than
+     * `allocator_scratch_size` */
+    pub fn new(storage: &'a mut [u8], clusters_count: u32) -> Option<Self> {
+        if storage.len() < allocator_scratch_size(clusters_count) {
+            return None;
+        }
+        let bits = clusters_count as usize + 2;
+        let groups = bits.div_ceil(GROUP as usize);
+        let (free_storage, loaded_storage) = storage.split_at_mut(bitmap_bytes(bits));
+        Some(ClusterAllocator {
+            free: Bitmap::new(free_storage, bits)?,
+            loaded: Bitmap::new(loaded_storage, groups)?,
+            groups: groups as u32,
+            loaded_groups: 0,
+            clusters_count,
+            loaded_free: 0,
+            free_count: None,
+            hint: 2,
+        })
+    }
+
+    /* Start from the FSInfo free count and next free hint, when valid */
+    pub fn read_fs_info<D: BlockDevice>(&mut self, vol: &mut Volume<D>) -> Result<(), VolumeError> {
+        let Some(info) = vol.read_fs_info()? else {
+            return Ok(());
+        };
+        if info.free_count <= self.clusters_count && self.loaded_groups < self.groups {
+            self.free_count = Some(info.free_count);
+        }
+        if self.is_cluster(info.next_free) {
+            self.hint = info.next_free;
+        }
+        Ok(())
+    }
+
+    /* Read the whole FAT, the free count is then exact */
+    pub fn load_all<D: BlockDevice>(&mut self, vol: &mut Volume<D>) -> Result<(), VolumeError> {
+        for group in 0..self.groups {
+            self.load_group(vol, group)?;
+        }
+        Ok(())
+    }
+
+    pub fn free_count(&self) -> Option<u32> {
+        self.free_count
+    }
+
+    /* Where the next `Fit::Next` search starts */
+    pub fn hint(&self) -> u32 {
+        self.hint
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.clusters_count
+    }
+
+    fn load_group<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        group: u32,
+    ) -> Result<(), VolumeError> {
+        if self.loaded.get(group as usize) {
+            return Ok(());
+        }
+        let first = (group * GROUP).max(2);
+        let end = ((group + 1) * GROUP).min(self.clusters_count + 2);
+        for cluster in first..end {
+            if vol.fat_entry(cluster)? == 0 {
+                self.free.set(cluster as usize);
+                self.loaded_free += 1;
+            }
+        }
+        self.loaded.set(group as usize);
+        self.loaded_groups += 1;
+        if self.loaded_groups == self.groups {
+            self.free_count = Some(self.loaded_free);
+        }
+        Ok(())
+    }
+
+    fn is_free<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        cluster: u32,
+    ) -> Result<bool, VolumeError> {
+        self.load_group(vol, cluster / GROUP)?;
+        Ok(self.free.get(cluster as usize))
+    }
+
+    /* A free cluster chosen by `fit`, None when the volume is full. The
+     * cluster stays free until claimed */
+    pub fn find<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        fit: Fit,
+    ) -> Result<Option<u32>, VolumeError> {
+        if self.clusters_count == 0 {
+            return Ok(None);
+        }
+        match fit {
+            Fit::First => self.scan_from(vol, 2),
+            Fit::Next => self.scan_from(vol, self.hint),
+            Fit::Near(goal) => self.nearest(vol, goal),
+        }
+    }
+
+    /* First free cluster from `start`, wrapping around to cluster 2 */
+    fn scan_from<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        start: u32,
+    ) -> Result<Option<u32>, VolumeError> {
+        let count = self.clusters_count;
+        let start = if self.is_cluster(start) { start } else { 2 };
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if self.is_free(vol, cluster)? {
+                return Ok(Some(cluster));
+            }
+        }
+        Ok(None)
+    }
+
+    /* Free cluster closest to `goal`, the one after it on a tie */
+    fn nearest<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        goal: u32,
+    ) -> Result<Option<u32>, VolumeError> {
+        let goal = goal.clamp(2, self.clusters_count + 1);
+        for distance in 0..self.clusters_count {
+            let after = goal + distance;
+            let before = goal.checked_sub(distance).filter(|&c| c >= 2);
+            if after >= self.clusters_count + 2 && before.is_none() {
+                break;
+            }
+            if self.is_cluster(after) && self.is_free(vol, after)? {
+                return Ok(Some(after));
+            }
+            if let Some(before) = before
+                && self.is_free(vol, before)?
+            {
+                return Ok(Some(before));
+            }
+        }
+        Ok(None)
+    }
+
+    /* First cluster of the smallest run of at least `len` free clusters,
+     * the lowest one among equals. Reads the whole FAT */
+    pub fn find_run<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        len: u32,
+    ) -> Result<Option<u32>, VolumeError> {
+        self.load_all(vol)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        let mut best: Option<(u32, u32)> = None;
+        let mut start = 0u32;
+        let mut run = 0u32;
+        for cluster in 2..self.clusters_count + 3 {
+            if self.is_cluster(cluster) && self.free.get(cluster as usize) {
+                if run == 0 {
+                    start = cluster;
+                }
+                run += 1;
+                continue;
+            }
+            if run >= len && best.is_none_or(|(_, best_run)| run < best_run) {
+                best = Some((start, run));
+                if run == len {
+                    break;
+                }
+            }
+            run = 0;
+        }
+        Ok(best.map(|(start, _)| start))
+    }
+
+    /* Set the FAT entry of free `cluster` to `value` and take it off the map */
+    pub fn claim<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), VolumeError> {
+        self.load_group(vol, cluster / GROUP)?;
+        vol.set_fat_entry(cluster, value)?;
+        self.reserve(cluster);
+        self.hint = if cluster - 1 == self.clusters_count {
+            2
+        } else {
+            cluster + 1
+        };
+        Ok(())
+    }
+
+    /* Keep `cluster` from being handed out though its FAT entry says free,
+     * for clusters a caller already uses */
+    pub fn reserve(&mut self, cluster: u32) {
+        if !self.free.get(cluster as usize) {
+            return;
+        }
+        self.free.clear(cluster as usize);
+        self.loaded_free -= 1;
+        self.free_count = self.free_count.map(|n| n.saturating_sub(1));
+    }
+
+    /* Free `cluster` in the FAT and give it back to the map */
+    pub fn release<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        cluster: u32,
+    ) -> Result<(), VolumeError> {
+        self.load_group(vol, cluster / GROUP)?;
+        vol.set_fat_entry(cluster, 0)?;
+        if !self.free.get(cluster as usize) {
+            self.free.set(cluster as usize);
+            self.loaded_free += 1;
+            self.free_count = self.free_count.map(|n| n + 1);
+        }
+        Ok(())
+    }
+
+    /* Write the free count, when known, and the hint back to FSInfo */
+    pub fn sync_fs_info<D: BlockDevice>(&self, vol: &mut Volume<D>) -> Result<(), VolumeError> {
+        let Some(info) = vol.read_fs_info()? else {
+            return Ok(());
+        };
+        vol.write_fs_info(FsInfo {
+            free_count: self.free_count.unwrap_or(info.free_count),
+            next_free: self.hint,
+        })
+    }
+}
+
+/* What `free` reports */
+struct FreeSpace {
+    count: u32,
+    /* FSInfo free count, when it has one */
+    recorded: Option<u32>,
+    next: Option<u32>,
+    first: Option<u32>,
+    run: Option<u32>,
+}
+
+fn survey<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    free: &mut ClusterAllocator,
+    wanted: Option<u32>,
+) -> Result<FreeSpace, VolumeError> {
+    free.read_fs_info(vol)?;
+    let recorded = free.free_count();
+    free.load_all(vol)?;
+    let run = match wanted {
+        Some(len) => free.find_run(vol, len)?,
+        None => None,
+    };
+    Ok(FreeSpace {
+        count: free.free_count().unwrap_or(0),
+        recorded,
+        next: free.find(vol, Fit::Next)?,
+        first: free.find(vol, Fit::First)?,
+        run,
+    })
+}
+
+/* `free [<clusters>]`: free space of the image and where a contiguous
+ * run of that many clusters would go */
+pub fn free_command(dev: &mut ImageDevice, args: &[&[u8]]) -> usize {
+    let wanted = match args.first() {
+        None => None,
+        Some(word) => match parse_u64(word) {
+            Some(n) if n > 0 && n <= u32::MAX as u64 => Some(n as u32),
+            _ => {
+                print("Usage: free [<clusters>]");
+                return 1;
+            }
+        },
+    };
+
+    let mut vol = match Volume::mount(dev) {
+        Ok(vol) => vol,
+        Err(_) => {
+            print("Failed to mount the volume");
+            return 1;
+        }
+    };
+
+    let clusters_count = vol.clusters_count;
+    let result = with_scratch(|scratch| {
+        ClusterAllocator::new(scratch, clusters_count)
+            .map(|mut free| survey(&mut vol, &mut free, wanted))
+    });
+    let space = match result {
+        Some(Ok(space)) => space,
+        Some(Err(_)) => {
+            print("Failed to read the FAT");
+            return 1;
+        }
+        None => {
+            print("Volume has too many clusters to be mapped");
+            return 1;
+        }
+    };
+
+    print_no_ln("Free clusters: ");
+    print_number(space.count as u64);
+    print_no_ln(" of ");
+    print_number(clusters_count as u64);
+    print("");
+    if let Some(recorded) = space.recorded.filter(|&n| n != space.count) {
+        print_no_ln("FSInfo says:   ");
+        print_number(recorded as u64);
+        print("");
+    }
+    if let Some(next) = space.next {
+        print_no_ln("Next free:     ");
+        print_number(next as u64);
+        print("");
+    }
+    if let Some(first) = space.first {
+        print_no_ln("First free:    ");
+        print_number(first as u64);
+        print("");
+    }
+    if let Some(len) = wanted {
+        match space.run {
+            Some(start) => {
+                print_no_ln("Best fit:      ");
+                print_number(start as u64);
+                print("");
+            }
+            None => {
+                print_no_ln("No run of ");
+                print_number(len as u64);
+                print(" free clusters");
+            }
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fixture::{ImageBuilder, Node};
+    use crate::format::{FormatOptions, format_volume};
+    use crate::volume::FAT_EOC_MARK;
+    use std::vec;
+    use std::vec::Vec;
+
+    const SIZE: usize = 40 * 1024 * 1024;
+
+    fn formatted() -> Vec<u8> {
+        let mut data = vec![0u8; SIZE];
+        format_volume(
+            &mut MemDevice { data: &mut data },
+            SIZE as u64,
+            &FormatOptions::default(),
+        )
+        .unwrap();
+        data
+    }
+
+    #[test]
+    fn picks_clusters_by_fit() {
+        let mut data = formatted();
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        /* Free: 10..=12, 14..=15 and 17 on */
+        for cluster in (3..10).chain([13, 16]) {
+            vol.set_fat_entry(cluster, FAT_EOC_MARK).unwrap();
+        }
+        let mut storage = vec![0u8; allocator_scratch_size(vol.clusters_count)];
+        let mut free = ClusterAllocator::new(&mut storage, vol.clusters_count).unwrap();
+        free.read_fs_info(&mut vol).unwrap();
+        assert_eq!(free.hint(), 3);
+
+        assert_eq!(free.find(&mut vol, Fit::First).unwrap(), Some(10));
+        assert_eq!(free.find(&mut vol, Fit::Next).unwrap(), Some(10));
+        assert_eq!(free.find(&mut vol, Fit::Near(15)).unwrap(), Some(15));
+        assert_eq!(free.find(&mut vol, Fit::Near(13)).unwrap(), Some(14));
+        assert_eq!(free.find(&mut vol, Fit::Near(6)).unwrap(), Some(10));
+
+        /* The smallest run that is long enough */
+        assert_eq!(free.find_run(&mut vol, 2).unwrap(), Some(14));
+        assert_eq!(free.find_run(&mut vol, 3).unwrap(), Some(10));
+        assert_eq!(free.find_run(&mut vol, 4).unwrap(), Some(17));
+        let count = vol.clusters_count;
+        assert_eq!(free.find_run(&mut vol, count).unwrap(), None);
+
+        free.claim(&mut vol, 10, FAT_EOC_MARK).unwrap();
+        assert_eq!(vol.fat_entry(10).unwrap(), FAT_EOC_MARK);
+        assert_eq!(free.find(&mut vol, Fit::Next).unwrap(), Some(11));
+        assert_eq!(free.find(&mut vol, Fit::First).unwrap(), Some(11));
+        free.release(&mut vol, 10).unwrap();
+        assert_eq!(vol.fat_entry(10).unwrap(), 0);
+        assert_eq!(free.find(&mut vol, Fit::First).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn loads_lazily_and_keeps_fs_info_up_to_date() {
+        let mut data = formatted();
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let count = vol.clusters_count;
+        let mut storage = vec![0u8; allocator_scratch_size(count)];
+        assert!(ClusterAllocator::new(&mut storage[1..], count).is_none());
+
+        let mut free = ClusterAllocator::new(&mut storage, count).unwrap();
+        free.read_fs_info(&mut vol).unwrap();
+        assert_eq!(free.free_count(), Some(count - 1));
+        assert_eq!(free.find(&mut vol, Fit::Next).unwrap(), Some(3));
+        assert_eq!(free.loaded_groups, 1);
+
+        free.claim(&mut vol, 3, FAT_EOC_MARK).unwrap();
+        free.claim(&mut vol, 4, FAT_EOC_MARK).unwrap();
+        free.release(&mut vol, 4).unwrap();
+        assert_eq!(free.free_count(), Some(count - 2));
+        free.sync_fs_info(&mut vol).unwrap();
+        assert_eq!(
+            vol.read_fs_info().unwrap(),
+            Some(FsInfo {
+                free_count: count - 2,
+                next_free: 5,
+            })
+        );
+
+        /* A wrong FSInfo count holds until the whole FAT is read */
+        vol.write_fs_info(FsInfo {
+            free_count: 7,
+            next_free: 5,
+        })
+        .unwrap();
+        let mut free = ClusterAllocator::new(&mut storage, count).unwrap();
+        free.read_fs_info(&mut vol).unwrap();
+        assert_eq!(free.free_count(), Some(7));
+        free.load_all(&mut vol).unwrap();
+        assert_eq!(free.free_count(), Some(count - 2));
+    }
+
+    #[test]
+    fn maps_fat12_and_fat16_volumes() {
+        let tree = [Node::File("a.txt", &[1u8; 5000])];
+        for builder in [ImageBuilder::fat12(), ImageBuilder::fat16()] {
+            let mut data = builder.build(&tree);
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            let count = vol.clusters_count;
+            let used = 5000u32.div_ceil(vol.cluster_size as u32);
+            let mut storage = vec![0u8; allocator_scratch_size(count)];
+            let mut free = ClusterAllocator::new(&mut storage, count).unwrap();
+            free.read_fs_info(&mut vol).unwrap();
+            free.load_all(&mut vol).unwrap();
+            assert_eq!(free.free_count(), Some(count - used));
+            assert_eq!(free.find(&mut vol, Fit::First).unwrap(), Some(2 + used));
+
+            free.claim(&mut vol, 2 + used, FAT_EOC_MARK).unwrap();
+            assert_eq!(vol.fat_entry(2 + used).unwrap(), FAT_EOC_MARK);
+            assert_eq!(vol.fat_entry(1 + used).unwrap(), FAT_EOC_MARK);
+        }
+    }
+}
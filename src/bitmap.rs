@@ -0,0 +1,73 @@
+/* One bit per item over caller provided storage, we have no allocator */
+pub struct Bitmap<'a> {
+    bits: &'a mut [u8],
+    len: usize,
+}
+
+/* Bytes of storage needed for `len` bits */
+pub const fn bitmap_bytes(len: usize) -> usize {
+    len.div_ceil(8)
+}
+
+impl<'a> Bitmap<'a> {
+    /* None when `storage` is too small, the bits start cleared */
+    pub fn new(storage: &'a mut [u8], len: usize) -> Option<Self> {
+        let bytes = bitmap_bytes(len);
+        if storage.len() < bytes {
+            return None;
+        }
+        let bits = &mut storage[..bytes];
+        bits.fill(0);
+        Some(Bitmap { bits, len })
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        i < self.len && self.bits[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    pub fn set(&mut self, i: usize) {
+        if i < self.len {
+            self.bits[i / 8] |= 1 << (i % 8);
+        }
+    }
+
+    pub fn clear(&mut self, i: usize) {
+        if i < self.len {
+            self.bits[i / 8] &= !(1 << (i % 8));
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get() {
+        let mut storage = [0xFFu8; 4];
+        let mut bm = Bitmap::new(&mut storage, 20).unwrap();
+        assert_eq!(bm.count_ones(), 0);
+
+        bm.set(0);
+        bm.set(9);
+        bm.set(19);
+        bm.set(20); /* Out of range, ignored */
+        assert!(bm.get(0) && bm.get(9) && bm.get(19));
+        assert!(!bm.get(1) && !bm.get(20));
+        assert_eq!(bm.count_ones(), 3);
+
+        bm.clear(9);
+        assert!(!bm.get(9));
+        assert_eq!(bm.count_ones(), 2);
+    }
+
+    #[test]
+    fn storage_too_small() {
+        let mut storage = [0u8; 2];
+        assert!(Bitmap::new(&mut storage, 17).is_none());
+    }
+}
@@ -1,27 +1,133 @@
 use crate::helpers::{u8_le_to_u16, u8_to_u32_le};
+use crate::volume::MAX_CLUSTER_SIZE;
+
+/* Below these cluster counts a volume is FAT12, then FAT16 (fatgen103) */
+pub const FAT12_MAX_CLUSTERS: u32 = 4085;
+pub const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
 
 pub struct BootSector {
     pub bytes_per_sector: u16,
     pub sectors_per_cluster: u8,
     pub reserved_sectors_count: u16,
     pub fats_count: u8,
+    /* Entries of the fixed root directory of FAT12/16, 0 on FAT32 */
+    pub root_entry_count: u16,
     pub fat_size_sectors: u32,
+    /* Bit 7 set: FATs aren't mirrored, bits 0-3 give the active one.
+     * FAT32 only like the two fields below, 0 otherwise */
+    pub ext_flags: u16,
     pub root_cluster: u32,
+    pub total_sectors: u32,
+    pub fs_info_sector: u16,
+    /* From the cluster count, as the spec defines it */
+    pub fat_type: FatType,
+}
+
+impl BootSector {
+    pub fn root_dir_sectors(&self) -> u32 {
+        (self.root_entry_count as u32 * 32).div_ceil(self.bytes_per_sector as u32)
+    }
+
+    /* Sectors before the data region: reserved ones, FATs and fixed root */
+    pub fn system_sectors(&self) -> u64 {
+        self.reserved_sectors_count as u64
+            + self.fats_count as u64 * self.fat_size_sectors as u64
+            + self.root_dir_sectors() as u64
+    }
+
+    /* Clusters the data region holds, the FAT may describe fewer */
+    pub fn data_clusters(&self) -> u64 {
+        (self.total_sectors as u64).saturating_sub(self.system_sectors())
+            / core::cmp::max(self.sectors_per_cluster as u64, 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootSectorError {
+    /* No 0x55AA at the end of the sector */
+    BadSignature,
+    /* Sector size, cluster size or region sizes no FAT volume can have */
+    BadGeometry,
+    /* exFAT boot region not matching its checksum sector */
+    ChecksumMismatch,
 }
 
 pub fn verify_boot_sector_signature(bs: &[u8; 512]) -> bool {
-    return bs[510] == 0x55 && bs[511] == 0xAA;
+    bs[510] == 0x55 && bs[511] == 0xAA
 }
 
-pub fn parse_boot_sector(bs: &[u8; 512]) -> BootSector {
-    BootSector {
+/* Parse the BPB, refusing values the layout can't be derived from */
+pub fn parse_boot_sector(bs: &[u8; 512]) -> Result<BootSector, BootSectorError> {
+    if !verify_boot_sector_signature(bs) {
+        return Err(BootSectorError::BadSignature);
+    }
+
+    /* The 16 bits count is used when it fits, the 32 bits one otherwise */
+    let total_sectors_16 = u8_le_to_u16(&bs[19..21]) as u32;
+    let total_sectors = if total_sectors_16 != 0 {
+        total_sectors_16
+    } else {
+        u8_to_u32_le(&bs[32..36])
+    };
+
+    /* FAT12/16 have a 16 bits FAT size, FAT32 sets it to 0 and moves the
+     * extended fields in its place */
+    let fat_size_16 = u8_le_to_u16(&bs[22..24]) as u32;
+    let small = fat_size_16 != 0;
+
+    let mut parsed = BootSector {
         bytes_per_sector: u8_le_to_u16(&bs[11..13]),
         sectors_per_cluster: bs[13],
         reserved_sectors_count: u8_le_to_u16(&bs[14..16]),
         fats_count: bs[16],
-        fat_size_sectors: u8_to_u32_le(&bs[36..40]),
-        root_cluster: u8_to_u32_le(&bs[44..48]),
+        root_entry_count: u8_le_to_u16(&bs[17..19]),
+        fat_size_sectors: if small { fat_size_16 } else { u8_to_u32_le(&bs[36..40]) },
+        ext_flags: if small { 0 } else { u8_le_to_u16(&bs[40..42]) },
+        root_cluster: if small { 0 } else { u8_to_u32_le(&bs[44..48]) },
+        total_sectors,
+        fs_info_sector: if small { 0 } else { u8_le_to_u16(&bs[48..50]) },
+        fat_type: FatType::Fat32,
+    };
+
+    let bps = parsed.bytes_per_sector as u32;
+    let spc = parsed.sectors_per_cluster as u32;
+    if !matches!(bps, 512 | 1024 | 2048 | 4096) || spc == 0 || !spc.is_power_of_two() {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if (bps * spc) as usize > MAX_CLUSTER_SIZE {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if parsed.reserved_sectors_count == 0 || parsed.fats_count == 0 || parsed.fat_size_sectors == 0 {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if parsed.total_sectors as u64 <= parsed.system_sectors() {
+        return Err(BootSectorError::BadGeometry);
     }
+
+    let clusters = parsed.data_clusters();
+    parsed.fat_type = if clusters < FAT12_MAX_CLUSTERS as u64 {
+        FatType::Fat12
+    } else if clusters < FAT16_MAX_CLUSTERS as u64 {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    };
+    /* The layout of the BPB has to agree with the cluster count */
+    if small != (parsed.fat_type != FatType::Fat32) {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if !small && parsed.root_entry_count != 0 {
+        return Err(BootSectorError::BadGeometry);
+    }
+
+    Ok(parsed)
 }
 
 #[cfg(test)]
@@ -61,19 +167,107 @@ mod tests {
         bs[38] = 0x00;
         bs[39] = 0x00;
 
+        /* Ext_flags: no mirroring, FAT 1 active */
+        bs[40] = 0x81;
+        bs[41] = 0x00;
+
         /* Root_cluster = 2 */
         bs[44] = 0x02;
         bs[45] = 0x00;
         bs[46] = 0x00;
         bs[47] = 0x00;
 
-        let parsed = parse_boot_sector(&bs);
+        /* Total_sectors_32 = 1048576, enough clusters for FAT32 */
+        bs[32] = 0x00;
+        bs[33] = 0x00;
+        bs[34] = 0x10;
+        bs[35] = 0x00;
+
+        /* Fs_info_sector = 1 */
+        bs[48] = 0x01;
+        bs[49] = 0x00;
+
+        bs[510] = 0x55;
+        bs[511] = 0xAA;
+
+        let parsed = parse_boot_sector(&bs).unwrap();
 
         assert_eq!(parsed.bytes_per_sector, 512);
         assert_eq!(parsed.sectors_per_cluster, 8);
         assert_eq!(parsed.reserved_sectors_count, 32);
         assert_eq!(parsed.fats_count, 2);
         assert_eq!(parsed.fat_size_sectors, 12345);
+        assert_eq!(parsed.ext_flags, 0x0081);
         assert_eq!(parsed.root_cluster, 2);
+        assert_eq!(parsed.total_sectors, 1048576);
+        assert_eq!(parsed.fat_type, FatType::Fat32);
+        assert_eq!(parsed.fs_info_sector, 1);
+    }
+
+    #[test]
+    fn parse_rejects_bad_geometry() {
+        let mut bs = [0u8; 512];
+        assert_eq!(parse_boot_sector(&bs).err(), Some(BootSectorError::BadSignature));
+
+        bs[510] = 0x55;
+        bs[511] = 0xAA;
+        /* All zero BPB */
+        assert_eq!(parse_boot_sector(&bs).err(), Some(BootSectorError::BadGeometry));
+
+        bs[11..13].copy_from_slice(&512u16.to_le_bytes());
+        bs[13] = 1;
+        bs[14..16].copy_from_slice(&32u16.to_le_bytes());
+        bs[16] = 2;
+        bs[36..40].copy_from_slice(&1000u32.to_le_bytes());
+        bs[32..36].copy_from_slice(&100000u32.to_le_bytes());
+        assert!(parse_boot_sector(&bs).is_ok());
+
+        /* 3 sectors per cluster */
+        bs[13] = 3;
+        assert_eq!(parse_boot_sector(&bs).err(), Some(BootSectorError::BadGeometry));
+        bs[13] = 1;
+
+        /* FAT32 layout with a FAT16 cluster count */
+        bs[32..36].copy_from_slice(&10000u32.to_le_bytes());
+        assert_eq!(parse_boot_sector(&bs).err(), Some(BootSectorError::BadGeometry));
+        bs[32..36].copy_from_slice(&100000u32.to_le_bytes());
+
+        /* FATs bigger than the volume */
+        bs[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(parse_boot_sector(&bs).err(), Some(BootSectorError::BadGeometry));
+    }
+
+    #[test]
+    fn fat_type_follows_cluster_count() {
+        /* 1.44 MB floppy: 2847 clusters */
+        let mut bs = [0u8; 512];
+        bs[510] = 0x55;
+        bs[511] = 0xAA;
+        bs[11..13].copy_from_slice(&512u16.to_le_bytes());
+        bs[13] = 1;
+        bs[14..16].copy_from_slice(&1u16.to_le_bytes());
+        bs[16] = 2;
+        bs[17..19].copy_from_slice(&224u16.to_le_bytes());
+        bs[19..21].copy_from_slice(&2880u16.to_le_bytes());
+        bs[22..24].copy_from_slice(&9u16.to_le_bytes());
+        /* FAT32 fields that must be ignored */
+        bs[44..48].copy_from_slice(&7u32.to_le_bytes());
+
+        let parsed = parse_boot_sector(&bs).unwrap();
+        assert_eq!(parsed.fat_type, FatType::Fat12);
+        assert_eq!(parsed.root_dir_sectors(), 14);
+        assert_eq!(parsed.data_clusters(), 2847);
+        assert_eq!(parsed.root_cluster, 0);
+
+        /* 4 sectors per cluster over 32768 sectors: 8167 clusters */
+        bs[13] = 4;
+        bs[14..16].copy_from_slice(&4u16.to_le_bytes());
+        bs[17..19].copy_from_slice(&512u16.to_le_bytes());
+        bs[19..21].copy_from_slice(&0u16.to_le_bytes());
+        bs[32..36].copy_from_slice(&32768u32.to_le_bytes());
+        bs[22..24].copy_from_slice(&32u16.to_le_bytes());
+        let parsed = parse_boot_sector(&bs).unwrap();
+        assert_eq!(parsed.fat_type, FatType::Fat16);
+        assert_eq!(parsed.data_clusters(), 8167);
     }
 }
@@ -0,0 +1,604 @@
+use crate::cli::{print, print_no_ln, print_number};
+use crate::device::{BlockDevice, DeviceError, FileDevice, MmapDevice};
+use crate::partition::Window;
+use crate::sys::{close, file_size, open_rw};
+
+/* Cached unit, whatever the sector size of the volume */
+pub const CACHE_BLOCK_SIZE: usize = 512;
+/* 256 KiB: a whole buffered FAT and a few of the largest clusters */
+pub const CACHE_BLOCKS: usize = 512;
+const EMPTY: u64 = u64::MAX;
+/* What a cache keeps its blocks in, lent by its owner so the blocks don't
+ * move with it */
+pub type CacheBlocks<const N: usize = CACHE_BLOCKS> = [[u8; CACHE_BLOCK_SIZE]; N];
+/* Images larger than that are mapped instead of cached */
+pub const MAP_ABOVE: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /* Blocks dropped to make room */
+    pub evictions: u64,
+    /* Dirty blocks written to the device, on eviction or flush */
+    pub writebacks: u64,
+}
+
+/* Bounded LRU cache of 512 byte blocks in front of a device. Writes stay
+ * in the cache until `flush` or until their block is evicted. Requests
+ * larger than half the capacity go around it for the blocks it misses, so
+ * one big read doesn't empty it */
+pub struct SectorCache<'a, D: BlockDevice, const N: usize = CACHE_BLOCKS> {
+    pub dev: D,
+    /* Slots in use at most, up to N */
+    capacity: usize,
+    blocks: &'a mut CacheBlocks<N>,
+    /* Block number held by each slot, EMPTY when free */
+    tags: [u64; N],
+    /* Tick of the last access, the smallest one is evicted */
+    used: [u64; N],
+    dirty: [bool; N],
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<'a, D: BlockDevice> SectorCache<'a, D> {
+    pub fn new(dev: D, blocks: &'a mut CacheBlocks) -> Self {
+        Self::with_capacity(dev, blocks, CACHE_BLOCKS)
+    }
+}
+
+impl<'a, D: BlockDevice, const N: usize> SectorCache<'a, D, N> {
+    /* `capacity` blocks, clamped to 1..=N */
+    pub fn with_capacity(dev: D, blocks: &'a mut CacheBlocks<N>, capacity: usize) -> Self {
+        SectorCache {
+            dev,
+            capacity: capacity.clamp(1, N),
+            blocks,
+            tags: [EMPTY; N],
+            used: [0; N],
+            dirty: [false; N],
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /* Blocks held, and how many of them wait for a write back */
+    pub fn occupancy(&self) -> (usize, usize) {
+        let held = self.tags[..self.capacity].iter().filter(|&&t| t != EMPTY).count();
+        let dirty = self.dirty[..self.capacity].iter().filter(|&&d| d).count();
+        (held, dirty)
+    }
+
+    /* Write every dirty block back, in block order */
+    pub fn flush(&mut self) -> Result<(), DeviceError> {
+        loop {
+            let next = (0..self.capacity)
+                .filter(|&slot| self.dirty[slot])
+                .min_by_key(|&slot| self.tags[slot]);
+            let Some(slot) = next else {
+                return Ok(());
+            };
+            self.write_back(slot)?;
+        }
+    }
+
+    /* Forget every block, after the device was changed behind the cache.
+     * Dirty blocks are dropped too, flush first to keep them */
+    pub fn invalidate(&mut self) {
+        self.tags.fill(EMPTY);
+        self.dirty.fill(false);
+    }
+
+    fn lookup(&mut self, block: u64) -> Option<usize> {
+        let slot = self.tags[..self.capacity].iter().position(|&t| t == block)?;
+        self.tick += 1;
+        self.used[slot] = self.tick;
+        self.stats.hits += 1;
+        Some(slot)
+    }
+
+    fn write_back(&mut self, slot: usize) -> Result<(), DeviceError> {
+        self.dev.write_at(self.tags[slot] * CACHE_BLOCK_SIZE as u64, &self.blocks[slot])?;
+        self.dirty[slot] = false;
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+
+    /* A slot for `block`: a free one, or the least recently used one once
+     * written back */
+    fn claim(&mut self, block: u64) -> Result<usize, DeviceError> {
+        let slot = match self.tags[..self.capacity].iter().position(|&t| t == EMPTY) {
+            Some(slot) => slot,
+            None => {
+                let slot = (0..self.capacity).min_by_key(|&slot| self.used[slot]).unwrap_or(0);
+                if self.dirty[slot] {
+                    self.write_back(slot)?;
+                }
+                self.stats.evictions += 1;
+                slot
+            }
+        };
+        self.tick += 1;
+        self.tags[slot] = block;
+        self.used[slot] = self.tick;
+        Ok(slot)
+    }
+
+    /* Slot holding `block` read from the device */
+    fn load(&mut self, block: u64) -> Result<usize, DeviceError> {
+        let slot = self.claim(block)?;
+        if let Err(e) = self.dev.read_at(block * CACHE_BLOCK_SIZE as u64, &mut self.blocks[slot]) {
+            self.tags[slot] = EMPTY;
+            return Err(e);
+        }
+        Ok(slot)
+    }
+
+    fn is_large(&self, offset: u64, len: usize) -> bool {
+        let first = offset / CACHE_BLOCK_SIZE as u64;
+        let last = (offset + len as u64).div_ceil(CACHE_BLOCK_SIZE as u64);
+        last - first > self.capacity as u64 / 2
+    }
+}
+
+impl<D: BlockDevice, const N: usize> BlockDevice for SectorCache<'_, D, N> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(DeviceError::OutOfBounds)?;
+        let large = self.is_large(offset, buf.len());
+        /* Start of the missed whole blocks being gathered into one read */
+        let mut direct: Option<u64> = None;
+        let mut pos = offset;
+
+        while pos < end {
+            let block = pos / CACHE_BLOCK_SIZE as u64;
+            let in_block = (pos % CACHE_BLOCK_SIZE as u64) as usize;
+            let len = core::cmp::min(CACHE_BLOCK_SIZE - in_block, (end - pos) as usize);
+            let dst = (pos - offset) as usize;
+
+            let slot = self.lookup(block);
+            if slot.is_none() {
+                self.stats.misses += 1;
+                if large && len == CACHE_BLOCK_SIZE {
+                    direct.get_or_insert(pos);
+                    pos += len as u64;
+                    continue;
+                }
+            }
+            if let Some(start) = direct.take() {
+                self.dev.read_at(start, &mut buf[(start - offset) as usize..dst])?;
+            }
+            let slot = match slot {
+                Some(slot) => slot,
+                None => match self.load(block) {
+                    Ok(slot) => slot,
+                    Err(_) => {
+                        /* A partial block at the end of the device */
+                        self.dev.read_at(pos, &mut buf[dst..dst + len])?;
+                        pos += len as u64;
+                        continue;
+                    }
+                },
+            };
+            buf[dst..dst + len].copy_from_slice(&self.blocks[slot][in_block..in_block + len]);
+            pos += len as u64;
+        }
+        if let Some(start) = direct {
+            self.dev.read_at(start, &mut buf[(start - offset) as usize..])?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(DeviceError::OutOfBounds)?;
+        let large = self.is_large(offset, buf.len());
+        let mut direct: Option<u64> = None;
+        let mut pos = offset;
+
+        while pos < end {
+            let block = pos / CACHE_BLOCK_SIZE as u64;
+            let in_block = (pos % CACHE_BLOCK_SIZE as u64) as usize;
+            let len = core::cmp::min(CACHE_BLOCK_SIZE - in_block, (end - pos) as usize);
+            let src = (pos - offset) as usize;
+
+            let slot = self.lookup(block);
+            if slot.is_none() {
+                self.stats.misses += 1;
+                if large && len == CACHE_BLOCK_SIZE {
+                    direct.get_or_insert(pos);
+                    pos += len as u64;
+                    continue;
+                }
+            }
+            if let Some(start) = direct.take() {
+                self.dev.write_at(start, &buf[(start - offset) as usize..src])?;
+            }
+            let slot = match slot {
+                Some(slot) => Ok(slot),
+                /* Overwritten whole, no need to read it first */
+                None if len == CACHE_BLOCK_SIZE => self.claim(block),
+                None => self.load(block),
+            };
+            match slot {
+                Ok(slot) => {
+                    self.blocks[slot][in_block..in_block + len].copy_from_slice(&buf[src..src + len]);
+                    self.dirty[slot] = true;
+                }
+                Err(_) => self.dev.write_at(pos, &buf[src..src + len])?,
+            }
+            pos += len as u64;
+        }
+        if let Some(start) = direct {
+            self.dev.write_at(start, &buf[(start - offset) as usize..])?;
+        }
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        self.dev.size()
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        SectorCache::flush(self)?;
+        self.dev.flush()
+    }
+}
+
+/* The image the shell was started on. Commands read it through `fd` and
+ * reopen `path` to write */
+#[derive(Clone, Copy)]
+pub struct ImageFile {
+    pub fd: usize,
+    /* NUL terminated */
+    pub path: [u8; 257],
+    /* Started with --read-only, the commands writing are refused */
+    pub read_only: bool,
+}
+
+impl ImageFile {
+    /* A descriptor to write the image through, to be closed by the caller.
+     * None once the user was told why not */
+    pub fn open_writable(&self) -> Option<usize> {
+        if self.read_only {
+            print("The image was opened with --read-only");
+            return None;
+        }
+        match open_rw(self.path.as_ptr(), false) {
+            Ok(rw) => Some(rw),
+            Err(e) => {
+                print_no_ln("Failed to open the image for writing: ");
+                print(e.description());
+                None
+            }
+        }
+    }
+}
+
+/* What commands read and write an image through: the sector cache, or a
+ * mapping of the whole image when it is large. Offsets are relative to the
+ * window the volume lives in. The shell keeps one, attached to the image
+ * it mounted, and lends it to the commands */
+pub struct ImageDevice<'a> {
+    cache: SectorCache<'a, FileDevice>,
+    /* Used instead of the cache when set */
+    map: Option<MmapDevice>,
+    window: Window,
+}
+
+impl<'a> ImageDevice<'a> {
+    /* Attached to no image yet, every access is out of its empty window */
+    pub fn new(blocks: &'a mut CacheBlocks) -> Self {
+        ImageDevice {
+            cache: SectorCache::new(FileDevice::new(0), blocks),
+            map: None,
+            window: Window::NONE,
+        }
+    }
+
+    /* Read the volume in `window` of the image open as `fd`, forgetting
+     * the blocks of the previous one */
+    pub fn attach(&mut self, fd: usize, window: Window) {
+        self.reopen(fd, false);
+        self.window = window;
+        self.cache.invalidate();
+    }
+
+    /* Back to no image, once the one attached was closed */
+    pub fn detach(&mut self) {
+        self.map = None;
+        self.window = Window::NONE;
+        self.cache.invalidate();
+    }
+
+    /* Go through `fd`, mapping it when the image is large. `writable`
+     * needs `fd` opened for writing */
+    fn reopen(&mut self, fd: usize, writable: bool) {
+        let large = file_size(fd).is_ok_and(|size| size > MAP_ABOVE);
+        self.cache.dev = FileDevice::new(fd);
+        self.map = if large { MmapDevice::map(fd, writable).ok() } else { None };
+    }
+
+    /* Run `f` with the device writing through a descriptor reopened on
+     * `image` for writing, then read through the shell's one again. Blocks
+     * `f` left unflushed are dropped. None when the image can't be written,
+     * the user was told why */
+    pub fn with_writable<R>(&mut self, image: &ImageFile, f: impl FnOnce(&mut Self) -> R) -> Option<R> {
+        let rw = image.open_writable()?;
+        self.reopen(rw, true);
+        let result = f(self);
+        self.reopen(image.fd, false);
+        self.cache.invalidate();
+        close(rw);
+        Some(result)
+    }
+
+    /* The sector cache, None when the image is mapped */
+    pub fn cache(&self) -> Option<&SectorCache<'a, FileDevice>> {
+        match self.map {
+            Some(_) => None,
+            None => Some(&self.cache),
+        }
+    }
+
+    /* Image offset of `len` bytes at `offset` in the window */
+    fn place(&self, offset: u64, len: usize) -> Result<u64, DeviceError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.window.len => Ok(self.window.offset + offset),
+            _ => Err(DeviceError::OutOfBounds),
+        }
+    }
+}
+
+impl BlockDevice for ImageDevice<'_> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let at = self.place(offset, buf.len())?;
+        match &mut self.map {
+            Some(map) => map.read_at(at, buf),
+            None => self.cache.read_at(at, buf),
+        }
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        let at = self.place(offset, buf.len())?;
+        match &mut self.map {
+            Some(map) => map.write_at(at, buf),
+            None => self.cache.write_at(at, buf),
+        }
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        let image = match &mut self.map {
+            Some(map) => map.size(),
+            None => self.cache.size(),
+        }?;
+        Ok(image.saturating_sub(self.window.offset).min(self.window.len))
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        match &mut self.map {
+            Some(map) => map.flush(),
+            None => BlockDevice::flush(&mut self.cache),
+        }
+    }
+
+    fn borrow(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let at = self.place(offset, len).ok()?;
+        self.map.as_ref()?.borrow(at, len)
+    }
+}
+
+/* `cache stats` */
+pub fn print_cache_stats<D: BlockDevice, const N: usize>(cache: &SectorCache<'_, D, N>) {
+    let stats = cache.stats();
+    let counters = [
+        ("Hits:         ", stats.hits),
+        ("Misses:       ", stats.misses),
+        ("Evictions:    ", stats.evictions),
+        ("Write-backs:  ", stats.writebacks),
+    ];
+    for (label, value) in counters {
+        print_no_ln(label);
+        print_number(value);
+        print("");
+    }
+
+    let (held, dirty) = cache.occupancy();
+    print_no_ln("Held blocks:  ");
+    print_number(held as u64);
+    print_no_ln(" of ");
+    print_number(cache.capacity() as u64);
+    print_no_ln(", ");
+    print_number(dirty as u64);
+    print(" dirty");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn image() -> Vec<u8> {
+        (0..64 * 1024u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn repeated_reads_hit_the_cache() {
+        let mut data = image();
+        let expected = data.clone();
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; 8];
+        let mut cache = SectorCache::with_capacity(MemDevice { data: &mut data }, &mut blocks, 8);
+
+        let mut buf = [0u8; 700];
+        cache.read_at(300, &mut buf).unwrap();
+        assert_eq!(&buf[..], &expected[300..1000]);
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2, ..CacheStats::default() });
+
+        cache.read_at(100, &mut buf[..200]).unwrap();
+        assert_eq!(&buf[..200], &expected[100..300]);
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.occupancy(), (2, 0));
+    }
+
+    #[test]
+    fn the_least_recently_used_block_goes_first() {
+        let mut data = image();
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; 4];
+        let mut cache = SectorCache::with_capacity(MemDevice { data: &mut data }, &mut blocks, 3);
+        let mut b = [0u8; 1];
+        for block in [0u64, 1, 2, 0, 3] {
+            cache.read_at(block * 512, &mut b).unwrap();
+        }
+        /* 1 was evicted for 3, 0 was touched again */
+        assert_eq!(cache.stats().evictions, 1);
+        let misses = cache.stats().misses;
+        cache.read_at(0, &mut b).unwrap();
+        cache.read_at(2 * 512, &mut b).unwrap();
+        assert_eq!(cache.stats().misses, misses);
+        cache.read_at(512, &mut b).unwrap();
+        assert_eq!(cache.stats().misses, misses + 1);
+    }
+
+    #[test]
+    fn writes_stay_in_the_cache_until_flushed() {
+        let mut data = image();
+        let expected = data.clone();
+        {
+            let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; 4];
+            let mut cache = SectorCache::with_capacity(MemDevice { data: &mut data }, &mut blocks, 4);
+            cache.write_at(510, &[1, 2, 3, 4]).unwrap();
+            cache.write_at(1024, &[9u8; 512]).unwrap();
+            let mut back = [0u8; 6];
+            cache.read_at(509, &mut back).unwrap();
+            assert_eq!(back, [expected[509], 1, 2, 3, 4, expected[514]]);
+            assert_eq!(cache.occupancy(), (3, 3));
+
+            assert_eq!(&cache.dev.data[510..514], &expected[510..514]);
+            BlockDevice::flush(&mut cache).unwrap();
+            assert_eq!(cache.occupancy(), (3, 0));
+            assert_eq!(cache.stats().writebacks, 3);
+        }
+        assert_eq!(&data[510..514], &[1, 2, 3, 4]);
+        assert_eq!(&data[1024..1536], &[9u8; 512]);
+        assert_eq!(&data[514..1024], &expected[514..1024]);
+    }
+
+    #[test]
+    fn dirty_blocks_are_written_back_on_eviction() {
+        let mut data = image();
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; 2];
+        let mut cache = SectorCache::with_capacity(MemDevice { data: &mut data }, &mut blocks, 2);
+        cache.write_at(0, &[7]).unwrap();
+        let mut b = [0u8; 1];
+        cache.read_at(512, &mut b).unwrap();
+        cache.read_at(1024, &mut b).unwrap();
+        assert_eq!(cache.stats().writebacks, 1);
+        assert_eq!(cache.dev.data[0], 7);
+    }
+
+    #[test]
+    fn large_requests_go_around_the_cache() {
+        let mut data = image();
+        let expected = data.clone();
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; 8];
+        let mut cache = SectorCache::with_capacity(MemDevice { data: &mut data }, &mut blocks, 8);
+        let mut b = [0u8; 1];
+        cache.read_at(1024 + 7, &mut b).unwrap();
+        cache.write_at(1024 + 8, &[0xEE]).unwrap();
+
+        /* Ten blocks: the cached one comes from the cache, the rest in one go */
+        let mut buf = vec![0u8; 10 * 512];
+        cache.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf[1024 + 8], 0xEE);
+        assert_eq!(&buf[..1024], &expected[..1024]);
+        assert_eq!(&buf[1536..], &expected[1536..10 * 512]);
+        assert_eq!(cache.occupancy(), (1, 1));
+
+        cache.write_at(0, &vec![5u8; 10 * 512]).unwrap();
+        assert_eq!(cache.dev.data[4096], 5);
+        /* The cached block got the new bytes and is written on flush */
+        assert_eq!(cache.dev.data[1024 + 9], expected[1024 + 9]);
+        cache.flush().unwrap();
+        assert_eq!(cache.dev.data[1024 + 9], 5);
+    }
+
+    #[test]
+    fn a_partial_last_block_is_read_around_the_cache() {
+        let mut data = vec![3u8; 700];
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; 4];
+        let mut cache = SectorCache::with_capacity(MemDevice { data: &mut data }, &mut blocks, 4);
+        let mut buf = [0u8; 100];
+        cache.read_at(600, &mut buf).unwrap();
+        assert_eq!(buf, [3u8; 100]);
+        assert!(cache.read_at(650, &mut buf).is_err());
+    }
+
+    #[test]
+    fn image_device_stays_inside_its_window() {
+        let data = image();
+        let path = std::env::temp_dir().join(std::format!("fat32-{}-window", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let mut c_path = path.to_str().unwrap().as_bytes().to_vec();
+        c_path.push(0);
+        let fd = crate::sys::open(c_path.as_ptr()).unwrap();
+
+        let window = Window {
+            offset: 4096,
+            len: 8192,
+        };
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; CACHE_BLOCKS];
+        let mut dev = ImageDevice::new(&mut blocks);
+        assert_eq!(dev.read_at(0, &mut [0u8; 1]), Err(DeviceError::OutOfBounds));
+        dev.attach(fd, window);
+        assert_eq!(dev.size(), Ok(8192));
+        let mut buf = [0u8; 16];
+        dev.read_at(100, &mut buf).unwrap();
+        assert_eq!(&buf[..], &data[4196..4212]);
+        assert_eq!(dev.read_at(8190, &mut buf), Err(DeviceError::OutOfBounds));
+
+        crate::sys::close(fd);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writes_go_through_a_reopened_descriptor() {
+        let data = image();
+        let path = std::env::temp_dir().join(std::format!("fat32-{}-writable", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let mut c_path = path.to_str().unwrap().as_bytes().to_vec();
+        c_path.push(0);
+        let fd = crate::sys::open(c_path.as_ptr()).unwrap();
+        let mut image = ImageFile {
+            fd,
+            path: [0u8; 257],
+            read_only: false,
+        };
+        image.path[..c_path.len()].copy_from_slice(&c_path);
+
+        let mut blocks = [[0u8; CACHE_BLOCK_SIZE]; CACHE_BLOCKS];
+        let mut dev = ImageDevice::new(&mut blocks);
+        dev.attach(fd, Window::WHOLE);
+        let mut buf = [0u8; 4];
+        dev.read_at(512, &mut buf).unwrap();
+        let written = dev.with_writable(&image, |dev| {
+            dev.write_at(512, b"abcd")?;
+            dev.flush()
+        });
+        assert_eq!(written, Some(Ok(())));
+        dev.read_at(512, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(&std::fs::read(&path).unwrap()[512..516], b"abcd");
+
+        crate::sys::close(fd);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1581 @@
+use crate::allocator::{ClusterAllocator, Fit, allocator_scratch_size};
+use crate::bitmap::{Bitmap, bitmap_bytes};
+use crate::cli::{print, print_bytes_hex, print_no_ln, print_number};
+use crate::cache::{ImageDevice, ImageFile};
+use crate::device::{BlockDevice, DeviceError};
+use crate::dir_entry::{
+    ATTR_ARCHIVE, ATTR_DIRECTORY, DIR_ENTRY_SIZE, DOT_DOT_NAME, DOT_NAME, ENTRY_DELETED,
+    ENTRY_END, LFN_MAX_ENTRIES, LfnBuilder, build_dir_entry, is_legal_short_name, is_lfn_entry, lfn_to_utf8,
+    parse_dir_entry,
+};
+use crate::fat::build_short_name;
+use crate::format::zero_range;
+use crate::helpers::{u16_to_u8_le, u32_to_u8_le, u8_to_u32_le, write_padded_decimal};
+use crate::sys::{now_seconds, print_bytes, read};
+use crate::volume::{
+    FAT_BAD_CLUSTER, FAT_END_OF_CHAIN, FAT_EOC_MARK, FSINFO_LEAD_SIGNATURE,
+    FSINFO_STRUCT_SIGNATURE, FSINFO_TRAIL_SIGNATURE, FSINFO_UNKNOWN, MAX_CLUSTER_SIZE, Volume,
+    VolumeError,
+};
+use crate::walk::{MAX_DEPTH, Path, has_entry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    /* The first cluster is outside of the data region */
+    InvalidStart { cluster: u32 },
+    /* `cluster` is in a chain but marked free in the FAT */
+    FreeInChain { cluster: u32 },
+    /* The FAT entry of `cluster` is reserved, bad or out of range */
+    InvalidLink { cluster: u32, value: u32 },
+    /* `cluster` already belongs to another chain */
+    CrossLinked { cluster: u32 },
+    /* The size needs a different number of clusters than the chain has */
+    SizeMismatch { size: u32, clusters: u32 },
+    MissingDot,
+    MissingDotDot,
+    IllegalShortName,
+    /* Long name entries not followed by their short entry */
+    OrphanLfn { entries: u32 },
+    /* Allocated clusters no entry points to */
+    LostChain { start: u32, clusters: u32 },
+    FsInfoInvalid,
+    FsInfoFreeCount { recorded: u32, actual: u32 },
+    TooDeep,
+}
+
+impl IssueKind {
+    /* Whether repair mode knows how to fix it */
+    pub fn is_fixable(&self) -> bool {
+        !matches!(self, IssueKind::IllegalShortName | IssueKind::TooDeep)
+    }
+}
+
+pub struct Issue<'a> {
+    pub kind: IssueKind,
+    /* Path of the entry concerned, empty for volume wide issues */
+    pub path: &'a [u8],
+    /* Repairing and a fix exists: the handler decides whether it's applied */
+    pub fixable: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CheckReport {
+    pub files: u32,
+    pub directories: u32,
+    /* Clusters reached from the directory tree */
+    pub used_clusters: u32,
+    pub free_clusters: u32,
+    pub lost_chains: u32,
+    pub lost_clusters: u32,
+    pub errors: u32,
+    pub fixed: u32,
+}
+
+impl CheckReport {
+    /* Same meaning as dosfsck: 0 clean, 1 errors were found */
+    pub fn exit_code(&self) -> usize {
+        if self.errors == 0 { 0 } else { 1 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckError {
+    Volume(VolumeError),
+    /* The scratch buffer can't hold the cluster bitmaps */
+    ScratchTooSmall,
+}
+
+impl From<VolumeError> for CheckError {
+    fn from(e: VolumeError) -> Self {
+        CheckError::Volume(e)
+    }
+}
+
+impl From<DeviceError> for CheckError {
+    fn from(e: DeviceError) -> Self {
+        CheckError::Volume(VolumeError::Device(e))
+    }
+}
+
+/* Exit code when the volume could not be checked at all */
+pub const CHECK_FAILED_EXIT_CODE: usize = 2;
+
+/* A directory holds 65536 entries at most */
+const DIR_MAX_BYTES: u64 = 65536 * DIR_ENTRY_SIZE as u64;
+
+/* Scratch bytes `check_volume` needs for a volume of `clusters_count` clusters */
+pub fn check_scratch_size(clusters_count: u32) -> usize {
+    2 * bitmap_bytes(clusters_count as usize + 2) + allocator_scratch_size(clusters_count)
+}
+
+struct Chain {
+    clusters: u32,
+    /* Last cluster to keep when cutting the chain before `problem`, 0 if none */
+    last: u32,
+    problem: Option<IssueKind>,
+}
+
+/* Cluster bookkeeping shared by the scan and the repairs */
+struct Clusters<'a> {
+    /* Clusters belonging to a chain already walked */
+    used: Bitmap<'a>,
+    /* Free clusters per the FAT, read as repairs need them */
+    free: ClusterAllocator<'a>,
+    /* Clusters taken by repairs */
+    allocated: u32,
+}
+
+impl Clusters<'_> {
+    /* Follow a chain marking its clusters as used, stops at the first problem */
+    fn walk_chain<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        start: u32,
+    ) -> Result<Chain, VolumeError> {
+        if !vol.is_valid_cluster(start) {
+            return Ok(Chain {
+                clusters: 0,
+                last: 0,
+                problem: Some(IssueKind::InvalidStart { cluster: start }),
+            });
+        }
+
+        let mut clusters = 0u32;
+        let mut last = 0u32;
+        let mut cluster = start;
+        loop {
+            /* Also what ends a chain looping on itself */
+            if self.used.get(cluster as usize) {
+                return Ok(Chain {
+                    clusters,
+                    last,
+                    problem: Some(IssueKind::CrossLinked { cluster }),
+                });
+            }
+            self.used.set(cluster as usize);
+            clusters += 1;
+            last = cluster;
+
+            let next = vol.fat_entry(cluster)?;
+            if next == 0 {
+                return Ok(Chain {
+                    clusters,
+                    last,
+                    problem: Some(IssueKind::FreeInChain { cluster }),
+                });
+            }
+            if next >= FAT_END_OF_CHAIN {
+                return Ok(Chain {
+                    clusters,
+                    last,
+                    problem: None,
+                });
+            }
+            if !vol.is_valid_cluster(next) {
+                return Ok(Chain {
+                    clusters,
+                    last,
+                    problem: Some(IssueKind::InvalidLink {
+                        cluster,
+                        value: next,
+                    }),
+                });
+            }
+            cluster = next;
+        }
+    }
+
+    /* Take the free cluster closest to `near` and end a chain with it, None
+     * when the volume is full */
+    fn alloc<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        near: u32,
+    ) -> Result<Option<u32>, VolumeError> {
+        while let Some(cluster) = self.free.find(vol, Fit::Near(near))? {
+            /* Free in the FAT yet part of a chain walked, cut there */
+            if self.used.get(cluster as usize) {
+                self.free.reserve(cluster);
+                continue;
+            }
+            self.free.claim(vol, cluster, FAT_EOC_MARK)?;
+            self.used.set(cluster as usize);
+            self.allocated += 1;
+            return Ok(Some(cluster));
+        }
+        Ok(None)
+    }
+
+    /* Free a chain we own, up to its end or its first broken link */
+    fn free_chain<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        start: u32,
+    ) -> Result<(), VolumeError> {
+        let mut cluster = start;
+        for _ in 0..vol.clusters_count {
+            if !vol.is_valid_cluster(cluster) {
+                break;
+            }
+            let next = vol.fat_entry(cluster)?;
+            self.free.release(vol, cluster)?;
+            self.used.clear(cluster as usize);
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /* Offset of a free slot in directory `dir` past its first `skip` slots,
+     * a full directory grows by one zeroed cluster. None when out of space */
+    fn free_slot<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        dir: u32,
+        skip: usize,
+    ) -> Result<Option<u64>, VolumeError> {
+        let mut slot = 0usize;
+        let mut cluster = dir;
+
+        for _ in 0..vol.clusters_count {
+            let base = vol.dir_block_offset(cluster);
+            let per_cluster = vol.dir_block_size(cluster) / DIR_ENTRY_SIZE;
+            for i in 0..per_cluster {
+                slot += 1;
+                if slot <= skip {
+                    continue;
+                }
+                let offset = base + (i * DIR_ENTRY_SIZE) as u64;
+                let mut first = [0u8; 1];
+                vol.dev.read_at(offset, &mut first)?;
+                if first[0] == ENTRY_DELETED {
+                    return Ok(Some(offset));
+                }
+                if first[0] == ENTRY_END {
+                    /* The end of the directory moves one slot further */
+                    if i + 1 < per_cluster {
+                        vol.dev.write_at(offset + DIR_ENTRY_SIZE as u64, &[ENTRY_END])?;
+                    }
+                    return Ok(Some(offset));
+                }
+            }
+
+            /* The fixed root can't grow */
+            if vol.is_fixed_root(cluster) {
+                return Ok(None);
+            }
+            let next = vol.fat_entry(cluster)?;
+            if !vol.is_valid_cluster(next) {
+                let Some(new) = self.alloc(vol, cluster)? else {
+                    return Ok(None);
+                };
+                let offset = vol.cluster_offset(new);
+                zero_range(&mut vol.dev, offset, vol.cluster_size as u64)?;
+                vol.set_fat_entry(cluster, new)?;
+                return Ok(Some(offset));
+            }
+            cluster = next;
+        }
+        Ok(None)
+    }
+
+    /* Copy the clusters a chain shares with another one from `shared` on, at
+     * most `count` of them. Returns the first copy (0 if none) and how many */
+    fn copy_shared<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        buf: &mut [u8],
+        shared: u32,
+        count: u32,
+    ) -> Result<(u32, u32), VolumeError> {
+        let mut src = shared;
+        let mut head = 0u32;
+        let mut prev = 0u32;
+        let mut copied = 0u32;
+
+        while copied < count {
+            let near = if prev == 0 { src } else { prev };
+            let Some(dst) = self.alloc(vol, near)? else {
+                break;
+            };
+            vol.read_cluster(src, buf)?;
+            vol.write_cluster(dst, buf)?;
+            if prev == 0 {
+                head = dst;
+            } else {
+                vol.set_fat_entry(prev, dst)?;
+            }
+            prev = dst;
+            copied += 1;
+
+            let next = vol.fat_entry(src)?;
+            if !vol.is_valid_cluster(next) {
+                break;
+            }
+            src = next;
+        }
+        Ok((head, copied))
+    }
+
+    /* Create an empty FOUND.nnn in the root, None when out of space or names */
+    fn make_found_dir<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+    ) -> Result<Option<u32>, VolumeError> {
+        let root = vol.bs.root_cluster;
+        let mut name = *b"FOUND   000";
+        let mut n = 0u32;
+        while has_entry(vol, root, &name)? {
+            n += 1;
+            if n == 1000 {
+                return Ok(None);
+            }
+            write_padded_decimal(n, &mut name[8..11]);
+        }
+
+        let Some(cluster) = self.alloc(vol, root)? else {
+            return Ok(None);
+        };
+        let offset = vol.cluster_offset(cluster);
+        zero_range(&mut vol.dev, offset, vol.cluster_size as u64)?;
+
+        let now = now_seconds();
+        let dot = build_dir_entry(DOT_NAME, ATTR_DIRECTORY, cluster, 0, now);
+        let dot_dot = build_dir_entry(DOT_DOT_NAME, ATTR_DIRECTORY, 0, 0, now);
+        vol.dev.write_at(offset, &dot)?;
+        vol.dev.write_at(offset + DIR_ENTRY_SIZE as u64, &dot_dot)?;
+
+        let Some(slot) = self.free_slot(vol, root, 0)? else {
+            self.free_chain(vol, cluster)?;
+            return Ok(None);
+        };
+        let entry = build_dir_entry(&name, ATTR_DIRECTORY, cluster, 0, now);
+        vol.dev.write_at(slot, &entry)?;
+        Ok(Some(cluster))
+    }
+
+    /* Write the `.` (slot 0) or `..` (slot 1) entry of directory `dir`,
+     * moving away the entry sitting there. False when there's no room for it */
+    fn fix_dot<D: BlockDevice>(
+        &mut self,
+        vol: &mut Volume<D>,
+        dir: u32,
+        slot: usize,
+        target: u32,
+    ) -> Result<bool, VolumeError> {
+        let offset = vol.cluster_offset(dir) + (slot * DIR_ENTRY_SIZE) as u64;
+        let mut old = [0u8; DIR_ENTRY_SIZE];
+        vol.dev.read_at(offset, &mut old)?;
+
+        let taken = old[0] != ENTRY_END
+            && old[0] != ENTRY_DELETED
+            && !is_lfn_entry(&old)
+            && &old[0..11] != DOT_NAME
+            && &old[0..11] != DOT_DOT_NAME;
+        if taken {
+            let Some(to) = self.free_slot(vol, dir, 2)? else {
+                return Ok(false);
+            };
+            vol.dev.write_at(to, &old)?;
+        } else if old[0] == ENTRY_END {
+            vol.dev.write_at(offset + DIR_ENTRY_SIZE as u64, &[ENTRY_END])?;
+        }
+
+        let name = if slot == 0 { DOT_NAME } else { DOT_DOT_NAME };
+        let entry = build_dir_entry(name, ATTR_DIRECTORY, target, 0, now_seconds());
+        vol.dev.write_at(offset, &entry)?;
+        Ok(true)
+    }
+}
+
+/* Cluster number `n` (from 0) of a chain known to be that long */
+fn nth_cluster<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    start: u32,
+    n: u32,
+) -> Result<u32, VolumeError> {
+    let mut cluster = start;
+    for _ in 0..n {
+        cluster = vol.fat_entry(cluster)?;
+    }
+    Ok(cluster)
+}
+
+/* Length of a chain up to its end or first broken link */
+fn chain_length<D: BlockDevice>(vol: &mut Volume<D>, start: u32) -> Result<u32, VolumeError> {
+    let mut clusters = 0u32;
+    let mut cluster = start;
+    while vol.is_valid_cluster(cluster) && clusters < vol.clusters_count {
+        clusters += 1;
+        cluster = vol.fat_entry(cluster)?;
+    }
+    Ok(clusters)
+}
+
+/* Point the short entry at `offset` to another chain and size */
+fn set_entry_chain<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    offset: u64,
+    first: u32,
+    size: u32,
+) -> Result<(), VolumeError> {
+    let mut e = [0u8; DIR_ENTRY_SIZE];
+    vol.dev.read_at(offset, &mut e)?;
+    u16_to_u8_le((first >> 16) as u16, &mut e[20..22]);
+    u16_to_u8_le(first as u16, &mut e[26..28]);
+    u32_to_u8_le(size, &mut e[28..32]);
+    vol.dev.write_at(offset, &e)?;
+    Ok(())
+}
+
+fn delete_entries<D: BlockDevice>(vol: &mut Volume<D>, offsets: &[u64]) -> Result<(), VolumeError> {
+    for &offset in offsets {
+        vol.dev.write_at(offset, &[ENTRY_DELETED])?;
+    }
+    Ok(())
+}
+
+struct Frame {
+    /* First cluster of the directory */
+    first: u32,
+    cluster: u32,
+    /* Next entry to look at in `cluster` */
+    index: usize,
+    /* Clusters of the validated chain left, `cluster` included */
+    remaining: u32,
+    path_len: usize,
+}
+
+/* Report one issue and count it, true when it has to be fixed */
+fn report<F: FnMut(&Issue) -> bool>(
+    report: &mut CheckReport,
+    on_issue: &mut F,
+    repair: bool,
+    kind: IssueKind,
+    path: &[u8],
+) -> bool {
+    report.errors += 1;
+    let fixable = repair && kind.is_fixable();
+    let fix = on_issue(&Issue {
+        kind,
+        path,
+        fixable,
+    });
+    if fixable && fix {
+        report.fixed += 1;
+    }
+    fixable && fix
+}
+
+/* Report long name entries left without their short entry, deleting them
+ * when agreed. True when the directory was written to */
+fn report_orphans<D: BlockDevice, F: FnMut(&Issue) -> bool>(
+    vol: &mut Volume<D>,
+    rep: &mut CheckReport,
+    on_issue: &mut F,
+    repair: bool,
+    offsets: &[u64],
+    path: &[u8],
+) -> Result<bool, VolumeError> {
+    if offsets.is_empty() {
+        return Ok(false);
+    }
+    let kind = IssueKind::OrphanLfn {
+        entries: offsets.len() as u32,
+    };
+    if !report(rep, on_issue, repair, kind, path) {
+        return Ok(false);
+    }
+    delete_entries(vol, offsets)?;
+    Ok(true)
+}
+
+/* The first two entries of a subdirectory must point to itself and its parent */
+fn check_dots<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    cluster: u32,
+    parent: u32,
+) -> Result<(bool, bool), VolumeError> {
+    let mut head = [0u8; 2 * DIR_ENTRY_SIZE];
+    let offset = vol.cluster_offset(cluster);
+    vol.dev.read_at(offset, &mut head)?;
+
+    let dot = parse_dir_entry(&head[..DIR_ENTRY_SIZE]);
+    let dot_dot = parse_dir_entry(&head[DIR_ENTRY_SIZE..]);
+
+    let dot_ok = &dot.name == DOT_NAME && dot.is_dir() && dot.first_cluster == cluster;
+
+    /* `..` of a root child holds 0, some writers put the root cluster there */
+    let parent_ok = dot_dot.first_cluster == parent
+        || (parent == vol.bs.root_cluster && dot_dot.first_cluster == 0);
+    let dot_dot_ok = &dot_dot.name == DOT_DOT_NAME && dot_dot.is_dir() && parent_ok;
+
+    Ok((dot_ok, dot_dot_ok))
+}
+
+/* Read only scan of the whole volume. Every problem found is handed to
+ * `on_issue`, `scratch` must hold `check_scratch_size` bytes */
+pub fn check_volume<D: BlockDevice, F: FnMut(&Issue)>(
+    vol: &mut Volume<D>,
+    scratch: &mut [u8],
+    mut on_issue: F,
+) -> Result<CheckReport, CheckError> {
+    scan(vol, scratch, false, |issue| {
+        on_issue(issue);
+        false
+    })
+}
+
+/* Same scan, fixing each problem `on_issue` returns true for */
+pub fn repair_volume<D: BlockDevice, F: FnMut(&Issue) -> bool>(
+    vol: &mut Volume<D>,
+    scratch: &mut [u8],
+    on_issue: F,
+) -> Result<CheckReport, CheckError> {
+    scan(vol, scratch, true, on_issue)
+}
+
+fn scan<D: BlockDevice, F: FnMut(&Issue) -> bool>(
+    vol: &mut Volume<D>,
+    scratch: &mut [u8],
+    repair: bool,
+    mut on_issue: F,
+) -> Result<CheckReport, CheckError> {
+    let bits = vol.clusters_count as usize + 2;
+    if scratch.len() < check_scratch_size(vol.clusters_count) {
+        return Err(CheckError::ScratchTooSmall);
+    }
+    let (used_storage, rest) = scratch.split_at_mut(bitmap_bytes(bits));
+    let (pointed_storage, free_storage) = rest.split_at_mut(bitmap_bytes(bits));
+    let used = Bitmap::new(used_storage, bits).ok_or(CheckError::ScratchTooSmall)?;
+    let mut pointed = Bitmap::new(pointed_storage, bits).ok_or(CheckError::ScratchTooSmall)?;
+    let free = ClusterAllocator::new(free_storage, vol.clusters_count)
+        .ok_or(CheckError::ScratchTooSmall)?;
+    let mut cl = Clusters {
+        used,
+        free,
+        allocated: 0,
+    };
+
+    let mut rep = CheckReport::default();
+    let mut path = Path::new();
+
+    /* ---------- Directory tree ---------- */
+    let root = vol.bs.root_cluster;
+    let chain = if vol.is_fixed_root(root) {
+        Chain {
+            clusters: 1,
+            last: 0,
+            problem: None,
+        }
+    } else {
+        cl.walk_chain(vol, root)?
+    };
+    if let Some(kind) = chain.problem {
+        /* Mounting checked the first cluster, so there is one to keep */
+        if report(&mut rep, &mut on_issue, repair, kind, b"/") {
+            vol.set_fat_entry(chain.last, FAT_EOC_MARK)?;
+        }
+    }
+    rep.directories += 1;
+
+    let mut stack: [Frame; MAX_DEPTH] = core::array::from_fn(|_| Frame {
+        first: 0,
+        cluster: 0,
+        index: 0,
+        remaining: 0,
+        path_len: 0,
+    });
+    let mut depth = 0usize;
+    if chain.clusters > 0 {
+        stack[0] = Frame {
+            first: root,
+            cluster: root,
+            index: 0,
+            remaining: chain.clusters,
+            path_len: 0,
+        };
+        depth = 1;
+    }
+
+    let cluster_size = vol.cluster_size as u64;
+    let mut cluster_buf = [0u8; MAX_CLUSTER_SIZE];
+    /* Directory block held by `cluster_buf`, cluster 0 being the fixed root */
+    let mut buffered = u32::MAX;
+    let mut lfn = LfnBuilder::new();
+    /* Where the entries gathered by `lfn` are */
+    let mut lfn_offsets = [0u64; LFN_MAX_ENTRIES];
+    let mut name_buf = [0u8; 4 * 260];
+
+    while depth > 0 {
+        let top = depth - 1;
+        path.len = stack[top].path_len;
+
+        if stack[top].index == vol.dir_block_size(stack[top].cluster) / DIR_ENTRY_SIZE {
+            stack[top].remaining -= 1;
+            if stack[top].remaining == 0 {
+                let pending = lfn.reset() as usize;
+                let offsets = &lfn_offsets[..pending];
+                if report_orphans(vol, &mut rep, &mut on_issue, repair, offsets, path.as_bytes())? {
+                    buffered = u32::MAX;
+                }
+                depth -= 1;
+                continue;
+            }
+            stack[top].cluster = vol.fat_entry(stack[top].cluster)?;
+            stack[top].index = 0;
+        }
+
+        let cluster = stack[top].cluster;
+        if buffered != cluster {
+            vol.read_dir_block(cluster, &mut cluster_buf)?;
+            buffered = cluster;
+        }
+
+        let index = stack[top].index;
+        stack[top].index += 1;
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry.copy_from_slice(&cluster_buf[index * DIR_ENTRY_SIZE..(index + 1) * DIR_ENTRY_SIZE]);
+        let offset = vol.dir_block_offset(cluster) + (index * DIR_ENTRY_SIZE) as u64;
+
+        if entry[0] == ENTRY_END || entry[0] == ENTRY_DELETED {
+            let pending = lfn.reset() as usize;
+            let offsets = &lfn_offsets[..pending];
+            if report_orphans(vol, &mut rep, &mut on_issue, repair, offsets, path.as_bytes())? {
+                buffered = u32::MAX;
+            }
+            if entry[0] == ENTRY_END {
+                depth -= 1;
+            }
+            continue;
+        }
+
+        if is_lfn_entry(&entry) {
+            let pending = lfn.pending() as usize;
+            if lfn.push(&entry) > 0 {
+                /* Either this entry starts a new sequence, or it is lost too */
+                let mut lost = [0u64; LFN_MAX_ENTRIES + 1];
+                lost[..pending].copy_from_slice(&lfn_offsets[..pending]);
+                let mut count = pending;
+                if lfn.pending() == 0 {
+                    lost[count] = offset;
+                    count += 1;
+                }
+                let offsets = &lost[..count];
+                if report_orphans(vol, &mut rep, &mut on_issue, repair, offsets, path.as_bytes())? {
+                    buffered = u32::MAX;
+                }
+            }
+            let pending = lfn.pending() as usize;
+            if pending > 0 {
+                lfn_offsets[pending - 1] = offset;
+            }
+            continue;
+        }
+
+        let e = parse_dir_entry(&entry);
+
+        /* Name shown in the reports: long name when there is a valid one */
+        let pending = lfn.pending() as usize;
+        let name_len = match lfn.finish(&e.name) {
+            Ok(Some(units)) => lfn_to_utf8(units, &mut name_buf),
+            Ok(None) => build_short_name(&e.name[0..8], &e.name[8..11], &mut name_buf),
+            Err(_) => {
+                let offsets = &lfn_offsets[..pending];
+                if report_orphans(vol, &mut rep, &mut on_issue, repair, offsets, path.as_bytes())? {
+                    buffered = u32::MAX;
+                }
+                build_short_name(&e.name[0..8], &e.name[8..11], &mut name_buf)
+            }
+        };
+
+        if e.is_volume_id() || e.is_dot() {
+            continue;
+        }
+
+        path.push(&name_buf[..name_len]);
+
+        if !is_legal_short_name(&e.name) {
+            let kind = IssueKind::IllegalShortName;
+            report(&mut rep, &mut on_issue, repair, kind, path.as_bytes());
+        }
+
+        if e.is_dir() {
+            rep.directories += 1;
+
+            let mut first = e.first_cluster;
+            let chain = cl.walk_chain(vol, first)?;
+            let mut clusters = chain.clusters;
+            if let Some(kind) = chain.problem
+                && report(&mut rep, &mut on_issue, repair, kind, path.as_bytes())
+            {
+                /* A directory has no size, all it shares gets copied. Not
+                 * when it shares its own clusters or an ancestor's: a loop */
+                let mut copies = (0u32, 0u32);
+                if let IssueKind::CrossLinked { cluster } = kind
+                    && !in_chain(vol, first, clusters, cluster)?
+                    && !in_ancestors(vol, &stack[..depth], cluster)?
+                {
+                    let max = (DIR_MAX_BYTES / cluster_size) as u32;
+                    let count = core::cmp::min(chain_length(vol, cluster)?, max.saturating_sub(clusters));
+                    copies = cl.copy_shared(vol, &mut cluster_buf, cluster, count)?;
+                    buffered = u32::MAX;
+                }
+
+                let (copy, copied) = copies;
+                if chain.last == 0 && copied == 0 {
+                    /* Nothing usable */
+                    delete_entries(vol, &[offset])?;
+                    buffered = u32::MAX;
+                } else if chain.last == 0 {
+                    first = copy;
+                    set_entry_chain(vol, offset, first, 0)?;
+                    cl.fix_dot(vol, first, 0, first)?;
+                    buffered = u32::MAX;
+                } else {
+                    let next = if copied == 0 { FAT_EOC_MARK } else { copy };
+                    vol.set_fat_entry(chain.last, next)?;
+                }
+                clusters += copied;
+            }
+            /* Nothing to walk, or another entry owns this directory already */
+            if clusters == 0 {
+                continue;
+            }
+
+            let parent = stack[top].first;
+            let (dot_ok, dot_dot_ok) = check_dots(vol, first, parent)?;
+            let mut dots_fixed = false;
+            if !dot_ok {
+                let kind = IssueKind::MissingDot;
+                if report(&mut rep, &mut on_issue, repair, kind, path.as_bytes()) {
+                    dots_fixed |= cl.fix_dot(vol, first, 0, first)?;
+                }
+            }
+            if !dot_dot_ok {
+                let kind = IssueKind::MissingDotDot;
+                if report(&mut rep, &mut on_issue, repair, kind, path.as_bytes()) {
+                    let target = if parent == root { 0 } else { parent };
+                    dots_fixed |= cl.fix_dot(vol, first, 1, target)?;
+                }
+            }
+            /* Moving an entry out of the way may have grown the directory */
+            if dots_fixed {
+                clusters = chain_length(vol, first)?;
+            }
+
+            if depth == MAX_DEPTH {
+                let kind = IssueKind::TooDeep;
+                report(&mut rep, &mut on_issue, repair, kind, path.as_bytes());
+                continue;
+            }
+
+            stack[depth] = Frame {
+                first,
+                cluster: first,
+                index: 0,
+                remaining: clusters,
+                path_len: path.len,
+            };
+            depth += 1;
+            continue;
+        }
+
+        rep.files += 1;
+
+        let mut first = e.first_cluster;
+        let mut size = e.file_size;
+        let mut clusters = 0;
+        if first != 0 {
+            let chain = cl.walk_chain(vol, first)?;
+            clusters = chain.clusters;
+            if let Some(kind) = chain.problem {
+                if !report(&mut rep, &mut on_issue, repair, kind, path.as_bytes()) {
+                    continue;
+                }
+
+                /* Only what the size asks for gets copied off a cross link */
+                let wanted = (size as u64).div_ceil(cluster_size) as u32;
+                let mut copies = (0u32, 0u32);
+                if let IssueKind::CrossLinked { cluster } = kind
+                    && wanted > clusters
+                    && !in_chain(vol, first, clusters, cluster)?
+                {
+                    copies = cl.copy_shared(vol, &mut cluster_buf, cluster, wanted - clusters)?;
+                    buffered = u32::MAX;
+                }
+
+                let (copy, copied) = copies;
+                if chain.last == 0 {
+                    first = copy;
+                    if copied == 0 {
+                        size = 0;
+                    }
+                    set_entry_chain(vol, offset, first, size)?;
+                    buffered = u32::MAX;
+                } else {
+                    let next = if copied == 0 { FAT_EOC_MARK } else { copy };
+                    vol.set_fat_entry(chain.last, next)?;
+                }
+                clusters += copied;
+            }
+        }
+
+        let expected = (size as u64).div_ceil(cluster_size);
+        if expected != clusters as u64 {
+            let kind = IssueKind::SizeMismatch { size, clusters };
+            if report(&mut rep, &mut on_issue, repair, kind, path.as_bytes()) {
+                if expected > clusters as u64 {
+                    /* Data past the chain is gone, keep what the chain holds */
+                    let bytes = core::cmp::min(clusters as u64 * cluster_size, u32::MAX as u64);
+                    set_entry_chain(vol, offset, first, bytes as u32)?;
+                } else if expected == 0 {
+                    cl.free_chain(vol, first)?;
+                    set_entry_chain(vol, offset, 0, size)?;
+                } else {
+                    let end = nth_cluster(vol, first, expected as u32 - 1)?;
+                    let tail = vol.fat_entry(end)?;
+                    vol.set_fat_entry(end, FAT_EOC_MARK)?;
+                    cl.free_chain(vol, tail)?;
+                }
+                buffered = u32::MAX;
+            }
+        }
+    }
+
+    /* ---------- Lost chains ---------- */
+    for cluster in 2..bits as u32 {
+        let value = vol.fat_entry(cluster)?;
+        if value == 0 {
+            rep.free_clusters += 1;
+        } else if value != FAT_BAD_CLUSTER && !cl.used.get(cluster as usize) {
+            rep.lost_clusters += 1;
+            if vol.is_valid_cluster(value) {
+                pointed.set(value as usize);
+            }
+        }
+    }
+    let allocated = cl.allocated;
+
+    let mut found_dir = 0u32;
+    let mut found_files = 0u32;
+    /* Lost clusters left where they were */
+    let mut unsaved = 0u32;
+
+    /* Chain heads first, then whatever is left: lost chains looping on themselves */
+    for heads_only in [true, false] {
+        for cluster in 2..bits as u32 {
+            if cl.used.get(cluster as usize) || (heads_only && pointed.get(cluster as usize)) {
+                continue;
+            }
+            let value = vol.fat_entry(cluster)?;
+            if value == 0 || value == FAT_BAD_CLUSTER {
+                continue;
+            }
+            let chain = cl.walk_chain(vol, cluster)?;
+            rep.lost_chains += 1;
+            let kind = IssueKind::LostChain {
+                start: cluster,
+                clusters: chain.clusters,
+            };
+            if !report(&mut rep, &mut on_issue, repair, kind, b"") {
+                unsaved += chain.clusters;
+                continue;
+            }
+
+            /* Saved as FOUND.nnn/FILEnnnn.CHK, cut where it stopped being ours */
+            if chain.problem.is_some() {
+                vol.set_fat_entry(chain.last, FAT_EOC_MARK)?;
+            }
+            if found_dir == 0 {
+                found_dir = cl.make_found_dir(vol)?.unwrap_or(0);
+            }
+            let slot = match found_dir {
+                0 => None,
+                dir => cl.free_slot(vol, dir, 2)?,
+            };
+            let Some(slot) = slot else {
+                rep.fixed -= 1;
+                unsaved += chain.clusters;
+                continue;
+            };
+
+            let mut name = *b"FILE0000CHK";
+            write_padded_decimal(found_files, &mut name[4..8]);
+            found_files += 1;
+            let bytes = core::cmp::min(chain.clusters as u64 * cluster_size, u32::MAX as u64);
+            let entry = build_dir_entry(&name, ATTR_ARCHIVE, cluster, bytes as u32, now_seconds());
+            vol.dev.write_at(slot, &entry)?;
+        }
+    }
+
+    rep.free_clusters -= cl.allocated - allocated;
+    rep.used_clusters = cl.used.count_ones() as u32 - unsaved;
+
+    /* ---------- FSInfo ---------- */
+    let fs_info = vol.bs.fs_info_sector;
+    if fs_info != 0 && fs_info != 0xFFFF && fs_info < vol.bs.reserved_sectors_count {
+        let mut sector = [0u8; 4096];
+        vol.read_sector(fs_info as u32, &mut sector)?;
+
+        let lead = u8_to_u32_le(&sector[0..4]);
+        let structure = u8_to_u32_le(&sector[484..488]);
+        if lead != FSINFO_LEAD_SIGNATURE || structure != FSINFO_STRUCT_SIGNATURE {
+            if report(&mut rep, &mut on_issue, repair, IssueKind::FsInfoInvalid, b"") {
+                sector.fill(0);
+                u32_to_u8_le(FSINFO_LEAD_SIGNATURE, &mut sector[0..4]);
+                u32_to_u8_le(FSINFO_STRUCT_SIGNATURE, &mut sector[484..488]);
+                u32_to_u8_le(rep.free_clusters, &mut sector[488..492]);
+                u32_to_u8_le(FSINFO_UNKNOWN, &mut sector[492..496]);
+                u32_to_u8_le(FSINFO_TRAIL_SIGNATURE, &mut sector[508..512]);
+                vol.write_sector(fs_info as u32, &sector)?;
+            }
+        } else {
+            let recorded = u8_to_u32_le(&sector[488..492]);
+            if recorded != FSINFO_UNKNOWN && recorded != rep.free_clusters {
+                let kind = IssueKind::FsInfoFreeCount {
+                    recorded,
+                    actual: rep.free_clusters,
+                };
+                if report(&mut rep, &mut on_issue, repair, kind, b"") {
+                    u32_to_u8_le(rep.free_clusters, &mut sector[488..492]);
+                    vol.write_sector(fs_info as u32, &sector)?;
+                }
+            }
+        }
+    }
+
+    /* Repairs took clusters, the next search starts past them */
+    if cl.allocated > 0
+        && let Some(mut info) = vol.read_fs_info()?
+    {
+        info.next_free = cl.free.hint();
+        vol.write_fs_info(info)?;
+    }
+
+    Ok(rep)
+}
+
+/* Whether `cluster` is among the first `count` clusters of the chain at `start` */
+fn in_chain<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    start: u32,
+    count: u32,
+    cluster: u32,
+) -> Result<bool, VolumeError> {
+    let mut current = start;
+    for _ in 0..count {
+        if current == cluster {
+            return Ok(true);
+        }
+        current = vol.fat_entry(current)?;
+    }
+    Ok(false)
+}
+
+/* Whether `cluster` belongs to one of the directories being walked */
+fn in_ancestors<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    frames: &[Frame],
+    cluster: u32,
+) -> Result<bool, VolumeError> {
+    for frame in frames {
+        let clusters = chain_length(vol, frame.first)?;
+        if in_chain(vol, frame.first, clusters, cluster)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/* __________ Shell __________ */
+pub fn print_issue(issue: &Issue) {
+    if !issue.path.is_empty() {
+        print_bytes(issue.path);
+        print_no_ln(": ");
+    }
+
+    match issue.kind {
+        IssueKind::InvalidStart { cluster } => {
+            print_no_ln("first cluster ");
+            print_number(cluster as u64);
+            print(" is outside of the data region");
+        }
+        IssueKind::FreeInChain { cluster } => {
+            print_no_ln("chain runs into free cluster ");
+            print_number(cluster as u64);
+            print("");
+        }
+        IssueKind::InvalidLink { cluster, value } => {
+            print_no_ln("cluster ");
+            print_number(cluster as u64);
+            print_no_ln(" points to invalid cluster ");
+            print_bytes_hex(&value.to_be_bytes());
+        }
+        IssueKind::CrossLinked { cluster } => {
+            print_no_ln("cross-linked on cluster ");
+            print_number(cluster as u64);
+            print("");
+        }
+        IssueKind::SizeMismatch { size, clusters } => {
+            print_no_ln("size ");
+            print_number(size as u64);
+            print_no_ln(" doesn't match its chain of ");
+            print_number(clusters as u64);
+            print(" clusters");
+        }
+        IssueKind::MissingDot => print("missing or invalid '.' entry"),
+        IssueKind::MissingDotDot => print("missing or invalid '..' entry"),
+        IssueKind::IllegalShortName => print("illegal short name"),
+        IssueKind::OrphanLfn { entries } => {
+            print_number(entries as u64);
+            print(" orphaned long name entries");
+        }
+        IssueKind::LostChain { start, clusters } => {
+            print_no_ln("lost chain of ");
+            print_number(clusters as u64);
+            print_no_ln(" clusters starting at ");
+            print_number(start as u64);
+            print("");
+        }
+        IssueKind::FsInfoInvalid => print("FSInfo sector has invalid signatures"),
+        IssueKind::FsInfoFreeCount { recorded, actual } => {
+            print_no_ln("FSInfo free count is ");
+            print_number(recorded as u64);
+            print_no_ln(", should be ");
+            print_number(actual as u64);
+            print("");
+        }
+        IssueKind::TooDeep => print("directory tree too deep, not checked"),
+    }
+}
+
+pub fn print_report(report: &CheckReport, clusters_count: u32) {
+    print_number(report.files as u64);
+    print_no_ln(" files, ");
+    print_number(report.directories as u64);
+    print_no_ln(" directories, ");
+    print_number(report.used_clusters as u64);
+    print_no_ln("/");
+    print_number(clusters_count as u64);
+    print(" clusters used");
+
+    if report.errors == 0 {
+        print("No errors found");
+    } else {
+        print_number(report.errors as u64);
+        print_no_ln(" errors found");
+        if report.fixed > 0 {
+            print_no_ln(", ");
+            print_number(report.fixed as u64);
+            print_no_ln(" fixed");
+        }
+        print("");
+    }
+}
+
+/* `-r`: ask before each fix */
+fn ask_fix(issue: &Issue) -> bool {
+    print_issue(issue);
+    if !issue.fixable {
+        return false;
+    }
+
+    print_no_ln("  Fix? [y/N] ");
+    let mut answer = [0u8; 16];
+    let n = read(0, answer.as_mut_ptr(), answer.len()).unwrap_or(0);
+    n > 0 && (answer[0] == b'y' || answer[0] == b'Y')
+}
+
+/* `-y`: fix everything that can be */
+fn fix_all(issue: &Issue) -> bool {
+    print_issue(issue);
+    if issue.fixable {
+        print("  Fixing");
+    }
+    true
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CheckMode {
+    ReadOnly,
+    Interactive,
+    Automatic,
+}
+
+/* Bitmaps for up to 16M clusters, the shell is single threaded */
+const CHECK_SCRATCH_SIZE: usize = 7 << 20;
+static mut CHECK_SCRATCH: [u8; CHECK_SCRATCH_SIZE] = [0u8; CHECK_SCRATCH_SIZE];
+
+/* Lend the shell's bitmap scratch buffer to `f`, calls must not nest */
+pub fn with_scratch<R>(f: impl FnOnce(&mut [u8]) -> R) -> R {
+    // Safety: building a mutable slice over a `static mut`.
+    //
+    // The shell runs on a single thread and commands don't nest, so no other
+    // reference to the buffer exists while `f` holds this one.
+    // SAFETY: see above, the slice covers exactly the static and doesn't outlive `f`.
+    let scratch = unsafe {
+        core::slice::from_raw_parts_mut((&raw mut CHECK_SCRATCH).cast::<u8>(), CHECK_SCRATCH_SIZE)
+    };
+    f(scratch)
+}
+
+/* `check [-n | -r | -y]` on the mounted image, returns the exit code.
+ * Read only by default like `dosfsck -n`, repairs go through a writable
+ * descriptor on the image */
+pub fn check_command(dev: &mut ImageDevice, image: &ImageFile, args: &[&[u8]]) -> usize {
+    let mut mode = CheckMode::ReadOnly;
+    for &word in args {
+        mode = match word {
+            b"-n" => CheckMode::ReadOnly,
+            b"-r" => CheckMode::Interactive,
+            b"-y" => CheckMode::Automatic,
+            _ => {
+                print("Usage: check [-n | -r | -y]");
+                return CHECK_FAILED_EXIT_CODE;
+            }
+        };
+    }
+
+    if mode == CheckMode::ReadOnly {
+        return run_check(dev, mode);
+    }
+    dev.with_writable(image, |dev| run_check(dev, mode)).unwrap_or(CHECK_FAILED_EXIT_CODE)
+}
+
+fn run_check(dev: &mut ImageDevice, mode: CheckMode) -> usize {
+    let mut vol = match Volume::mount(dev) {
+        Ok(vol) => vol,
+        Err(_) => {
+            print("Failed to mount the volume");
+            return CHECK_FAILED_EXIT_CODE;
+        }
+    };
+
+    let result = with_scratch(|scratch| match mode {
+        CheckMode::ReadOnly => check_volume(&mut vol, scratch, print_issue),
+        CheckMode::Interactive => repair_volume(&mut vol, scratch, ask_fix),
+        CheckMode::Automatic => repair_volume(&mut vol, scratch, fix_all),
+    });
+    if vol.flush().is_err() {
+        print("Failed to write the volume");
+        return CHECK_FAILED_EXIT_CODE;
+    }
+
+    match result {
+        Ok(report) => {
+            print_report(&report, vol.clusters_count);
+            report.exit_code()
+        }
+        Err(CheckError::ScratchTooSmall) => {
+            print("Volume has too many clusters to be checked");
+            CHECK_FAILED_EXIT_CODE
+        }
+        Err(CheckError::Volume(_)) => {
+            print("Failed to access the volume");
+            CHECK_FAILED_EXIT_CODE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::dir_entry::lfn_checksum;
+    use crate::fixture::{ImageBuilder, Node};
+    use crate::format::{FormatOptions, format_volume};
+    use crate::helpers::{u16_to_u8_le, u32_to_u8_le};
+    use std::vec;
+    use std::vec::Vec;
+
+    const SIZE: usize = 40 * 1024 * 1024;
+
+    struct Image {
+        data: Vec<u8>,
+        fat_start: usize,
+        fat_bytes: usize,
+        data_start: usize,
+    }
+
+    impl Image {
+        fn new() -> Self {
+            let mut data = vec![0u8; SIZE];
+            let summary = format_volume(
+                &mut MemDevice { data: &mut data },
+                SIZE as u64,
+                &FormatOptions::default(),
+            )
+            .unwrap();
+            let fat_bytes = summary.fat_size_sectors as usize * 512;
+            Image {
+                data,
+                fat_start: 32 * 512,
+                fat_bytes,
+                data_start: 32 * 512 + 2 * fat_bytes,
+            }
+        }
+
+        fn set_fat(&mut self, cluster: u32, value: u32) {
+            for i in 0..2 {
+                let off = self.fat_start + i * self.fat_bytes + cluster as usize * 4;
+                u32_to_u8_le(value, &mut self.data[off..off + 4]);
+            }
+        }
+
+        fn chain(&mut self, clusters: &[u32]) {
+            for w in clusters.windows(2) {
+                self.set_fat(w[0], w[1]);
+            }
+            self.set_fat(*clusters.last().unwrap(), 0x0FFFFFFF);
+        }
+
+        fn entry(
+            &mut self,
+            cluster: u32,
+            index: usize,
+            name: &[u8; 11],
+            attr: u8,
+            first: u32,
+            size: u32,
+        ) {
+            let off = self.data_start + (cluster as usize - 2) * 512 + index * 32;
+            let e = &mut self.data[off..off + 32];
+            e[0..11].copy_from_slice(name);
+            e[11] = attr;
+            u16_to_u8_le((first >> 16) as u16, &mut e[20..22]);
+            u16_to_u8_le(first as u16, &mut e[26..28]);
+            u32_to_u8_le(size, &mut e[28..32]);
+        }
+
+        /* Subdirectory at `cluster` in `parent`, with its dot entries */
+        fn dir(&mut self, parent: u32, index: usize, name: &[u8; 11], cluster: u32) {
+            self.chain(&[cluster]);
+            self.entry(parent, index, name, 0x10, cluster, 0);
+            self.entry(cluster, 0, DOT_NAME, 0x10, cluster, 0);
+            self.entry(
+                cluster,
+                1,
+                DOT_DOT_NAME,
+                0x10,
+                if parent == 2 { 0 } else { parent },
+                0,
+            );
+        }
+
+        /* Keep the FSInfo free count in line with the FAT */
+        fn fix_fsinfo(&mut self) {
+            let mut free = 0u32;
+            let clusters = (self.fat_bytes / 4) as u32;
+            let mut vol = Volume::mount(MemDevice {
+                data: &mut self.data,
+            })
+            .unwrap();
+            for c in 2..core::cmp::min(clusters, vol.clusters_count + 2) {
+                if vol.fat_entry(c).unwrap() == 0 {
+                    free += 1;
+                }
+            }
+            u32_to_u8_le(free, &mut self.data[512 + 488..512 + 492]);
+        }
+
+        fn check(&mut self) -> (CheckReport, Vec<IssueKind>) {
+            let mut vol = Volume::mount(MemDevice {
+                data: &mut self.data,
+            })
+            .unwrap();
+            let mut scratch = vec![0u8; check_scratch_size(vol.clusters_count)];
+            let mut issues = Vec::new();
+            let report = check_volume(&mut vol, &mut scratch, |i| issues.push(i.kind)).unwrap();
+            (report, issues)
+        }
+
+        /* Repair answering `fix` to every question */
+        fn repair(&mut self, fix: bool) -> CheckReport {
+            let mut vol = Volume::mount(MemDevice {
+                data: &mut self.data,
+            })
+            .unwrap();
+            let mut scratch = vec![0u8; check_scratch_size(vol.clusters_count)];
+            repair_volume(&mut vol, &mut scratch, |_| fix).unwrap()
+        }
+
+        fn raw_entry(&self, cluster: u32, index: usize) -> &[u8] {
+            let off = self.data_start + (cluster as usize - 2) * 512 + index * 32;
+            &self.data[off..off + 32]
+        }
+    }
+
+    #[test]
+    fn fresh_volume_is_clean() {
+        let mut img = Image::new();
+        let (report, issues) = img.check();
+        assert!(issues.is_empty());
+        assert_eq!(report.exit_code(), 0);
+        assert_eq!(report.directories, 1);
+        assert_eq!(report.used_clusters, 1);
+    }
+
+    #[test]
+    fn consistent_tree_is_clean() {
+        let mut img = Image::new();
+        img.chain(&[3, 4]);
+        img.entry(2, 0, b"FILE    TXT", 0x20, 3, 600);
+        img.dir(2, 1, b"FOLDER     ", 5);
+        img.chain(&[6]);
+        img.entry(5, 2, b"INNER   BIN", 0x20, 6, 10);
+        img.fix_fsinfo();
+
+        let (report, issues) = img.check();
+        assert_eq!(issues, Vec::new());
+        assert_eq!(report.files, 2);
+        assert_eq!(report.directories, 2);
+        assert_eq!(report.used_clusters, 5);
+    }
+
+    #[test]
+    fn finds_size_mismatch_and_cross_link() {
+        let mut img = Image::new();
+        img.chain(&[3, 4]);
+        img.entry(2, 0, b"A       TXT", 0x20, 3, 100);
+        img.entry(2, 1, b"B       TXT", 0x20, 4, 100);
+        img.fix_fsinfo();
+
+        let (report, issues) = img.check();
+        assert_eq!(
+            issues,
+            vec![
+                IssueKind::SizeMismatch {
+                    size: 100,
+                    clusters: 2
+                },
+                IssueKind::CrossLinked { cluster: 4 },
+            ]
+        );
+        assert_eq!(report.exit_code(), 1);
+    }
+
+    #[test]
+    fn finds_lost_chains_loops_and_bad_links() {
+        let mut img = Image::new();
+        /* Lost chain, then a lost loop without a head */
+        img.chain(&[10, 11, 12]);
+        img.set_fat(20, 21);
+        img.set_fat(21, 20);
+        /* File running into a reserved value */
+        img.set_fat(30, 1);
+        img.entry(2, 0, b"BAD     TXT", 0x20, 30, 512);
+        img.fix_fsinfo();
+
+        let (report, issues) = img.check();
+        assert_eq!(
+            issues,
+            vec![
+                IssueKind::InvalidLink {
+                    cluster: 30,
+                    value: 1
+                },
+                IssueKind::LostChain {
+                    start: 10,
+                    clusters: 3
+                },
+                IssueKind::LostChain {
+                    start: 20,
+                    clusters: 2
+                },
+            ]
+        );
+        assert_eq!(report.lost_chains, 2);
+        assert_eq!(report.lost_clusters, 5);
+    }
+
+    #[test]
+    fn finds_directory_problems() {
+        let mut img = Image::new();
+        img.dir(2, 0, b"DIR        ", 3);
+        /* Wrong `..` */
+        img.entry(3, 1, DOT_DOT_NAME, 0x10, 7, 0);
+        img.entry(3, 2, b"lower   txt", 0x20, 0, 0);
+        /* Long name entry whose short entry is gone */
+        img.entry(3, 3, b"\x41x\0y\0z\0\0\0\xFF\xFF", 0x0F, 0, 0);
+        img.data[img.data_start + 512 + 3 * 32 + 13] = lfn_checksum(b"GONE    TXT");
+        img.entry(3, 4, b"OTHER   TXT", 0x20, 0, 0);
+        img.fix_fsinfo();
+
+        let (_, issues) = img.check();
+        assert_eq!(
+            issues,
+            vec![
+                IssueKind::MissingDotDot,
+                IssueKind::IllegalShortName,
+                IssueKind::OrphanLfn { entries: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_directory_loop_and_bad_fsinfo() {
+        let mut img = Image::new();
+        img.dir(2, 0, b"DIR        ", 3);
+        /* Subdirectory pointing back to the root */
+        img.entry(3, 2, b"LOOP       ", 0x10, 2, 0);
+
+        let (_, issues) = img.check();
+        assert_eq!(
+            issues,
+            vec![
+                IssueKind::CrossLinked { cluster: 2 },
+                IssueKind::FsInfoFreeCount {
+                    recorded: img_free_before(&img),
+                    actual: img_free_before(&img) - 1,
+                },
+            ]
+        );
+    }
+
+    /* Free count written by the formatter */
+    fn img_free_before(img: &Image) -> u32 {
+        crate::helpers::u8_to_u32_le(&img.data[512 + 488..512 + 492])
+    }
+
+    #[test]
+    fn repairs_chains_and_saves_lost_ones() {
+        let mut img = Image::new();
+        /* Over-long chain */
+        img.chain(&[3, 4, 5]);
+        img.entry(2, 0, b"LONG    TXT", 0x20, 3, 100);
+        /* Cross link: B starts in the middle of A */
+        img.chain(&[6, 7, 8]);
+        img.entry(2, 1, b"A       TXT", 0x20, 6, 1536);
+        img.entry(2, 2, b"B       TXT", 0x20, 7, 1024);
+        img.data[img.data_start + 5 * 512] = b'X';
+        /* Chain running into a reserved value, size too big */
+        img.set_fat(9, 1);
+        img.entry(2, 3, b"BAD     TXT", 0x20, 9, 5000);
+        /* Lost chain */
+        img.chain(&[20, 21]);
+
+        let report = img.repair(true);
+        assert_eq!(report.errors, report.fixed);
+        assert_eq!(report.lost_chains, 1);
+
+        let (report, issues) = img.check();
+        assert_eq!(issues, Vec::new());
+        assert_eq!(report.files, 5);
+
+        /* B got its own copy of clusters 7 and 8 */
+        let b = parse_dir_entry(img.raw_entry(2, 2));
+        assert!(b.first_cluster != 7);
+        assert_eq!(img.raw_entry(b.first_cluster, 0)[0], b'X');
+
+        let bad = parse_dir_entry(img.raw_entry(2, 3));
+        assert_eq!(bad.file_size, 512);
+
+        let found = parse_dir_entry(img.raw_entry(2, 4));
+        assert_eq!(&found.name, b"FOUND   000");
+        let chk = parse_dir_entry(img.raw_entry(found.first_cluster, 2));
+        assert_eq!(&chk.name, b"FILE0000CHK");
+        assert_eq!(chk.first_cluster, 20);
+        assert_eq!(chk.file_size, 1024);
+    }
+
+    #[test]
+    fn repairs_directory_problems() {
+        let mut img = Image::new();
+        img.dir(2, 0, b"DIR        ", 3);
+        /* `.` replaced by a file, wrong `..` */
+        img.entry(3, 0, b"FILE    TXT", 0x20, 0, 0);
+        img.entry(3, 1, DOT_DOT_NAME, 0x10, 7, 0);
+        /* Orphan long name entry */
+        img.entry(3, 2, b"\x41x\0y\0z\0\0\0\xFF\xFF", 0x0F, 0, 0);
+        img.data[img.data_start + 512 + 2 * 32 + 13] = lfn_checksum(b"GONE    TXT");
+        img.entry(3, 3, b"OTHER   TXT", 0x20, 0, 0);
+        /* Subdirectory pointing back to the root */
+        img.entry(2, 1, b"LOOP       ", 0x10, 2, 0);
+
+        img.repair(true);
+        let (_, issues) = img.check();
+        assert_eq!(issues, Vec::new());
+
+        assert_eq!(img.raw_entry(2, 1)[0], ENTRY_DELETED);
+        assert_eq!(img.raw_entry(3, 2)[0], ENTRY_DELETED);
+        assert_eq!(&img.raw_entry(3, 4)[0..11], b"FILE    TXT");
+    }
+
+    #[test]
+    fn splits_cross_linked_directories() {
+        let mut img = Image::new();
+        img.dir(2, 0, b"DIR        ", 3);
+        img.chain(&[6]);
+        img.entry(3, 2, b"INNER   TXT", 0x20, 6, 10);
+        img.data[img.data_start + 4 * 512] = b'I';
+        /* Second entry for the same directory */
+        img.entry(2, 1, b"TWIN       ", 0x10, 3, 0);
+        /* SHARE runs into the second cluster of BIG */
+        img.dir(2, 2, b"BIG        ", 4);
+        img.chain(&[4, 5]);
+        img.dir(2, 3, b"SHARE      ", 9);
+        img.set_fat(9, 5);
+
+        let report = img.repair(true);
+        assert_eq!(report.errors, report.fixed);
+        let (report, issues) = img.check();
+        assert_eq!(issues, Vec::new());
+        assert_eq!((report.files, report.directories), (2, 5));
+
+        /* TWIN got its own copy of DIR, and of the file in it */
+        let twin = parse_dir_entry(img.raw_entry(2, 1));
+        assert!(twin.first_cluster != 3);
+        assert_eq!(parse_dir_entry(img.raw_entry(twin.first_cluster, 0)).first_cluster, twin.first_cluster);
+        let inner = parse_dir_entry(img.raw_entry(twin.first_cluster, 2));
+        assert_eq!(&inner.name, b"INNER   TXT");
+        assert!(inner.first_cluster != 6);
+        assert_eq!(img.raw_entry(inner.first_cluster, 0)[0], b'I');
+        assert_eq!(&parse_dir_entry(img.raw_entry(3, 2)).name, b"INNER   TXT");
+
+        /* SHARE keeps its first cluster and links a copy of the second */
+        let mut vol = Volume::mount(MemDevice { data: &mut img.data }).unwrap();
+        let next = vol.fat_entry(9).unwrap();
+        assert!(next != 5 && vol.is_valid_cluster(next));
+        assert_eq!(vol.fat_entry(5).unwrap(), FAT_EOC_MARK);
+    }
+
+    #[test]
+    fn checks_and_repairs_fat12_and_fat16() {
+        let tree = [
+            Node::Dir("sub", &[Node::File("inner.txt", b"inner file\n")]),
+            Node::File("A long file name.txt", &[7u8; 5000]),
+        ];
+        for builder in [ImageBuilder::fat12(), ImageBuilder::fat16()] {
+            let mut data = builder.build(&tree);
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            let mut scratch = vec![0u8; check_scratch_size(vol.clusters_count)];
+            let mut issues = Vec::new();
+            let report = check_volume(&mut vol, &mut scratch, |i| issues.push(i.kind)).unwrap();
+            assert_eq!(issues, Vec::new());
+            assert_eq!((report.files, report.directories), (2, 2));
+
+            /* A lost chain goes to FOUND.000 in the fixed root */
+            let lost = vol.clusters_count;
+            vol.set_fat_entry(lost, FAT_EOC_MARK).unwrap();
+            let report = repair_volume(&mut vol, &mut scratch, |_| true).unwrap();
+            assert_eq!((report.lost_chains, report.fixed), (1, 1));
+            assert!(has_entry(&mut vol, 0, b"FOUND   000").unwrap());
+            let report = check_volume(&mut vol, &mut scratch, |_| {}).unwrap();
+            assert_eq!((report.errors, report.directories), (0, 3));
+        }
+    }
+
+    #[test]
+    fn declined_fixes_leave_the_volume_alone() {
+        let mut img = Image::new();
+        img.chain(&[3, 4]);
+        img.entry(2, 0, b"A       TXT", 0x20, 3, 100);
+        img.chain(&[20]);
+        let before = img.data.clone();
+
+        let report = img.repair(false);
+        assert_eq!(report.errors, 3);
+        assert_eq!(report.fixed, 0);
+        assert!(img.data == before);
+    }
+}
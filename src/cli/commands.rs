@@ -0,0 +1,385 @@
+use crate::cli::{print, print_no_ln};
+use crate::sys::print_bytes;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandId {
+    Help,
+    Exit,
+    Cd,
+    Pwd,
+    Ls,
+    Tree,
+    Find,
+    Grep,
+    Cat,
+    Format,
+    Cache,
+    Check,
+    Fatcmp,
+    Free,
+    Undelete,
+    Mkdir,
+    Write,
+    Rm,
+    Mv,
+}
+
+/* What Tab offers for the arguments of a command */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    Nothing,
+    /* Directories of the image */
+    Dir,
+    /* Files and directories of the image */
+    Path,
+    Command,
+}
+
+pub struct Command {
+    pub id: CommandId,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /* Arguments, as `help` shows them */
+    pub usage: &'static str,
+    /* Arguments taken, at least and at most */
+    pub min_args: usize,
+    pub max_args: usize,
+    pub completes: ArgKind,
+    /* One line for `help` */
+    pub summary: &'static str,
+    /* Printed by `help <command>` after the summary */
+    pub details: &'static [&'static str],
+}
+
+impl Command {
+    pub fn accepts(&self, args: usize) -> bool {
+        (self.min_args..=self.max_args).contains(&args)
+    }
+
+    pub fn print_usage(&self) {
+        print_no_ln("Usage: ");
+        print_no_ln(self.name);
+        if !self.usage.is_empty() {
+            print_no_ln(" ");
+        }
+        print(self.usage);
+    }
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        id: CommandId::Help,
+        name: "help",
+        aliases: &["?"],
+        usage: "[command]",
+        min_args: 0,
+        max_args: 1,
+        completes: ArgKind::Command,
+        summary: "List the commands, or explain one",
+        details: &[],
+    },
+    Command {
+        id: CommandId::Exit,
+        name: "exit",
+        aliases: &["quit"],
+        usage: "",
+        min_args: 0,
+        max_args: 0,
+        completes: ArgKind::Nothing,
+        summary: "Leave the shell",
+        details: &["Returns the exit code of the last `check`, `fatcmp`, `free` or `undelete`"],
+    },
+    Command {
+        id: CommandId::Cd,
+        name: "cd",
+        aliases: &[],
+        usage: "<dir>",
+        min_args: 1,
+        max_args: 1,
+        completes: ArgKind::Dir,
+        summary: "Change the current directory and list it",
+        details: &[
+            "`..` goes up, a path starting with `/` from the root",
+            "and `-` back to the previous directory",
+        ],
+    },
+    Command {
+        id: CommandId::Pwd,
+        name: "pwd",
+        aliases: &[],
+        usage: "",
+        min_args: 0,
+        max_args: 0,
+        completes: ArgKind::Nothing,
+        summary: "Print the path of the current directory",
+        details: &["Long names when there are, like the prompt and `tree`"],
+    },
+    Command {
+        id: CommandId::Ls,
+        name: "ls",
+        aliases: &["dir"],
+        usage: "[--deleted | -R [tree options] | <dir>]",
+        min_args: 0,
+        max_args: 7,
+        completes: ArgKind::Dir,
+        summary: "List a directory, the current one by default",
+        details: &[
+            "`--deleted` lists the deleted entries of the current directory only",
+            "and `-R` the directories below too, taking the options of `tree`",
+        ],
+    },
+    Command {
+        id: CommandId::Tree,
+        name: "tree",
+        aliases: &[],
+        usage: "[-a] [-d] [-t] [-L levels] [dir]",
+        min_args: 0,
+        max_args: 6,
+        completes: ArgKind::Dir,
+        summary: "Draw the directories below one, the current one by default",
+        details: &[
+            "-a  Hidden entries too, by attribute or a name starting with `.`",
+            "-d  Directories only",
+            "-t  Files and bytes right in each directory",
+            "-L  Levels drawn at most",
+        ],
+    },
+    Command {
+        id: CommandId::Find,
+        name: "find",
+        aliases: &[],
+        usage: "[dir] [-name glob] [-type f|d] [-size range] [-mtime range] [-attr rhsda] [-l]",
+        min_args: 0,
+        max_args: 12,
+        completes: ArgKind::Dir,
+        summary: "Search the entries below a directory, the current one by default",
+        details: &[
+            "-name   `*`, `?` and `[a-z]` over the long or the short name, any case",
+            "-type   f for files, d for directories",
+            "-size   Bytes of files as `min..max`, with k, M or G, either end optional",
+            "-mtime  Days modified as `YYYY-MM-DD..YYYY-MM-DD`, either end optional",
+            "-attr   Read-only, hidden, system, directory and archive bits all set",
+            "-l      Cluster, size and date before each path",
+        ],
+    },
+    Command {
+        id: CommandId::Grep,
+        name: "grep",
+        aliases: &[],
+        usage: "[-i] [-n] [-r] <pattern> <path>",
+        min_args: 2,
+        max_args: 5,
+        completes: ArgKind::Path,
+        summary: "Print the lines of a file matching a regular expression",
+        details: &[
+            "-i  Ignore the case of ASCII letters",
+            "-n  Number the lines",
+            "-r  Every file below a directory",
+            "Patterns take `.`, `[a-z]`, `\\d`, `\\w`, `\\s`, `*`, `+`, `?`, `^` and `$`",
+        ],
+    },
+    Command {
+        id: CommandId::Cat,
+        name: "cat",
+        aliases: &["more"],
+        usage: "<file>",
+        min_args: 1,
+        max_args: 1,
+        completes: ArgKind::Path,
+        summary: "Print a file",
+        details: &[],
+    },
+    Command {
+        id: CommandId::Format,
+        name: "format",
+        aliases: &["mkfs"],
+        usage: "<image> [size_mb] [-t fat32|exfat] [-c spc] [-s bps] [-r reserved] [-f fats] [-H hidden] [-n label] [-i id]",
+        min_args: 1,
+        max_args: 18,
+        completes: ArgKind::Nothing,
+        summary: "Create and format an image, then mount it",
+        details: &[
+            "Without a size the whole file is used. Above 32 GB the volume is exFAT",
+            "unless `-t fat32` is given",
+        ],
+    },
+    Command {
+        id: CommandId::Cache,
+        name: "cache",
+        aliases: &[],
+        usage: "stats",
+        min_args: 1,
+        max_args: 1,
+        completes: ArgKind::Nothing,
+        summary: "Show how the sector cache and the name lookups do",
+        details: &[],
+    },
+    Command {
+        id: CommandId::Check,
+        name: "check",
+        aliases: &["fsck"],
+        usage: "[-n | -r | -y]",
+        min_args: 0,
+        max_args: 1,
+        completes: ArgKind::Nothing,
+        summary: "Check the volume, and repair it with -r or -y",
+        details: &[
+            "-n  Read only, the default",
+            "-r  Ask before each fix",
+            "-y  Fix everything",
+        ],
+    },
+    Command {
+        id: CommandId::Fatcmp,
+        name: "fatcmp",
+        aliases: &[],
+        usage: "[-s]",
+        min_args: 0,
+        max_args: 1,
+        completes: ArgKind::Nothing,
+        summary: "Compare the FAT copies",
+        details: &["-s  Write the copy agreeing best with the directory tree over the others"],
+    },
+    Command {
+        id: CommandId::Free,
+        name: "free",
+        aliases: &["df"],
+        usage: "[clusters]",
+        min_args: 0,
+        max_args: 1,
+        completes: ArgKind::Nothing,
+        summary: "Show the free space",
+        details: &["With a count, where the smallest run of that many free clusters starts"],
+    },
+    Command {
+        id: CommandId::Undelete,
+        name: "undelete",
+        aliases: &[],
+        usage: "<name>",
+        min_args: 1,
+        max_args: 1,
+        completes: ArgKind::Nothing,
+        summary: "Restore a deleted entry of the current directory",
+        details: &["`ls --deleted` shows what can be restored"],
+    },
+    Command {
+        id: CommandId::Mkdir,
+        name: "mkdir",
+        aliases: &[],
+        usage: "<dir>",
+        min_args: 1,
+        max_args: 1,
+        completes: ArgKind::Dir,
+        summary: "Create a directory, exFAT only",
+        details: &[],
+    },
+    Command {
+        id: CommandId::Write,
+        name: "write",
+        aliases: &[],
+        usage: "<file> [text...]",
+        min_args: 1,
+        max_args: usize::MAX,
+        completes: ArgKind::Path,
+        summary: "Create or replace a file with the text, exFAT only",
+        details: &["The words of the text are joined by single spaces"],
+    },
+    Command {
+        id: CommandId::Rm,
+        name: "rm",
+        aliases: &["del"],
+        usage: "<path>",
+        min_args: 1,
+        max_args: 1,
+        completes: ArgKind::Path,
+        summary: "Delete a file or an empty directory, exFAT only",
+        details: &[],
+    },
+    Command {
+        id: CommandId::Mv,
+        name: "mv",
+        aliases: &["ren"],
+        usage: "<path> <new name>",
+        min_args: 2,
+        max_args: 2,
+        completes: ArgKind::Path,
+        summary: "Rename an entry within its directory, exFAT only",
+        details: &[],
+    },
+];
+
+/* The command called `name` or one of its aliases */
+pub fn find_command(name: &[u8]) -> Option<&'static Command> {
+    COMMANDS
+        .iter()
+        .find(|c| c.name.as_bytes() == name || c.aliases.iter().any(|a| a.as_bytes() == name))
+}
+
+pub fn print_unknown_command(name: &[u8]) {
+    print_no_ln("Unknown command `");
+    print_bytes(name);
+    print("`, type `help` for the list");
+}
+
+/* `help [command]`, returns the exit code */
+pub fn help_command(args: &[&[u8]]) -> usize {
+    let Some(&name) = args.first() else {
+        for command in COMMANDS {
+            print_no_ln("  ");
+            print_no_ln(command.name);
+            print_bytes(&[b' '; 10][..10 - command.name.len()]);
+            print(command.summary);
+        }
+        print("");
+        print("Type `help <command>` for its arguments");
+        return 0;
+    };
+    let Some(command) = find_command(name) else {
+        print_unknown_command(name);
+        return 1;
+    };
+    command.print_usage();
+    print(command.summary);
+    for line in command.details {
+        print_no_ln("  ");
+        print(line);
+    }
+    if !command.aliases.is_empty() {
+        print_no_ln("Also: ");
+        for (i, alias) in command.aliases.iter().enumerate() {
+            if i > 0 {
+                print_no_ln(", ");
+            }
+            print_no_ln(alias);
+        }
+        print("");
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_commands_by_name_and_alias() {
+        assert_eq!(find_command(b"cat").map(|c| c.id), Some(CommandId::Cat));
+        assert_eq!(find_command(b"more").map(|c| c.id), Some(CommandId::Cat));
+        assert!(find_command(b"ca").is_none());
+
+        let mv = find_command(b"mv").unwrap();
+        assert!(!mv.accepts(1) && mv.accepts(2) && !mv.accepts(3));
+        assert!(find_command(b"write").unwrap().accepts(20));
+
+        /* Names are unique across the table */
+        let mut names = std::vec::Vec::new();
+        for command in COMMANDS {
+            names.push(command.name);
+            names.extend(command.aliases);
+        }
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::cli::editor::{LINE_MAX, is_continuation};
+use crate::cli::words::{Lexed, Quoting, quote_into};
+use crate::sys::print_bytes;
+
+/* Names kept for a double Tab listing, the common start covers them all */
+const CANDIDATES_MAX: usize = 256;
+const NAMES_SIZE: usize = 16384;
+/* Columns the listing fits in */
+const SCREEN_WIDTH: usize = 80;
+
+/* A prefix match regardless of ASCII case, the way FAT names compare */
+pub fn starts_with_ignore_case(name: &[u8], prefix: &[u8]) -> bool {
+    name.len() >= prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/* Names a word can be completed to, a directory with a '/' after it */
+pub struct Candidates {
+    /* What the names offered must start with */
+    prefix: [u8; LINE_MAX],
+    prefix_len: usize,
+    dirs_only: bool,
+    names: [u8; NAMES_SIZE],
+    ends: [usize; CANDIDATES_MAX],
+    count: usize,
+    /* Offered, kept or not */
+    total: usize,
+    /* Start shared by every name offered, in the case of the first one */
+    common: [u8; LINE_MAX],
+    common_len: usize,
+}
+
+impl Default for Candidates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Candidates {
+    pub fn new() -> Self {
+        Candidates {
+            prefix: [0; LINE_MAX],
+            prefix_len: 0,
+            dirs_only: false,
+            names: [0; NAMES_SIZE],
+            ends: [0; CANDIDATES_MAX],
+            count: 0,
+            total: 0,
+            common: [0; LINE_MAX],
+            common_len: 0,
+        }
+    }
+
+    /* Start over for names beginning with `prefix` */
+    pub fn start(&mut self, prefix: &[u8]) {
+        self.prefix[..prefix.len()].copy_from_slice(prefix);
+        self.prefix_len = prefix.len();
+        self.dirs_only = false;
+        self.count = 0;
+        self.total = 0;
+        self.common_len = 0;
+    }
+
+    /* Leave files out, for commands taking a directory */
+    pub fn dirs_only(&mut self) {
+        self.dirs_only = true;
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn get(&self, i: usize) -> &[u8] {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        &self.names[start..self.ends[i]]
+    }
+
+    pub fn common(&self) -> &[u8] {
+        &self.common[..self.common_len]
+    }
+
+    /* Offer `name`, false when it doesn't start with the prefix. It is
+     * taken once: an entry matching by long and short name counts for one */
+    pub fn push(&mut self, name: &[u8], is_dir: bool) -> bool {
+        if !starts_with_ignore_case(name, &self.prefix[..self.prefix_len]) {
+            return false;
+        }
+        let len = name.len() + is_dir as usize;
+        if (self.dirs_only && !is_dir)
+            || len > LINE_MAX
+            || (0..self.count)
+                .any(|i| self.get(i).strip_suffix(b"/").unwrap_or(self.get(i)) == name)
+        {
+            return true;
+        }
+
+        if self.total == 0 {
+            self.common[..name.len()].copy_from_slice(name);
+            self.common_len = len;
+            if is_dir {
+                self.common[name.len()] = b'/';
+            }
+        } else {
+            let shared = self.common[..self.common_len]
+                .iter()
+                .zip(name.iter().chain(is_dir.then_some(&b'/')))
+                .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+                .count();
+            self.common_len = shared;
+            /* Not in the middle of a UTF-8 sequence */
+            while self.common_len > 0
+                && self.common_len < LINE_MAX
+                && is_continuation(self.common[self.common_len])
+            {
+                self.common_len -= 1;
+            }
+        }
+        self.total += 1;
+
+        let start = if self.count == 0 {
+            0
+        } else {
+            self.ends[self.count - 1]
+        };
+        if self.count < CANDIDATES_MAX && start + len <= NAMES_SIZE {
+            self.names[start..start + name.len()].copy_from_slice(name);
+            if is_dir {
+                self.names[start + name.len()] = b'/';
+            }
+            self.ends[self.count] = start + len;
+            self.count += 1;
+        }
+        true
+    }
+
+    /* Names in columns, like `ls` in a terminal */
+    pub fn print(&self) {
+        let chars = |name: &[u8]| name.iter().filter(|&&c| !is_continuation(c)).count();
+        let width = (0..self.count)
+            .map(|i| chars(self.get(i)))
+            .max()
+            .unwrap_or(0)
+            + 2;
+        let columns = (SCREEN_WIDTH / width).max(1);
+        let rows = self.count.div_ceil(columns);
+        for row in 0..rows {
+            for column in 0..columns {
+                let i = column * rows + row;
+                if i >= self.count {
+                    break;
+                }
+                let name = self.get(i);
+                print_bytes(name);
+                if column + 1 < columns && i + rows < self.count {
+                    print_bytes(&[b' '; SCREEN_WIDTH][..width - chars(name)]);
+                }
+            }
+            print_bytes(b"\n");
+        }
+        if self.total > self.count {
+            print_bytes(b"...\n");
+        }
+    }
+}
+
+/* Where Tab finds names: the shell knows its commands and the image */
+pub trait Complete {
+    /* Every command, `out` keeps the ones matching */
+    fn commands(&mut self, out: &mut Candidates);
+    /* The entries of `dir`, as typed. `command` is the first word of the
+     * line */
+    fn paths(&mut self, command: &[u8], dir: &[u8], out: &mut Candidates);
+}
+
+/* The word the cursor ends, unquoted */
+struct Word {
+    start: usize,
+    /* Words before it */
+    index: usize,
+    /* Quote it started with, 0 for none */
+    quote: u8,
+    text: [u8; LINE_MAX],
+    len: usize,
+    command: [u8; LINE_MAX],
+    command_len: usize,
+}
+
+fn word_before(line: &[u8]) -> Word {
+    let mut word = Word {
+        start: line.len(),
+        index: 0,
+        quote: 0,
+        text: [0; LINE_MAX],
+        len: 0,
+        command: [0; LINE_MAX],
+        command_len: 0,
+    };
+    let mut quoting = Quoting::default();
+    let mut in_word = false;
+    for (i, &c) in line.iter().enumerate() {
+        let lexed = quoting.feed(c);
+        if !in_word && lexed != Lexed::Space {
+            in_word = true;
+            word.start = i;
+            word.quote = if matches!(c, b'\'' | b'"') { c } else { 0 };
+        }
+        match lexed {
+            Lexed::Char(c) => {
+                word.text[word.len] = c;
+                word.len += 1;
+            }
+            Lexed::Space if in_word => {
+                if word.index == 0 {
+                    word.command = word.text;
+                    word.command_len = word.len;
+                }
+                word.index += 1;
+                word.len = 0;
+                in_word = false;
+            }
+            _ => {}
+        }
+    }
+    if !in_word {
+        word.start = line.len();
+        word.quote = 0;
+    }
+    word
+}
+
+/* Complete the word before `cursor` in `line` of `len` bytes: to the only
+ * name matching it, or to what all of them start with. The new length and
+ * cursor when the line changed, `candidates` holds the names either way */
+pub fn complete(
+    line: &mut [u8; LINE_MAX],
+    len: usize,
+    cursor: usize,
+    completer: &mut dyn Complete,
+    candidates: &mut Candidates,
+) -> Option<(usize, usize)> {
+    let word = word_before(&line[..cursor]);
+    let text = &word.text[..word.len];
+    let command = &word.command[..word.command_len];
+    let dir_len = match text.iter().rposition(|&c| c == b'/') {
+        Some(slash) if word.index > 0 => slash + 1,
+        _ => 0,
+    };
+    candidates.start(&text[dir_len..]);
+    if word.index == 0 {
+        completer.commands(candidates);
+    } else {
+        completer.paths(command, &text[..dir_len], candidates);
+    }
+
+    let done = candidates.total() == 1;
+    let completion = candidates.common();
+    if candidates.total() == 0 || (!done && completion.len() <= word.len - dir_len) {
+        return None;
+    }
+
+    /* The word again, quoted the way it was started */
+    let mut raw = [0u8; LINE_MAX];
+    let mut raw_len = 0;
+    if word.quote != 0 {
+        raw[0] = word.quote;
+        raw_len = 1;
+    }
+    let mut fits = quote_into(&text[..dir_len], word.quote, &mut raw, &mut raw_len)
+        && quote_into(completion, word.quote, &mut raw, &mut raw_len);
+    if done && !completion.ends_with(b"/") {
+        /* Finished: the quote closes and the next word can start */
+        let end: &[u8] = match word.quote {
+            0 => b" ",
+            b'"' => b"\" ",
+            _ => b"' ",
+        };
+        fits &= raw_len + end.len() <= LINE_MAX;
+        if fits {
+            raw[raw_len..raw_len + end.len()].copy_from_slice(end);
+            raw_len += end.len();
+        }
+    }
+
+    let new_len = word.start + raw_len + (len - cursor);
+    if !fits || new_len > LINE_MAX {
+        return None;
+    }
+    line.copy_within(cursor..len, word.start + raw_len);
+    line[word.start..word.start + raw_len].copy_from_slice(&raw[..raw_len]);
+    Some((new_len, word.start + raw_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    /* A root with a few entries and `My Documents` holding two files */
+    struct Tree;
+
+    impl Complete for Tree {
+        fn commands(&mut self, out: &mut Candidates) {
+            for name in [&b"cat"[..], b"cd", b"check", b"ls"] {
+                out.push(name, false);
+            }
+        }
+
+        fn paths(&mut self, command: &[u8], dir: &[u8], out: &mut Candidates) {
+            let entries: &[(&[u8], bool)] = match dir {
+                b"" | b"/" => &[
+                    (b"My Documents", true),
+                    (b"music", true),
+                    (b"readme.txt", false),
+                ],
+                b"My Documents/" | b"my documents/" => {
+                    &[(b"It's done.txt", false), (b"Ideas.txt", false)]
+                }
+                _ => &[],
+            };
+            if command == b"cd" {
+                out.dirs_only();
+            }
+            for &(name, is_dir) in entries {
+                out.push(name, is_dir);
+            }
+        }
+    }
+
+    fn tab(line: &str, cursor: usize) -> Option<std::string::String> {
+        let mut buf = [0u8; LINE_MAX];
+        buf[..line.len()].copy_from_slice(line.as_bytes());
+        let mut tree = Tree;
+        let mut candidates = Box::new(Candidates::new());
+        let (len, _) = complete(&mut buf, line.len(), cursor, &mut tree, &mut candidates)?;
+        Some(std::string::String::from_utf8(buf[..len].to_vec()).unwrap())
+    }
+
+    #[test]
+    fn completes_commands_and_paths() {
+        assert_eq!(tab("ch", 2).as_deref(), Some("check "));
+        /* `cat`, `cd` and `check` share nothing more */
+        assert_eq!(tab("c", 1), None);
+        assert_eq!(tab("cat rea", 7).as_deref(), Some("cat readme.txt "));
+        /* `My Documents` and `music` */
+        assert_eq!(tab("cd m", 4), None);
+        assert_eq!(tab("cd mu", 5).as_deref(), Some("cd music/"));
+        assert_eq!(tab("cd /mu", 6).as_deref(), Some("cd /music/"));
+        assert_eq!(tab("cat nope", 8), None);
+        assert_eq!(tab("cd rea", 6), None);
+    }
+
+    #[test]
+    fn keeps_the_quoting_of_the_word() {
+        assert_eq!(tab("cd my", 5).as_deref(), Some("cd My\\ Documents/"));
+        assert_eq!(tab("cat \"my", 7).as_deref(), Some("cat \"My Documents/"));
+        assert_eq!(
+            tab("cat 'My Documents/It", 20).as_deref(),
+            Some("cat 'My Documents/It'\\''s done.txt' ")
+        );
+        assert_eq!(
+            tab("cat My\\ Documents/it", 20).as_deref(),
+            Some("cat My\\ Documents/It\\'s\\ done.txt ")
+        );
+        assert_eq!(
+            tab("cat my\\ documents/id", 20).as_deref(),
+            Some("cat my\\ documents/Ideas.txt ")
+        );
+        /* Text after the cursor stays */
+        assert_eq!(
+            tab("cat rea | x", 7).as_deref(),
+            Some("cat readme.txt  | x")
+        );
+    }
+
+    #[test]
+    fn common_start_covers_names_not_kept() {
+        let mut candidates = Box::new(Candidates::new());
+        for i in 0..CANDIDATES_MAX + 10 {
+            candidates.push(std::format!("file{i:03}").as_bytes(), false);
+        }
+        candidates.push(b"FILE", true);
+        candidates.push(b"fi\xC3\xA9", false);
+        assert_eq!(candidates.total(), CANDIDATES_MAX + 12);
+        assert_eq!(candidates.common(), b"fi");
+        assert_eq!(candidates.get(0), b"file000");
+    }
+}
@@ -1,2 +1,2 @@
 pub static HEX: &[u8; 16] = b"0123456789ABCDEF";
-pub static CLI_NAME: &str = "[fat32]: ";
+pub static CLI_NAME: &str = "[fat32]";
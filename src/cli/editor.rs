@@ -0,0 +1,912 @@
+use crate::cli::complete::{Candidates, Complete, complete};
+use crate::sys::consts::STDIN_FILENO;
+use crate::sys::{
+    close, ftruncate, open, open_append, open_rw, print_bytes, read, tcgetattr, tcsetattr, write,
+};
+
+/* Longest line, the same as the plain read it replaces */
+pub const LINE_MAX: usize = 256;
+/* Lines kept, in memory and in the history file */
+const HISTORY_LEN: usize = 200;
+const HISTORY_FILE: &[u8] = b"/.fat32_history";
+const QUERY_MAX: usize = 64;
+const SEARCH_PROMPT: &[u8] = b"(reverse-i-search)`";
+const FAILED_SEARCH_PROMPT: &[u8] = b"(failed reverse-i-search)`";
+
+/* What a keystroke, or an escape sequence, means to the editor */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Insert(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    WordLeft,
+    WordRight,
+    DeleteWordBefore,
+    DeleteWordAfter,
+    KillToEnd,
+    KillToStart,
+    /* Ctrl-D, the end of input on an empty line */
+    EndOfInput,
+    Interrupt,
+    ReverseSearch,
+    /* Ctrl-G, leaves a search */
+    Cancel,
+    Tab,
+    /* Keys with nothing bound to them */
+    Ignored,
+}
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    /* After ESC */
+    Esc,
+    /* After ESC O */
+    Ss3,
+    /* After ESC [, with the numeric parameters so far */
+    Csi { params: [u16; 2], count: usize },
+}
+
+/* Turns the bytes read from the terminal into keys, escape sequences can
+ * span several reads */
+pub struct Decoder {
+    state: Escape,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            state: Escape::None,
+        }
+    }
+
+    /* The key `byte` completes, None in the middle of a sequence */
+    pub fn feed(&mut self, byte: u8) -> Option<Key> {
+        match self.state {
+            Escape::None => self.plain(byte),
+            Escape::Esc => {
+                self.state = Escape::None;
+                match byte {
+                    b'[' => {
+                        self.state = Escape::Csi {
+                            params: [0; 2],
+                            count: 0,
+                        }
+                    }
+                    b'O' => self.state = Escape::Ss3,
+                    b'b' => return Some(Key::WordLeft),
+                    b'f' => return Some(Key::WordRight),
+                    b'd' => return Some(Key::DeleteWordAfter),
+                    0x7f | 0x08 => return Some(Key::DeleteWordBefore),
+                    _ => return Some(Key::Ignored),
+                }
+                None
+            }
+            Escape::Ss3 => {
+                self.state = Escape::None;
+                Some(match byte {
+                    b'A' => Key::Up,
+                    b'B' => Key::Down,
+                    b'C' => Key::Right,
+                    b'D' => Key::Left,
+                    b'H' => Key::Home,
+                    b'F' => Key::End,
+                    _ => Key::Ignored,
+                })
+            }
+            Escape::Csi {
+                mut params,
+                mut count,
+            } => {
+                match byte {
+                    b'0'..=b'9' => {
+                        let i = count.min(1);
+                        params[i] = params[i]
+                            .saturating_mul(10)
+                            .saturating_add((byte - b'0') as u16);
+                        self.state = Escape::Csi {
+                            params,
+                            count: count.max(1),
+                        };
+                        return None;
+                    }
+                    b';' => {
+                        count += 1;
+                        self.state = Escape::Csi { params, count };
+                        return None;
+                    }
+                    _ => self.state = Escape::None,
+                }
+                /* Ctrl held: "1;5C", Alt: "1;3C" */
+                let by_word = count > 1 && matches!(params[1], 3 | 5);
+                Some(match (byte, params[0]) {
+                    (b'A', _) => Key::Up,
+                    (b'B', _) => Key::Down,
+                    (b'C', _) if by_word => Key::WordRight,
+                    (b'D', _) if by_word => Key::WordLeft,
+                    (b'C', _) => Key::Right,
+                    (b'D', _) => Key::Left,
+                    (b'H', _) | (b'~', 1 | 7) => Key::Home,
+                    (b'F', _) | (b'~', 4 | 8) => Key::End,
+                    (b'~', 3) => Key::Delete,
+                    _ => Key::Ignored,
+                })
+            }
+        }
+    }
+
+    fn plain(&mut self, byte: u8) -> Option<Key> {
+        Some(match byte {
+            0x01 => Key::Home,
+            0x02 => Key::Left,
+            0x03 => Key::Interrupt,
+            0x04 => Key::EndOfInput,
+            0x05 => Key::End,
+            0x06 => Key::Right,
+            0x07 => Key::Cancel,
+            0x08 | 0x7f => Key::Backspace,
+            b'\t' => Key::Tab,
+            b'\r' | b'\n' => Key::Enter,
+            0x0b => Key::KillToEnd,
+            0x0e => Key::Down,
+            0x10 => Key::Up,
+            0x12 => Key::ReverseSearch,
+            0x15 => Key::KillToStart,
+            0x17 => Key::DeleteWordBefore,
+            0x1b => {
+                self.state = Escape::Esc;
+                return None;
+            }
+            /* UTF-8 sequences go in byte by byte */
+            0x20..=0x7e | 0x80..=0xff => Key::Insert(byte),
+            _ => Key::Ignored,
+        })
+    }
+}
+
+/* Lines entered, oldest first. The oldest goes once it is full */
+pub struct History {
+    lines: [[u8; LINE_MAX]; HISTORY_LEN],
+    lens: [usize; HISTORY_LEN],
+    /* Slot of the oldest line */
+    start: usize,
+    len: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            lines: [[0; LINE_MAX]; HISTORY_LEN],
+            lens: [0; HISTORY_LEN],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /* Line `i`, 0 being the oldest */
+    pub fn get(&self, i: usize) -> &[u8] {
+        let slot = (self.start + i) % HISTORY_LEN;
+        &self.lines[slot][..self.lens[slot]]
+    }
+
+    /* False when `line` isn't kept: empty, too long or the same as the
+     * newest line */
+    pub fn push(&mut self, line: &[u8]) -> bool {
+        if line.is_empty()
+            || line.len() > LINE_MAX
+            || (self.len > 0 && self.get(self.len - 1) == line)
+        {
+            return false;
+        }
+        let slot = if self.len == HISTORY_LEN {
+            let oldest = self.start;
+            self.start = (self.start + 1) % HISTORY_LEN;
+            oldest
+        } else {
+            self.len += 1;
+            (self.start + self.len - 1) % HISTORY_LEN
+        };
+        self.lines[slot][..line.len()].copy_from_slice(line);
+        self.lens[slot] = line.len();
+        true
+    }
+
+    /* Newest line before `before` holding `query` */
+    pub fn search(&self, query: &[u8], before: usize) -> Option<usize> {
+        (0..before.min(self.len)).rev().find(|&i| {
+            self.get(i)
+                .windows(query.len().max(1))
+                .any(|w| query.is_empty() || w == query)
+        })
+    }
+}
+
+/* Ctrl-R in progress */
+#[derive(Clone, Copy)]
+struct Search {
+    query: [u8; QUERY_MAX],
+    len: usize,
+    /* History line shown, None before anything matched */
+    found: Option<usize>,
+    failed: bool,
+}
+
+/* What a key leaves the line in */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    Submit,
+    EndOfInput,
+    Interrupt,
+}
+
+/* What `read_line` got */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadLine {
+    Line(usize),
+    EndOfInput,
+    Interrupted,
+}
+
+/* Bytes of one screen update, written at once so the line doesn't flicker */
+struct Screen {
+    buf: [u8; 1024],
+    len: usize,
+}
+
+impl Screen {
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    fn push_number(&mut self, mut n: usize) {
+        let mut digits = [0u8; 20];
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        self.push(&digits[i..]);
+    }
+}
+
+pub fn is_continuation(c: u8) -> bool {
+    c & 0xC0 == 0x80
+}
+
+/* Columns `bytes` take, one per character */
+fn columns(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&c| !is_continuation(c)).count()
+}
+
+fn is_word(c: u8) -> bool {
+    c != b' ' && c != b'/'
+}
+
+/* The shell's prompt: a line edited in raw mode with emacs style keys,
+ * an up/down history saved to ~/.fat32_history and Ctrl-R search. When
+ * stdin isn't a terminal, lines are read as they come */
+pub struct LineEditor {
+    history: History,
+    decoder: Decoder,
+    line: [u8; LINE_MAX],
+    len: usize,
+    cursor: usize,
+    /* History line shown, None while on the new line */
+    browsing: Option<usize>,
+    /* The new line, put back when coming down from the history */
+    draft: [u8; LINE_MAX],
+    draft_len: usize,
+    search: Option<Search>,
+    candidates: Candidates,
+    /* NUL terminated, empty without a home directory */
+    history_path: [u8; 257],
+}
+
+impl LineEditor {
+    /* Loads the history file of `home` when given */
+    pub fn new(home: Option<&[u8]>) -> Self {
+        let mut editor = LineEditor {
+            history: History::new(),
+            decoder: Decoder::new(),
+            line: [0; LINE_MAX],
+            len: 0,
+            cursor: 0,
+            browsing: None,
+            draft: [0; LINE_MAX],
+            draft_len: 0,
+            search: None,
+            candidates: Candidates::new(),
+            history_path: [0; 257],
+        };
+        if let Some(home) = home.filter(|h| !h.is_empty() && h.len() + HISTORY_FILE.len() < 257) {
+            editor.history_path[..home.len()].copy_from_slice(home);
+            editor.history_path[home.len()..home.len() + HISTORY_FILE.len()]
+                .copy_from_slice(HISTORY_FILE);
+            editor.load_history();
+        }
+        editor
+    }
+
+    pub fn line(&self) -> &[u8] {
+        &self.line[..self.len]
+    }
+
+    fn set_line(&mut self, line: &[u8]) {
+        let n = line.len().min(LINE_MAX);
+        self.line[..n].copy_from_slice(&line[..n]);
+        self.len = n;
+        self.cursor = n;
+    }
+
+    fn remove(&mut self, from: usize, to: usize) {
+        self.line.copy_within(to..self.len, from);
+        self.len -= to - from;
+        self.cursor = from;
+    }
+
+    /* Start of the character before the cursor, of a UTF-8 sequence */
+    fn char_before(&self) -> usize {
+        let mut i = self.cursor.saturating_sub(1);
+        while i > 0 && is_continuation(self.line[i]) {
+            i -= 1;
+        }
+        i
+    }
+
+    fn char_after(&self) -> usize {
+        let mut i = (self.cursor + 1).min(self.len);
+        while i < self.len && is_continuation(self.line[i]) {
+            i += 1;
+        }
+        i
+    }
+
+    fn word_left(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !is_word(self.line[i - 1]) {
+            i -= 1;
+        }
+        while i > 0 && is_word(self.line[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+
+    fn word_right(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.len && !is_word(self.line[i]) {
+            i += 1;
+        }
+        while i < self.len && is_word(self.line[i]) {
+            i += 1;
+        }
+        i
+    }
+
+    fn save_draft(&mut self) {
+        self.draft[..self.len].copy_from_slice(&self.line[..self.len]);
+        self.draft_len = self.len;
+    }
+
+    fn restore_draft(&mut self) {
+        let draft = self.draft;
+        self.set_line(&draft[..self.draft_len]);
+    }
+
+    /* Start over on an empty line */
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.cursor = 0;
+        self.browsing = None;
+        self.search = None;
+        self.decoder = Decoder::new();
+    }
+
+    pub fn apply(&mut self, key: Key) -> Action {
+        if let Some(search) = self.search {
+            match self.apply_search(search, key) {
+                Some(action) => return action,
+                /* Any other key ends the search on the line found */
+                None => self.search = None,
+            }
+        }
+
+        match key {
+            Key::Insert(c) if self.len < LINE_MAX => {
+                self.line
+                    .copy_within(self.cursor..self.len, self.cursor + 1);
+                self.line[self.cursor] = c;
+                self.len += 1;
+                self.cursor += 1;
+            }
+            Key::Enter => return Action::Submit,
+            Key::Backspace if self.cursor > 0 => self.remove(self.char_before(), self.cursor),
+            Key::EndOfInput if self.len == 0 => return Action::EndOfInput,
+            Key::Delete | Key::EndOfInput if self.cursor < self.len => {
+                let end = self.char_after();
+                self.remove(self.cursor, end);
+            }
+            Key::Left => self.cursor = self.char_before(),
+            Key::Right => self.cursor = self.char_after(),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.len,
+            Key::WordLeft => self.cursor = self.word_left(),
+            Key::WordRight => self.cursor = self.word_right(),
+            Key::DeleteWordBefore => self.remove(self.word_left(), self.cursor),
+            Key::DeleteWordAfter => {
+                let end = self.word_right();
+                self.remove(self.cursor, end);
+            }
+            Key::KillToEnd => self.len = self.cursor,
+            Key::KillToStart => self.remove(0, self.cursor),
+            Key::Up => {
+                let older = match self.browsing {
+                    None if !self.history.is_empty() => {
+                        self.save_draft();
+                        Some(self.history.len() - 1)
+                    }
+                    Some(i) if i > 0 => Some(i - 1),
+                    _ => None,
+                };
+                if let Some(i) = older {
+                    self.browsing = Some(i);
+                    let mut line = [0u8; LINE_MAX];
+                    let entry = self.history.get(i);
+                    line[..entry.len()].copy_from_slice(entry);
+                    self.set_line(&line[..entry.len()]);
+                }
+            }
+            Key::Down => match self.browsing {
+                Some(i) if i + 1 < self.history.len() => {
+                    self.browsing = Some(i + 1);
+                    let mut line = [0u8; LINE_MAX];
+                    let entry = self.history.get(i + 1);
+                    line[..entry.len()].copy_from_slice(entry);
+                    self.set_line(&line[..entry.len()]);
+                }
+                Some(_) => {
+                    self.browsing = None;
+                    self.restore_draft();
+                }
+                None => {}
+            },
+            Key::ReverseSearch => {
+                self.save_draft();
+                self.search = Some(Search {
+                    query: [0; QUERY_MAX],
+                    len: 0,
+                    found: None,
+                    failed: false,
+                });
+            }
+            Key::Interrupt => return Action::Interrupt,
+            _ => {}
+        }
+        Action::Continue
+    }
+
+    /* A key during Ctrl-R, None when it ends the search and is to be
+     * applied to the line found */
+    fn apply_search(&mut self, mut search: Search, key: Key) -> Option<Action> {
+        let newest = self.history.len();
+        match key {
+            Key::Insert(c) => {
+                if search.len < QUERY_MAX {
+                    search.query[search.len] = c;
+                    search.len += 1;
+                }
+                /* The line shown still counts if it holds the longer query */
+                let from = search.found.map_or(newest, |i| i + 1);
+                self.find(&mut search, from);
+            }
+            Key::Backspace => {
+                search.len = search.len.saturating_sub(1);
+                self.find(&mut search, newest);
+            }
+            Key::ReverseSearch => {
+                let from = search.found.unwrap_or(newest);
+                self.find(&mut search, from);
+            }
+            Key::Cancel | Key::Interrupt => {
+                self.search = None;
+                self.restore_draft();
+                return Some(Action::Continue);
+            }
+            Key::Enter => {
+                self.search = None;
+                return Some(Action::Submit);
+            }
+            _ => return None,
+        }
+        self.search = Some(search);
+        Some(Action::Continue)
+    }
+
+    fn find(&mut self, search: &mut Search, before: usize) {
+        match self.history.search(&search.query[..search.len], before) {
+            Some(i) => {
+                search.found = Some(i);
+                search.failed = false;
+                let mut line = [0u8; LINE_MAX];
+                let entry = self.history.get(i);
+                line[..entry.len()].copy_from_slice(entry);
+                self.set_line(&line[..entry.len()]);
+            }
+            None => search.failed = true,
+        }
+    }
+
+    /* Redraw the prompt and the line, the cursor where it belongs */
+    fn render(&self, prompt: &[u8]) {
+        let mut screen = Screen {
+            buf: [0; 1024],
+            len: 0,
+        };
+        screen.push(b"\r");
+        let column = match &self.search {
+            Some(search) => {
+                let label = if search.failed {
+                    FAILED_SEARCH_PROMPT
+                } else {
+                    SEARCH_PROMPT
+                };
+                screen.push(label);
+                screen.push(&search.query[..search.len]);
+                screen.push(b"': ");
+                screen.push(self.line());
+                label.len() + columns(&search.query[..search.len]) + 3 + columns(self.line())
+            }
+            None => {
+                screen.push(prompt);
+                screen.push(self.line());
+                columns(prompt) + columns(&self.line[..self.cursor])
+            }
+        };
+        screen.push(b"\x1b[K\r");
+        if column > 0 {
+            screen.push(b"\x1b[");
+            screen.push_number(column);
+            screen.push(b"C");
+        }
+        print_bytes(&screen.buf[..screen.len]);
+    }
+
+    /* Read a line into `out` after showing `prompt` */
+    pub fn read_line(
+        &mut self,
+        prompt: &[u8],
+        out: &mut [u8; LINE_MAX],
+        completer: &mut dyn Complete,
+    ) -> ReadLine {
+        let saved = match tcgetattr(STDIN_FILENO) {
+            Ok(saved) if tcsetattr(STDIN_FILENO, &saved.raw()).is_ok() => saved,
+            _ => return read_plain(prompt, out),
+        };
+        let result = self.edit(prompt, completer);
+        let _ = tcsetattr(STDIN_FILENO, &saved);
+
+        if let ReadLine::Line(len) = result {
+            out[..len].copy_from_slice(&self.line[..len]);
+            let mut line = [0u8; LINE_MAX];
+            line[..len].copy_from_slice(&self.line[..len]);
+            if self.history.push(&line[..len]) {
+                self.append_history(&line[..len]);
+            }
+        }
+        result
+    }
+
+    /* Complete the word before the cursor, a second Tab lists the names
+     * when there is nothing more to add */
+    fn tab(&mut self, completer: &mut dyn Complete, again: bool) {
+        /* A line found by Ctrl-R is taken as is */
+        self.search = None;
+        match complete(
+            &mut self.line,
+            self.len,
+            self.cursor,
+            completer,
+            &mut self.candidates,
+        ) {
+            Some((len, cursor)) => {
+                self.len = len;
+                self.cursor = cursor;
+            }
+            None if again && self.candidates.total() > 1 => {
+                print_bytes(b"\n");
+                self.candidates.print();
+            }
+            None => print_bytes(b"\x07"),
+        }
+    }
+
+    fn edit(&mut self, prompt: &[u8], completer: &mut dyn Complete) -> ReadLine {
+        self.reset();
+        self.render(prompt);
+        let mut after_tab = false;
+        loop {
+            let mut byte = [0u8; 1];
+            if read(STDIN_FILENO, byte.as_mut_ptr(), 1).unwrap_or(0) == 0 {
+                print_bytes(b"\n");
+                return ReadLine::EndOfInput;
+            }
+            let Some(key) = self.decoder.feed(byte[0]) else {
+                continue;
+            };
+            let action = match key {
+                Key::Tab => {
+                    self.tab(completer, after_tab);
+                    Action::Continue
+                }
+                key => self.apply(key),
+            };
+            after_tab = key == Key::Tab;
+            self.render(prompt);
+            match action {
+                Action::Continue => {}
+                Action::Submit => {
+                    print_bytes(b"\n");
+                    return ReadLine::Line(self.len);
+                }
+                Action::EndOfInput => {
+                    print_bytes(b"\n");
+                    return ReadLine::EndOfInput;
+                }
+                Action::Interrupt => {
+                    print_bytes(b"^C\n");
+                    return ReadLine::Interrupted;
+                }
+            }
+        }
+    }
+
+    /* Keep the last HISTORY_LEN lines of the file, rewriting it when it
+     * grew past them */
+    fn load_history(&mut self) {
+        let Ok(fd) = open(self.history_path.as_ptr()) else {
+            return;
+        };
+        let mut lines = 0usize;
+        let mut line = [0u8; LINE_MAX];
+        let mut len = 0usize;
+        let mut too_long = false;
+        let mut chunk = [0u8; 4096];
+        loop {
+            let n = read(fd, chunk.as_mut_ptr(), chunk.len()).unwrap_or(0);
+            if n == 0 {
+                break;
+            }
+            for &c in &chunk[..n] {
+                if c != b'\n' {
+                    too_long |= len == LINE_MAX;
+                    if !too_long {
+                        line[len] = c;
+                        len += 1;
+                    }
+                    continue;
+                }
+                if !too_long && self.history.push(&line[..len]) {
+                    lines += 1;
+                }
+                len = 0;
+                too_long = false;
+            }
+        }
+        close(fd);
+        if lines > HISTORY_LEN {
+            self.rewrite_history();
+        }
+    }
+
+    fn rewrite_history(&self) {
+        let Ok(fd) = open_rw(self.history_path.as_ptr(), true) else {
+            return;
+        };
+        if ftruncate(fd, 0).is_ok() {
+            /* History is best effort, a failed write only loses lines */
+            for i in 0..self.history.len() {
+                let line = self.history.get(i);
+                let _ = write(fd, line.as_ptr(), line.len());
+                let _ = write(fd, b"\n".as_ptr(), 1);
+            }
+        }
+        close(fd);
+    }
+
+    fn append_history(&self, line: &[u8]) {
+        if self.history_path[0] == 0 {
+            return;
+        }
+        let Ok(fd) = open_append(self.history_path.as_ptr()) else {
+            return;
+        };
+        let mut entry = [0u8; LINE_MAX + 1];
+        entry[..line.len()].copy_from_slice(line);
+        entry[line.len()] = b'\n';
+        let _ = write(fd, entry.as_ptr(), line.len() + 1);
+        close(fd);
+    }
+}
+
+/* Prompt and read up to a newline when stdin is a pipe or a file */
+fn read_plain(prompt: &[u8], out: &mut [u8; LINE_MAX]) -> ReadLine {
+    print_bytes(prompt);
+    let mut len = 0usize;
+    loop {
+        let mut byte = [0u8; 1];
+        if read(STDIN_FILENO, byte.as_mut_ptr(), 1).unwrap_or(0) == 0 {
+            return if len == 0 {
+                ReadLine::EndOfInput
+            } else {
+                ReadLine::Line(len)
+            };
+        }
+        if byte[0] == b'\n' {
+            return ReadLine::Line(len);
+        }
+        /* The rest of a line too long is dropped */
+        if len < LINE_MAX {
+            out[len] = byte[0];
+            len += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    fn editor() -> Box<LineEditor> {
+        Box::new(LineEditor::new(None))
+    }
+
+    /* Feed `bytes` as typed, the last action */
+    fn type_in(editor: &mut LineEditor, bytes: &[u8]) -> Action {
+        let mut action = Action::Continue;
+        for &b in bytes {
+            if let Some(key) = editor.decoder.feed(b) {
+                action = editor.apply(key);
+            }
+        }
+        action
+    }
+
+    #[test]
+    fn decodes_escape_sequences() {
+        let mut decoder = Decoder::new();
+        let mut keys = std::vec::Vec::new();
+        for &b in b"\x1b[A\x1b[1;5D\x1bOF\x1b[3~\x1bb\x17a" {
+            keys.extend(decoder.feed(b));
+        }
+        assert_eq!(
+            keys,
+            [
+                Key::Up,
+                Key::WordLeft,
+                Key::End,
+                Key::Delete,
+                Key::WordLeft,
+                Key::DeleteWordBefore,
+                Key::Insert(b'a')
+            ]
+        );
+    }
+
+    #[test]
+    fn edits_in_the_middle_of_the_line() {
+        let mut e = editor();
+        type_in(&mut e, b"cat dir/file.txt");
+        /* Ctrl-W then Ctrl-A, insert, Ctrl-E */
+        type_in(&mut e, b"\x17\x01x\x05");
+        assert_eq!(e.line(), b"xcat dir/");
+        assert_eq!(e.cursor, e.len);
+
+        type_in(&mut e, b"\x1b[D\x1b[D\x7f");
+        assert_eq!(e.line(), b"xcat dr/");
+        /* Alt-b, Ctrl-K */
+        type_in(&mut e, b"\x1bb\x0b");
+        assert_eq!(e.line(), b"xcat ");
+        type_in(&mut e, b"\x01\x1b[3~");
+        assert_eq!(e.line(), b"cat ");
+        type_in(&mut e, b"\x1b[F\x15yz");
+        assert_eq!(e.line(), b"yz");
+        assert_eq!(type_in(&mut e, b"\r"), Action::Submit);
+
+        let mut e = editor();
+        assert_eq!(type_in(&mut e, b"\x04"), Action::EndOfInput);
+        assert_eq!(type_in(&mut e, b"ab\x01\x04"), Action::Continue);
+        assert_eq!(e.line(), b"b");
+        assert_eq!(type_in(&mut e, b"\x03"), Action::Interrupt);
+    }
+
+    #[test]
+    fn browses_the_history() {
+        let mut e = editor();
+        for line in [&b"ls"[..], b"cd sub", b"cd sub", b"", b"cat a.txt"] {
+            e.history.push(line);
+        }
+        assert_eq!(e.history.len(), 3);
+
+        type_in(&mut e, b"dra");
+        type_in(&mut e, b"\x1b[A\x1b[A");
+        assert_eq!(e.line(), b"cd sub");
+        type_in(&mut e, b"\x10\x10");
+        assert_eq!(e.line(), b"ls");
+        type_in(&mut e, b"\x1b[B\x0e\x0e");
+        assert_eq!(e.line(), b"dra");
+    }
+
+    #[test]
+    fn searches_the_history_backwards() {
+        let mut e = editor();
+        for line in [&b"cat a.txt"[..], b"ls", b"cat b.txt", b"cd sub"] {
+            e.history.push(line);
+        }
+        type_in(&mut e, b"\x12ca");
+        assert_eq!(e.line(), b"cat b.txt");
+        type_in(&mut e, b"\x12");
+        assert_eq!(e.line(), b"cat a.txt");
+        /* Nothing older, the line stays */
+        type_in(&mut e, b"\x12");
+        assert_eq!(e.line(), b"cat a.txt");
+        assert!(e.search.is_some_and(|s| s.failed));
+
+        /* Another key edits the line found */
+        type_in(&mut e, b"\x1b[D!");
+        assert!(e.search.is_none());
+        assert_eq!(e.line(), b"cat a.tx!t");
+
+        let mut e = editor();
+        e.history.push(b"fatcmp");
+        type_in(&mut e, b"draft\x12fat\x07");
+        assert_eq!(e.line(), b"draft");
+        assert_eq!(type_in(&mut e, b"\x12fat\r"), Action::Submit);
+        assert_eq!(e.line(), b"fatcmp");
+    }
+
+    #[test]
+    fn history_drops_the_oldest_lines() {
+        let mut history = Box::new(History::new());
+        for i in 0..HISTORY_LEN + 5 {
+            history.push(std::format!("line {i}").as_bytes());
+        }
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history.get(0), b"line 5");
+        assert_eq!(history.search(b"line 7", 3), Some(2));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::cli::consts::{CLI_NAME, HEX};
+use crate::cli::consts::HEX;
+use crate::helpers::write_padded_decimal;
 use crate::sys::print_bytes;
 
 /* __________ Helpers __________ */
@@ -31,6 +32,19 @@ pub fn print_bytes_hex(bytes: &[u8]) {
     print_bytes(b"\n");
 }
 
+// Print a 32 bits value as 0x followed by 8 hex digits, without new line
+pub fn print_hex_u32(value: u32) {
+    let mut buf = [0u8; 10];
+    buf[0] = b'0';
+    buf[1] = b'x';
+    for (i, &b) in value.to_be_bytes().iter().enumerate() {
+        let hex = byte_to_hex(b);
+        buf[2 + 2 * i] = hex[0];
+        buf[3 + 2 * i] = hex[1];
+    }
+    print_bytes(&buf);
+}
+
 pub fn clear_cli() {
     print_bytes(b"\x1B[H\x1B[2J\x1B[3J\x1B[0m");
 }
@@ -52,9 +66,15 @@ pub fn print_line() {
     print("_______________________________________________________________________________");
 }
 
-pub fn print_ls(entry: &[u8], is_dir: bool, last: bool, indent_level: usize) {
-    for _ in 0..indent_level {
-        print_bytes(b"|   ");
+/* Connector and icon of a listed entry, below levels that each still
+ * have entries under this one or not */
+pub fn print_branch(is_dir: bool, last: bool, branches: &[bool]) {
+    for &more in branches {
+        if more {
+            print_bytes(b"\xE2\x94\x82   ");
+        } else {
+            print_bytes(b"    ");
+        }
     }
 
     if last {
@@ -68,7 +88,36 @@ pub fn print_ls(entry: &[u8], is_dir: bool, last: bool, indent_level: usize) {
     } else {
         print_bytes(b"\xF0\x9F\x93\x84 ");
     }
+}
 
+pub fn print_ls(entry: &[u8], is_dir: bool, last: bool, branches: &[bool]) {
+    print_branch(is_dir, last, branches);
     print_bytes(entry);
     print_bytes(b"\n");
 }
+
+// Print an unsigned number in decimal, without new line
+pub fn print_number(mut n: u64) {
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    print_bytes(&buf[i..]);
+}
+
+/* A packed FAT date and time as YYYY-MM-DD HH:MM, without new line */
+pub fn print_fat_date(date: u16, time: u16) {
+    let mut out = *b"0000-00-00 00:00";
+    write_padded_decimal(1980 + (date >> 9) as u32, &mut out[0..4]);
+    write_padded_decimal(((date >> 5) & 0x0F) as u32, &mut out[5..7]);
+    write_padded_decimal((date & 0x1F) as u32, &mut out[8..10]);
+    write_padded_decimal((time >> 11) as u32, &mut out[11..13]);
+    write_padded_decimal(((time >> 5) & 0x3F) as u32, &mut out[14..16]);
+    print_bytes(&out);
+}
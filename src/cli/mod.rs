@@ -1,5 +1,10 @@
+pub mod commands;
+pub mod complete;
 pub mod consts;
+pub mod editor;
 pub mod helpers;
+pub mod options;
+pub mod words;
 
 pub use consts::*;
 pub use helpers::*;
@@ -0,0 +1,182 @@
+use crate::cli::{print, print_no_ln};
+use crate::helpers::parse_u64;
+use crate::sys::print_bytes;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DEFAULT_IMAGE: &[u8] = b"disk.img";
+/* Names the image when the command line doesn't */
+pub const IMAGE_VAR: &[u8] = b"FAT32_IMAGE";
+/* Longest image path, the shell keeps it NUL terminated in 257 bytes */
+pub const PATH_MAX: usize = 256;
+
+/* How the shell was asked to open its image */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options<'a> {
+    pub image: &'a [u8],
+    /* Partition of the image holding the volume, from 1 */
+    pub partition: Option<u32>,
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invocation<'a> {
+    Shell(Options<'a>),
+    Help,
+    Version,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionError<'a> {
+    Unknown(&'a [u8]),
+    /* The option needs a value after it */
+    MissingValue(&'a [u8]),
+    BadPartition(&'a [u8]),
+    /* A second image path */
+    Unexpected(&'a [u8]),
+    PathTooLong,
+}
+
+/* `fat32 [options] [image]`, the image defaulting to `env_image` then
+ * disk.img. `--help` and `--version` win over everything else */
+pub fn parse_options<'a>(
+    mut args: impl Iterator<Item = &'a [u8]>,
+    env_image: Option<&'a [u8]>,
+) -> Result<Invocation<'a>, OptionError<'a>> {
+    let mut image: Option<&[u8]> = None;
+    let mut partition = None;
+    let mut read_only = false;
+    let mut options_done = false;
+
+    while let Some(arg) = args.next() {
+        if options_done || arg == b"-" || !arg.starts_with(b"-") {
+            if image.replace(arg).is_some() {
+                return Err(OptionError::Unexpected(arg));
+            }
+            continue;
+        }
+        let (name, inline) = match arg.iter().position(|&c| c == b'=') {
+            Some(i) if arg.starts_with(b"--") => (&arg[..i], Some(&arg[i + 1..])),
+            _ => (arg, None),
+        };
+        match name {
+            b"--" => options_done = true,
+            b"-h" | b"--help" => return Ok(Invocation::Help),
+            b"-V" | b"--version" => return Ok(Invocation::Version),
+            b"-r" | b"--read-only" => read_only = true,
+            b"-w" | b"--read-write" => read_only = false,
+            b"-p" | b"--partition" => {
+                let value = match inline {
+                    Some(value) => value,
+                    None => args.next().ok_or(OptionError::MissingValue(name))?,
+                };
+                partition = match parse_u64(value) {
+                    Some(n) if n >= 1 && n <= u32::MAX as u64 => Some(n as u32),
+                    _ => return Err(OptionError::BadPartition(value)),
+                };
+            }
+            _ => return Err(OptionError::Unknown(arg)),
+        }
+    }
+
+    let path = image.or(env_image).unwrap_or(DEFAULT_IMAGE);
+    if path.len() > PATH_MAX {
+        return Err(OptionError::PathTooLong);
+    }
+    Ok(Invocation::Shell(Options {
+        image: path,
+        partition,
+        read_only,
+    }))
+}
+
+pub fn print_usage() {
+    print("Usage: fat32 [options] [image]");
+    print("Browse, check and repair a FAT12/16/32 or exFAT image");
+    print("");
+    print("  -p, --partition <n>  Use partition n of an MBR or GPT image, from 1");
+    print("  -r, --read-only      Refuse the commands writing to the image");
+    print("  -w, --read-write     Allow them, the default");
+    print("  -h, --help           Show this help");
+    print("  -V, --version        Show the version");
+    print("");
+    print("The image defaults to $FAT32_IMAGE, then disk.img");
+}
+
+pub fn print_version() {
+    print_no_ln("fat32 ");
+    print(VERSION);
+}
+
+pub fn print_option_error(e: OptionError) {
+    let (message, arg): (&str, &[u8]) = match e {
+        OptionError::Unknown(arg) => ("Unknown option ", arg),
+        OptionError::MissingValue(arg) => ("Missing value for ", arg),
+        OptionError::BadPartition(arg) => ("Not a partition number: ", arg),
+        OptionError::Unexpected(arg) => ("Only one image can be given: ", arg),
+        OptionError::PathTooLong => ("Image path too long", b""),
+    };
+    print_no_ln(message);
+    print_bytes(arg);
+    print("");
+    print("Try `fat32 --help`");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse<'a>(
+        args: &'a [&'a [u8]],
+        env: Option<&'a [u8]>,
+    ) -> Result<Invocation<'a>, OptionError<'a>> {
+        parse_options(args.iter().copied(), env)
+    }
+
+    fn shell<'a>(result: Result<Invocation<'a>, OptionError<'a>>) -> Options<'a> {
+        match result {
+            Ok(Invocation::Shell(options)) => options,
+            other => panic!("not a shell invocation: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn picks_the_image_and_options() {
+        let options = shell(parse(&[], None));
+        assert_eq!(options.image, b"disk.img");
+        assert_eq!((options.partition, options.read_only), (None, false));
+
+        let options = shell(parse(&[], Some(b"env.img")));
+        assert_eq!(options.image, b"env.img");
+
+        let options = shell(parse(
+            &[b"-r", b"a.img", b"--partition", b"2"],
+            Some(b"env.img"),
+        ));
+        assert_eq!(options.image, b"a.img");
+        assert_eq!((options.partition, options.read_only), (Some(2), true));
+
+        let options = shell(parse(
+            &[b"--read-only", b"--partition=3", b"-w", b"--", b"-x.img"],
+            None,
+        ));
+        assert_eq!(options.image, b"-x.img");
+        assert_eq!((options.partition, options.read_only), (Some(3), false));
+    }
+
+    #[test]
+    fn help_version_and_errors() {
+        assert_eq!(parse(&[b"a.img", b"--help"], None), Ok(Invocation::Help));
+        assert_eq!(parse(&[b"-V", b"-x"], None), Ok(Invocation::Version));
+        assert_eq!(parse(&[b"-x"], None), Err(OptionError::Unknown(b"-x")));
+        assert_eq!(parse(&[b"-p"], None), Err(OptionError::MissingValue(b"-p")));
+        assert_eq!(
+            parse(&[b"-p", b"0"], None),
+            Err(OptionError::BadPartition(b"0"))
+        );
+        assert_eq!(
+            parse(&[b"a.img", b"b.img"], None),
+            Err(OptionError::Unexpected(b"b.img"))
+        );
+        assert_eq!(parse(&[&[b'a'; 300]], None), Err(OptionError::PathTooLong));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::cli::editor::LINE_MAX;
+use crate::cli::print;
+
+/* What a byte of a command line turns out to be */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lexed {
+    /* Part of a word */
+    Char(u8),
+    /* Whitespace between words */
+    Space,
+    /* A quote or a backslash, dropped from the word */
+    Syntax,
+}
+
+/* Quoting state along a command line: 'single quotes' keep everything,
+ * "double quotes" and a backslash outside quotes escape the next byte */
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Quoting {
+    quote: u8,
+    escape: bool,
+}
+
+impl Quoting {
+    pub fn feed(&mut self, c: u8) -> Lexed {
+        if self.escape {
+            self.escape = false;
+            return Lexed::Char(c);
+        }
+        match (self.quote, c) {
+            (0, b' ' | b'\t') => Lexed::Space,
+            (0, b'\'' | b'"') => {
+                self.quote = c;
+                Lexed::Syntax
+            }
+            (q, _) if q == c => {
+                self.quote = 0;
+                Lexed::Syntax
+            }
+            (b'\'', _) => Lexed::Char(c),
+            (_, b'\\') => {
+                self.escape = true;
+                Lexed::Syntax
+            }
+            _ => Lexed::Char(c),
+        }
+    }
+
+    /* Inside quotes or right after a backslash */
+    pub fn is_open(&self) -> bool {
+        self.quote != 0 || self.escape
+    }
+}
+
+/* Words a command line holds at most */
+pub const WORDS_MAX: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /* A quote left open, or a backslash ending the line */
+    Unterminated,
+    TooManyWords,
+}
+
+/* A command line split into words, quotes and backslashes removed */
+pub struct Words {
+    buf: [u8; LINE_MAX],
+    ends: [usize; WORDS_MAX],
+    count: usize,
+}
+
+impl Words {
+    pub fn get(&self, i: usize) -> &[u8] {
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        &self.buf[start..self.ends[i]]
+    }
+
+    fn end_word(&mut self, end: usize) -> Result<(), TokenError> {
+        if self.count == WORDS_MAX {
+            return Err(TokenError::TooManyWords);
+        }
+        self.ends[self.count] = end;
+        self.count += 1;
+        Ok(())
+    }
+
+    /* The words as slices, and how many there are */
+    pub fn as_slices(&self) -> ([&[u8]; WORDS_MAX], usize) {
+        let mut slices: [&[u8]; WORDS_MAX] = [&[]; WORDS_MAX];
+        for (i, slice) in slices[..self.count].iter_mut().enumerate() {
+            *slice = self.get(i);
+        }
+        (slices, self.count)
+    }
+}
+
+/* Split `line` like a shell would: on blanks outside quotes, `""` being an
+ * empty word */
+pub fn tokenize(line: &[u8]) -> Result<Words, TokenError> {
+    let mut words = Words {
+        buf: [0; LINE_MAX],
+        ends: [0; WORDS_MAX],
+        count: 0,
+    };
+    let mut quoting = Quoting::default();
+    let mut len = 0;
+    let mut in_word = false;
+    for &c in line {
+        match quoting.feed(c) {
+            Lexed::Char(c) => {
+                if len == LINE_MAX {
+                    break;
+                }
+                words.buf[len] = c;
+                len += 1;
+                in_word = true;
+            }
+            Lexed::Syntax => in_word = true,
+            Lexed::Space if in_word => {
+                words.end_word(len)?;
+                in_word = false;
+            }
+            Lexed::Space => {}
+        }
+    }
+    if quoting.is_open() {
+        return Err(TokenError::Unterminated);
+    }
+    if in_word {
+        words.end_word(len)?;
+    }
+    Ok(words)
+}
+
+pub fn print_token_error(e: TokenError) {
+    match e {
+        TokenError::Unterminated => print("Missing a closing quote, or a character after `\\`"),
+        TokenError::TooManyWords => print("Too many words on the line"),
+    }
+}
+
+/* Append `word` to `out` at `*len` so it reads back as is within `quote`
+ * (0 for none), false when it doesn't fit */
+pub fn quote_into(word: &[u8], quote: u8, out: &mut [u8], len: &mut usize) -> bool {
+    let mut push = |bytes: &[u8]| {
+        if *len + bytes.len() > out.len() {
+            return false;
+        }
+        out[*len..*len + bytes.len()].copy_from_slice(bytes);
+        *len += bytes.len();
+        true
+    };
+    for &c in word {
+        let fits = match (quote, c) {
+            /* Close, escaped quote, reopen */
+            (b'\'', b'\'') => push(b"'\\''"),
+            (b'\'', _) => push(&[c]),
+            (b'"', b'"' | b'\\') => push(&[b'\\', c]),
+            (b'"', _) => push(&[c]),
+            (_, b' ' | b'\t' | b'\'' | b'"' | b'\\') => push(&[b'\\', c]),
+            _ => push(&[c]),
+        };
+        if !fits {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn words(line: &[u8]) -> Vec<u8> {
+        let mut quoting = Quoting::default();
+        line.iter()
+            .filter_map(|&c| match quoting.feed(c) {
+                Lexed::Char(c) => Some(c),
+                Lexed::Space => Some(b'|'),
+                Lexed::Syntax => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quotes_round_trip() {
+        assert_eq!(
+            words(br#"cat "a b"/c\ d 'e"f' "g\"h""#),
+            b"cat|a b/c d|e\"f|g\"h"
+        );
+
+        for quote in [0, b'\'', b'"'] {
+            let name = br#"it's a "name" \ here"#;
+            let mut out = [0u8; 64];
+            let mut len = 0;
+            if quote != 0 {
+                out[0] = quote;
+                len = 1;
+            }
+            assert!(quote_into(name, quote, &mut out, &mut len));
+            if quote != 0 {
+                out[len] = quote;
+                len += 1;
+            }
+            assert_eq!(words(&out[..len]), name);
+        }
+        let mut out = [0u8; 4];
+        let mut len = 0;
+        assert!(!quote_into(b"a b c", 0, &mut out, &mut len));
+    }
+
+    #[test]
+    fn tokenizes_like_a_shell() {
+        let words = tokenize(br#"  cat "My Docs"/a\ b '' x"y"z  "#).unwrap();
+        let (slices, count) = words.as_slices();
+        assert_eq!(&slices[..count], [&b"cat"[..], b"My Docs/a b", b"", b"xyz"]);
+
+        assert_eq!(tokenize(b"").unwrap().as_slices().1, 0);
+        assert_eq!(tokenize(b"cat 'a").err(), Some(TokenError::Unterminated));
+        assert_eq!(tokenize(b"cat a\\").err(), Some(TokenError::Unterminated));
+        let many = b"a ".repeat(WORDS_MAX + 1);
+        assert_eq!(tokenize(&many).err(), Some(TokenError::TooManyWords));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::sys::{Errno, fdatasync, file_size, mmap, msync, munmap, pread, pwrite};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /* The syscall failed with this error */
+    Syscall(Errno),
+    /* Fewer bytes than requested were transferred */
+    Short,
+    /* The access goes past the end of the device */
+    OutOfBounds,
+    /* A write to a device opened for reading */
+    ReadOnly,
+}
+
+/* Anything a FAT volume can live on: an image file, a partition, memory */
+pub trait BlockDevice {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError>;
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError>;
+
+    /* Size of the device in bytes */
+    fn size(&mut self) -> Result<u64, DeviceError>;
+
+    /* Push out whatever a layer above the device keeps back */
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    /* The `len` bytes at `offset` in place, without a copy, for devices
+     * holding the whole image in memory. None otherwise, `read_at` then */
+    fn borrow(&self, _offset: u64, _len: usize) -> Option<&[u8]> {
+        None
+    }
+}
+
+/* So a volume can borrow a device kept by the caller, like the shell's cache */
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        (**self).write_at(offset, buf)
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        (**self).size()
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        (**self).flush()
+    }
+
+    fn borrow(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        (**self).borrow(offset, len)
+    }
+}
+
+/* __________ File descriptor __________ */
+pub struct FileDevice {
+    pub fd: usize,
+}
+
+impl FileDevice {
+    pub fn new(fd: usize) -> Self {
+        FileDevice { fd }
+    }
+}
+
+impl BlockDevice for FileDevice {
+    /* pread may return less than asked, the rest is read after it */
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let mut done = 0usize;
+        while done < buf.len() {
+            let rest = &mut buf[done..];
+            // Safety: `rest` is a live mutable slice, valid for `rest.len()` bytes.
+            // SAFETY: pointer and length come from the same slice.
+            let r = unsafe { pread(self.fd, rest.as_mut_ptr(), rest.len(), offset + done as u64) }
+                .map_err(DeviceError::Syscall)?;
+            if r == 0 {
+                return Err(DeviceError::Short);
+            }
+            done += r;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        let mut done = 0usize;
+        while done < buf.len() {
+            let rest = &buf[done..];
+            // Safety: `rest` is a live slice, valid for reads of `rest.len()` bytes.
+            // SAFETY: pointer and length come from the same slice.
+            let w = unsafe { pwrite(self.fd, rest.as_ptr(), rest.len(), offset + done as u64) }
+                .map_err(DeviceError::Syscall)?;
+            if w == 0 {
+                return Err(DeviceError::Short);
+            }
+            done += w;
+        }
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        file_size(self.fd).map_err(DeviceError::Syscall)
+    }
+
+    /* Writes reach the disk before the command reports success */
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        fdatasync(self.fd).map_err(DeviceError::Syscall)
+    }
+}
+
+/* __________ Memory map __________ */
+/* The whole image mapped in memory: reads are plain copies out of the
+ * mapping or borrows of it, writes land in the page cache and reach the
+ * file on `flush` */
+pub struct MmapDevice {
+    addr: *mut u8,
+    len: usize,
+    writable: bool,
+}
+
+impl MmapDevice {
+    pub fn map(fd: usize, writable: bool) -> Result<Self, DeviceError> {
+        let len = file_size(fd).map_err(DeviceError::Syscall)? as usize;
+        let addr = mmap(fd, len, writable).map_err(DeviceError::Syscall)?;
+        Ok(MmapDevice { addr, len, writable })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // Safety: the mapping is `len` bytes long and lives as long as `self`.
+        // SAFETY: `addr` and `len` come from a successful mmap, unmapped only on drop.
+        unsafe { core::slice::from_raw_parts(self.addr, self.len) }
+    }
+
+    fn range(&self, offset: u64, len: usize) -> Result<core::ops::Range<usize>, DeviceError> {
+        let start = usize::try_from(offset).map_err(|_| DeviceError::OutOfBounds)?;
+        let end = start.checked_add(len).ok_or(DeviceError::OutOfBounds)?;
+        if end > self.len {
+            return Err(DeviceError::OutOfBounds);
+        }
+        Ok(start..end)
+    }
+}
+
+impl Drop for MmapDevice {
+    fn drop(&mut self) {
+        // Safety: borrows of the mapping are tied to `self`, none is left.
+        // SAFETY: `addr` and `len` come from a successful mmap.
+        let _ = unsafe { munmap(self.addr, self.len) };
+    }
+}
+
+impl BlockDevice for MmapDevice {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.bytes()[range]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        if !self.writable {
+            return Err(DeviceError::ReadOnly);
+        }
+        let range = self.range(offset, buf.len())?;
+        // Safety: the mapping is writable and `range` lies within it.
+        // SAFETY: `&mut self` means no borrow of the mapping is alive.
+        let bytes = unsafe { core::slice::from_raw_parts_mut(self.addr, self.len) };
+        bytes[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        Ok(self.len as u64)
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        if !self.writable {
+            return Ok(());
+        }
+        msync(self.addr, self.len).map_err(DeviceError::Syscall)
+    }
+
+    fn borrow(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let range = self.range(offset, len).ok()?;
+        Some(&self.bytes()[range])
+    }
+}
+
+/* __________ Memory __________ */
+#[cfg(test)]
+pub struct MemDevice<'a> {
+    pub data: &'a mut [u8],
+}
+
+#[cfg(test)]
+impl BlockDevice for MemDevice<'_> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(buf.len())
+            .ok_or(DeviceError::OutOfBounds)?;
+        if end > self.data.len() {
+            return Err(DeviceError::OutOfBounds);
+        }
+        buf.copy_from_slice(&self.data[start..end]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(buf.len())
+            .ok_or(DeviceError::OutOfBounds)?;
+        if end > self.data.len() {
+            return Err(DeviceError::OutOfBounds);
+        }
+        self.data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        Ok(self.data.len() as u64)
+    }
+}
+
+/* Like MemDevice, and lends its bytes like a mapping does */
+#[cfg(test)]
+pub struct MappedMemDevice<'a>(pub MemDevice<'a>);
+
+#[cfg(test)]
+impl BlockDevice for MappedMemDevice<'_> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.0.read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.0.write_at(offset, buf)
+    }
+
+    fn size(&mut self) -> Result<u64, DeviceError> {
+        self.0.size()
+    }
+
+    fn borrow(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let start = usize::try_from(offset).ok()?;
+        self.0.data.get(start..start.checked_add(len)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::{close, open, open_rw};
+    use std::format;
+    use std::vec::Vec;
+
+    /* A scratch file holding `data`, and its NUL terminated path */
+    fn scratch(name: &str, data: &[u8]) -> (std::path::PathBuf, Vec<u8>) {
+        let path = std::env::temp_dir().join(format!("fat32-{}-{name}", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let mut c_path = path.to_str().unwrap().as_bytes().to_vec();
+        c_path.push(0);
+        (path, c_path)
+    }
+
+    #[test]
+    fn mapping_reads_in_place_and_writes_on_flush() {
+        let data: Vec<u8> = (0..8192u32).map(|i| i as u8).collect();
+        let (path, c_path) = scratch("rw", &data);
+        let fd = open_rw(c_path.as_ptr(), false).unwrap();
+
+        let mut map = MmapDevice::map(fd, true).unwrap();
+        assert_eq!(map.size(), Ok(8192));
+        assert_eq!(map.borrow(1000, 4), Some(&data[1000..1004]));
+        assert_eq!(map.borrow(8190, 4), None);
+        let mut buf = [0u8; 3];
+        map.read_at(255, &mut buf).unwrap();
+        assert_eq!(buf, [255, 0, 1]);
+        assert_eq!(map.read_at(8191, &mut buf), Err(DeviceError::OutOfBounds));
+
+        map.write_at(4096, b"mapped").unwrap();
+        map.flush().unwrap();
+        drop(map);
+        close(fd);
+        assert_eq!(&std::fs::read(&path).unwrap()[4096..4102], b"mapped");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_mapping_refuses_writes() {
+        let (path, c_path) = scratch("ro", &[7u8; 4096]);
+        let fd = open(c_path.as_ptr()).unwrap();
+
+        let mut map = MmapDevice::map(fd, false).unwrap();
+        assert_eq!(map.write_at(0, b"x"), Err(DeviceError::ReadOnly));
+        assert_eq!(map.flush(), Ok(()));
+        assert_eq!(map.borrow(0, 2), Some(&[7u8, 7][..]));
+        drop(map);
+        close(fd);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
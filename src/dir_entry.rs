@@ -0,0 +1,368 @@
+use crate::helpers::{fat_datetime, u16_to_u8_le, u32_to_u8_le, u8_le_to_u16, u8_to_u32_le};
+
+pub const DIR_ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+/* First name byte of a deleted entry, and of the end of a directory */
+pub const ENTRY_DELETED: u8 = 0xE5;
+pub const ENTRY_END: u8 = 0x00;
+
+pub const DOT_NAME: &[u8; 11] = b".          ";
+pub const DOT_DOT_NAME: &[u8; 11] = b"..         ";
+
+/* 20 entries of 13 UTF-16 units */
+pub const LFN_MAX_ENTRIES: usize = 20;
+pub const LFN_MAX_UNITS: usize = 260;
+pub const LFN_UNITS_PER_ENTRY: usize = 13;
+/* Ordinal flag of the entry holding the end of the name, stored first */
+pub const LFN_LAST_ENTRY: u8 = 0x40;
+/* Where the 13 UTF-16 units of an LFN entry sit */
+pub const LFN_UNIT_OFFSETS: [usize; LFN_UNITS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub file_size: u32,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_volume_id(&self) -> bool {
+        self.attr & ATTR_VOLUME_ID != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        &self.name == DOT_NAME || &self.name == DOT_DOT_NAME
+    }
+}
+
+pub fn parse_dir_entry(entry: &[u8]) -> DirEntry {
+    let mut name = [0u8; 11];
+    name.copy_from_slice(&entry[0..11]);
+
+    let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
+    let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
+
+    DirEntry {
+        name,
+        attr: entry[11],
+        first_cluster: (cluster_high << 16) | cluster_low,
+        file_size: u8_to_u32_le(&entry[28..32]),
+    }
+}
+
+/* On-disk short entry, every timestamp set to `secs` (Unix time) */
+pub fn build_dir_entry(name: &[u8; 11], attr: u8, first_cluster: u32, size: u32, secs: u64) -> [u8; 32] {
+    let mut e = [0u8; 32];
+    e[0..11].copy_from_slice(name);
+    e[11] = attr;
+
+    let (date, time) = fat_datetime(secs);
+    u16_to_u8_le(time, &mut e[14..16]);
+    u16_to_u8_le(date, &mut e[16..18]);
+    u16_to_u8_le(date, &mut e[18..20]);
+    u16_to_u8_le((first_cluster >> 16) as u16, &mut e[20..22]);
+    u16_to_u8_le(time, &mut e[22..24]);
+    u16_to_u8_le(date, &mut e[24..26]);
+    u16_to_u8_le(first_cluster as u16, &mut e[26..28]);
+    u32_to_u8_le(size, &mut e[28..32]);
+    e
+}
+
+pub fn is_lfn_entry(entry: &[u8]) -> bool {
+    entry[11] & 0x3F == ATTR_LONG_NAME
+}
+
+/* Checksum of the short name stored in each of its LFN entries */
+pub fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    let mut sum: u8 = 0;
+    for &c in name {
+        sum = sum.rotate_right(1).wrapping_add(c);
+    }
+    sum
+}
+
+fn is_legal_short_name_char(c: u8) -> bool {
+    if c < 0x20 || c == 0x7F || c.is_ascii_lowercase() {
+        return false;
+    }
+    !matches!(
+        c,
+        b'"' | b'*'
+            | b'+'
+            | b','
+            | b'.'
+            | b'/'
+            | b':'
+            | b';'
+            | b'<'
+            | b'='
+            | b'>'
+            | b'?'
+            | b'['
+            | b'\\'
+            | b']'
+            | b'|'
+    )
+}
+
+/* 8.3 name as stored on disk, `.` and `..` are not legal here */
+pub fn is_legal_short_name(name: &[u8; 11]) -> bool {
+    if name[0] == b' ' {
+        return false;
+    }
+    /* 0x05 stands for a leading 0xE5 */
+    let first_ok = name[0] == 0x05 || is_legal_short_name_char(name[0]);
+    first_ok && name[1..].iter().all(|&c| is_legal_short_name_char(c))
+}
+
+/* `name.ext` as typed to its padded uppercase 8.3 form, None when it
+ * doesn't fit or isn't a legal short name */
+pub fn pack_short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.iter().rposition(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &name[name.len()..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+
+    let mut packed = [b' '; 11];
+    for (dst, &c) in packed[..8].iter_mut().zip(base) {
+        *dst = c.to_ascii_uppercase();
+    }
+    for (dst, &c) in packed[8..].iter_mut().zip(ext) {
+        *dst = c.to_ascii_uppercase();
+    }
+    if is_legal_short_name(&packed) {
+        Some(packed)
+    } else {
+        None
+    }
+}
+
+/* Collects the LFN entries preceding a short entry, in on-disk order */
+pub struct LfnBuilder {
+    units: [u16; LFN_MAX_UNITS],
+    checksum: u8,
+    /* Ordinal the next entry must have, 0 once the sequence is complete */
+    expected: u8,
+    /* Entries gathered so far */
+    count: u8,
+}
+
+impl Default for LfnBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LfnBuilder {
+    pub fn new() -> Self {
+        LfnBuilder {
+            units: [0xFFFF; LFN_MAX_UNITS],
+            checksum: 0,
+            expected: 0,
+            count: 0,
+        }
+    }
+
+    /* Drop the gathered entries, returns how many were pending */
+    pub fn reset(&mut self) -> u8 {
+        let pending = self.count;
+        self.count = 0;
+        self.expected = 0;
+        pending
+    }
+
+    /* Entries gathered and not yet closed by a short entry */
+    pub fn pending(&self) -> u8 {
+        self.count
+    }
+
+    /* Feed one LFN entry, returns the number of entries orphaned by it */
+    pub fn push(&mut self, entry: &[u8]) -> u8 {
+        let order = entry[0];
+        let n = order & 0x3F;
+
+        if n == 0 || n as usize * LFN_UNITS_PER_ENTRY > LFN_MAX_UNITS {
+            return self.reset() + 1;
+        }
+
+        let mut orphans = 0;
+        if order & LFN_LAST_ENTRY != 0 {
+            /* A new sequence starts, whatever was pending is lost */
+            orphans = self.reset();
+            self.units = [0xFFFF; LFN_MAX_UNITS];
+            self.checksum = entry[13];
+            self.expected = n;
+        } else if self.count == 0 || n != self.expected || entry[13] != self.checksum {
+            return self.reset() + 1;
+        }
+
+        let base = (n as usize - 1) * LFN_UNITS_PER_ENTRY;
+        for (i, &off) in LFN_UNIT_OFFSETS.iter().enumerate() {
+            self.units[base + i] = u8_le_to_u16(&entry[off..off + 2]);
+        }
+
+        self.count += 1;
+        self.expected = n - 1;
+        orphans
+    }
+
+    /* Close the sequence on its short entry. Ok(None) when there was no
+     * long name, Err(n) when `n` pending entries don't belong to it */
+    pub fn finish(&mut self, short_name: &[u8; 11]) -> Result<Option<&[u16]>, u8> {
+        if self.count == 0 {
+            return Ok(None);
+        }
+        if self.expected != 0 || self.checksum != lfn_checksum(short_name) {
+            return Err(self.reset());
+        }
+
+        self.count = 0;
+        let len = self
+            .units
+            .iter()
+            .position(|&u| u == 0x0000 || u == 0xFFFF)
+            .unwrap_or(LFN_MAX_UNITS);
+        Ok(Some(&self.units[..len]))
+    }
+}
+
+/* UTF-16 long name to UTF-8, invalid surrogates become '?' */
+pub fn lfn_to_utf8(units: &[u16], out: &mut [u8]) -> usize {
+    let mut len = 0usize;
+    let mut buf = [0u8; 4];
+    for c in core::char::decode_utf16(units.iter().copied()) {
+        let c = c.unwrap_or('?');
+        let encoded = c.encode_utf8(&mut buf);
+        if len + encoded.len() > out.len() {
+            break;
+        }
+        out[len..len + encoded.len()].copy_from_slice(encoded.as_bytes());
+        len += encoded.len();
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* LFN entry `order` of `name` holding `chars` (at most 13) */
+    fn lfn_entry(order: u8, checksum: u8, chars: &[u8]) -> [u8; 32] {
+        let mut e = [0u8; 32];
+        e[0] = order;
+        e[11] = ATTR_LONG_NAME;
+        e[13] = checksum;
+        for (i, &off) in LFN_UNIT_OFFSETS.iter().enumerate() {
+            let unit: u16 = if i < chars.len() {
+                chars[i] as u16
+            } else if i == chars.len() {
+                0
+            } else {
+                0xFFFF
+            };
+            e[off] = unit as u8;
+            e[off + 1] = (unit >> 8) as u8;
+        }
+        e
+    }
+
+    #[test]
+    fn parse_dir_entry_fields() {
+        let mut e = [0u8; 32];
+        e[0..11].copy_from_slice(b"FILE    TXT");
+        e[11] = 0x20;
+        e[20] = 0x01;
+        e[26] = 0x34;
+        e[27] = 0x12;
+        e[28] = 0x0F;
+
+        let d = parse_dir_entry(&e);
+        assert_eq!(&d.name, b"FILE    TXT");
+        assert_eq!(d.first_cluster, 0x00011234);
+        assert_eq!(d.file_size, 15);
+        assert!(!d.is_dir() && !d.is_dot());
+    }
+
+    #[test]
+    fn build_dir_entry_round_trips() {
+        let e = build_dir_entry(b"DIR        ", ATTR_DIRECTORY, 0x00012345, 0, 0);
+        let d = parse_dir_entry(&e);
+        assert!(d.is_dir());
+        assert_eq!(d.first_cluster, 0x00012345);
+        /* Before 1980, clamped to 1980-01-01 */
+        assert_eq!(u8_le_to_u16(&e[24..26]), (1 << 5) | 1);
+    }
+
+    #[test]
+    fn short_name_legality() {
+        assert!(is_legal_short_name(b"FILE    TXT"));
+        assert!(is_legal_short_name(b"A~1     $$$"));
+        assert!(!is_legal_short_name(b"file    txt"));
+        assert!(!is_legal_short_name(b" FILE   TXT"));
+        assert!(!is_legal_short_name(b"FI*E    TXT"));
+        assert!(!is_legal_short_name(DOT_NAME));
+    }
+
+    #[test]
+    fn packs_typed_short_names() {
+        assert_eq!(pack_short_name(b"notes.txt"), Some(*b"NOTES   TXT"));
+        assert_eq!(pack_short_name(b"README"), Some(*b"README     "));
+        assert_eq!(pack_short_name(b"toolongname.txt"), None);
+        assert_eq!(pack_short_name(b"a.b.c"), None);
+        assert_eq!(pack_short_name(b".txt"), None);
+    }
+
+    #[test]
+    fn checksum_matches_known_value() {
+        /* Value computed by Linux for "README  TXT" */
+        assert_eq!(lfn_checksum(b"README  TXT"), 0x73);
+    }
+
+    #[test]
+    fn builds_long_name_across_entries() {
+        let short = b"LONGFI~1TXT";
+        let sum = lfn_checksum(short);
+        let mut b = LfnBuilder::new();
+        assert_eq!(b.push(&lfn_entry(0x42, sum, b"e.txt")), 0);
+        assert_eq!(b.push(&lfn_entry(0x01, sum, b"long file nam")), 0);
+
+        let name = b.finish(short).unwrap().unwrap();
+        let mut out = [0u8; 64];
+        let len = lfn_to_utf8(name, &mut out);
+        assert_eq!(&out[..len], b"long file name.txt");
+    }
+
+    #[test]
+    fn detects_orphans() {
+        let sum = lfn_checksum(b"LONGFI~1TXT");
+        let mut b = LfnBuilder::new();
+
+        /* Middle entry without its start */
+        assert_eq!(b.push(&lfn_entry(0x01, sum, b"abc")), 1);
+
+        /* Checksum of another short name */
+        b.push(&lfn_entry(0x41, sum, b"abc"));
+        assert_eq!(b.finish(b"OTHER   TXT"), Err(1));
+
+        /* Sequence restarted before completion */
+        b.push(&lfn_entry(0x42, sum, b"abc"));
+        assert_eq!(b.push(&lfn_entry(0x41, sum, b"abc")), 1);
+        assert!(b.finish(b"LONGFI~1TXT").unwrap().is_some());
+    }
+}
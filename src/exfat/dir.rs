@@ -0,0 +1,1028 @@
+/* exFAT directories: 32 byte entries grouped in sets. A file is a file
+ * entry, a stream extension giving its data and name entries of 15 UTF-16
+ * units each, all covered by the checksum of the file entry */
+
+use super::upcase::UpcaseTable;
+use super::write::{WriteError, make_dir, remove, rename, write_file};
+use super::{ExfatVolume, Stream};
+use crate::cli::commands::CommandId;
+use crate::cli::complete::Candidates;
+use crate::cli::editor::LINE_MAX;
+use crate::cli::{print, print_ls, reset_cli};
+use crate::cache::{ImageDevice, ImageFile};
+use crate::device::BlockDevice;
+use crate::dir_entry::lfn_to_utf8;
+use crate::fat::print_volume_error;
+use crate::find::{find, parse_find_args};
+use crate::grep::{Grep, parse_grep_args};
+use crate::helpers::{u8_le_to_u16, u8_to_u32_le, u16_to_u8_le, u32_to_u8_le};
+use crate::sys::print_bytes;
+use crate::volume::VolumeError;
+use crate::tree::{TreeEntry, TreeSource, parse_tree_args, print_tree};
+use crate::walk::{MAX_DEPTH, Path};
+use core::ops::ControlFlow;
+
+pub const ENTRY_END: u8 = 0x00;
+/* Bit 7 of the type: the entry is in use, cleared on delete */
+pub const ENTRY_IN_USE: u8 = 0x80;
+pub const ENTRY_BITMAP: u8 = 0x81;
+pub const ENTRY_UPCASE: u8 = 0x82;
+pub const ENTRY_LABEL: u8 = 0x83;
+pub const ENTRY_FILE: u8 = 0x85;
+pub const ENTRY_STREAM: u8 = 0xC0;
+pub const ENTRY_NAME: u8 = 0xC1;
+/* Type bits shared by in use secondary entries */
+const SECONDARY_MASK: u8 = 0xC0;
+
+pub const ATTR_DIRECTORY: u16 = 0x10;
+/* Stream extension flags */
+pub const STREAM_ALLOCATION_POSSIBLE: u8 = 0x01;
+pub const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+
+pub const LABEL_MAX_UNITS: usize = 11;
+pub const NAME_MAX_UNITS: usize = 255;
+pub const NAME_UNITS_PER_ENTRY: usize = 15;
+/* A stream extension and enough name entries for 255 units */
+pub const MAX_SECONDARY: usize = 1 + NAME_MAX_UNITS.div_ceil(NAME_UNITS_PER_ENTRY);
+pub const MAX_SET_ENTRIES: usize = 1 + MAX_SECONDARY;
+
+/* First cluster and data length, at the same place in the bitmap, up-case
+ * table and stream extension entries */
+pub fn entry_stream(entry: &[u8; 32], contiguous: bool) -> Stream {
+    Stream {
+        first_cluster: u8_to_u32_le(&entry[20..24]),
+        size: u8_to_u32_le(&entry[24..28]) as u64 | (u8_to_u32_le(&entry[28..32]) as u64) << 32,
+        contiguous,
+    }
+}
+
+/* Checksum of an entry set, its own field (bytes 2 and 3) left out */
+pub fn entry_set_checksum(set: &[[u8; 32]]) -> u16 {
+    let mut sum = 0u16;
+    for (i, &b) in set.iter().flatten().enumerate() {
+        if i == 2 || i == 3 {
+            continue;
+        }
+        sum = sum.rotate_right(1).wrapping_add(b as u16);
+    }
+    sum
+}
+
+/* Hash of an up-cased name, stored in the stream extension to skip most
+ * name comparisons */
+pub fn name_hash(upcased: &[u16]) -> u16 {
+    let mut hash = 0u16;
+    for &unit in upcased {
+        for b in unit.to_le_bytes() {
+            hash = hash.rotate_right(1).wrapping_add(b as u16);
+        }
+    }
+    hash
+}
+
+/* A decoded file entry set */
+#[derive(Clone, Copy)]
+pub struct ExfatEntry {
+    pub attributes: u16,
+    pub stream: Stream,
+    /* Bytes actually written, the rest up to the data length reads as zeroes */
+    pub valid_size: u64,
+    pub name_hash: u16,
+    /* Packed FAT date in the high half, time in the low one */
+    pub modified: u32,
+    pub name: [u16; NAME_MAX_UNITS],
+    pub name_len: usize,
+    /* Position of the file entry in its directory, in entries */
+    pub index: u64,
+}
+
+impl ExfatEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn name(&self) -> &[u16] {
+        &self.name[..self.name_len]
+    }
+}
+
+/* Decode a set, None when its checksum or layout is off */
+pub fn decode_entry_set(set: &[[u8; 32]]) -> Option<ExfatEntry> {
+    let file = &set[0];
+    let secondary_count = file[1] as usize;
+    if file[0] != ENTRY_FILE || secondary_count < 2 || set.len() != secondary_count + 1 {
+        return None;
+    }
+    if entry_set_checksum(set) != u8_le_to_u16(&file[2..4]) {
+        return None;
+    }
+
+    let stream = &set[1];
+    let name_len = stream[3] as usize;
+    if stream[0] != ENTRY_STREAM || name_len == 0 || name_len.div_ceil(NAME_UNITS_PER_ENTRY) > secondary_count - 1 {
+        return None;
+    }
+
+    let flags = stream[1];
+    let mut entry = ExfatEntry {
+        attributes: u8_le_to_u16(&file[4..6]),
+        /* No allocation possible: no cluster, whatever the fields say */
+        stream: if flags & STREAM_ALLOCATION_POSSIBLE == 0 {
+            Stream::EMPTY
+        } else {
+            entry_stream(stream, flags & STREAM_NO_FAT_CHAIN != 0)
+        },
+        valid_size: u8_to_u32_le(&stream[8..12]) as u64 | (u8_to_u32_le(&stream[12..16]) as u64) << 32,
+        name_hash: u8_le_to_u16(&stream[4..6]),
+        modified: u8_to_u32_le(&file[12..16]),
+        name: [0u16; NAME_MAX_UNITS],
+        name_len,
+        index: 0,
+    };
+    if entry.valid_size > entry.stream.size && flags & STREAM_ALLOCATION_POSSIBLE != 0 {
+        return None;
+    }
+
+    for (i, unit) in entry.name[..name_len].iter_mut().enumerate() {
+        let name_entry = &set[2 + i / NAME_UNITS_PER_ENTRY];
+        if name_entry[0] != ENTRY_NAME {
+            return None;
+        }
+        let off = 2 + (i % NAME_UNITS_PER_ENTRY) * 2;
+        *unit = u8_le_to_u16(&name_entry[off..off + 2]);
+    }
+    Some(entry)
+}
+
+/* Raw entries of a directory in order, read a sector at a time */
+pub struct DirReader {
+    stream: Stream,
+    /* Byte position of the next entry */
+    pos: u64,
+    cursor: (u32, u64),
+    buf: [u8; 512],
+    buf_start: u64,
+}
+
+impl DirReader {
+    pub fn new(stream: Stream) -> Self {
+        DirReader {
+            stream,
+            pos: 0,
+            cursor: (0, 0),
+            buf: [0u8; 512],
+            buf_start: u64::MAX,
+        }
+    }
+
+    /* Position of the next entry, in entries */
+    pub fn index(&self) -> u64 {
+        self.pos / 32
+    }
+
+    pub fn next<D: BlockDevice>(&mut self, vol: &mut ExfatVolume<D>) -> Result<Option<[u8; 32]>, VolumeError> {
+        if self.pos + 32 > self.stream.size {
+            return Ok(None);
+        }
+        let start = self.pos - self.pos % 512;
+        if start != self.buf_start {
+            let len = core::cmp::min(self.stream.size - start, 512) as usize;
+            self.buf_start = u64::MAX;
+            vol.read_stream(&self.stream, &mut self.cursor, start, &mut self.buf[..len])?;
+            self.buf_start = start;
+        }
+        let off = (self.pos - start) as usize;
+        let mut entry = [0u8; 32];
+        entry.copy_from_slice(&self.buf[off..off + 32]);
+        self.pos += 32;
+        Ok(Some(entry))
+    }
+}
+
+/* Hand every valid file entry set of directory `dir` to `cb` until it
+ * returns Some. Sets with a bad checksum or missing entries are skipped */
+pub fn iterate_dir<D, R, F>(vol: &mut ExfatVolume<D>, dir: &Stream, mut cb: F) -> Result<Option<R>, VolumeError>
+where
+    D: BlockDevice,
+    F: FnMut(&ExfatEntry) -> Option<R>,
+{
+    let mut reader = DirReader::new(*dir);
+    let mut set = [[0u8; 32]; MAX_SET_ENTRIES];
+    /* Entry read while collecting a set it doesn't belong to, and its index */
+    let mut pending: Option<([u8; 32], u64)> = None;
+
+    loop {
+        let (entry, index) = match pending.take() {
+            Some(pending) => pending,
+            None => match reader.next(vol)? {
+                Some(entry) => (entry, reader.index() - 1),
+                None => return Ok(None),
+            },
+        };
+        if entry[0] == ENTRY_END {
+            return Ok(None);
+        }
+        if entry[0] & ENTRY_IN_USE == 0 {
+            /* Deleted or unused */
+            continue;
+        }
+        let count = entry[1] as usize;
+        if entry[0] != ENTRY_FILE || !(2..=MAX_SECONDARY).contains(&count) {
+            continue;
+        }
+
+        set[0] = entry;
+        let mut len = 1;
+        while len <= count {
+            match reader.next(vol)? {
+                Some(next) if next[0] & SECONDARY_MASK == SECONDARY_MASK => {
+                    set[len] = next;
+                    len += 1;
+                }
+                Some(next) => {
+                    pending = Some((next, reader.index() - 1));
+                    break;
+                }
+                None => break,
+            }
+        }
+        if len <= count {
+            continue;
+        }
+
+        if let Some(mut file) = decode_entry_set(&set[..len]) {
+            file.index = index;
+            if let Some(res) = cb(&file) {
+                return Ok(Some(res));
+            }
+        }
+    }
+}
+
+/* Raw entries of the set whose file entry is entry `index` of `dir`, and
+ * how many there are */
+pub fn read_entry_set<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    dir: &Stream,
+    index: u64,
+) -> Result<([[u8; 32]; MAX_SET_ENTRIES], usize), VolumeError> {
+    let mut set = [[0u8; 32]; MAX_SET_ENTRIES];
+    let mut cursor = (0, 0);
+    vol.read_stream(dir, &mut cursor, index * 32, &mut set[0])?;
+    let count = 1 + set[0][1] as usize;
+    if set[0][0] != ENTRY_FILE || !(3..=MAX_SET_ENTRIES).contains(&count) {
+        return Err(VolumeError::Corrupt);
+    }
+    for (i, entry) in set[1..count].iter_mut().enumerate() {
+        vol.read_stream(dir, &mut cursor, (index + 1 + i as u64) * 32, entry)?;
+    }
+    Ok((set, count))
+}
+
+/* Fill the name entries, name length and hash of a set whose file and
+ * stream entries are `set[0]` and `set[1]`. Returns the entries it takes,
+ * the checksum is left to whoever writes it */
+pub fn fill_entry_set_name(set: &mut [[u8; 32]; MAX_SET_ENTRIES], name: &[u16], upcase: &UpcaseTable) -> usize {
+    let names = name.len().div_ceil(NAME_UNITS_PER_ENTRY);
+    set[0][1] = 1 + names as u8;
+    set[1][3] = name.len() as u8;
+
+    let mut upcased = [0u16; NAME_MAX_UNITS];
+    for (u, &unit) in upcased.iter_mut().zip(name) {
+        *u = upcase[unit as usize];
+    }
+    u16_to_u8_le(name_hash(&upcased[..name.len()]), &mut set[1][4..6]);
+
+    for (entry, chunk) in set[2..2 + names].iter_mut().zip(name.chunks(NAME_UNITS_PER_ENTRY)) {
+        *entry = [0u8; 32];
+        entry[0] = ENTRY_NAME;
+        for (i, &unit) in chunk.iter().enumerate() {
+            u16_to_u8_le(unit, &mut entry[2 + i * 2..4 + i * 2]);
+        }
+    }
+    2 + names
+}
+
+/* Point a stream extension at `stream`, NoFatChain when it is contiguous */
+pub fn set_entry_stream(ext: &mut [u8; 32], stream: &Stream, valid_size: u64) {
+    ext[1] = if stream.contiguous && stream.size > 0 {
+        STREAM_ALLOCATION_POSSIBLE | STREAM_NO_FAT_CHAIN
+    } else {
+        STREAM_ALLOCATION_POSSIBLE
+    };
+    ext[8..16].copy_from_slice(&valid_size.to_le_bytes());
+    u32_to_u8_le(if stream.size > 0 { stream.first_cluster } else { 0 }, &mut ext[20..24]);
+    ext[24..32].copy_from_slice(&stream.size.to_le_bytes());
+}
+
+/* UTF-8 name to UTF-16 units, None when it is too long */
+pub fn utf8_to_units(name: &[u8], out: &mut [u16; NAME_MAX_UNITS]) -> Option<usize> {
+    let name = core::str::from_utf8(name).ok()?;
+    let mut len = 0;
+    for unit in name.encode_utf16() {
+        *out.get_mut(len)? = unit;
+        len += 1;
+    }
+    Some(len)
+}
+
+/* Entry named `name` in `dir`, compared through the up-case table */
+pub fn find_entry<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    dir: &Stream,
+    name: &[u8],
+) -> Result<Option<ExfatEntry>, VolumeError> {
+    let mut wanted = [0u16; NAME_MAX_UNITS];
+    let len = match utf8_to_units(name, &mut wanted) {
+        Some(len) if len > 0 => len,
+        _ => return Ok(None),
+    };
+    for unit in wanted[..len].iter_mut() {
+        *unit = vol.upcase(*unit);
+    }
+    let hash = name_hash(&wanted[..len]);
+
+    /* Sets with the same hash are compared by name, the scan goes on past
+     * those that differ */
+    let mut from = 0;
+    loop {
+        let candidate = iterate_dir(vol, dir, |entry| {
+            let matches = entry.index >= from && entry.name_hash == hash && entry.name_len == len;
+            if matches { Some(*entry) } else { None }
+        })?;
+        let Some(entry) = candidate else {
+            return Ok(None);
+        };
+        if entry.name().iter().zip(&wanted[..len]).all(|(&u, &w)| vol.upcase(u) == w) {
+            return Ok(Some(entry));
+        }
+        from = entry.index + 1;
+    }
+}
+
+/* Directories from the root down to the current one: exFAT has no `..`
+ * entries, going up pops the stack */
+#[derive(Clone, Copy)]
+pub struct ExfatPath {
+    dirs: [Stream; MAX_DEPTH],
+    /* Index of each directory's entry set in its parent */
+    sets: [u64; MAX_DEPTH],
+    depth: usize,
+}
+
+impl Default for ExfatPath {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExfatPath {
+    pub fn new() -> Self {
+        ExfatPath {
+            dirs: [Stream::EMPTY; MAX_DEPTH],
+            sets: [0; MAX_DEPTH],
+            depth: 0,
+        }
+    }
+
+    pub fn current<D: BlockDevice>(&self, vol: &ExfatVolume<D>) -> Stream {
+        match self.depth {
+            0 => vol.root,
+            depth => self.dirs[depth - 1],
+        }
+    }
+
+    /* The parent directory and the index of this one's set in it, None at
+     * the root */
+    pub fn parent(&self) -> Option<(ExfatPath, u64)> {
+        let depth = self.depth.checked_sub(1)?;
+        let mut parent = *self;
+        parent.depth = depth;
+        Some((parent, self.sets[depth]))
+    }
+
+    /* Replace the stream of the current directory once it has grown */
+    pub fn set_current(&mut self, stream: Stream) {
+        if let Some(dir) = self.depth.checked_sub(1) {
+            self.dirs[dir] = stream;
+        }
+    }
+
+    /* Read every level again from its parent, the streams kept may be stale
+     * after a write. Stops at the first level that is gone */
+    pub fn refresh<D: BlockDevice>(&mut self, vol: &mut ExfatVolume<D>) -> Result<(), VolumeError> {
+        for level in 0..self.depth {
+            let parent = if level == 0 { vol.root } else { self.dirs[level - 1] };
+            /* A deleted set no longer starts with a file entry */
+            let entry = match read_entry_set(vol, &parent, self.sets[level]) {
+                Ok((set, count)) => decode_entry_set(&set[..count]),
+                Err(VolumeError::Corrupt) => None,
+                Err(e) => return Err(e),
+            };
+            match entry {
+                Some(entry) if entry.is_dir() => self.dirs[level] = entry.stream,
+                _ => {
+                    self.depth = level;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* Names of the levels from the root, as stored */
+    pub fn path<D: BlockDevice>(&self, vol: &mut ExfatVolume<D>, out: &mut Path) -> Result<(), VolumeError> {
+        out.len = 0;
+        let mut name = [0u8; 1024];
+        for level in 0..self.depth {
+            let parent = if level == 0 { vol.root } else { self.dirs[level - 1] };
+            let (set, count) = read_entry_set(vol, &parent, self.sets[level])?;
+            let entry = decode_entry_set(&set[..count]).ok_or(VolumeError::Corrupt)?;
+            let len = lfn_to_utf8(entry.name(), &mut name);
+            out.push(&name[..len]);
+        }
+        Ok(())
+    }
+}
+
+/* Follow `path` from `cwd` (or the root when absolute), Ok(false) and
+ * `cwd` untouched when a component isn't a directory */
+pub fn change_dir<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &mut ExfatPath,
+    path: &[u8],
+) -> Result<bool, VolumeError> {
+    let mut target = if path.first() == Some(&b'/') { ExfatPath::new() } else { *cwd };
+    for component in path.split(|&c| c == b'/') {
+        match component {
+            b"" | b"." => {}
+            b".." => target.depth = target.depth.saturating_sub(1),
+            name => {
+                let dir = target.current(vol);
+                match find_entry(vol, &dir, name)? {
+                    Some(entry) if entry.is_dir() && target.depth < MAX_DEPTH => {
+                        target.dirs[target.depth] = entry.stream;
+                        target.sets[target.depth] = entry.index;
+                        target.depth += 1;
+                    }
+                    _ => return Ok(false),
+                }
+            }
+        }
+    }
+    *cwd = target;
+    Ok(true)
+}
+
+/* Directory part and last component of `path` */
+pub fn split_path(path: &[u8]) -> (&[u8], &[u8]) {
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(0) => (&path[..1], &path[1..]),
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (&path[..0], path),
+    }
+}
+
+/* File at `path` from `cwd`, Ok(None) when missing or a directory */
+pub fn open_file<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    path: &[u8],
+) -> Result<Option<ExfatEntry>, VolumeError> {
+    let (parent, name) = split_path(path);
+    let mut dir = *cwd;
+    if !change_dir(vol, &mut dir, parent)? {
+        return Ok(None);
+    }
+    let stream = dir.current(vol);
+    Ok(find_entry(vol, &stream, name)?.filter(|entry| !entry.is_dir()))
+}
+
+/* Hand the content of `entry` to `out` in chunks */
+pub fn read_file<D: BlockDevice, W: FnMut(&[u8])>(
+    vol: &mut ExfatVolume<D>,
+    entry: &ExfatEntry,
+    out: W,
+) -> Result<(), VolumeError> {
+    read_stream_data(vol, &entry.stream, entry.valid_size, out)
+}
+
+/* The bytes of `stream` in chunks, zeroes past `valid_size` */
+fn read_stream_data<D: BlockDevice, W: FnMut(&[u8])>(
+    vol: &mut ExfatVolume<D>,
+    stream: &Stream,
+    valid_size: u64,
+    mut out: W,
+) -> Result<(), VolumeError> {
+    if stream.size > 0 && !vol.is_cluster_allocated(stream.first_cluster)? {
+        /* Data in clusters the bitmap calls free: a stale entry */
+        return Err(VolumeError::Corrupt);
+    }
+    let mut chunk = [0u8; 4096];
+    let mut cursor = (0, 0);
+    let mut pos = 0u64;
+    while pos < stream.size {
+        let len = core::cmp::min(stream.size - pos, chunk.len() as u64) as usize;
+        if pos + len as u64 <= valid_size {
+            vol.read_stream(stream, &mut cursor, pos, &mut chunk[..len])?;
+        } else {
+            /* Past the valid data length: zeroes, whatever the clusters hold */
+            chunk[..len].fill(0);
+            if pos < valid_size {
+                let valid = (valid_size - pos) as usize;
+                vol.read_stream(stream, &mut cursor, pos, &mut chunk[..valid])?;
+            }
+        }
+        out(&chunk[..len]);
+        pos += len as u64;
+    }
+    Ok(())
+}
+
+/* Offer the entries of directory `dir` from `cwd` to Tab, like
+ * `fat::complete_names` */
+pub fn complete_names<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    dir: &[u8],
+    out: &mut Candidates,
+) -> Result<(), VolumeError> {
+    let mut target = *cwd;
+    target.refresh(vol)?;
+    if !change_dir(vol, &mut target, dir)? {
+        return Ok(());
+    }
+    let stream = target.current(vol);
+    let mut name = [0u8; 1024];
+    iterate_dir::<_, (), _>(vol, &stream, |entry| {
+        let len = lfn_to_utf8(entry.name(), &mut name);
+        out.push(&name[..len], entry.is_dir());
+        None
+    })?;
+    Ok(())
+}
+
+/* An exFAT directory tree for `tree`, directories found by their stream */
+pub struct ExfatTree<'a, 'b, D: BlockDevice> {
+    pub vol: &'a mut ExfatVolume<'b, D>,
+}
+
+impl<D: BlockDevice> TreeSource for ExfatTree<'_, '_, D> {
+    type Dir = Stream;
+
+    fn scan(
+        &mut self,
+        dir: Stream,
+        cb: &mut dyn FnMut(&TreeEntry<Stream>) -> ControlFlow<()>,
+    ) -> Result<(), VolumeError> {
+        let mut name = [0u8; 1024];
+        iterate_dir(self.vol, &dir, |entry| {
+            let len = lfn_to_utf8(entry.name(), &mut name);
+            let tree_entry = TreeEntry {
+                name: &name[..len],
+                short: &[],
+                /* A file reads up to its valid size, then zeroes up to `size` */
+                node: if entry.is_dir() {
+                    entry.stream
+                } else {
+                    Stream {
+                        size: entry.valid_size,
+                        ..entry.stream
+                    }
+                },
+                is_dir: entry.is_dir(),
+                attributes: entry.attributes as u8,
+                modified: entry.modified,
+                first_cluster: entry.stream.first_cluster,
+                size: entry.stream.size,
+            };
+            cb(&tree_entry).is_break().then_some(())
+        })?;
+        Ok(())
+    }
+
+    fn read(&mut self, entry: &TreeEntry<Stream>, out: &mut dyn FnMut(&[u8])) -> Result<(), VolumeError> {
+        let stream = Stream {
+            size: entry.size,
+            ..entry.node
+        };
+        read_stream_data(self.vol, &stream, entry.node.size, out)
+    }
+}
+
+/* Print `path` then the entries of `dir`, like `fat::list_dir` */
+pub fn list_dir<D: BlockDevice>(vol: &mut ExfatVolume<D>, dir: &Stream, path: &[u8]) {
+    print_bytes(path);
+    print("\n");
+
+    /* Printed one behind so the last one gets its corner */
+    let mut previous: Option<([u8; 1024], usize, bool)> = None;
+    let listed = iterate_dir::<_, (), _>(vol, dir, |entry| {
+        if let Some((name, len, is_dir)) = &previous {
+            print_ls(&name[..*len], *is_dir, false, &[]);
+        }
+        let mut name = [0u8; 1024];
+        let len = lfn_to_utf8(entry.name(), &mut name);
+        previous = Some((name, len, entry.is_dir()));
+        None
+    });
+    if let Some((name, len, is_dir)) = &previous {
+        print_ls(&name[..*len], *is_dir, true, &[]);
+    }
+    if let Err(e) = listed {
+        print_volume_error(e);
+    }
+}
+
+fn print_write_error(e: WriteError) {
+    match e {
+        WriteError::Volume(e) => print_volume_error(e),
+        WriteError::InvalidName => print("Not a valid exFAT name"),
+        WriteError::NameTaken => print("An entry by that name already exists"),
+        WriteError::NotFound => print("File not found"),
+        WriteError::NotEmpty => print("Folder not empty"),
+        WriteError::IsDirectory => print("That is a folder"),
+        WriteError::NoSpace => print("No space left on the volume"),
+    }
+}
+
+/* `mkdir`, `write`, `rm` and `mv` on the image reopened for writing, the
+ * arguments counted by the command table */
+fn exfat_write_command(
+    dev: &mut ImageDevice,
+    image: &ImageFile,
+    upcase: &mut UpcaseTable,
+    cwd: &ExfatPath,
+    id: CommandId,
+    args: &[&[u8]],
+) {
+    let target = args[0];
+    /* The words of `write` come back joined by single spaces */
+    let mut text = [0u8; LINE_MAX];
+    let mut text_len = 0;
+    for (i, word) in args[1..].iter().enumerate() {
+        if i > 0 {
+            text[text_len] = b' ';
+            text_len += 1;
+        }
+        text[text_len..text_len + word.len()].copy_from_slice(word);
+        text_len += word.len();
+    }
+    let rest = &text[..text_len];
+
+    let written = dev.with_writable(image, |dev| match ExfatVolume::mount(dev, upcase) {
+        Ok(mut vol) => {
+            let changed = match id {
+                CommandId::Mkdir => make_dir(&mut vol, cwd, target),
+                CommandId::Write => write_file(&mut vol, cwd, target, rest),
+                CommandId::Rm => remove(&mut vol, cwd, target),
+                _ => rename(&mut vol, cwd, target, rest),
+            };
+            /* Whatever happened, the blocks written so far go to disk */
+            let flushed = vol.flush().map_err(WriteError::from);
+            changed.and(flushed)
+        }
+        Err(e) => Err(e.into()),
+    });
+    if let Some(Err(e)) = written {
+        print_write_error(e);
+    }
+}
+
+/* Directory at `path` from `cwd` and its path from the root in `shown`,
+ * Ok(None) when there is no such directory */
+fn resolve_dir<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    path: &[u8],
+    shown: &mut Path,
+) -> Result<Option<ExfatPath>, VolumeError> {
+    let mut target = *cwd;
+    if !change_dir(vol, &mut target, path)? {
+        return Ok(None);
+    }
+    target.path(vol, shown)?;
+    Ok(Some(target))
+}
+
+/* `ls`, `cd`, `tree`, `find`, `grep`, `cat` and the write commands on a mounted exFAT image, the
+ * FAT only commands are refused. `path` follows `cwd`, the volume is
+ * mounted with `upcase` as its table */
+pub fn exfat_command(
+    dev: &mut ImageDevice,
+    image: &ImageFile,
+    upcase: &mut UpcaseTable,
+    cwd: &mut ExfatPath,
+    path: &mut Path,
+    id: CommandId,
+    args: &[&[u8]],
+) {
+    let writes = matches!(id, CommandId::Mkdir | CommandId::Write | CommandId::Rm | CommandId::Mv);
+    if writes {
+        exfat_write_command(dev, image, upcase, cwd, id, args);
+    }
+
+    let mut vol = match ExfatVolume::mount(&mut *dev, upcase) {
+        Ok(vol) => vol,
+        Err(e) => {
+            print_volume_error(e);
+            return;
+        }
+    };
+    /* A write may have moved or removed a directory on the way */
+    if let Err(e) = cwd.refresh(&mut vol).and_then(|()| cwd.path(&mut vol, path)) {
+        print_volume_error(e);
+        return;
+    }
+    if writes {
+        return;
+    }
+
+    let arg = args.first().copied().unwrap_or_default();
+    match id {
+        CommandId::Ls if args.is_empty() => {
+            reset_cli();
+            let dir = cwd.current(&vol);
+            list_dir(&mut vol, &dir, path.as_bytes());
+        }
+        CommandId::Ls if arg == b"--deleted" => print("Only FAT12, FAT16 and FAT32 volumes are supported by this command"),
+        CommandId::Ls | CommandId::Cd => {
+            let mut shown = Path::new();
+            match resolve_dir(&mut vol, cwd, arg, &mut shown) {
+                Ok(Some(target)) => {
+                    let dir = target.current(&vol);
+                    list_dir(&mut vol, &dir, shown.as_bytes());
+                    if id == CommandId::Cd {
+                        *cwd = target;
+                        *path = shown;
+                    }
+                }
+                Ok(None) => print("Folder not found"),
+                Err(e) => print_volume_error(e),
+            }
+        }
+        CommandId::Tree => {
+            /* Checked by the shell already */
+            let Some((opts, arg)) = parse_tree_args(args) else {
+                return;
+            };
+            let mut shown = Path::new();
+            match resolve_dir(&mut vol, cwd, arg, &mut shown) {
+                Ok(Some(target)) => {
+                    let dir = target.current(&vol);
+                    if let Err(e) = print_tree(&mut ExfatTree { vol: &mut vol }, dir, shown.as_bytes(), &opts) {
+                        print_volume_error(e);
+                    }
+                }
+                Ok(None) => print("Folder not found"),
+                Err(e) => print_volume_error(e),
+            }
+        }
+        CommandId::Find => {
+            let Some((opts, arg)) = parse_find_args(args) else {
+                return;
+            };
+            let mut shown = Path::new();
+            match resolve_dir(&mut vol, cwd, arg, &mut shown) {
+                Ok(Some(target)) => {
+                    let dir = target.current(&vol);
+                    if let Err(e) = find(&mut ExfatTree { vol: &mut vol }, dir, shown.as_bytes(), &opts) {
+                        print_volume_error(e);
+                    }
+                }
+                Ok(None) => print("Folder not found"),
+                Err(e) => print_volume_error(e),
+            }
+        }
+        CommandId::Grep => {
+            let Some((opts, pattern, arg)) = parse_grep_args(args) else {
+                return;
+            };
+            let Some(mut grep) = Grep::new(pattern, opts) else {
+                print("Invalid pattern");
+                return;
+            };
+            let mut shown = Path::new();
+            let searched = match resolve_dir(&mut vol, cwd, arg, &mut shown) {
+                Ok(Some(target)) if opts.recursive => {
+                    let dir = target.current(&vol);
+                    grep.tree(&mut ExfatTree { vol: &mut vol }, dir, shown.as_bytes())
+                }
+                Ok(Some(_)) => {
+                    print("That is a directory, search it with -r");
+                    return;
+                }
+                Ok(None) => match open_file(&mut vol, cwd, arg) {
+                    Ok(Some(entry)) => grep.file(b"", arg, |out| read_file(&mut vol, &entry, out)),
+                    Ok(None) => {
+                        print("File not found");
+                        return;
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match searched {
+                Ok(()) => grep.print_summary(),
+                Err(e) => print_volume_error(e),
+            }
+        }
+        CommandId::Cat => match open_file(&mut vol, cwd, arg) {
+            Ok(Some(entry)) => {
+                if let Err(e) = read_file(&mut vol, &entry, print_bytes) {
+                    print_volume_error(e);
+                }
+            }
+            Ok(None) => print("File not found"),
+            Err(e) => print_volume_error(e),
+        },
+        _ => print("Only FAT12, FAT16 and FAT32 volumes are supported by this command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::exfat::fixture::ExfatBuilder;
+    use crate::exfat::upcase::UPCASE_ENTRIES;
+    use crate::fixture::Node;
+    use std::vec;
+    use std::vec::Vec;
+
+    fn fixture(builder: ExfatBuilder) -> (Vec<u8>, Vec<u8>) {
+        let big: Vec<u8> = (0..20000u32).map(|i| (i % 253) as u8).collect();
+        let tree = [
+            Node::Dir(
+                "Photos",
+                &[
+                    Node::File("été.jpg", b"summer"),
+                    Node::Dir("Deeper", &[Node::File("leaf.txt", b"leaf")]),
+                ],
+            ),
+            Node::File("A rather long file name to need two name entries.bin", &big),
+            Node::Deleted("gone.txt", b"deleted"),
+            Node::File("empty.txt", b""),
+        ];
+        (builder.build(&tree), big)
+    }
+
+    fn names(vol: &mut ExfatVolume<MemDevice>, dir: &Stream) -> Vec<std::string::String> {
+        let mut names = Vec::new();
+        iterate_dir(vol, dir, |entry| {
+            names.push(std::string::String::from_utf16(entry.name()).unwrap());
+            None::<()>
+        })
+        .unwrap();
+        names
+    }
+
+    fn cat(vol: &mut ExfatVolume<MemDevice>, path: &[u8]) -> Option<Vec<u8>> {
+        let entry = open_file(vol, &ExfatPath::new(), path).unwrap()?;
+        let mut out = Vec::new();
+        read_file(vol, &entry, |b| out.extend_from_slice(b)).unwrap();
+        Some(out)
+    }
+
+    #[test]
+    fn checksums_match_the_spec_examples() {
+        /* "A": 0x41 then 0x41 rotated right plus the zero high byte */
+        assert_eq!(name_hash(&[0x41]), 0x8020);
+        assert_ne!(name_hash(&[0x41, 0x42]), name_hash(&[0x42, 0x41]));
+        let mut set = [[0u8; 32]; 2];
+        set[0][0] = ENTRY_FILE;
+        set[0][2] = 0xAB;
+        let sum = entry_set_checksum(&set);
+        set[0][3] = 0xCD;
+        assert_eq!(entry_set_checksum(&set), sum);
+        set[1][0] = 1;
+        assert_ne!(entry_set_checksum(&set), sum);
+    }
+
+    #[test]
+    fn iterate_decodes_sets_and_skips_deleted_ones() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        for builder in [ExfatBuilder::new(), ExfatBuilder::new().fat_chains().fragmented()] {
+            let (mut data, _) = fixture(builder);
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            let root = vol.root;
+            assert_eq!(
+                names(&mut vol, &root),
+                vec!["Photos", "A rather long file name to need two name entries.bin", "empty.txt"]
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_is_case_insensitive_through_the_upcase_table() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let (mut data, _) = fixture(ExfatBuilder::new());
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let mut cwd = ExfatPath::new();
+        assert!(change_dir(&mut vol, &mut cwd, b"PHOTOS/deeper").unwrap());
+        /* Shown as stored, whatever the case typed */
+        let mut path = Path::new();
+        cwd.path(&mut vol, &mut path).unwrap();
+        assert_eq!(path.as_bytes(), b"/Photos/Deeper");
+        let dir = cwd.current(&vol);
+        assert_eq!(names(&mut vol, &dir), vec!["leaf.txt"]);
+
+        assert!(change_dir(&mut vol, &mut cwd, b"../../photos/./").unwrap());
+        let dir = cwd.current(&vol);
+        assert_eq!(names(&mut vol, &dir), vec!["été.jpg", "Deeper"]);
+        assert!(change_dir(&mut vol, &mut cwd, b"..").unwrap());
+        cwd.path(&mut vol, &mut path).unwrap();
+        assert_eq!(path.as_bytes(), b"/");
+
+        /* Up-casing é gives É */
+        assert_eq!(cat(&mut vol, "/photos/ÉTÉ.JPG".as_bytes()), Some(b"summer".to_vec()));
+        assert!(!change_dir(&mut vol, &mut cwd, b"empty.txt").unwrap());
+        assert!(!change_dir(&mut vol, &mut cwd, b"missing").unwrap());
+        assert_eq!(cat(&mut vol, b"photos"), None);
+        assert_eq!(cat(&mut vol, b"gone.txt"), None);
+    }
+
+    #[test]
+    fn read_file_over_contiguous_and_chained_data() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        for builder in [
+            ExfatBuilder::new(),
+            ExfatBuilder::new().fat_chains(),
+            ExfatBuilder::new().fat_chains().fragmented(),
+        ] {
+            let (mut data, big) = fixture(builder);
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            assert_eq!(cat(&mut vol, b"a rather long file name to need two name entries.bin"), Some(big));
+            assert_eq!(cat(&mut vol, b"Photos/Deeper/leaf.txt"), Some(b"leaf".to_vec()));
+            assert_eq!(cat(&mut vol, b"empty.txt"), Some(Vec::new()));
+        }
+    }
+
+    #[test]
+    fn bytes_past_the_valid_size_read_as_zeroes() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let (mut data, big) = fixture(ExfatBuilder::new());
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let root = vol.root;
+        let mut entry = find_entry(&mut vol, &root, b"A rather long file name to need two name entries.bin")
+            .unwrap()
+            .unwrap();
+        entry.valid_size = 5000;
+        let mut out = Vec::new();
+        read_file(&mut vol, &entry, |b| out.extend_from_slice(b)).unwrap();
+        assert_eq!(&out[..5000], &big[..5000]);
+        assert!(out[5000..].iter().all(|&b| b == 0));
+        assert_eq!(out.len(), big.len());
+    }
+
+    #[test]
+    fn damaged_sets_are_skipped() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let (mut data, _) = fixture(ExfatBuilder::new());
+        let name: Vec<u8> = "empty.txt".encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+        let offset = data.windows(name.len()).position(|w| w == name.as_slice()).unwrap();
+        /* A name unit changed without fixing the checksum */
+        data[offset] = b'E';
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let root = vol.root;
+        assert_eq!(names(&mut vol, &root).len(), 2);
+        assert!(find_entry(&mut vol, &root, b"empty.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn data_the_bitmap_calls_free_is_corrupt() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let (mut data, _) = fixture(ExfatBuilder::new());
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let entry = open_file(&mut vol, &ExfatPath::new(), b"/photos/deeper/leaf.txt").unwrap().unwrap();
+        let bit = (entry.stream.first_cluster - 2) as u64;
+        let at = vol.cluster_offset(vol.bitmap.first_cluster) + bit / 8;
+        let mut byte = [0u8; 1];
+        vol.dev.read_at(at, &mut byte).unwrap();
+        vol.dev.write_at(at, &[byte[0] & !(1 << (bit % 8))]).unwrap();
+        assert_eq!(read_file(&mut vol, &entry, |_| {}), Err(VolumeError::Corrupt));
+    }
+
+    #[test]
+    fn chains_shorter_than_the_size_are_corrupt() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let (mut data, _) = fixture(ExfatBuilder::new().fat_chains());
+        let (entry, fat) = {
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            let root = vol.root;
+            let entry = find_entry(&mut vol, &root, b"A rather long file name to need two name entries.bin")
+                .unwrap()
+                .unwrap();
+            (entry, vol.fat_start as usize)
+        };
+        let first = entry.stream.first_cluster;
+        data[fat + first as usize * 4..fat + first as usize * 4 + 4].copy_from_slice(&[0xFF; 4]);
+
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let mut sink = vec![];
+        assert_eq!(
+            read_file(&mut vol, &entry, |b| sink.extend_from_slice(b)),
+            Err(VolumeError::Corrupt)
+        );
+    }
+}
@@ -0,0 +1,325 @@
+/* In-memory exFAT images built from the same tree description as the FAT
+ * ones, see `crate::fixture` */
+
+use super::dir::{
+    ATTR_DIRECTORY, ENTRY_BITMAP, ENTRY_FILE, ENTRY_IN_USE, ENTRY_LABEL, ENTRY_NAME, ENTRY_STREAM,
+    ENTRY_UPCASE, NAME_UNITS_PER_ENTRY, STREAM_ALLOCATION_POSSIBLE, STREAM_NO_FAT_CHAIN,
+    entry_set_checksum, name_hash,
+};
+use super::upcase::{compressed_upcase, upcase_unit};
+use super::{BOOT_REGION_SECTORS, EXFAT_EOC_MARK, Stream, boot_checksum};
+use crate::device::MemDevice;
+use crate::fixture::{FIXTURE_TIME, Node};
+use crate::helpers::{fat_datetime, u16_to_u8_le, u32_to_u8_le};
+use std::vec;
+use std::vec::Vec;
+
+const BPS: usize = 512;
+const FAT_OFFSET: usize = 32;
+
+pub struct ExfatBuilder {
+    size: usize,
+    sectors_per_cluster_shift: u8,
+    /* Files and directories linked in the FAT instead of NoFatChain */
+    fat_chains: bool,
+    /* Leave a free cluster after every allocated one */
+    fragmented: bool,
+}
+
+impl Default for ExfatBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExfatBuilder {
+    /* 8 MiB with 4 KiB clusters */
+    pub fn new() -> Self {
+        ExfatBuilder {
+            size: 8 * 1024 * 1024,
+            sectors_per_cluster_shift: 3,
+            fat_chains: false,
+            fragmented: false,
+        }
+    }
+
+    pub fn fat_chains(mut self) -> Self {
+        self.fat_chains = true;
+        self
+    }
+
+    /* Implies FAT chains, the clusters of a file aren't contiguous */
+    pub fn fragmented(mut self) -> Self {
+        self.fat_chains = true;
+        self.fragmented = true;
+        self
+    }
+
+    pub fn build(&self, tree: &[Node]) -> Vec<u8> {
+        let spc = 1usize << self.sectors_per_cluster_shift;
+        let total = self.size / BPS;
+        let fat_length = (total / spc + 2) * 4;
+        let fat_length = fat_length.div_ceil(BPS);
+        let heap_offset = (FAT_OFFSET + fat_length).next_multiple_of(spc);
+        let cluster_count = (total - heap_offset) / spc;
+
+        let mut b = Builder {
+            data: vec![0u8; self.size],
+            cluster_size: spc * BPS,
+            fat_start: FAT_OFFSET * BPS,
+            heap_start: heap_offset * BPS,
+            used: vec![false; cluster_count],
+            next: 2,
+            step: if self.fragmented { 2 } else { 1 },
+            fat_chains: self.fat_chains,
+        };
+        b.set_fat(0, 0xFFFFFFF8);
+        b.set_fat(1, EXFAT_EOC_MARK);
+
+        /* Bitmap and up-case table are always FAT chained */
+        let bitmap_size = cluster_count.div_ceil(8);
+        let bitmap = b.alloc(bitmap_size, true, 1);
+        let mut upcase = Vec::new();
+        compressed_upcase(|unit| upcase.extend_from_slice(&unit.to_le_bytes()));
+        let table = b.alloc(upcase.len(), true, 1);
+        b.write(&table, &upcase);
+        let checksum = upcase
+            .iter()
+            .fold(0u32, |sum, &byte| sum.rotate_right(1).wrapping_add(byte as u32));
+
+        let mut head = Vec::new();
+        let mut label = [0u8; 32];
+        label[0] = ENTRY_LABEL;
+        label[1] = 4;
+        for (i, c) in b"CARD".iter().enumerate() {
+            label[2 + i * 2] = *c;
+        }
+        head.push(label);
+        head.push(stream_entry(ENTRY_BITMAP, &bitmap));
+        let mut up = stream_entry(ENTRY_UPCASE, &table);
+        u32_to_u8_le(checksum, &mut up[4..8]);
+        head.push(up);
+
+        let root = b.dir(&head, tree, true);
+
+        /* Bitmap last, once every cluster is handed out */
+        let mut bits = vec![0u8; bitmap_size];
+        for (i, _) in b.used.iter().enumerate().filter(|(_, used)| **used) {
+            bits[i / 8] |= 1 << (i % 8);
+        }
+        b.write(&bitmap, &bits);
+
+        let mut data = b.data;
+        let bs = &mut data[..BPS];
+        bs[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        bs[3..11].copy_from_slice(b"EXFAT   ");
+        bs[72..80].copy_from_slice(&(total as u64).to_le_bytes());
+        u32_to_u8_le(FAT_OFFSET as u32, &mut bs[80..84]);
+        u32_to_u8_le(fat_length as u32, &mut bs[84..88]);
+        u32_to_u8_le(heap_offset as u32, &mut bs[88..92]);
+        u32_to_u8_le(cluster_count as u32, &mut bs[92..96]);
+        u32_to_u8_le(root.first_cluster, &mut bs[96..100]);
+        u32_to_u8_le(0x1234_5678, &mut bs[100..104]);
+        u16_to_u8_le(0x0100, &mut bs[104..106]);
+        bs[108] = 9;
+        bs[109] = self.sectors_per_cluster_shift;
+        bs[110] = 1;
+        bs[111] = 0x80;
+        bs[112] = 0xFF;
+        bs[510] = 0x55;
+        bs[511] = 0xAA;
+        /* Extended boot sectors only carry a signature */
+        for sector in 1..9 {
+            data[sector * BPS + BPS - 2] = 0x55;
+            data[sector * BPS + BPS - 1] = 0xAA;
+        }
+        let sum = boot_checksum(&mut MemDevice { data: &mut data }, 0, BPS).unwrap();
+        for i in 0..BPS / 4 {
+            u32_to_u8_le(sum, &mut data[11 * BPS + i * 4..11 * BPS + i * 4 + 4]);
+        }
+        let region = BOOT_REGION_SECTORS as usize * BPS;
+        data.copy_within(0..region, region);
+        data
+    }
+}
+
+/* Bitmap or up-case table entry pointing at `stream` */
+fn stream_entry(kind: u8, stream: &Stream) -> [u8; 32] {
+    let mut e = [0u8; 32];
+    e[0] = kind;
+    u32_to_u8_le(stream.first_cluster, &mut e[20..24]);
+    e[24..32].copy_from_slice(&stream.size.to_le_bytes());
+    e
+}
+
+/* File entry set of `name`, checksum and name hash filled in */
+pub fn entry_set(name: &str, attributes: u16, stream: &Stream, flags: u8) -> Vec<[u8; 32]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let upcased: Vec<u16> = units.iter().map(|&u| upcase_unit(u)).collect();
+    let names = units.len().div_ceil(NAME_UNITS_PER_ENTRY);
+    let (date, time) = fat_datetime(FIXTURE_TIME);
+    let stamp = (date as u32) << 16 | time as u32;
+
+    let mut file = [0u8; 32];
+    file[0] = ENTRY_FILE;
+    file[1] = 1 + names as u8;
+    u16_to_u8_le(attributes, &mut file[4..6]);
+    for field in [8, 12, 16] {
+        u32_to_u8_le(stamp, &mut file[field..field + 4]);
+    }
+
+    let mut ext = [0u8; 32];
+    ext[0] = ENTRY_STREAM;
+    ext[1] = flags;
+    ext[3] = units.len() as u8;
+    u16_to_u8_le(name_hash(&upcased), &mut ext[4..6]);
+    ext[8..16].copy_from_slice(&stream.size.to_le_bytes());
+    u32_to_u8_le(stream.first_cluster, &mut ext[20..24]);
+    ext[24..32].copy_from_slice(&stream.size.to_le_bytes());
+
+    let mut set = vec![file, ext];
+    for chunk in units.chunks(NAME_UNITS_PER_ENTRY) {
+        let mut e = [0u8; 32];
+        e[0] = ENTRY_NAME;
+        for (i, &unit) in chunk.iter().enumerate() {
+            u16_to_u8_le(unit, &mut e[2 + i * 2..4 + i * 2]);
+        }
+        set.push(e);
+    }
+    let checksum = entry_set_checksum(&set);
+    u16_to_u8_le(checksum, &mut set[0][2..4]);
+    set
+}
+
+struct Builder {
+    data: Vec<u8>,
+    cluster_size: usize,
+    fat_start: usize,
+    heap_start: usize,
+    /* Clusters to mark in the bitmap, from cluster 2 */
+    used: Vec<bool>,
+    next: u32,
+    step: u32,
+    fat_chains: bool,
+}
+
+impl Builder {
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        let at = self.fat_start + cluster as usize * 4;
+        u32_to_u8_le(value, &mut self.data[at..at + 4]);
+    }
+
+    /* Clusters for `size` bytes (at least `min`), linked unless contiguous
+     * and NoFatChain */
+    fn alloc(&mut self, size: usize, chained: bool, min: usize) -> Stream {
+        let count = core::cmp::max(size.div_ceil(self.cluster_size), min) as u32;
+        if count == 0 {
+            return Stream::EMPTY;
+        }
+        let step = if chained { self.step } else { 1 };
+        let clusters: Vec<u32> = (0..count).map(|i| self.next + i * step).collect();
+        self.next += count * step;
+        assert!(
+            (self.next as usize - 2) <= self.used.len(),
+            "fixture tree doesn't fit in the volume"
+        );
+        for (i, &cluster) in clusters.iter().enumerate() {
+            self.used[cluster as usize - 2] = true;
+            if chained {
+                let next = clusters.get(i + 1).copied().unwrap_or(EXFAT_EOC_MARK);
+                self.set_fat(cluster, next);
+            }
+        }
+        Stream {
+            first_cluster: clusters[0],
+            size: size as u64,
+            contiguous: !chained,
+        }
+    }
+
+    /* Follow `stream` over the FAT or contiguously and copy `bytes` in */
+    fn write(&mut self, stream: &Stream, bytes: &[u8]) {
+        let mut cluster = stream.first_cluster;
+        for chunk in bytes.chunks(self.cluster_size) {
+            let at = self.heap_start + (cluster as usize - 2) * self.cluster_size;
+            self.data[at..at + chunk.len()].copy_from_slice(chunk);
+            cluster = if stream.contiguous {
+                cluster + 1
+            } else {
+                let at = self.fat_start + cluster as usize * 4;
+                u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap())
+            };
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        if self.fat_chains {
+            STREAM_ALLOCATION_POSSIBLE
+        } else {
+            STREAM_ALLOCATION_POSSIBLE | STREAM_NO_FAT_CHAIN
+        }
+    }
+
+    /* Directory holding `head` then the sets of `tree`, clusters for the
+     * directory first then for its children in order */
+    fn dir(&mut self, head: &[[u8; 32]], tree: &[Node], root: bool) -> Stream {
+        let entries: usize = head.len() + tree.iter().map(entries_for).sum::<usize>();
+        let size = (entries * 32).div_ceil(self.cluster_size).max(1) * self.cluster_size;
+        /* The root has no stream entry, it must be FAT chained */
+        let stream = self.alloc(size, root || self.fat_chains, 1);
+
+        let mut bytes: Vec<u8> = head.concat();
+        for node in tree {
+            let set = match node {
+                Node::File(name, content) | Node::Deleted(name, content) => {
+                    let deleted = matches!(node, Node::Deleted(..));
+                    let file = self.alloc(content.len(), self.fat_chains, 0);
+                    if file.size > 0 {
+                        self.write(&file, content);
+                    }
+                    let flags = if file.size > 0 { self.flags() } else { STREAM_ALLOCATION_POSSIBLE };
+                    let mut set = entry_set(name, 0x20, &file, flags);
+                    if deleted {
+                        set.iter_mut().for_each(|e| e[0] &= !ENTRY_IN_USE);
+                        self.free(&file);
+                    }
+                    set
+                }
+                Node::Dir(name, children) => {
+                    let child = self.dir(&[], children, false);
+                    entry_set(name, ATTR_DIRECTORY, &child, self.flags())
+                }
+            };
+            bytes.extend_from_slice(&set.concat());
+        }
+        self.write(&stream, &bytes);
+        stream
+    }
+
+    /* Deleted files keep their data but leave the bitmap and the FAT */
+    fn free(&mut self, stream: &Stream) {
+        let count = stream.size.div_ceil(self.cluster_size as u64) as u32;
+        let mut cluster = stream.first_cluster;
+        for _ in 0..count {
+            self.used[cluster as usize - 2] = false;
+            let next = if stream.contiguous {
+                cluster + 1
+            } else {
+                let at = self.fat_start + cluster as usize * 4;
+                u32::from_le_bytes(self.data[at..at + 4].try_into().unwrap())
+            };
+            if !stream.contiguous {
+                self.set_fat(cluster, 0);
+            }
+            cluster = next;
+        }
+    }
+}
+
+/* Directory entries `node` takes */
+fn entries_for(node: &Node) -> usize {
+    let name = match node {
+        Node::File(name, _) | Node::Dir(name, _) | Node::Deleted(name, _) => name,
+    };
+    2 + name.encode_utf16().count().div_ceil(NAME_UNITS_PER_ENTRY)
+}
@@ -0,0 +1,337 @@
+/* Writing an empty exFAT volume: both boot regions with their checksum
+ * sector, one FAT, then the bitmap, the up-case table and the root in the
+ * first clusters of the heap */
+
+use super::dir::{ENTRY_BITMAP, ENTRY_LABEL, ENTRY_UPCASE, LABEL_MAX_UNITS};
+use super::upcase::compressed_upcase;
+use super::{BOOT_REGION_SECTORS, EXFAT_EOC_MARK, EXFAT_MAX_CLUSTERS, EXFAT_OEM_NAME, MAX_CLUSTER_SHIFT, boot_checksum_sector};
+use crate::device::{BlockDevice, DeviceError};
+use crate::format::{FormatError, FormatOptions, zero_range};
+use crate::helpers::{u16_to_u8_le, u32_to_u8_le};
+
+/* The spec's smallest volume */
+const EXFAT_MIN_BYTES: u64 = 1024 * 1024;
+const FIRST_CLUSTER: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExfatGeometry {
+    pub volume_length: u64,
+    pub fat_offset: u32,
+    pub fat_length: u32,
+    pub heap_offset: u32,
+    pub cluster_count: u32,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+}
+
+impl ExfatGeometry {
+    pub fn cluster_size(&self) -> u64 {
+        1 << (self.bytes_per_sector_shift + self.sectors_per_cluster_shift)
+    }
+}
+
+/* Cluster size in bytes Windows picks for exFAT */
+pub fn exfat_default_cluster_size(volume_bytes: u64) -> u64 {
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+
+    if volume_bytes <= 256 * MIB {
+        4096
+    } else if volume_bytes <= 32 * GIB {
+        32768
+    } else {
+        131072
+    }
+}
+
+/* Single FAT right after the two boot regions, the heap aligned on a cluster */
+pub fn compute_exfat_geometry(volume_bytes: u64, opts: &FormatOptions) -> Result<ExfatGeometry, FormatError> {
+    let bps = opts.bytes_per_sector as u64;
+    if !matches!(bps, 512 | 1024 | 2048 | 4096) {
+        return Err(FormatError::InvalidOptions);
+    }
+    let spc = match opts.sectors_per_cluster {
+        Some(spc) => spc as u64,
+        None => core::cmp::max(1, exfat_default_cluster_size(volume_bytes) / bps),
+    };
+    if spc == 0 || !spc.is_power_of_two() || spc * bps > 1 << MAX_CLUSTER_SHIFT {
+        return Err(FormatError::InvalidOptions);
+    }
+    if volume_bytes < EXFAT_MIN_BYTES {
+        return Err(FormatError::TooFewClusters);
+    }
+
+    let total = volume_bytes / bps;
+    let fat_offset = (2 * BOOT_REGION_SECTORS).next_multiple_of(spc);
+    /* Sized for every cluster the volume could hold, a little generous */
+    let fat_length = ((total - fat_offset) / spc + 2).saturating_mul(4).div_ceil(bps);
+    let heap_offset = (fat_offset + fat_length).next_multiple_of(spc);
+    if heap_offset >= total {
+        return Err(FormatError::TooFewClusters);
+    }
+    let clusters = (total - heap_offset) / spc;
+    if clusters > EXFAT_MAX_CLUSTERS as u64 || heap_offset > u32::MAX as u64 {
+        return Err(FormatError::TooManyClusters);
+    }
+
+    Ok(ExfatGeometry {
+        volume_length: total,
+        fat_offset: fat_offset as u32,
+        fat_length: fat_length as u32,
+        heap_offset: heap_offset as u32,
+        cluster_count: clusters as u32,
+        bytes_per_sector_shift: bps.trailing_zeros() as u8,
+        sectors_per_cluster_shift: spc.trailing_zeros() as u8,
+    })
+}
+
+fn build_boot_sector(geo: &ExfatGeometry, opts: &FormatOptions, root: u32, percent: u8, sector: &mut [u8]) {
+    sector.fill(0);
+    sector[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+    sector[3..11].copy_from_slice(EXFAT_OEM_NAME);
+    /* Bytes 11 to 63 stay zero where FAT has its BPB */
+    sector[64..72].copy_from_slice(&(opts.hidden_sectors as u64).to_le_bytes());
+    sector[72..80].copy_from_slice(&geo.volume_length.to_le_bytes());
+    u32_to_u8_le(geo.fat_offset, &mut sector[80..84]);
+    u32_to_u8_le(geo.fat_length, &mut sector[84..88]);
+    u32_to_u8_le(geo.heap_offset, &mut sector[88..92]);
+    u32_to_u8_le(geo.cluster_count, &mut sector[92..96]);
+    u32_to_u8_le(root, &mut sector[96..100]);
+    u32_to_u8_le(opts.volume_id, &mut sector[100..104]);
+    /* Revision 1.0, clean volume on the first FAT */
+    u16_to_u8_le(0x0100, &mut sector[104..106]);
+    sector[108] = geo.bytes_per_sector_shift;
+    sector[109] = geo.sectors_per_cluster_shift;
+    sector[110] = 1;
+    sector[111] = 0x80;
+    sector[112] = percent;
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+}
+
+/* Write the main and backup boot regions: boot sector, extended boot
+ * sectors, OEM parameters and reserved sector left zeroed, checksum */
+fn write_boot_regions<D: BlockDevice>(dev: &mut D, boot: &[u8]) -> Result<(), DeviceError> {
+    let bps = boot.len();
+    let mut extended = [0u8; 4096];
+    let extended = &mut extended[..bps];
+    /* Extended boot sectors end with their own signature */
+    extended[bps - 2] = 0x55;
+    extended[bps - 1] = 0xAA;
+    let mut checksum = [0u8; 4096];
+    let checksum = &mut checksum[..bps];
+
+    let mut sum = boot_checksum_sector(0, 0, boot);
+    for index in 1..BOOT_REGION_SECTORS - 1 {
+        let sector = if index < 9 { &extended[..] } else { &checksum[..] };
+        sum = boot_checksum_sector(sum, index, sector);
+    }
+    for chunk in checksum.chunks_exact_mut(4) {
+        u32_to_u8_le(sum, chunk);
+    }
+
+    for region in [0, BOOT_REGION_SECTORS * bps as u64] {
+        dev.write_at(region, boot)?;
+        for index in 1..9 {
+            dev.write_at(region + index * bps as u64, extended)?;
+        }
+        dev.write_at(region + (BOOT_REGION_SECTORS - 1) * bps as u64, checksum)?;
+    }
+    Ok(())
+}
+
+/* Root entry for the bitmap or up-case table, both FAT chained */
+fn system_entry(kind: u8, first_cluster: u32, size: u64) -> [u8; 32] {
+    let mut e = [0u8; 32];
+    e[0] = kind;
+    u32_to_u8_le(first_cluster, &mut e[20..24]);
+    e[24..32].copy_from_slice(&size.to_le_bytes());
+    e
+}
+
+/* Write an empty exFAT file system on the first `volume_bytes` of `dev` */
+pub fn format_exfat<D: BlockDevice>(
+    dev: &mut D,
+    volume_bytes: u64,
+    opts: &FormatOptions,
+) -> Result<ExfatGeometry, FormatError> {
+    let geo = compute_exfat_geometry(volume_bytes, opts)?;
+    let bps = opts.bytes_per_sector as u64;
+    if dev.size()? < geo.volume_length * bps {
+        return Err(FormatError::Device(DeviceError::OutOfBounds));
+    }
+
+    /* Bitmap, up-case table then root, one after the other */
+    let cluster_size = geo.cluster_size();
+    let bitmap_bytes = (geo.cluster_count as u64).div_ceil(8);
+    let mut upcase_bytes = 0u64;
+    compressed_upcase(|_| upcase_bytes += 2);
+    let bitmap_clusters = bitmap_bytes.div_ceil(cluster_size) as u32;
+    let upcase_clusters = upcase_bytes.div_ceil(cluster_size) as u32;
+    let upcase_cluster = FIRST_CLUSTER + bitmap_clusters;
+    let root = upcase_cluster + upcase_clusters;
+    let used = bitmap_clusters + upcase_clusters + 1;
+    if used > geo.cluster_count {
+        return Err(FormatError::TooFewClusters);
+    }
+
+    /* ---------- Boot regions and FAT ---------- */
+    let heap_start = geo.heap_offset as u64 * bps;
+    zero_range(dev, 0, heap_start)?;
+    let mut boot = [0u8; 4096];
+    let boot = &mut boot[..bps as usize];
+    build_boot_sector(&geo, opts, root, (used as u64 * 100 / geo.cluster_count as u64) as u8, boot);
+    write_boot_regions(dev, boot)?;
+
+    let fat_start = geo.fat_offset as u64 * bps;
+    let mut entry = [0u8; 4];
+    let mut set_fat = |dev: &mut D, cluster: u32, value: u32| {
+        u32_to_u8_le(value, &mut entry);
+        dev.write_at(fat_start + cluster as u64 * 4, &entry)
+    };
+    /* Media descriptor and the reserved entry, then the three chains */
+    set_fat(dev, 0, 0xFFFFFFF8)?;
+    set_fat(dev, 1, EXFAT_EOC_MARK)?;
+    for (first, count) in [(FIRST_CLUSTER, bitmap_clusters), (upcase_cluster, upcase_clusters), (root, 1)] {
+        for cluster in first..first + count {
+            let next = if cluster + 1 == first + count { EXFAT_EOC_MARK } else { cluster + 1 };
+            set_fat(dev, cluster, next)?;
+        }
+    }
+
+    /* ---------- Heap ---------- */
+    zero_range(dev, heap_start, used as u64 * cluster_size)?;
+
+    /* The used clusters are the first ones */
+    let mut bits = [0u8; 512];
+    let mut done = 0u32;
+    while done < used {
+        bits.fill(0);
+        let chunk = core::cmp::min(used - done, bits.len() as u32 * 8);
+        for bit in 0..chunk {
+            bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+        dev.write_at(heap_start + done as u64 / 8, &bits[..chunk.div_ceil(8) as usize])?;
+        done += chunk;
+    }
+
+    let upcase_start = heap_start + bitmap_clusters as u64 * cluster_size;
+    let mut chunk = [0u8; 512];
+    let mut len = 0usize;
+    let mut written = 0u64;
+    let mut checksum = 0u32;
+    let mut result = Ok(());
+    compressed_upcase(|unit| {
+        for b in unit.to_le_bytes() {
+            checksum = checksum.rotate_right(1).wrapping_add(b as u32);
+            chunk[len] = b;
+            len += 1;
+        }
+        if len == chunk.len() {
+            result = result.and(dev.write_at(upcase_start + written, &chunk));
+            written += len as u64;
+            len = 0;
+        }
+    });
+    result?;
+    dev.write_at(upcase_start + written, &chunk[..len])?;
+
+    /* Label, bitmap and up-case table entries */
+    let root_start = heap_start + (root - FIRST_CLUSTER) as u64 * cluster_size;
+    let mut entries = [[0u8; 32]; 3];
+    let mut count = 0usize;
+    let label = opts.label.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+    if &opts.label != b"NO NAME    " && label > 0 {
+        entries[0][0] = ENTRY_LABEL;
+        entries[0][1] = core::cmp::min(label, LABEL_MAX_UNITS) as u8;
+        for (i, &c) in opts.label[..label].iter().enumerate() {
+            u16_to_u8_le(c as u16, &mut entries[0][2 + i * 2..4 + i * 2]);
+        }
+        count += 1;
+    }
+    entries[count] = system_entry(ENTRY_BITMAP, FIRST_CLUSTER, bitmap_bytes);
+    entries[count + 1] = system_entry(ENTRY_UPCASE, upcase_cluster, upcase_bytes);
+    u32_to_u8_le(checksum, &mut entries[count + 1][4..8]);
+    for (i, e) in entries[..count + 2].iter().enumerate() {
+        dev.write_at(root_start + i as u64 * 32, e)?;
+    }
+
+    Ok(geo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::exfat::dir::{ExfatPath, iterate_dir};
+    use crate::exfat::upcase::UPCASE_ENTRIES;
+    use crate::exfat::write::write_file;
+    use crate::exfat::{ExfatVolume, parse_exfat_boot_sector};
+    use crate::format::make_label;
+    use std::vec;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn default_cluster_size_follows_windows() {
+        assert_eq!(exfat_default_cluster_size(64 * MIB), 4096);
+        assert_eq!(exfat_default_cluster_size(16 * 1024 * MIB), 32768);
+        assert_eq!(exfat_default_cluster_size(64 * 1024 * MIB), 131072);
+    }
+
+    #[test]
+    fn geometry_covers_every_cluster() {
+        let opts = FormatOptions::default();
+        /* A 64 GB card */
+        let g = compute_exfat_geometry(64 * 1024 * MIB, &opts).unwrap();
+        assert_eq!(g.cluster_size(), 131072);
+        assert!(g.fat_length as u64 * 512 >= (g.cluster_count as u64 + 2) * 4);
+        assert!(g.heap_offset as u64 + ((g.cluster_count as u64) << g.sectors_per_cluster_shift) <= g.volume_length);
+        assert_eq!(g.heap_offset % (1 << g.sectors_per_cluster_shift), 0);
+
+        assert_eq!(compute_exfat_geometry(MIB / 2, &opts), Err(FormatError::TooFewClusters));
+        let opts = FormatOptions {
+            sectors_per_cluster: Some(3),
+            ..FormatOptions::default()
+        };
+        assert_eq!(compute_exfat_geometry(64 * MIB, &opts), Err(FormatError::InvalidOptions));
+    }
+
+    #[test]
+    fn format_writes_a_mountable_volume() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        for (size, bps) in [(32 * MIB, 512u16), (300 * MIB, 4096)] {
+            let mut image = vec![0xAAu8; size as usize];
+            let opts = FormatOptions {
+                bytes_per_sector: bps,
+                label: make_label(b"sdcard"),
+                volume_id: 0x12345678,
+                ..FormatOptions::default()
+            };
+            let geo = format_exfat(&mut MemDevice { data: &mut image }, size, &opts).unwrap();
+
+            let mut sector = [0u8; 512];
+            sector.copy_from_slice(&image[..512]);
+            let bs = parse_exfat_boot_sector(&sector).unwrap();
+            assert_eq!(bs.cluster_count, geo.cluster_count);
+            assert_eq!(bs.bytes_per_sector(), bps as u64);
+            assert_eq!(image[112], 0);
+            let backup = 12 * bps as usize;
+            assert_eq!(&image[..backup], &image[backup..2 * backup]);
+
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut image }, &mut upcase).unwrap();
+            assert_eq!(vol.cluster_size, geo.cluster_size());
+            let label: std::vec::Vec<u16> = "SDCARD".encode_utf16().collect();
+            assert_eq!(&vol.label[..vol.label_len], label.as_slice());
+            assert_eq!(vol.upcase(b'q' as u16), b'Q' as u16);
+            let root = vol.root;
+            assert_eq!(iterate_dir(&mut vol, &root, |_| Some(())).unwrap(), None);
+            /* Bitmap, up-case table and root */
+            let used = vol.clusters_count - vol.free_clusters().unwrap();
+            assert_eq!(used, root.first_cluster - 1);
+
+            write_file(&mut vol, &ExfatPath::new(), b"hello.txt", b"hello").unwrap();
+            assert_eq!(vol.free_clusters().unwrap(), vol.clusters_count - used - 1);
+        }
+    }
+}
@@ -0,0 +1,598 @@
+/* exFAT, the layout cards of 64 GB and up ship with. Same shape as the FAT
+ * side: a mounted volume over a block device, directory entry sets in
+ * `dir`, the up-case table in `upcase` */
+
+pub mod dir;
+#[cfg(test)]
+pub mod fixture;
+pub mod format;
+pub mod upcase;
+pub mod write;
+
+use crate::boot_sector::BootSectorError;
+use crate::device::BlockDevice;
+use crate::helpers::{u8_le_to_u16, u8_to_u32_le, u32_to_u8_le};
+use crate::volume::{MAX_SECTOR_SIZE, VolumeError};
+use dir::{ENTRY_BITMAP, ENTRY_END, ENTRY_LABEL, ENTRY_UPCASE, LABEL_MAX_UNITS};
+use upcase::{UPCASE_ENTRIES, UpcaseDecoder, UpcaseTable};
+
+pub const EXFAT_OEM_NAME: &[u8; 8] = b"EXFAT   ";
+pub const EXFAT_EOC_MARK: u32 = 0xFFFFFFFF;
+/* Highest cluster count the spec allows */
+pub const EXFAT_MAX_CLUSTERS: u32 = 0xFFFFFFF5;
+/* Main and backup boot regions are 12 sectors each: boot sector, 8
+ * extended ones, OEM parameters, a reserved one and the checksum sector */
+pub const BOOT_REGION_SECTORS: u64 = 12;
+/* Bytes of the boot sector the checksum skips: VolumeFlags and PercentInUse */
+const CHECKSUM_SKIPPED: [usize; 3] = [106, 107, 112];
+const VOLUME_FLAGS_OFFSET: u64 = 106;
+const PERCENT_IN_USE_OFFSET: u64 = 112;
+/* Volume flag set while a change is being written */
+const VOLUME_DIRTY: u16 = 0x02;
+/* Clusters are at most 32 MiB */
+const MAX_CLUSTER_SHIFT: u8 = 25;
+
+/* The fields of the exFAT boot sector this code uses */
+#[derive(Debug, Clone, Copy)]
+pub struct ExfatBootSector {
+    pub volume_length: u64,
+    pub fat_offset: u32,
+    pub fat_length: u32,
+    pub heap_offset: u32,
+    pub cluster_count: u32,
+    pub root_cluster: u32,
+    pub revision: u16,
+    /* Bit 0: active FAT, bit 1: volume dirty */
+    pub volume_flags: u16,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+    pub fats_count: u8,
+}
+
+impl ExfatBootSector {
+    pub fn bytes_per_sector(&self) -> u64 {
+        1 << self.bytes_per_sector_shift
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << (self.bytes_per_sector_shift + self.sectors_per_cluster_shift)
+    }
+}
+
+pub fn is_exfat(sector: &[u8; 512]) -> bool {
+    &sector[3..11] == EXFAT_OEM_NAME
+}
+
+/* Parse an exFAT boot sector, refusing values the layout can't be derived
+ * from. The boot region checksum is checked by `ExfatVolume::mount` */
+pub fn parse_exfat_boot_sector(bs: &[u8; 512]) -> Result<ExfatBootSector, BootSectorError> {
+    if bs[510] != 0x55 || bs[511] != 0xAA || !is_exfat(bs) {
+        return Err(BootSectorError::BadSignature);
+    }
+    /* Where FAT keeps its BPB, exFAT must have zeroes */
+    if bs[11..64].iter().any(|&b| b != 0) {
+        return Err(BootSectorError::BadGeometry);
+    }
+
+    let parsed = ExfatBootSector {
+        volume_length: u8_to_u32_le(&bs[72..76]) as u64 | (u8_to_u32_le(&bs[76..80]) as u64) << 32,
+        fat_offset: u8_to_u32_le(&bs[80..84]),
+        fat_length: u8_to_u32_le(&bs[84..88]),
+        heap_offset: u8_to_u32_le(&bs[88..92]),
+        cluster_count: u8_to_u32_le(&bs[92..96]),
+        root_cluster: u8_to_u32_le(&bs[96..100]),
+        revision: u8_le_to_u16(&bs[104..106]),
+        volume_flags: u8_le_to_u16(&bs[106..108]),
+        bytes_per_sector_shift: bs[108],
+        sectors_per_cluster_shift: bs[109],
+        fats_count: bs[110],
+    };
+
+    /* Only revision 1.x is defined */
+    if parsed.revision >> 8 != 1 {
+        return Err(BootSectorError::BadGeometry);
+    }
+    let bps_shift = parsed.bytes_per_sector_shift;
+    if !(9..=12).contains(&bps_shift) || parsed.sectors_per_cluster_shift > MAX_CLUSTER_SHIFT - bps_shift {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if parsed.fats_count != 1 && parsed.fats_count != 2 {
+        return Err(BootSectorError::BadGeometry);
+    }
+
+    /* Regions in order, each one after the previous and inside the volume */
+    let fats_end = parsed.fat_offset as u64 + parsed.fats_count as u64 * parsed.fat_length as u64;
+    let spc = 1u64 << parsed.sectors_per_cluster_shift;
+    let heap_end = parsed.heap_offset as u64 + parsed.cluster_count as u64 * spc;
+    if (parsed.fat_offset as u64) < 2 * BOOT_REGION_SECTORS
+        || (parsed.fat_length as u64) << bps_shift < (parsed.cluster_count as u64 + 2) * 4
+        || (parsed.heap_offset as u64) < fats_end
+        || heap_end > parsed.volume_length
+    {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if parsed.cluster_count == 0 || parsed.cluster_count > EXFAT_MAX_CLUSTERS {
+        return Err(BootSectorError::BadGeometry);
+    }
+    if parsed.root_cluster < 2 || parsed.root_cluster - 2 >= parsed.cluster_count {
+        return Err(BootSectorError::BadGeometry);
+    }
+
+    Ok(parsed)
+}
+
+/* Fold sector `index` of a boot region into the checksum `sum` */
+pub fn boot_checksum_sector(mut sum: u32, index: u64, sector: &[u8]) -> u32 {
+    for (i, &b) in sector.iter().enumerate() {
+        if index == 0 && CHECKSUM_SKIPPED.contains(&i) {
+            continue;
+        }
+        sum = sum.rotate_right(1).wrapping_add(b as u32);
+    }
+    sum
+}
+
+/* Checksum over the first 11 sectors of a boot region */
+pub fn boot_checksum<D: BlockDevice>(dev: &mut D, region: u64, bps: usize) -> Result<u32, VolumeError> {
+    let mut sector = [0u8; MAX_SECTOR_SIZE];
+    let mut sum = 0u32;
+    for index in 0..BOOT_REGION_SECTORS - 1 {
+        dev.read_at(region + index * bps as u64, &mut sector[..bps])?;
+        sum = boot_checksum_sector(sum, index, &sector[..bps]);
+    }
+    Ok(sum)
+}
+
+/* Whether the checksum sector of the region starting at `region` repeats
+ * the checksum of the 11 sectors before it */
+fn boot_region_is_valid<D: BlockDevice>(dev: &mut D, region: u64, bps: usize) -> Result<bool, VolumeError> {
+    let sum = boot_checksum(dev, region, bps)?;
+    let mut sector = [0u8; MAX_SECTOR_SIZE];
+    dev.read_at(region + (BOOT_REGION_SECTORS - 1) * bps as u64, &mut sector[..bps])?;
+    Ok(sector[..bps].chunks_exact(4).all(|c| u8_to_u32_le(c) == sum))
+}
+
+/* Data of a file or directory: a FAT chain, or `size` bytes of contiguous
+ * clusters when the entry sets NoFatChain */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stream {
+    pub first_cluster: u32,
+    pub size: u64,
+    pub contiguous: bool,
+}
+
+impl Stream {
+    pub const EMPTY: Stream = Stream {
+        first_cluster: 0,
+        size: 0,
+        contiguous: false,
+    };
+}
+
+/* A mounted exFAT volume: the device, the geometry from the boot sector,
+ * the allocation bitmap location and the decompressed up-case table */
+pub struct ExfatVolume<'a, D: BlockDevice> {
+    pub dev: D,
+    /* Active FAT */
+    pub fat_start: u64,
+    pub heap_start: u64,
+    pub cluster_size: u64,
+    pub clusters_count: u32,
+    pub root: Stream,
+    pub bitmap: Stream,
+    pub upcase: &'a mut UpcaseTable,
+    pub label: [u16; LABEL_MAX_UNITS],
+    pub label_len: usize,
+}
+
+impl<'a, D: BlockDevice> ExfatVolume<'a, D> {
+    /* Parse the boot sector, check the boot region (the backup one when the
+     * main one is damaged), then find the bitmap and up-case table in the
+     * root directory. The table is decompressed into `upcase` */
+    pub fn mount(mut dev: D, upcase: &'a mut UpcaseTable) -> Result<Self, VolumeError> {
+        let mut sector = [0u8; 512];
+        dev.read_at(0, &mut sector)?;
+        let mut boot = parse_exfat_boot_sector(&sector);
+        if let Ok(bs) = &boot {
+            let bps = bs.bytes_per_sector() as usize;
+            if !boot_region_is_valid(&mut dev, 0, bps)? {
+                boot = Err(BootSectorError::ChecksumMismatch);
+            }
+        }
+        if boot.is_err() {
+            /* Backup region, sector size taken from the backup itself */
+            for bps in [512u64, 1024, 2048, 4096] {
+                let region = BOOT_REGION_SECTORS * bps;
+                if dev.read_at(region, &mut sector).is_err() {
+                    continue;
+                }
+                if let Ok(bs) = parse_exfat_boot_sector(&sector)
+                    && bs.bytes_per_sector() == bps
+                    && boot_region_is_valid(&mut dev, region, bps as usize)?
+                {
+                    boot = Ok(bs);
+                    break;
+                }
+            }
+        }
+        let boot = boot?;
+
+        let bps = boot.bytes_per_sector();
+        let active = (boot.volume_flags & 1) as u64 % boot.fats_count as u64;
+        let fat_start = (boot.fat_offset as u64 + active * boot.fat_length as u64) * bps;
+        let mut vol = ExfatVolume {
+            dev,
+            fat_start,
+            heap_start: boot.heap_offset as u64 * bps,
+            cluster_size: boot.cluster_size(),
+            clusters_count: boot.cluster_count,
+            root: Stream::EMPTY,
+            bitmap: Stream::EMPTY,
+            upcase,
+            label: [0u16; LABEL_MAX_UNITS],
+            label_len: 0,
+        };
+
+        /* The root has no stream entry, its size is its chain length */
+        let mut clusters = 0u64;
+        let mut cluster = boot.root_cluster;
+        loop {
+            clusters += 1;
+            if clusters > vol.clusters_count as u64 {
+                return Err(VolumeError::Corrupt);
+            }
+            let next = vol.fat_entry(cluster)?;
+            if next == EXFAT_EOC_MARK {
+                break;
+            }
+            if !vol.is_valid_cluster(next) {
+                return Err(VolumeError::Corrupt);
+            }
+            cluster = next;
+        }
+        vol.root = Stream {
+            first_cluster: boot.root_cluster,
+            size: clusters * vol.cluster_size,
+            contiguous: false,
+        };
+
+        let mut upcase_entry = None;
+        let root = vol.root;
+        let mut reader = dir::DirReader::new(root);
+        while let Some(entry) = reader.next(&mut vol)? {
+            match entry[0] {
+                ENTRY_END => break,
+                /* With two FATs each has its bitmap, bit 0 of the flags tells which */
+                ENTRY_BITMAP if (entry[1] & 1) as u64 == active => {
+                    vol.bitmap = dir::entry_stream(&entry, false);
+                }
+                ENTRY_UPCASE if upcase_entry.is_none() => {
+                    upcase_entry = Some((dir::entry_stream(&entry, false), u8_to_u32_le(&entry[4..8])));
+                }
+                ENTRY_LABEL => {
+                    let len = core::cmp::min(entry[1] as usize, LABEL_MAX_UNITS);
+                    for i in 0..len {
+                        vol.label[i] = u8_le_to_u16(&entry[2 + i * 2..4 + i * 2]);
+                    }
+                    vol.label_len = len;
+                }
+                _ => {}
+            }
+        }
+
+        /* Both are mandatory, the bitmap must cover every cluster */
+        let (table, checksum) = upcase_entry.ok_or(VolumeError::Corrupt)?;
+        if vol.bitmap.size * 8 < vol.clusters_count as u64 {
+            return Err(VolumeError::Corrupt);
+        }
+        vol.load_upcase(table, checksum)?;
+        Ok(vol)
+    }
+
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.clusters_count
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.heap_start + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    pub fn fat_entry(&mut self, cluster: u32) -> Result<u32, VolumeError> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+        let mut raw = [0u8; 4];
+        self.dev.read_at(self.fat_start + cluster as u64 * 4, &mut raw)?;
+        Ok(u8_to_u32_le(&raw))
+    }
+
+    /* Cluster holding byte `pos` of `stream`, walking from `from` (its
+     * cluster number and the index of that cluster in the stream) */
+    pub fn seek(&mut self, stream: &Stream, from: (u32, u64), pos: u64) -> Result<(u32, u64), VolumeError> {
+        if pos >= stream.size || stream.size.div_ceil(self.cluster_size) > self.clusters_count as u64 {
+            return Err(VolumeError::Corrupt);
+        }
+        let index = pos / self.cluster_size;
+        let (mut cluster, mut at) = if from.1 <= index && self.is_valid_cluster(from.0) {
+            from
+        } else {
+            (stream.first_cluster, 0)
+        };
+
+        if stream.contiguous {
+            let last = stream.first_cluster as u64 + (stream.size - 1) / self.cluster_size;
+            if !self.is_valid_cluster(stream.first_cluster) || last >= self.clusters_count as u64 + 2 {
+                return Err(VolumeError::Corrupt);
+            }
+            return Ok(((stream.first_cluster as u64 + index) as u32, index));
+        }
+
+        if !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+        while at < index {
+            let next = self.fat_entry(cluster)?;
+            if !self.is_valid_cluster(next) {
+                /* The chain ends before the size says it should */
+                return Err(VolumeError::Corrupt);
+            }
+            cluster = next;
+            at += 1;
+        }
+        Ok((cluster, index))
+    }
+
+    /* Fill `buf` from byte `pos` of `stream`, `cursor` keeps the last
+     * cluster found so sequential reads don't walk the chain again */
+    pub fn read_stream(
+        &mut self,
+        stream: &Stream,
+        cursor: &mut (u32, u64),
+        pos: u64,
+        buf: &mut [u8],
+    ) -> Result<(), VolumeError> {
+        let end = pos.checked_add(buf.len() as u64).ok_or(VolumeError::Corrupt)?;
+        if end > stream.size {
+            return Err(VolumeError::Corrupt);
+        }
+        let mut done = 0usize;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            *cursor = self.seek(stream, *cursor, at)?;
+            let in_cluster = at % self.cluster_size;
+            let len = core::cmp::min(buf.len() - done, (self.cluster_size - in_cluster) as usize);
+            let offset = self.cluster_offset(cursor.0) + in_cluster;
+            self.dev.read_at(offset, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    pub fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), VolumeError> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+        let mut raw = [0u8; 4];
+        u32_to_u8_le(value, &mut raw);
+        self.dev.write_at(self.fat_start + cluster as u64 * 4, &raw)?;
+        Ok(())
+    }
+
+    /* Copy `buf` to byte `pos` of `stream`, which must be large enough */
+    pub fn write_stream(
+        &mut self,
+        stream: &Stream,
+        cursor: &mut (u32, u64),
+        pos: u64,
+        buf: &[u8],
+    ) -> Result<(), VolumeError> {
+        let end = pos.checked_add(buf.len() as u64).ok_or(VolumeError::Corrupt)?;
+        if end > stream.size {
+            return Err(VolumeError::Corrupt);
+        }
+        let mut done = 0usize;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            *cursor = self.seek(stream, *cursor, at)?;
+            let in_cluster = at % self.cluster_size;
+            let len = core::cmp::min(buf.len() - done, (self.cluster_size - in_cluster) as usize);
+            let offset = self.cluster_offset(cursor.0) + in_cluster;
+            self.dev.write_at(offset, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /* Whether the allocation bitmap marks `cluster` as used */
+    pub fn is_cluster_allocated(&mut self, cluster: u32) -> Result<bool, VolumeError> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+        let bit = (cluster - 2) as u64;
+        let mut byte = [0u8; 1];
+        let bitmap = self.bitmap;
+        self.read_stream(&bitmap, &mut (0, 0), bit / 8, &mut byte)?;
+        Ok(byte[0] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn set_cluster_allocated(&mut self, cluster: u32, used: bool) -> Result<(), VolumeError> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+        let bit = (cluster - 2) as u64;
+        let mut byte = [0u8; 1];
+        let bitmap = self.bitmap;
+        let mut cursor = (0, 0);
+        self.read_stream(&bitmap, &mut cursor, bit / 8, &mut byte)?;
+        if used {
+            byte[0] |= 1 << (bit % 8);
+        } else {
+            byte[0] &= !(1 << (bit % 8));
+        }
+        self.write_stream(&bitmap, &mut cursor, bit / 8, &byte)
+    }
+
+    /* Clusters the allocation bitmap calls free */
+    pub fn free_clusters(&mut self) -> Result<u32, VolumeError> {
+        let bitmap = self.bitmap;
+        let bytes = (self.clusters_count as u64).div_ceil(8);
+        let mut chunk = [0u8; 512];
+        let mut cursor = (0, 0);
+        let mut used = 0u32;
+        let mut pos = 0u64;
+        while pos < bytes {
+            let len = core::cmp::min(bytes - pos, chunk.len() as u64) as usize;
+            self.read_stream(&bitmap, &mut cursor, pos, &mut chunk[..len])?;
+            for (i, &b) in chunk[..len].iter().enumerate() {
+                /* Bits past the last cluster don't count */
+                let valid = core::cmp::min(8, self.clusters_count as u64 - (pos + i as u64) * 8);
+                used += (b as u32 & ((1u32 << valid) - 1)).count_ones();
+            }
+            pos += len as u64;
+        }
+        Ok(self.clusters_count - used)
+    }
+
+    /* Raise or clear VolumeDirty in the main boot sector, outside of the
+     * checksum like PercentInUse */
+    pub fn set_dirty(&mut self, dirty: bool) -> Result<(), VolumeError> {
+        let mut raw = [0u8; 2];
+        self.dev.read_at(VOLUME_FLAGS_OFFSET, &mut raw)?;
+        let flags = u8_le_to_u16(&raw);
+        let flags = if dirty { flags | VOLUME_DIRTY } else { flags & !VOLUME_DIRTY };
+        self.dev.write_at(VOLUME_FLAGS_OFFSET, &flags.to_le_bytes())?;
+        Ok(())
+    }
+
+    /* Bring PercentInUse in line with the bitmap */
+    pub fn update_percent_in_use(&mut self) -> Result<(), VolumeError> {
+        let used = (self.clusters_count - self.free_clusters()?) as u64;
+        let percent = used * 100 / self.clusters_count as u64;
+        self.dev.write_at(PERCENT_IN_USE_OFFSET, &[percent as u8])?;
+        Ok(())
+    }
+
+    /* Write back what the device keeps cached */
+    pub fn flush(&mut self) -> Result<(), VolumeError> {
+        self.dev.flush()?;
+        Ok(())
+    }
+
+    /* Decompress the up-case table and check it against its checksum */
+    fn load_upcase(&mut self, table: Stream, checksum: u32) -> Result<(), VolumeError> {
+        if !table.size.is_multiple_of(2) || table.size > UPCASE_ENTRIES as u64 * 2 {
+            return Err(VolumeError::Corrupt);
+        }
+        let mut decoder = UpcaseDecoder::new();
+        let mut cursor = (0, 0);
+        let mut chunk = [0u8; 512];
+        let mut sum = 0u32;
+        let mut pos = 0u64;
+        while pos < table.size {
+            let len = core::cmp::min(table.size - pos, chunk.len() as u64) as usize;
+            self.read_stream(&table, &mut cursor, pos, &mut chunk[..len])?;
+            for &b in &chunk[..len] {
+                sum = sum.rotate_right(1).wrapping_add(b as u32);
+            }
+            for unit in chunk[..len].chunks_exact(2) {
+                decoder.push(self.upcase, u8_le_to_u16(unit));
+            }
+            pos += len as u64;
+        }
+        if sum != checksum {
+            return Err(VolumeError::Corrupt);
+        }
+        decoder.finish(self.upcase);
+        Ok(())
+    }
+
+    pub fn upcase(&self, unit: u16) -> u16 {
+        self.upcase[unit as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::exfat::fixture::ExfatBuilder;
+
+    #[test]
+    fn mount_reads_geometry_bitmap_and_upcase() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        assert_eq!(vol.cluster_size, 4096);
+        assert_eq!(vol.upcase(b'a' as u16), b'A' as u16);
+        assert_eq!(vol.upcase(0x00E9), 0x00C9);
+        assert_eq!(vol.upcase(b'0' as u16), b'0' as u16);
+        assert_eq!(&vol.label[..vol.label_len], &[b'C' as u16, b'A' as u16, b'R' as u16, b'D' as u16]);
+
+        /* Bitmap, up-case table and root */
+        assert!(vol.is_cluster_allocated(2).unwrap());
+        let root = vol.root.first_cluster;
+        assert!(vol.is_cluster_allocated(root).unwrap());
+        assert!(!vol.is_cluster_allocated(root + 1).unwrap());
+    }
+
+    #[test]
+    fn damaged_main_boot_region_falls_back_to_backup() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        data[120] ^= 0xFF;
+        assert!(ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).is_ok());
+
+        /* Both regions damaged */
+        data[12 * 512 + 120] ^= 0xFF;
+        assert!(matches!(
+            ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase),
+            Err(VolumeError::InvalidBootSector)
+        ));
+    }
+
+    #[test]
+    fn flags_and_percent_in_use_are_outside_the_checksum() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        data[106] = 0x02;
+        data[112] = 42;
+        assert!(ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).is_ok());
+    }
+
+    #[test]
+    fn bad_upcase_checksum_is_refused() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        /* The table follows the one cluster bitmap */
+        let table = {
+            let vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            vol.cluster_offset(vol.bitmap.first_cluster + 1) as usize
+        };
+        data[table + 2] ^= 1;
+        assert!(matches!(
+            ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase),
+            Err(VolumeError::Corrupt)
+        ));
+    }
+
+    #[test]
+    fn parse_rejects_bad_geometry() {
+        let data = ExfatBuilder::new().build(&[]);
+        let mut sector = [0u8; 512];
+        sector.copy_from_slice(&data[..512]);
+        assert!(parse_exfat_boot_sector(&sector).is_ok());
+
+        let mut bad = sector;
+        bad[20] = 1;
+        assert_eq!(parse_exfat_boot_sector(&bad).err(), Some(BootSectorError::BadGeometry));
+        let mut bad = sector;
+        bad[108] = 13;
+        assert_eq!(parse_exfat_boot_sector(&bad).err(), Some(BootSectorError::BadGeometry));
+        let mut bad = sector;
+        bad[96..100].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(parse_exfat_boot_sector(&bad).err(), Some(BootSectorError::BadGeometry));
+        let mut bad = sector;
+        bad[3] = b'M';
+        assert_eq!(parse_exfat_boot_sector(&bad).err(), Some(BootSectorError::BadSignature));
+    }
+}
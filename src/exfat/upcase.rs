@@ -0,0 +1,126 @@
+/* The up-case table maps every UTF-16 unit to its upper case form, names
+ * are compared and hashed through it. On disk it may be compressed: 0xFFFF
+ * followed by a count stands for that many units mapping to themselves */
+
+pub const UPCASE_ENTRIES: usize = 0x10000;
+/* A decompressed table, 128 KiB: volumes borrow one from their caller */
+pub type UpcaseTable = [u16; UPCASE_ENTRIES];
+const COMPRESSED_RUN: u16 = 0xFFFF;
+
+/* Expands a table unit by unit, whatever chunks it is read in */
+pub struct UpcaseDecoder {
+    index: usize,
+    run: bool,
+}
+
+impl Default for UpcaseDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UpcaseDecoder {
+    pub fn new() -> Self {
+        UpcaseDecoder { index: 0, run: false }
+    }
+
+    pub fn push(&mut self, table: &mut [u16; UPCASE_ENTRIES], unit: u16) {
+        if self.run {
+            let end = core::cmp::min(self.index + unit as usize, UPCASE_ENTRIES);
+            for (i, slot) in table[self.index..end].iter_mut().enumerate() {
+                *slot = (self.index + i) as u16;
+            }
+            self.index = end;
+            self.run = false;
+        } else if unit == COMPRESSED_RUN {
+            self.run = true;
+        } else if self.index < UPCASE_ENTRIES {
+            table[self.index] = unit;
+            self.index += 1;
+        }
+    }
+
+    /* Units past the end of the table map to themselves */
+    pub fn finish(&mut self, table: &mut [u16; UPCASE_ENTRIES]) {
+        for (i, slot) in table.iter_mut().enumerate().skip(self.index) {
+            *slot = i as u16;
+        }
+        self.index = UPCASE_ENTRIES;
+    }
+}
+
+/* Upper case of `unit` when it is a single BMP character, itself otherwise */
+pub fn upcase_unit(unit: u16) -> u16 {
+    let Some(c) = char::from_u32(unit as u32) else {
+        return unit;
+    };
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(u), None) if (u as u32) < 0x10000 => u as u16,
+        _ => unit,
+    }
+}
+
+/* Compressed table built from `upcase_unit`, handed out unit by unit.
+ * Runs of units mapping to themselves longer than two become a 0xFFFF and
+ * a count */
+pub fn compressed_upcase(mut emit: impl FnMut(u16)) {
+    let mut unit = 0usize;
+    while unit < UPCASE_ENTRIES {
+        let mut run = 0usize;
+        while unit + run < UPCASE_ENTRIES
+            && run < COMPRESSED_RUN as usize
+            && upcase_unit((unit + run) as u16) == (unit + run) as u16
+        {
+            run += 1;
+        }
+        if run > 2 {
+            emit(COMPRESSED_RUN);
+            emit(run as u16);
+            unit += run;
+        } else {
+            emit(upcase_unit(unit as u16));
+            unit += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn compressed_table_expands_back() {
+        let mut units = Vec::new();
+        compressed_upcase(|u| units.push(u));
+        assert!(units.len() < 4096);
+
+        let mut table = [0u16; UPCASE_ENTRIES];
+        let mut decoder = UpcaseDecoder::new();
+        /* Chunk boundaries may fall between a 0xFFFF and its count */
+        for chunk in units.chunks(7) {
+            chunk.iter().for_each(|&u| decoder.push(&mut table, u));
+        }
+        decoder.finish(&mut table);
+
+        for (unit, &upper) in table.iter().enumerate() {
+            assert_eq!(upper, upcase_unit(unit as u16), "unit {unit:#x}");
+        }
+        assert_eq!(table[b'z' as usize], b'Z' as u16);
+        assert_eq!(table[0x03C9], 0x03A9);
+        assert_eq!(table[0xD800], 0xD800);
+    }
+
+    #[test]
+    fn short_table_maps_the_rest_to_itself() {
+        let mut table = [0u16; UPCASE_ENTRIES];
+        let mut decoder = UpcaseDecoder::new();
+        [COMPRESSED_RUN, 0x61, 0x41, 0x42].iter().for_each(|&u| decoder.push(&mut table, u));
+        decoder.finish(&mut table);
+        assert_eq!(table[0x60], 0x60);
+        assert_eq!(table[0x61], 0x41);
+        assert_eq!(table[0x62], 0x42);
+        assert_eq!(table[0x63], 0x63);
+    }
+}
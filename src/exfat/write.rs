@@ -0,0 +1,689 @@
+/* Creating, writing, deleting and renaming on exFAT. Every change keeps the
+ * allocation bitmap, the FAT of chained streams, NoFatChain and the entry
+ * set checksums in step; VolumeDirty is raised for the time it takes */
+
+use super::dir::{
+    ATTR_DIRECTORY, ENTRY_FILE, ENTRY_IN_USE, ENTRY_STREAM, ExfatEntry, ExfatPath, MAX_SET_ENTRIES,
+    NAME_MAX_UNITS, change_dir, entry_set_checksum, fill_entry_set_name, find_entry, iterate_dir,
+    read_entry_set, set_entry_stream, split_path, utf8_to_units,
+};
+use super::{EXFAT_EOC_MARK, ExfatVolume, Stream};
+use crate::device::{BlockDevice, DeviceError};
+use crate::helpers::{fat_datetime, u16_to_u8_le, u32_to_u8_le};
+use crate::sys::now_seconds;
+use crate::volume::VolumeError;
+
+/* File entry attribute of a file changed since the last backup */
+const ATTR_ARCHIVE: u16 = 0x20;
+/* Units a name can't hold besides the control ones */
+const INVALID_NAME_UNITS: &[u8] = b"\"*/:<>?\\|";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    Volume(VolumeError),
+    /* Empty, too long, `.`/`..` or with a character exFAT forbids */
+    InvalidName,
+    /* The directory already has an entry by that name */
+    NameTaken,
+    NotFound,
+    /* Directories are only deleted once empty */
+    NotEmpty,
+    /* The path names a directory where a file is expected */
+    IsDirectory,
+    NoSpace,
+}
+
+impl From<VolumeError> for WriteError {
+    fn from(e: VolumeError) -> Self {
+        WriteError::Volume(e)
+    }
+}
+
+impl From<DeviceError> for WriteError {
+    fn from(e: DeviceError) -> Self {
+        WriteError::Volume(e.into())
+    }
+}
+
+/* Walks the allocation bitmap forward a sector at a time */
+struct BitmapScan {
+    chunk: [u8; 512],
+    /* Byte of the bitmap `chunk` starts at */
+    start: u64,
+    cursor: (u32, u64),
+}
+
+impl BitmapScan {
+    fn new() -> Self {
+        BitmapScan {
+            chunk: [0u8; 512],
+            start: u64::MAX,
+            cursor: (0, 0),
+        }
+    }
+
+    fn is_free<D: BlockDevice>(&mut self, vol: &mut ExfatVolume<D>, cluster: u32) -> Result<bool, VolumeError> {
+        let bit = (cluster - 2) as u64;
+        let start = bit / 8 / 512 * 512;
+        if start != self.start {
+            let len = core::cmp::min(vol.bitmap.size - start, 512) as usize;
+            let bitmap = vol.bitmap;
+            self.start = u64::MAX;
+            vol.read_stream(&bitmap, &mut self.cursor, start, &mut self.chunk[..len])?;
+            self.start = start;
+        }
+        Ok(self.chunk[(bit / 8 - start) as usize] & (1 << (bit % 8)) == 0)
+    }
+}
+
+/* Link `count` clusters from `first` in the FAT, the last one ending it */
+fn link_run<D: BlockDevice>(vol: &mut ExfatVolume<D>, first: u32, count: u32) -> Result<(), VolumeError> {
+    for cluster in first..first + count {
+        let next = if cluster == first + count - 1 { EXFAT_EOC_MARK } else { cluster + 1 };
+        vol.set_fat_entry(cluster, next)?;
+    }
+    Ok(())
+}
+
+/* `count` clusters marked used in the bitmap: the first free run long
+ * enough when there is one, left out of the FAT for NoFatChain, otherwise
+ * the first free clusters linked in the FAT */
+fn allocate<D: BlockDevice>(vol: &mut ExfatVolume<D>, count: u32) -> Result<Stream, WriteError> {
+    if count == 0 {
+        return Ok(Stream::EMPTY);
+    }
+    let last = vol.clusters_count + 1;
+    let size = count as u64 * vol.cluster_size;
+
+    let mut scan = BitmapScan::new();
+    let mut run = (0u32, 0u32);
+    let mut free = 0u32;
+    for cluster in 2..=last {
+        if !scan.is_free(vol, cluster)? {
+            run.1 = 0;
+            continue;
+        }
+        free += 1;
+        if run.1 == 0 {
+            run.0 = cluster;
+        }
+        run.1 += 1;
+        if run.1 == count {
+            for c in run.0..run.0 + count {
+                vol.set_cluster_allocated(c, true)?;
+            }
+            return Ok(Stream {
+                first_cluster: run.0,
+                size,
+                contiguous: true,
+            });
+        }
+    }
+    if free < count {
+        return Err(WriteError::NoSpace);
+    }
+
+    let mut scan = BitmapScan::new();
+    let mut first = 0u32;
+    let mut previous = 0u32;
+    let mut taken = 0u32;
+    for cluster in 2..=last {
+        if taken == count {
+            break;
+        }
+        if !scan.is_free(vol, cluster)? {
+            continue;
+        }
+        vol.set_cluster_allocated(cluster, true)?;
+        if taken == 0 {
+            first = cluster;
+        } else {
+            vol.set_fat_entry(previous, cluster)?;
+        }
+        previous = cluster;
+        taken += 1;
+    }
+    vol.set_fat_entry(previous, EXFAT_EOC_MARK)?;
+    Ok(Stream {
+        first_cluster: first,
+        size,
+        contiguous: false,
+    })
+}
+
+/* `stream` with `more` clusters at its end. A contiguous stream stays so
+ * when the clusters after it are free, otherwise it gets linked in the FAT
+ * and NoFatChain has to go from its entry */
+fn grow<D: BlockDevice>(vol: &mut ExfatVolume<D>, stream: &Stream, more: u32) -> Result<Stream, WriteError> {
+    let have = stream.size.div_ceil(vol.cluster_size) as u32;
+    if have == 0 {
+        return allocate(vol, more);
+    }
+    let size = (have + more) as u64 * vol.cluster_size;
+
+    if stream.contiguous {
+        let after = stream.first_cluster + have;
+        let mut scan = BitmapScan::new();
+        let mut free = true;
+        for cluster in after..after + more {
+            if !vol.is_valid_cluster(cluster) || !scan.is_free(vol, cluster)? {
+                free = false;
+                break;
+            }
+        }
+        if free {
+            for cluster in after..after + more {
+                vol.set_cluster_allocated(cluster, true)?;
+            }
+            return Ok(Stream { size, ..*stream });
+        }
+        link_run(vol, stream.first_cluster, have)?;
+    }
+
+    let (last, _) = vol.seek(stream, (0, 0), (have as u64 - 1) * vol.cluster_size)?;
+    let added = allocate(vol, more)?;
+    if added.contiguous {
+        link_run(vol, added.first_cluster, more)?;
+    }
+    vol.set_fat_entry(last, added.first_cluster)?;
+    Ok(Stream {
+        first_cluster: stream.first_cluster,
+        size,
+        contiguous: false,
+    })
+}
+
+/* Hand the clusters of `stream` back to the bitmap, and to the FAT when
+ * it is chained */
+fn release<D: BlockDevice>(vol: &mut ExfatVolume<D>, stream: &Stream) -> Result<(), VolumeError> {
+    let count = stream.size.div_ceil(vol.cluster_size);
+    if count > vol.clusters_count as u64 {
+        return Err(VolumeError::Corrupt);
+    }
+    let mut cluster = stream.first_cluster;
+    for i in 0..count {
+        if !vol.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+        vol.set_cluster_allocated(cluster, false)?;
+        if stream.contiguous {
+            cluster += 1;
+        } else {
+            let next = vol.fat_entry(cluster)?;
+            vol.set_fat_entry(cluster, 0)?;
+            if next == EXFAT_EOC_MARK && i + 1 < count {
+                return Err(VolumeError::Corrupt);
+            }
+            cluster = next;
+        }
+    }
+    Ok(())
+}
+
+/* Zero bytes `from..to` of `stream` */
+fn zero_stream<D: BlockDevice>(vol: &mut ExfatVolume<D>, stream: &Stream, from: u64, to: u64) -> Result<(), VolumeError> {
+    let zeroes = [0u8; 512];
+    let mut cursor = (0, 0);
+    let mut pos = from;
+    while pos < to {
+        let len = core::cmp::min(to - pos, zeroes.len() as u64) as usize;
+        vol.write_stream(stream, &mut cursor, pos, &zeroes[..len])?;
+        pos += len as u64;
+    }
+    Ok(())
+}
+
+/* Write the first `count` entries of `set` at entry `index` of `dir`, with
+ * the checksum updated */
+fn write_entry_set<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    dir: &Stream,
+    index: u64,
+    set: &mut [[u8; 32]; MAX_SET_ENTRIES],
+    count: usize,
+) -> Result<(), VolumeError> {
+    let checksum = entry_set_checksum(&set[..count]);
+    u16_to_u8_le(checksum, &mut set[0][2..4]);
+    let mut cursor = (0, 0);
+    for (i, entry) in set[..count].iter().enumerate() {
+        vol.write_stream(dir, &mut cursor, (index + i as u64) * 32, entry)?;
+    }
+    Ok(())
+}
+
+/* Clear the in use bit of `count` entries from `index`, the way a set is
+ * deleted */
+fn delete_entries<D: BlockDevice>(vol: &mut ExfatVolume<D>, dir: &Stream, index: u64, count: u64) -> Result<(), VolumeError> {
+    let mut cursor = (0, 0);
+    for i in index..index + count {
+        let mut kind = [0u8; 1];
+        vol.read_stream(dir, &mut cursor, i * 32, &mut kind)?;
+        kind[0] &= !ENTRY_IN_USE;
+        vol.write_stream(dir, &mut cursor, i * 32, &kind)?;
+    }
+    Ok(())
+}
+
+/* First run of `count` unused entries in `dir`, deleted ones or past the end */
+fn find_free_entries<D: BlockDevice>(vol: &mut ExfatVolume<D>, dir: &Stream, count: usize) -> Result<Option<u64>, VolumeError> {
+    let mut reader = super::dir::DirReader::new(*dir);
+    let mut run = 0usize;
+    while let Some(entry) = reader.next(vol)? {
+        if entry[0] & ENTRY_IN_USE != 0 {
+            run = 0;
+            continue;
+        }
+        run += 1;
+        if run == count {
+            return Ok(Some(reader.index() - count as u64));
+        }
+    }
+    Ok(None)
+}
+
+/* Give the directory `dir` points at its new stream: the root keeps its
+ * chain only, a subdirectory's set in its parent is rewritten */
+fn set_dir_stream<D: BlockDevice>(vol: &mut ExfatVolume<D>, dir: &mut ExfatPath, stream: Stream) -> Result<(), VolumeError> {
+    dir.set_current(stream);
+    let Some((parent, index)) = dir.parent() else {
+        vol.root = stream;
+        return Ok(());
+    };
+    let parent = parent.current(vol);
+    let (mut set, count) = read_entry_set(vol, &parent, index)?;
+    set_entry_stream(&mut set[1], &stream, stream.size);
+    write_entry_set(vol, &parent, index, &mut set, count)
+}
+
+/* Index of `count` unused entries in `dir`, which gets a new zeroed
+ * cluster when it is full */
+fn reserve_entries<D: BlockDevice>(vol: &mut ExfatVolume<D>, dir: &mut ExfatPath, count: usize) -> Result<u64, WriteError> {
+    loop {
+        let stream = dir.current(vol);
+        if let Some(index) = find_free_entries(vol, &stream, count)? {
+            return Ok(index);
+        }
+        let grown = grow(vol, &stream, 1)?;
+        zero_stream(vol, &grown, stream.size, grown.size)?;
+        set_dir_stream(vol, dir, grown)?;
+    }
+}
+
+/* exFAT name of `name`, refusing what the spec forbids */
+fn name_units(name: &[u8], units: &mut [u16; NAME_MAX_UNITS]) -> Result<usize, WriteError> {
+    if name.is_empty() || name == b"." || name == b".." {
+        return Err(WriteError::InvalidName);
+    }
+    let len = utf8_to_units(name, units).ok_or(WriteError::InvalidName)?;
+    if units[..len].iter().any(|&u| u < 0x20 || (u < 0x80 && INVALID_NAME_UNITS.contains(&(u as u8)))) {
+        return Err(WriteError::InvalidName);
+    }
+    Ok(len)
+}
+
+/* Directory holding `path` from `cwd` and the last component's name */
+fn resolve<'a, D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    path: &'a [u8],
+) -> Result<(ExfatPath, &'a [u8]), WriteError> {
+    let (parent, name) = split_path(path);
+    let mut dir = *cwd;
+    if !change_dir(vol, &mut dir, parent)? {
+        return Err(WriteError::NotFound);
+    }
+    Ok((dir, name))
+}
+
+fn now_stamp() -> u32 {
+    let (date, time) = fat_datetime(now_seconds());
+    (date as u32) << 16 | time as u32
+}
+
+/* Add a set for `name` pointing at `stream` to `dir` */
+fn create_entry<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    dir: &mut ExfatPath,
+    name: &[u16],
+    attributes: u16,
+    stream: &Stream,
+    valid_size: u64,
+) -> Result<(), WriteError> {
+    let mut set = [[0u8; 32]; MAX_SET_ENTRIES];
+    set[0][0] = ENTRY_FILE;
+    u16_to_u8_le(attributes, &mut set[0][4..6]);
+    let stamp = now_stamp();
+    for field in [8, 12, 16] {
+        u32_to_u8_le(stamp, &mut set[0][field..field + 4]);
+    }
+    set[1][0] = ENTRY_STREAM;
+    set_entry_stream(&mut set[1], stream, valid_size);
+
+    let count = fill_entry_set_name(&mut set, name, vol.upcase);
+    let index = reserve_entries(vol, dir, count)?;
+    let stream = dir.current(vol);
+    write_entry_set(vol, &stream, index, &mut set, count)?;
+    Ok(())
+}
+
+/* Run `change` with VolumeDirty raised, then update PercentInUse. The flag
+ * stays up when the volume itself failed midway */
+fn modify<D, R, F>(vol: &mut ExfatVolume<D>, change: F) -> Result<R, WriteError>
+where
+    D: BlockDevice,
+    F: FnOnce(&mut ExfatVolume<D>) -> Result<R, WriteError>,
+{
+    vol.set_dirty(true)?;
+    let result = change(vol);
+    if let Err(WriteError::Volume(_)) = result {
+        return result;
+    }
+    vol.update_percent_in_use()?;
+    vol.set_dirty(false)?;
+    result
+}
+
+/* Create or replace the file at `path` with `data`. The new clusters are
+ * filled before the entry points at them, the old ones freed last */
+pub fn write_file<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    path: &[u8],
+    data: &[u8],
+) -> Result<(), WriteError> {
+    let mut units = [0u16; NAME_MAX_UNITS];
+    modify(vol, |vol| {
+        let (mut dir, name) = resolve(vol, cwd, path)?;
+        let len = name_units(name, &mut units)?;
+        let parent = dir.current(vol);
+        let existing = find_entry(vol, &parent, name)?;
+        if existing.is_some_and(|e| e.is_dir()) {
+            return Err(WriteError::IsDirectory);
+        }
+
+        let mut stream = allocate(vol, (data.len() as u64).div_ceil(vol.cluster_size) as u32)?;
+        stream.size = data.len() as u64;
+        vol.write_stream(&stream, &mut (0, 0), 0, data)?;
+
+        match existing {
+            Some(old) => {
+                let (mut set, count) = read_entry_set(vol, &parent, old.index)?;
+                set_entry_stream(&mut set[1], &stream, stream.size);
+                let stamp = now_stamp();
+                u32_to_u8_le(stamp, &mut set[0][12..16]);
+                u32_to_u8_le(stamp, &mut set[0][16..20]);
+                write_entry_set(vol, &parent, old.index, &mut set, count)?;
+                release(vol, &old.stream)?;
+            }
+            None => create_entry(vol, &mut dir, &units[..len], ATTR_ARCHIVE, &stream, stream.size)?,
+        }
+        Ok(())
+    })
+}
+
+/* Create an empty directory at `path`, one zeroed cluster */
+pub fn make_dir<D: BlockDevice>(vol: &mut ExfatVolume<D>, cwd: &ExfatPath, path: &[u8]) -> Result<(), WriteError> {
+    let mut units = [0u16; NAME_MAX_UNITS];
+    modify(vol, |vol| {
+        let (mut dir, name) = resolve(vol, cwd, path)?;
+        let len = name_units(name, &mut units)?;
+        let parent = dir.current(vol);
+        if find_entry(vol, &parent, name)?.is_some() {
+            return Err(WriteError::NameTaken);
+        }
+        let stream = allocate(vol, 1)?;
+        zero_stream(vol, &stream, 0, stream.size)?;
+        create_entry(vol, &mut dir, &units[..len], ATTR_DIRECTORY, &stream, stream.size)
+    })
+}
+
+/* Entry at `path` and the directory holding it */
+fn find_path<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    path: &[u8],
+) -> Result<(ExfatPath, ExfatEntry), WriteError> {
+    let (dir, name) = resolve(vol, cwd, path)?;
+    let parent = dir.current(vol);
+    let entry = find_entry(vol, &parent, name)?.ok_or(WriteError::NotFound)?;
+    Ok((dir, entry))
+}
+
+/* Delete the file or empty directory at `path` */
+pub fn remove<D: BlockDevice>(vol: &mut ExfatVolume<D>, cwd: &ExfatPath, path: &[u8]) -> Result<(), WriteError> {
+    modify(vol, |vol| {
+        let (dir, entry) = find_path(vol, cwd, path)?;
+        if entry.is_dir() && iterate_dir(vol, &entry.stream, |_| Some(()))?.is_some() {
+            return Err(WriteError::NotEmpty);
+        }
+        let parent = dir.current(vol);
+        let (_, count) = read_entry_set(vol, &parent, entry.index)?;
+        delete_entries(vol, &parent, entry.index, count as u64)?;
+        release(vol, &entry.stream)?;
+        Ok(())
+    })
+}
+
+/* Rename the entry at `path` to `new_name` in the same directory. The set
+ * stays in place when the new name needs no more entries, otherwise it
+ * moves to free ones */
+pub fn rename<D: BlockDevice>(
+    vol: &mut ExfatVolume<D>,
+    cwd: &ExfatPath,
+    path: &[u8],
+    new_name: &[u8],
+) -> Result<(), WriteError> {
+    let mut units = [0u16; NAME_MAX_UNITS];
+    modify(vol, |vol| {
+        let len = name_units(new_name, &mut units)?;
+        let (mut dir, entry) = find_path(vol, cwd, path)?;
+        let parent = dir.current(vol);
+        /* Changing only the case of a name finds the entry itself */
+        if find_entry(vol, &parent, new_name)?.is_some_and(|other| other.index != entry.index) {
+            return Err(WriteError::NameTaken);
+        }
+
+        let (mut set, old_count) = read_entry_set(vol, &parent, entry.index)?;
+        let count = fill_entry_set_name(&mut set, &units[..len], vol.upcase);
+        if count <= old_count {
+            write_entry_set(vol, &parent, entry.index, &mut set, count)?;
+            delete_entries(vol, &parent, entry.index + count as u64, (old_count - count) as u64)?;
+        } else {
+            let index = reserve_entries(vol, &mut dir, count)?;
+            let parent = dir.current(vol);
+            write_entry_set(vol, &parent, index, &mut set, count)?;
+            delete_entries(vol, &parent, entry.index, old_count as u64)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::exfat::dir::{open_file, read_file};
+    use crate::exfat::fixture::ExfatBuilder;
+    use crate::exfat::upcase::UPCASE_ENTRIES;
+    use crate::fixture::Node;
+    use crate::walk::Path;
+    use std::vec::Vec;
+    use std::{format, vec};
+
+    fn cat(vol: &mut ExfatVolume<MemDevice>, path: &[u8]) -> Option<Vec<u8>> {
+        let entry = open_file(vol, &ExfatPath::new(), path).unwrap()?;
+        let mut out = Vec::new();
+        read_file(vol, &entry, |b| out.extend_from_slice(b)).unwrap();
+        Some(out)
+    }
+
+    fn names(vol: &mut ExfatVolume<MemDevice>, dir: &Stream) -> Vec<std::string::String> {
+        let mut names = Vec::new();
+        iterate_dir(vol, dir, |entry| {
+            names.push(std::string::String::from_utf16(entry.name()).unwrap());
+            None::<()>
+        })
+        .unwrap();
+        names
+    }
+
+    #[test]
+    fn written_files_read_back_and_survive_a_remount() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[Node::Dir("docs", &[])]);
+        let big: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let free = {
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            let free = vol.free_clusters().unwrap();
+            let root = ExfatPath::new();
+            write_file(&mut vol, &root, b"docs/big.bin", &big).unwrap();
+            write_file(&mut vol, &root, "Été.txt".as_bytes(), b"hello").unwrap();
+            write_file(&mut vol, &root, b"empty", b"").unwrap();
+            assert_eq!(vol.free_clusters().unwrap(), free - 4);
+            free
+        };
+        assert_eq!(data[106] & 0x02, 0);
+
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        assert_eq!(cat(&mut vol, b"DOCS/BIG.BIN"), Some(big));
+        assert_eq!(cat(&mut vol, "éTÉ.txt".as_bytes()), Some(b"hello".to_vec()));
+        assert_eq!(cat(&mut vol, b"empty"), Some(Vec::new()));
+
+        /* Replacing gives the old clusters back */
+        let root = ExfatPath::new();
+        write_file(&mut vol, &root, b"docs/big.bin", b"small").unwrap();
+        assert_eq!(cat(&mut vol, b"docs/big.bin"), Some(b"small".to_vec()));
+        assert_eq!(vol.free_clusters().unwrap(), free - 2);
+        assert_eq!(write_file(&mut vol, &root, b"docs", b"x"), Err(WriteError::IsDirectory));
+        assert_eq!(write_file(&mut vol, &root, b"nowhere/file", b"x"), Err(WriteError::NotFound));
+        assert_eq!(write_file(&mut vol, &root, b"a:b", b"x"), Err(WriteError::InvalidName));
+    }
+
+    #[test]
+    fn fragmented_free_space_gives_a_fat_chain() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let cluster = vol.cluster_size as usize;
+        let root = ExfatPath::new();
+        /* Ten one cluster files and the rest of the volume in one, then
+         * every other small one deleted: no two free clusters in a row */
+        for i in 0..10 {
+            write_file(&mut vol, &root, format!("f{i}").as_bytes(), b"x").unwrap();
+        }
+        let rest = vec![0u8; cluster * vol.free_clusters().unwrap() as usize];
+        write_file(&mut vol, &root, b"rest", &rest).unwrap();
+        assert_eq!(vol.free_clusters().unwrap(), 0);
+        for i in (0..10).step_by(2) {
+            remove(&mut vol, &root, format!("f{i}").as_bytes()).unwrap();
+        }
+        let content: Vec<u8> = (0..cluster * 3).map(|i| i as u8).collect();
+        write_file(&mut vol, &root, b"spread", &content).unwrap();
+        let entry = open_file(&mut vol, &root, b"spread").unwrap().unwrap();
+        assert!(!entry.stream.contiguous);
+        assert_eq!(cat(&mut vol, b"spread"), Some(content));
+
+        let too_big = vec![0u8; cluster * vol.free_clusters().unwrap() as usize + 1];
+        assert_eq!(write_file(&mut vol, &root, b"more", &too_big), Err(WriteError::NoSpace));
+    }
+
+    #[test]
+    fn directories_grow_and_are_removed_once_empty() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let root = ExfatPath::new();
+        make_dir(&mut vol, &root, b"Sub").unwrap();
+        assert_eq!(make_dir(&mut vol, &root, b"sub"), Err(WriteError::NameTaken));
+
+        /* 3 entries a set, 128 entries a cluster: the directory needs more */
+        for i in 0..100 {
+            write_file(&mut vol, &root, format!("sub/file {i:03}").as_bytes(), b"").unwrap();
+        }
+        let mut sub = ExfatPath::new();
+        assert!(change_dir(&mut vol, &mut sub, b"sub").unwrap());
+        let dir = sub.current(&vol);
+        assert_eq!(dir.size, 3 * vol.cluster_size);
+        assert_eq!(names(&mut vol, &dir).len(), 100);
+
+        assert_eq!(remove(&mut vol, &root, b"sub"), Err(WriteError::NotEmpty));
+        for i in 0..100 {
+            remove(&mut vol, &root, format!("sub/FILE {i:03}").as_bytes()).unwrap();
+        }
+        let free = vol.free_clusters().unwrap();
+        remove(&mut vol, &root, b"sub").unwrap();
+        assert_eq!(vol.free_clusters().unwrap(), free + 3);
+        let root_dir = vol.root;
+        assert!(names(&mut vol, &root_dir).is_empty());
+        assert_eq!(remove(&mut vol, &root, b"sub"), Err(WriteError::NotFound));
+
+        /* A stale path goes back to what still exists */
+        sub.refresh(&mut vol).unwrap();
+        let mut path = Path::new();
+        sub.path(&mut vol, &mut path).unwrap();
+        assert_eq!(path.as_bytes(), b"/");
+    }
+
+    #[test]
+    fn the_root_grows_over_its_fat_chain() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        {
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            let root = ExfatPath::new();
+            for i in 0..60 {
+                make_dir(&mut vol, &root, format!("dir {i}").as_bytes()).unwrap();
+            }
+        }
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        assert_eq!(vol.root.size, 2 * vol.cluster_size);
+        let root = vol.root;
+        assert_eq!(names(&mut vol, &root).len(), 60);
+    }
+
+    #[test]
+    fn rename_in_place_or_to_new_entries() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let tree = [Node::File("a.txt", b"a"), Node::File("b.txt", b"b")];
+        for builder in [ExfatBuilder::new(), ExfatBuilder::new().fat_chains()] {
+            let mut data = builder.build(&tree);
+            let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+            let root = ExfatPath::new();
+            rename(&mut vol, &root, b"a.txt", b"A.TXT").unwrap();
+            assert_eq!(rename(&mut vol, &root, b"a.txt", b"B.txt"), Err(WriteError::NameTaken));
+            assert_eq!(rename(&mut vol, &root, b"a.txt", b"x/y"), Err(WriteError::InvalidName));
+
+            let long = "a name long enough for three name entries.txt";
+            rename(&mut vol, &root, b"b.txt", long.as_bytes()).unwrap();
+            let dir = vol.root;
+            assert_eq!(names(&mut vol, &dir), vec!["A.TXT", long]);
+            assert_eq!(cat(&mut vol, long.as_bytes()), Some(b"b".to_vec()));
+            assert_eq!(cat(&mut vol, b"b.txt"), None);
+
+            rename(&mut vol, &root, long.as_bytes(), b"c").unwrap();
+            assert_eq!(names(&mut vol, &dir), vec!["A.TXT", "c"]);
+            assert_eq!(cat(&mut vol, b"C"), Some(b"b".to_vec()));
+        }
+    }
+
+    #[test]
+    fn contiguous_streams_become_chains_when_they_cannot_extend() {
+        let mut upcase = [0u16; UPCASE_ENTRIES];
+        let mut data = ExfatBuilder::new().build(&[]);
+        let mut vol = ExfatVolume::mount(MemDevice { data: &mut data }, &mut upcase).unwrap();
+        let root = ExfatPath::new();
+        make_dir(&mut vol, &root, b"d").unwrap();
+        /* Takes the cluster right after the directory */
+        write_file(&mut vol, &root, b"blocker", b"x").unwrap();
+        for i in 0..50 {
+            write_file(&mut vol, &root, format!("d/{i}").as_bytes(), b"").unwrap();
+        }
+        let entry = open_file(&mut vol, &root, b"blocker").unwrap().unwrap();
+        let root_dir = vol.root;
+        let d = find_entry(&mut vol, &root_dir, b"d").unwrap().unwrap();
+        assert!(!d.stream.contiguous);
+        assert_eq!(d.stream.size, 2 * vol.cluster_size);
+        assert_eq!(vol.fat_entry(d.stream.first_cluster).unwrap(), entry.stream.first_cluster + 1);
+        assert_eq!(names(&mut vol, &d.stream).len(), 50);
+    }
+}
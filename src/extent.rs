@@ -0,0 +1,336 @@
+use crate::volume::VolumeError;
+
+/* Runs mapped at once, a file more fragmented than that is mapped one
+ * window of runs at a time */
+pub const MAX_EXTENTS: usize = 64;
+/* Window starts remembered, further apart as the file gets longer */
+const MAX_MARKS: usize = 64;
+const END_OF_CHAIN: u32 = 0x0FFFFFF8;
+
+/* `count` contiguous clusters from `cluster`, holding the clusters of the
+ * file from index `index` on */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extent {
+    pub index: u32,
+    pub cluster: u32,
+    pub count: u32,
+}
+
+/* Where window number `window` of a map starts in the chain */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Mark {
+    cluster: u32,
+    index: u32,
+    window: u32,
+}
+
+/* Cluster chain of a file as runs of contiguous clusters, built lazily as
+ * the file is read. Finding the cluster of an offset is a binary search
+ * over the window instead of a walk along the chain. A seek behind the
+ * window goes back to the closest mark before it, not to the first
+ * cluster */
+pub struct ExtentMap {
+    first_cluster: u32,
+    /* Clusters the file size calls for, the chain isn't followed further */
+    clusters: u32,
+    clusters_count: u32,
+    extents: [Extent; MAX_EXTENTS],
+    len: usize,
+    /* Cluster and file index the chain goes on with after the window, None
+     * once the chain ended inside it */
+    resume: Option<(u32, u32)>,
+    /* Number of the window `resume` starts */
+    next_window: u32,
+    /* Start of every `stride`th window met so far, in chain order */
+    marks: [Mark; MAX_MARKS],
+    marks_len: usize,
+    stride: u32,
+}
+
+impl ExtentMap {
+    /* `clusters_count` data clusters on the volume bound the chain */
+    pub fn new(first_cluster: u32, clusters: u32, clusters_count: u32) -> Self {
+        ExtentMap {
+            first_cluster,
+            clusters,
+            clusters_count,
+            extents: [Extent::default(); MAX_EXTENTS],
+            len: 0,
+            resume: if clusters > 0 { Some((first_cluster, 0)) } else { None },
+            next_window: 0,
+            marks: [Mark::default(); MAX_MARKS],
+            marks_len: 0,
+            stride: 1,
+        }
+    }
+
+    /* Runs of the current window */
+    pub fn extents(&self) -> &[Extent] {
+        &self.extents[..self.len]
+    }
+
+    /* Disk cluster of file cluster `index` and how many clusters follow it
+     * on disk (itself included), None past the end of the file. `next`
+     * gives the FAT entry of a cluster */
+    pub fn locate<F>(&mut self, index: u32, mut next: F) -> Result<Option<(u32, u32)>, VolumeError>
+    where
+        F: FnMut(u32) -> Result<u32, VolumeError>,
+    {
+        if index >= self.clusters {
+            return Ok(None);
+        }
+        if self.len > 0 && index < self.extents[0].index {
+            /* Behind the window, the chain only goes forward: from the
+             * last mark before `index` on */
+            let mark = self.marks[..self.marks_len]
+                .iter()
+                .rev()
+                .find(|mark| mark.index <= index)
+                .copied()
+                .unwrap_or(Mark {
+                    cluster: self.first_cluster,
+                    index: 0,
+                    window: 0,
+                });
+            self.len = 0;
+            self.resume = Some((mark.cluster, mark.index));
+            self.next_window = mark.window;
+        }
+        loop {
+            if let Some(extent) = self.find(index) {
+                let skip = index - extent.index;
+                return Ok(Some((extent.cluster + skip, extent.count - skip)));
+            }
+            let Some((cluster, at)) = self.resume else {
+                return Ok(None);
+            };
+            self.fill(cluster, at, &mut next)?;
+        }
+    }
+
+    fn find(&self, index: u32) -> Option<Extent> {
+        let extents = self.extents();
+        let i = extents.partition_point(|e| e.index + e.count <= index);
+        extents.get(i).filter(|e| e.index <= index).copied()
+    }
+
+    fn overlaps(&self, cluster: u32) -> bool {
+        self.extents().iter().any(|e| cluster >= e.cluster && cluster - e.cluster < e.count)
+    }
+
+    /* Remember where window `window` starts when it is one of every
+     * `stride`. Once full every other mark goes and `stride` doubles */
+    fn mark(&mut self, cluster: u32, index: u32, window: u32) {
+        let seen = self.marks[..self.marks_len].last().is_some_and(|last| last.window >= window);
+        if seen || !window.is_multiple_of(self.stride) {
+            return;
+        }
+        if self.marks_len == MAX_MARKS {
+            for i in 0..MAX_MARKS / 2 {
+                self.marks[i] = self.marks[2 * i];
+            }
+            self.marks_len = MAX_MARKS / 2;
+            self.stride *= 2;
+            if !window.is_multiple_of(self.stride) {
+                return;
+            }
+        }
+        self.marks[self.marks_len] = Mark { cluster, index, window };
+        self.marks_len += 1;
+    }
+
+    /* Replace the window with the runs from `cluster`, file cluster `index` */
+    fn fill<F>(&mut self, mut cluster: u32, mut index: u32, next: &mut F) -> Result<(), VolumeError>
+    where
+        F: FnMut(u32) -> Result<u32, VolumeError>,
+    {
+        self.mark(cluster, index, self.next_window);
+        self.len = 0;
+        self.resume = None;
+        loop {
+            if cluster < 2 || cluster - 2 >= self.clusters_count {
+                return Err(VolumeError::Corrupt);
+            }
+            /* Met again within the window: a looping chain */
+            if self.overlaps(cluster) {
+                return Err(VolumeError::Corrupt);
+            }
+            let len = self.len;
+            match self.extents[..len].last_mut() {
+                Some(last) if last.cluster + last.count == cluster => last.count += 1,
+                _ if len == MAX_EXTENTS => {
+                    self.resume = Some((cluster, index));
+                    self.next_window += 1;
+                    return Ok(());
+                }
+                _ => {
+                    self.extents[len] = Extent { index, cluster, count: 1 };
+                    self.len += 1;
+                }
+            }
+
+            index += 1;
+            if index == self.clusters {
+                return Ok(());
+            }
+            let following = next(cluster)?;
+            /* The chain ends before the size does */
+            if following >= END_OF_CHAIN {
+                return Err(VolumeError::Corrupt);
+            }
+            cluster = following;
+        }
+    }
+}
+
+/* A file opened for reading: where its data region is and its extent map,
+ * kept for as long as the file is read */
+pub struct OpenFile {
+    pub size: u64,
+    cluster_size: u64,
+    data_start: u64,
+    map: ExtentMap,
+}
+
+impl OpenFile {
+    /* Corrupt when `size` is more than the whole volume holds */
+    pub fn new(
+        first_cluster: u32,
+        size: u64,
+        cluster_size: u64,
+        data_start: u64,
+        clusters_count: u32,
+    ) -> Result<Self, VolumeError> {
+        if cluster_size == 0 {
+            return Err(VolumeError::InvalidBootSector);
+        }
+        let clusters = size.div_ceil(cluster_size);
+        if clusters > clusters_count as u64 {
+            return Err(VolumeError::Corrupt);
+        }
+        Ok(OpenFile {
+            size,
+            cluster_size,
+            data_start,
+            map: ExtentMap::new(first_cluster, clusters as u32, clusters_count),
+        })
+    }
+
+    /* Device offset and length of the bytes from `pos` that lie in one run
+     * of contiguous clusters, `max` at most. None at the end of the file */
+    pub fn run_at<F>(&mut self, pos: u64, max: usize, next: F) -> Result<Option<(u64, usize)>, VolumeError>
+    where
+        F: FnMut(u32) -> Result<u32, VolumeError>,
+    {
+        if pos >= self.size || max == 0 {
+            return Ok(None);
+        }
+        let index = (pos / self.cluster_size) as u32;
+        let Some((cluster, run)) = self.map.locate(index, next)? else {
+            return Ok(None);
+        };
+        let in_cluster = pos % self.cluster_size;
+        let run_bytes = run as u64 * self.cluster_size - in_cluster;
+        let len = (max as u64).min(run_bytes).min(self.size - pos) as usize;
+        let offset = self.data_start + (cluster as u64 - 2) * self.cluster_size + in_cluster;
+        Ok(Some((offset, len)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /* FAT lookups from (cluster, next) links, a missing one ends the chain */
+    fn chain(links: &[(u32, u32)]) -> impl FnMut(u32) -> Result<u32, VolumeError> + '_ {
+        |cluster| {
+            Ok(links
+                .iter()
+                .find(|&&(from, _)| from == cluster)
+                .map(|&(_, to)| to)
+                .unwrap_or(0x0FFFFFFF))
+        }
+    }
+
+    #[test]
+    fn groups_contiguous_clusters() {
+        let links = [(2, 3), (3, 4), (4, 9), (9, 10), (10, 5)];
+        let mut map = ExtentMap::new(2, 6, 100);
+        assert_eq!(map.locate(4, chain(&links)).unwrap(), Some((10, 1)));
+        assert_eq!(
+            map.extents(),
+            &[
+                Extent { index: 0, cluster: 2, count: 3 },
+                Extent { index: 3, cluster: 9, count: 2 },
+                Extent { index: 5, cluster: 5, count: 1 },
+            ]
+        );
+        assert_eq!(map.locate(1, chain(&links)).unwrap(), Some((3, 2)));
+        assert_eq!(map.locate(6, chain(&links)).unwrap(), None);
+
+        /* A chain shorter than the size */
+        let mut map = ExtentMap::new(2, 10, 100);
+        assert_eq!(map.locate(7, chain(&links)), Err(VolumeError::Corrupt));
+    }
+
+    #[test]
+    fn slides_its_window_over_long_chains() {
+        /* Every other cluster: one run per cluster */
+        let count = MAX_EXTENTS as u32 * 3;
+        let links: Vec<(u32, u32)> = (0..count - 1).map(|i| (2 + 2 * i, 4 + 2 * i)).collect();
+        let mut map = ExtentMap::new(2, count, 1000);
+        assert_eq!(map.locate(count - 1, chain(&links)).unwrap(), Some((2 * count, 1)));
+        assert_eq!(map.extents()[0].index, 2 * MAX_EXTENTS as u32);
+        assert_eq!(map.locate(1, chain(&links)).unwrap(), Some((4, 1)));
+        assert_eq!(map.extents()[0].index, 0);
+    }
+
+    #[test]
+    fn seeks_back_from_the_closest_mark() {
+        /* Every other cluster over 200 windows, marks every 4th window by then */
+        let count = MAX_EXTENTS as u32 * 200;
+        let mut map = ExtentMap::new(2, count, 4 * count);
+        assert_eq!(map.locate(count - 1, |cluster| Ok(cluster + 2)).unwrap(), Some((2 * count, 1)));
+
+        let mut calls = 0;
+        let index = 150 * MAX_EXTENTS as u32 + 5;
+        let located = map.locate(index, |cluster| {
+            calls += 1;
+            Ok(cluster + 2)
+        });
+        assert_eq!(located.unwrap(), Some((2 + 2 * index, 1)));
+        assert_eq!(map.extents()[0].index, 150 * MAX_EXTENTS as u32);
+        assert!(calls <= 4 * MAX_EXTENTS, "{calls} FAT lookups");
+    }
+
+    #[test]
+    fn rejects_loops_and_clusters_outside_the_volume() {
+        let mut map = ExtentMap::new(2, 10, 100);
+        assert_eq!(map.locate(5, chain(&[(2, 3), (3, 2)])), Err(VolumeError::Corrupt));
+        let mut map = ExtentMap::new(2, 10, 100);
+        assert_eq!(map.locate(5, chain(&[(2, 500)])), Err(VolumeError::Corrupt));
+        let mut map = ExtentMap::new(1, 1, 100);
+        assert_eq!(map.locate(0, chain(&[])), Err(VolumeError::Corrupt));
+        assert_eq!(OpenFile::new(2, 101 * 512, 512, 0, 100).err(), Some(VolumeError::Corrupt));
+    }
+
+    #[test]
+    fn hands_out_whole_runs() {
+        /* Clusters of 16 bytes, the data region at 1000 */
+        let links = [(2, 3), (3, 4), (4, 10), (10, 11)];
+        let mut file = OpenFile::new(2, 70, 16, 1000, 62).unwrap();
+        let mut runs = Vec::new();
+        let mut pos = 0;
+        while let Some((offset, len)) = file.run_at(pos, 100, chain(&links)).unwrap() {
+            runs.push((offset, len));
+            pos += len as u64;
+        }
+        assert_eq!(runs, [(1000, 48), (1128, 22)]);
+
+        /* From the middle of a cluster, and cut to `max` */
+        assert_eq!(file.run_at(44, 100, chain(&links)).unwrap(), Some((1044, 4)));
+        assert_eq!(file.run_at(5, 10, chain(&links)).unwrap(), Some((1005, 10)));
+        assert_eq!(file.run_at(70, 100, chain(&links)).unwrap(), None);
+    }
+}
@@ -1,78 +1,165 @@
-use crate::boot_sector::BootSector;
-use crate::cli::{print, print_line, print_ls, reset_cli};
+use crate::boot_sector::{BootSector, FatType};
+use crate::cli::complete::Candidates;
+use crate::cli::{print, print_line, print_ls, print_no_ln, reset_cli};
+use crate::device::{BlockDevice, DeviceError};
+use crate::dir_entry::{
+    ATTR_DIRECTORY, LFN_LAST_ENTRY, LFN_MAX_ENTRIES, LFN_MAX_UNITS, LfnBuilder, is_lfn_entry, lfn_to_utf8,
+};
+use crate::extent::OpenFile;
+use crate::lookup::{CachedEntry, LookupCache};
 use crate::helpers::{to_lowercase_ascii, u8_le_to_u16, u8_to_u32_le};
-use crate::sys::{print_bytes, read_at};
+use crate::sys::print_bytes;
+use crate::volume::{VolumeError, entry_offset, widen_entry};
+use crate::tree::{TreeEntry, TreeSource};
+use crate::walk::{MAX_DEPTH, MAX_PATH_LEN, Path};
+use core::ops::ControlFlow;
 
-const FAT_MAX_SIZE: usize = 65536;
 const CLUSTER_MAX_SIZE: usize = 65536;
+/* Past half the sector cache, so file data goes around it */
+pub const READ_CHUNK_SIZE: usize = 256 * 1024;
+/* Long name in UTF-8, the longest path component matched */
+pub const LONG_NAME_MAX: usize = 4 * LFN_MAX_UNITS;
 
 fn fat_entry(fat_buf: &[u8], cluster: u32) -> u32 {
-    let off = (cluster as usize) * 4;
-    if off + 4 > fat_buf.len() {
-        return 0x0FFFFFFF;
-    }
+    let off = match (cluster as usize).checked_mul(4) {
+        Some(off) if off <= fat_buf.len().saturating_sub(4) => off,
+        _ => return 0x0FFFFFFF,
+    };
     let v = u8_to_u32_le(&fat_buf[off..off + 4]);
     v & 0x0FFFFFFF
 }
 
+/* Same for any FAT type, FAT12/16 end of chain and bad marks come out as
+ * the FAT32 ones */
+fn typed_fat_entry(fat_type: FatType, fat_buf: &[u8], cluster: u32) -> u32 {
+    let cluster = cluster as usize;
+    let off = match fat_type {
+        FatType::Fat12 => cluster + cluster / 2,
+        FatType::Fat16 => cluster * 2,
+        FatType::Fat32 => return fat_entry(fat_buf, cluster as u32),
+    };
+    if off + 2 > fat_buf.len() {
+        return 0x0FFFFFFF;
+    }
+    let v = u8_le_to_u16(&fat_buf[off..off + 2]) as u32;
+    let v = match fat_type {
+        FatType::Fat12 if cluster & 1 == 1 => v >> 4,
+        FatType::Fat12 => v & 0x0FFF,
+        _ => v,
+    };
+    widen_entry(fat_type, v)
+}
+
 fn is_end_cluster(cluster: u32) -> bool {
     cluster >= 0x0FFFFFF8
 }
 
-fn read_fat_into(fd: usize, bs: &BootSector, fat_start: usize, fat_buf: &mut [u8]) -> isize {
-    let bytes_per_sector = bs.bytes_per_sector as usize;
-    let fat_size_bytes = bs.fat_size_sectors as usize * bytes_per_sector;
-    let read_size = core::cmp::min(fat_buf.len(), fat_size_bytes);
-    /// Safety: calls into the platform-specific `read_at` syscall wrapper with
-    /// a raw buffer pointer. The caller ensures `fat_buf` is large enough for
-    /// `read_size` bytes and `fat_start` is a valid offset within the image.
-    // SAFETY: `fat_buf.as_mut_ptr()` is valid for `read_size` bytes; `fd` and
-    // `fat_start` are assumed valid by caller.
-    unsafe { read_at(fd, fat_buf.as_mut_ptr(), read_size, fat_start) }
+/* Bytes per cluster, None when the BPB gives nonsense */
+fn cluster_size(bs: &BootSector) -> Option<usize> {
+    let size = bs.bytes_per_sector as usize * bs.sectors_per_cluster as usize;
+    if size == 0 || size > CLUSTER_MAX_SIZE {
+        None
+    } else {
+        Some(size)
+    }
 }
 
-fn read_cluster_into(
-    fd: usize,
-    bs: &BootSector,
-    data_start: usize,
-    cluster: u32,
-    buf: &mut [u8],
-) -> isize {
-    let bytes_per_sector = bs.bytes_per_sector as usize;
-    let sectors_per_cluster = bs.sectors_per_cluster as usize;
-    let cluster_size = bytes_per_sector * sectors_per_cluster;
-    if cluster < 2 {
-        return -1;
-    }
-    if cluster_size > buf.len() {
-        /* buffer provided is too small for a full cluster */
-        return -1;
+/* Byte offsets of the first FAT and of the data region, the fixed root
+ * directory of FAT12/16 sits right before the latter */
+pub fn fat_regions(bs: &BootSector) -> (usize, usize) {
+    let bps = bs.bytes_per_sector as u64;
+    let fat_start = bs.reserved_sectors_count as u64 * bps;
+    let data_start = bs.system_sectors() * bps;
+    (fat_start as usize, data_start as usize)
+}
+
+/* Number of data clusters, valid cluster numbers are 2..clusters_count + 2 */
+fn clusters_count(bs: &BootSector) -> u32 {
+    let fat_bytes = bs.fat_size_sectors as u64 * bs.bytes_per_sector as u64;
+    let fat_entries = match bs.fat_type {
+        FatType::Fat12 => fat_bytes * 2 / 3,
+        FatType::Fat16 => fat_bytes / 2,
+        FatType::Fat32 => fat_bytes / 4,
+    };
+    let fat_clusters = fat_entries.saturating_sub(2);
+    core::cmp::min(core::cmp::min(bs.data_clusters(), fat_clusters), 0x0FFFFFF5) as u32
+}
+
+/* Guards a walk along a cluster chain: every cluster entered must be in
+ * the data region, and a chain can't hold more clusters than the volume
+ * has, so a looping chain ends in an error whatever the size of the FAT */
+struct ChainGuard {
+    steps: u32,
+    clusters_count: u32,
+}
+
+impl ChainGuard {
+    fn new(bs: &BootSector) -> Self {
+        ChainGuard {
+            steps: 0,
+            clusters_count: clusters_count(bs),
+        }
     }
 
-    let read_size = cluster_size;
+    fn enter(&mut self, cluster: u32) -> Result<(), VolumeError> {
+        if cluster < 2 || cluster - 2 >= self.clusters_count {
+            return Err(VolumeError::Corrupt);
+        }
+        if self.steps == self.clusters_count {
+            return Err(VolumeError::Corrupt);
+        }
+        self.steps += 1;
+        Ok(())
+    }
+}
 
-    let cluster_index = match (cluster as usize).checked_sub(2) {
-        Some(v) => v,
-        None => return -1,
+/* Entry of `cluster` in the first FAT, read on demand through the
+ * device's sector cache. The entries of an even and odd cluster pair are
+ * read together, so a FAT12 entry never needs a second read */
+fn next_cluster<D: BlockDevice>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    cluster: u32,
+) -> Result<u32, VolumeError> {
+    let pair = cluster & !1;
+    let len = match bs.fat_type {
+        FatType::Fat12 => 3,
+        FatType::Fat16 => 4,
+        FatType::Fat32 => 8,
     };
+    let mut window = [0u8; 8];
+    dev.read_at(fat_start as u64 + entry_offset(bs.fat_type, pair), &mut window[..len])?;
+    Ok(typed_fat_entry(bs.fat_type, &window[..len], cluster - pair))
+}
 
-    let offset = match cluster_index.checked_mul(cluster_size) {
-        Some(v) => match data_start.checked_add(v) {
-            Some(o) => o,
-            None => return -1,
-        },
-        None => return -1,
-    };
+fn read_cluster_into<D: BlockDevice>(
+    dev: &mut D,
+    bs: &BootSector,
+    data_start: u64,
+    cluster: u32,
+    buf: &mut [u8],
+) -> Result<(), VolumeError> {
+    let cluster_size = cluster_size(bs).ok_or(VolumeError::InvalidBootSector)?;
+    if cluster < 2 || cluster_size > buf.len() {
+        return Err(VolumeError::Corrupt);
+    }
 
-    /// Safety: call to platform-specific `read_at` using raw pointer `buf`.
-    ///
-    /// The function checks that `cluster_size` fits into `buf.len()` before
-    /// invoking the syscall, ensuring the buffer is large enough.
-    // SAFETY: `buf.as_mut_ptr()` points to `buf` and is valid for `read_size` bytes.
-    unsafe { read_at(fd, buf.as_mut_ptr(), read_size, offset) }
+    let offset = cluster_base(data_start, cluster, cluster_size)?;
+    dev.read_at(offset, &mut buf[..cluster_size])?;
+    Ok(())
 }
 
-fn build_short_name(name: &[u8], ext: &[u8], out: &mut [u8]) -> usize {
+/* Device offset of `cluster` */
+fn cluster_base(data_start: u64, cluster: u32, cluster_size: usize) -> Result<u64, VolumeError> {
+    (cluster as u64)
+        .checked_sub(2)
+        .and_then(|v| v.checked_mul(cluster_size as u64))
+        .and_then(|v| v.checked_add(data_start))
+        .ok_or(VolumeError::Corrupt)
+}
+
+pub fn build_short_name(name: &[u8], ext: &[u8], out: &mut [u8]) -> usize {
     let mut idx = 0usize;
 
     /* Trim name trailing spaces */
@@ -81,11 +168,11 @@ fn build_short_name(name: &[u8], ext: &[u8], out: &mut [u8]) -> usize {
         name_end -= 1;
     }
 
-    for j in 0..name_end {
+    for &c in &name[..name_end] {
         if idx >= out.len() {
             break;
         }
-        out[idx] = name[j];
+        out[idx] = c;
         idx += 1;
     }
 
@@ -100,11 +187,11 @@ fn build_short_name(name: &[u8], ext: &[u8], out: &mut [u8]) -> usize {
             out[idx] = b'.';
             idx += 1;
         }
-        for j in 0..ext_end {
+        for &c in &ext[..ext_end] {
             if idx >= out.len() {
                 break;
             }
-            out[idx] = ext[j];
+            out[idx] = c;
             idx += 1;
         }
     }
@@ -112,105 +199,172 @@ fn build_short_name(name: &[u8], ext: &[u8], out: &mut [u8]) -> usize {
     idx
 }
 
-fn iterate_dir_entries<R, F>(
-    fd: usize,
-    bs: &BootSector,
-    fat_start: usize,
-    data_start: usize,
-    start_cluster: u32,
-    mut cb: F,
-) -> Option<R>
+/* Hand the live short entries of `block` and their device offsets to `cb`,
+ * the LFN entries before them too with `with_lfn`. Breaks with its result
+ * or with None on the end of directory marker. `block` was read at `base` */
+fn visit_entries<R, F>(block: &[u8], base: u64, with_lfn: bool, cb: &mut F) -> ControlFlow<Option<R>>
 where
-    F: FnMut(&[u8], bool) -> Option<R>,
+    F: FnMut(&[u8], bool, u64) -> Option<R>,
 {
-    let bytes_per_sector = bs.bytes_per_sector as usize;
-    let sectors_per_cluster = bs.sectors_per_cluster as usize;
-    let cluster_size = bytes_per_sector * sectors_per_cluster;
+    let entries = block.len() / 32;
 
-    if cluster_size == 0 || cluster_size > CLUSTER_MAX_SIZE {
-        return None;
-    }
+    for i in 0..entries {
+        let off = i * 32;
+        let entry = &block[off..off + 32];
 
-    if start_cluster < 2 {
-        return None;
-    }
+        let first = entry[0];
+        if first == 0x00 {
+            /* No more entries in this directory */
+            return ControlFlow::Break(None);
+        }
+        if first == 0xE5 {
+            /* Deleted */
+            continue;
+        }
 
-    let fat_size_bytes = bs.fat_size_sectors as usize * bytes_per_sector;
-    let fat_buf_size = core::cmp::min(FAT_MAX_SIZE, fat_size_bytes);
+        let attr = entry[11];
+        if (attr & 0x0F) == 0x0F {
+            /* LFN entry */
+            if with_lfn && let Some(res) = cb(entry, false, base + off as u64) {
+                return ControlFlow::Break(Some(res));
+            }
+            continue;
+        }
+        if (attr & 0x08) != 0 {
+            /* Volume id */
+            continue;
+        }
 
-    let mut fat_buf = [0u8; FAT_MAX_SIZE];
+        let last = i == entries - 1;
 
-    let r = read_fat_into(fd, bs, fat_start, &mut fat_buf[..fat_buf_size]);
-    if r < 0 || r as usize != fat_buf_size {
-        return None;
+        if let Some(res) = cb(entry, last, base + off as u64) {
+            return ControlFlow::Break(Some(res));
+        }
     }
 
-    let mut cluster_buf = [0u8; CLUSTER_MAX_SIZE];
+    ControlFlow::Continue(())
+}
 
-    let mut cluster = start_cluster;
+pub fn iterate_dir_entries<D, R, F>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    start_cluster: u32,
+    mut cb: F,
+) -> Result<Option<R>, VolumeError>
+where
+    D: BlockDevice,
+    F: FnMut(&[u8], bool) -> Option<R>,
+{
+    iterate_dir_entries_at(dev, bs, fat_start, data_start, start_cluster, false, |entry, last, _| {
+        cb(entry, last)
+    })
+}
 
-    while !is_end_cluster(cluster) {
-        let rr = read_cluster_into(
-            fd,
-            bs,
-            data_start,
-            cluster,
-            &mut cluster_buf[..cluster_size],
-        );
-        if rr < 0 || rr as usize != cluster_size {
+/* `iterate_dir_entries` with the long name of each entry in UTF-8, when it
+ * has a valid one */
+pub fn iterate_named_entries<D, R, F>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    start_cluster: u32,
+    mut cb: F,
+) -> Result<Option<R>, VolumeError>
+where
+    D: BlockDevice,
+    F: FnMut(&[u8], Option<&[u8]>) -> Option<R>,
+{
+    let mut lfn = LfnBuilder::new();
+    let mut name_buf = [0u8; LONG_NAME_MAX];
+    iterate_dir_entries_at(dev, bs, fat_start, data_start, start_cluster, true, |entry, _last, _| {
+        if is_lfn_entry(entry) {
+            lfn.push(entry);
             return None;
         }
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&entry[0..11]);
+        let long_len = match lfn.finish(&short_name) {
+            Ok(Some(units)) => Some(lfn_to_utf8(units, &mut name_buf)),
+            _ => None,
+        };
+        cb(entry, long_len.map(|len| &name_buf[..len]))
+    })
+}
 
-        let entries = cluster_size / 32;
-
-        for i in 0..entries {
-            let off = i * 32;
-            let entry = &cluster_buf[off..off + 32];
-
-            let first = entry[0];
-            if first == 0x00 {
-                /* No more entries in this directory */
-                return None;
-            }
-            if first == 0xE5 {
-                /* Deleted */
-                continue;
-            }
+/* `iterate_dir_entries` with the device offset of each entry */
+fn iterate_dir_entries_at<D, R, F>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    start_cluster: u32,
+    with_lfn: bool,
+    mut cb: F,
+) -> Result<Option<R>, VolumeError>
+where
+    D: BlockDevice,
+    F: FnMut(&[u8], bool, u64) -> Option<R>,
+{
+    let cluster_size = cluster_size(bs).ok_or(VolumeError::InvalidBootSector)?;
 
-            let attr = entry[11];
-            if (attr & 0x0F) == 0x0F {
-                /* LFN entry */
-                continue;
-            }
-            if (attr & 0x08) != 0 {
-                /* Volume id */
-                continue;
+    let mut cluster_buf = [0u8; CLUSTER_MAX_SIZE];
+    let mut guard = ChainGuard::new(bs);
+
+    /* FAT12/16 root: a fixed run of entries before the data region */
+    if start_cluster == 0 && bs.fat_type != FatType::Fat32 {
+        let root_size = bs.root_entry_count as usize * 32;
+        let root_start = (data_start as u64)
+            .saturating_sub(bs.root_dir_sectors() as u64 * bs.bytes_per_sector as u64);
+        let mut done = 0;
+        while done < root_size {
+            let len = core::cmp::min(root_size - done, CLUSTER_MAX_SIZE);
+            let base = root_start + done as u64;
+            dev.read_at(base, &mut cluster_buf[..len])?;
+            if let ControlFlow::Break(res) = visit_entries(&cluster_buf[..len], base, with_lfn, &mut cb) {
+                return Ok(res);
             }
+            done += len;
+        }
+        return Ok(None);
+    }
 
-            let last = i == entries - 1;
+    let mut cluster = start_cluster;
 
-            if let Some(res) = cb(entry, last) {
-                return Some(res);
+    loop {
+        guard.enter(cluster)?;
+        let base = cluster_base(data_start as u64, cluster, cluster_size)?;
+        /* Borrowed in place when the device maps the image */
+        let block = match dev.borrow(base, cluster_size) {
+            Some(block) => block,
+            None => {
+                read_cluster_into(dev, bs, data_start as u64, cluster, &mut cluster_buf)?;
+                &cluster_buf[..cluster_size]
             }
+        };
+
+        if let ControlFlow::Break(res) = visit_entries(block, base, with_lfn, &mut cb) {
+            return Ok(res);
         }
 
         /* Move to next cluster in chain */
-        let next = fat_entry(&fat_buf[..fat_buf_size], cluster);
-        if next == 0 || is_end_cluster(next) {
+        let next = next_cluster(dev, bs, fat_start, cluster)?;
+        if is_end_cluster(next) {
             break;
         }
         cluster = next;
     }
 
-    None
+    Ok(None)
 }
 
-pub fn list_root(fd: usize, bs: &BootSector, fat_start: usize, data_start: usize) {
-    list_dir(fd, bs, fat_start, data_start, bs.root_cluster, b"/");
+pub fn list_root<D: BlockDevice>(dev: &mut D, bs: &BootSector, fat_start: usize, data_start: usize) {
+    list_dir(dev, bs, fat_start, data_start, bs.root_cluster, b"/");
 }
 
-pub fn list_dir(
-    fd: usize,
+pub fn list_dir<D: BlockDevice>(
+    dev: &mut D,
     bs: &BootSector,
     fat_start: usize,
     data_start: usize,
@@ -220,8 +374,8 @@ pub fn list_dir(
     print_bytes(path);
     print("\n");
 
-    let _ = iterate_dir_entries::<(), _>(
-        fd,
+    let listed = iterate_dir_entries::<_, (), _>(
+        dev,
         bs,
         fat_start,
         data_start,
@@ -243,36 +397,250 @@ pub fn list_dir(
             /* 0x10 = directory flag */
             let is_dir = (attr & 0x10) != 0;
 
-            let indent_level = 0;
-
-            print_ls(&lower_name[..lower_len], is_dir, last, indent_level);
+            print_ls(&lower_name[..lower_len], is_dir, last, &[]);
 
             None
         },
     );
+    if let Err(e) = listed {
+        print_volume_error(e);
+    }
     print_line();
 }
 
-pub fn change_directory(
-    fd: usize,
+pub fn print_volume_error(e: VolumeError) {
+    match e {
+        VolumeError::Corrupt => print("The volume is corrupt"),
+        VolumeError::InvalidBootSector => print("The boot sector is invalid"),
+        VolumeError::Device(DeviceError::Syscall(errno)) => {
+            print_no_ln("Failed to access the image: ");
+            print(errno.description());
+        }
+        VolumeError::Device(_) => print("Failed to read the image"),
+    }
+}
+
+/* Lower case 8.3 name of a short entry, what path components are matched
+ * against */
+fn lower_short_name(entry: &[u8], out: &mut [u8; 13]) -> usize {
+    let mut name = [0u8; 13];
+    let name_len = build_short_name(&entry[0..8], &entry[8..11], &mut name);
+    to_lowercase_ascii(&name[..name_len], out)
+}
+
+fn cached_entry(entry: &[u8]) -> CachedEntry {
+    let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
+    let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
+    CachedEntry {
+        cluster: (cluster_high << 16) | cluster_low,
+        size: u8_to_u32_le(&entry[28..32]),
+        attr: entry[11],
+    }
+}
+
+/* Add the short and long names of every entry of `dir` to the lookup
+ * index, both under the offset of the short entry, or give the directory
+ * up when it holds more than the index */
+fn index_dir<D: BlockDevice>(
+    dev: &mut D,
+    lookup: &mut LookupCache,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    dir: u32,
+) -> Result<(), VolumeError> {
+    lookup.begin_index(dir);
+    let mut lfn = LfnBuilder::new();
+    let mut name_buf = [0u8; LONG_NAME_MAX];
+    let indexed = iterate_dir_entries_at(dev, bs, fat_start, data_start, dir, true, |entry, _last, offset| {
+        if is_lfn_entry(entry) {
+            lfn.push(entry);
+            return None;
+        }
+        let mut lower_name = [0u8; 13];
+        let lower_len = lower_short_name(entry, &mut lower_name);
+        if !lookup.insert(&lower_name[..lower_len], offset) {
+            return Some(());
+        }
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&entry[0..11]);
+        if let Ok(Some(units)) = lfn.finish(&short_name) {
+            let len = lfn_to_utf8(units, &mut name_buf);
+            let long = &mut name_buf[..len];
+            long.make_ascii_lowercase();
+            if *long != lower_name[..lower_len] && !lookup.insert(long, offset) {
+                return Some(());
+            }
+        }
+        None
+    });
+    match indexed {
+        Ok(None) => lookup.commit_index(),
+        Ok(Some(())) => lookup.abandon_index(),
+        Err(e) => {
+            lookup.invalidate();
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/* Entry named `name` (lower case) in `dir`, by its short name or by its
+ * long name matched regardless of ASCII case. The directory is indexed on
+ * its first lookup, later ones read back the entries the index points at */
+fn find_entry<D: BlockDevice>(
+    dev: &mut D,
+    lookup: &mut LookupCache,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    dir: u32,
+    name: &[u8],
+) -> Result<Option<CachedEntry>, VolumeError> {
+    if !lookup.is_indexed(dir) && !lookup.is_too_large(dir) {
+        index_dir(dev, lookup, bs, fat_start, data_start, dir)?;
+    }
+
+    let short_matches = |entry: &[u8]| {
+        let mut lower_name = [0u8; 13];
+        let lower_len = lower_short_name(entry, &mut lower_name);
+        &lower_name[..lower_len] == name
+    };
+
+    if !lookup.is_indexed(dir) {
+        /* Too large for the index: a plain scan */
+        return iterate_named_entries(dev, bs, fat_start, data_start, dir, |entry, long| {
+            let found = short_matches(entry) || long.is_some_and(|long| long.eq_ignore_ascii_case(name));
+            found.then(|| cached_entry(entry))
+        });
+    }
+    let mut found = None;
+    for offset in lookup.candidates(dir, name) {
+        let mut entry = [0u8; 32];
+        dev.read_at(offset, &mut entry)?;
+        if short_matches(&entry) || long_name_is(dev, bs, fat_start, data_start, dir, offset, name)? {
+            found = Some(cached_entry(&entry));
+            break;
+        }
+    }
+    lookup.count_lookup(found.is_some());
+    Ok(found)
+}
+
+/* Whether the LFN entries before the short entry at `offset` in `dir`
+ * spell `name`, regardless of ASCII case */
+fn long_name_is<D: BlockDevice>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    dir: u32,
+    offset: u64,
+    name: &[u8],
+) -> Result<bool, VolumeError> {
+    let mut short_name = [0u8; 11];
+    dev.read_at(offset, &mut short_name)?;
+
+    /* Read backwards up to the first entry of the long name */
+    let mut run = [[0u8; 32]; LFN_MAX_ENTRIES];
+    let mut count = 0;
+    let mut at = offset;
+    while count < LFN_MAX_ENTRIES {
+        let Some(prev) = previous_entry(dev, bs, fat_start, data_start, dir, at)? else {
+            break;
+        };
+        let lfn_entry = &mut run[count];
+        dev.read_at(prev, lfn_entry)?;
+        if lfn_entry[0] == 0xE5 || !is_lfn_entry(lfn_entry) {
+            break;
+        }
+        count += 1;
+        if lfn_entry[0] & LFN_LAST_ENTRY != 0 {
+            break;
+        }
+        at = prev;
+    }
+
+    let mut lfn = LfnBuilder::new();
+    for lfn_entry in run[..count].iter().rev() {
+        lfn.push(lfn_entry);
+    }
+    let mut name_buf = [0u8; LONG_NAME_MAX];
+    Ok(match lfn.finish(&short_name) {
+        Ok(Some(units)) => {
+            let len = lfn_to_utf8(units, &mut name_buf);
+            name_buf[..len].eq_ignore_ascii_case(name)
+        }
+        _ => false,
+    })
+}
+
+/* Device offset of the entry before the one at `offset` in `dir`, None at
+ * the start of the directory */
+fn previous_entry<D: BlockDevice>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    dir: u32,
+    offset: u64,
+) -> Result<Option<u64>, VolumeError> {
+    if dir == 0 && bs.fat_type != FatType::Fat32 {
+        let root_start = (data_start as u64)
+            .saturating_sub(bs.root_dir_sectors() as u64 * bs.bytes_per_sector as u64);
+        return Ok((offset > root_start).then(|| offset - 32));
+    }
+
+    let cluster_size = cluster_size(bs).ok_or(VolumeError::InvalidBootSector)?;
+    let rel = offset.checked_sub(data_start as u64).ok_or(VolumeError::Corrupt)?;
+    if rel % cluster_size as u64 != 0 {
+        return Ok(Some(offset - 32));
+    }
+
+    /* First entry of its cluster: the last one of the cluster before */
+    let cluster = (rel / cluster_size as u64 + 2) as u32;
+    let mut guard = ChainGuard::new(bs);
+    let mut prev = dir;
+    while prev != cluster {
+        guard.enter(prev)?;
+        let next = next_cluster(dev, bs, fat_start, prev)?;
+        if next == cluster {
+            let base = cluster_base(data_start as u64, prev, cluster_size)?;
+            return Ok(Some(base + cluster_size as u64 - 32));
+        }
+        if is_end_cluster(next) {
+            break;
+        }
+        prev = next;
+    }
+    Ok(None)
+}
+
+/* Cluster of the directory at `dir_name`, from `current_cluster` unless
+ * absolute, Ok(None) when there is no such directory */
+pub fn find_directory<D: BlockDevice>(
+    dev: &mut D,
+    lookup: &mut LookupCache,
     bs: &BootSector,
     fat_start: usize,
     data_start: usize,
     current_cluster: u32,
     dir_name: &[u8],
-) -> Option<u32> {
-    if dir_name.len() == 0 {
-        return Some(current_cluster);
+) -> Result<Option<u32>, VolumeError> {
+    if dir_name.is_empty() {
+        return Ok(Some(current_cluster));
     }
 
     /* Determine starting cluster: absolute path if starts with '/' */
     let mut working_cluster = if dir_name[0] == b'/' { bs.root_cluster } else { current_cluster };
 
-    /* If path is exactly "/" then list root */
+    /* If path is exactly "/" then it's the root */
     if dir_name.len() == 1 && dir_name[0] == b'/' {
-        reset_cli();
-        list_dir(fd, bs, fat_start, data_start, bs.root_cluster, b"/");
-        return Some(bs.root_cluster);
+        return Ok(Some(bs.root_cluster));
+    }
+
+    if let Some(entry) = lookup.cached_path(current_cluster, dir_name) {
+        return Ok(entry.is_dir().then_some(entry.cluster));
     }
 
     /* Iterate over components separated by '/' */
@@ -292,332 +660,325 @@ pub fn change_directory(
         let comp = &dir_name[start..i];
 
         /* Normalize to lowercase */
-        let mut lower_comp = [0u8; 13];
+        let mut lower_comp = [0u8; LONG_NAME_MAX];
         let comp_len = to_lowercase_ascii(comp, &mut lower_comp);
+        let lower_comp = &lower_comp[..comp_len];
 
         /* '.' -> no-op */
-        if comp_len == 1 && lower_comp[0] == b'.' {
+        if lower_comp == b"." {
             continue;
         }
 
-        /* '..' -> find parent entry in current directory */
-        if comp_len == 2 && lower_comp[0] == b'.' && lower_comp[1] == b'.' {
-            let found_parent = iterate_dir_entries::<u32, _>(
-                fd,
-                bs,
-                fat_start,
-                data_start,
-                working_cluster,
-                |entry, _last| {
-                    let name = &entry[0..8];
-                    let ext = &entry[8..11];
-
-                    let mut out = [0u8; 13];
-                    let name_len = build_short_name(name, ext, &mut out);
-                    let name_bytes = &out[..name_len];
-
-                    let mut lower_name = [0u8; 13];
-                    let lower_len = to_lowercase_ascii(name_bytes, &mut lower_name);
-
-                    if lower_len == comp_len && &lower_name[..lower_len] == &lower_comp[..comp_len] {
-                        let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
-                        let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
-                        let target_cluster = (cluster_high << 16) | (cluster_low & 0xFFFF);
-                        return Some(target_cluster);
-                    }
-
-                    None
-                },
-            );
-
-            if let Some(mut target_cluster) = found_parent {
-                if target_cluster < 2 {
-                    target_cluster = bs.root_cluster;
-                }
-                working_cluster = target_cluster;
-                continue;
-            }
+        let found = find_entry(dev, lookup, bs, fat_start, data_start, working_cluster, lower_comp)?;
 
-            if current_cluster == bs.root_cluster {
-                reset_cli();
-                list_dir(fd, bs, fat_start, data_start, bs.root_cluster, b"/");
-                return Some(bs.root_cluster);
+        /* '..' -> the parent entry, cluster 0 stands for the root */
+        if lower_comp == b".." {
+            match found {
+                Some(entry) if entry.cluster < 2 => working_cluster = bs.root_cluster,
+                Some(entry) => working_cluster = entry.cluster,
+                None if current_cluster == bs.root_cluster => return Ok(Some(bs.root_cluster)),
+                None => return Ok(None),
             }
-
-            return None;
-        }
-
-        /* General case: find a matching subdirectory by name in working_cluster */
-        let found = iterate_dir_entries::<u32, _>(
-            fd,
-            bs,
-            fat_start,
-            data_start,
-            working_cluster,
-            |entry, _last| {
-                let attr = entry[11];
-                let is_dir = (attr & 0x10) != 0;
-                if !is_dir {
-                    return None;
-                }
-
-                let name = &entry[0..8];
-                let ext = &entry[8..11];
-
-                let mut out = [0u8; 13];
-                let name_len = build_short_name(name, ext, &mut out);
-                let name_bytes = &out[..name_len];
-
-                let mut lower_name = [0u8; 13];
-                let lower_len = to_lowercase_ascii(name_bytes, &mut lower_name);
-
-                if lower_len == comp_len && &lower_name[..lower_len] == &lower_comp[..comp_len] {
-                    let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
-                    let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
-                    let target_cluster = (cluster_high << 16) | (cluster_low & 0xFFFF);
-                    return Some(target_cluster);
-                }
-
-                None
-            },
-        );
-
-        if let Some(target_cluster) = found {
-            working_cluster = target_cluster;
             continue;
         }
 
-        return None;
+        /* General case: a subdirectory by that name */
+        match found {
+            Some(entry) if entry.is_dir() => working_cluster = entry.cluster,
+            _ => return Ok(None),
+        }
     }
 
     /* At this point, working_cluster points to the target directory */
-    reset_cli();
-
-    /* Build printable path from original input */
-    let mut path_buf = [0u8; 64];
-    let copy_len = core::cmp::min(dir_name.len(), path_buf.len());
-    for j in 0..copy_len {
-        path_buf[j] = dir_name[j];
-    }
-    let path_slice = &path_buf[..copy_len];
-
-    list_dir(fd, bs, fat_start, data_start, working_cluster, path_slice);
-
-    Some(working_cluster)
+    let resolved = CachedEntry {
+        cluster: working_cluster,
+        size: 0,
+        attr: ATTR_DIRECTORY,
+    };
+    lookup.remember_path(current_cluster, dir_name, resolved);
+    Ok(Some(working_cluster))
 }
 
-pub fn read_file(
-    fd: usize,
+/* `find_directory` then list what was found under its path from the root */
+pub fn change_directory<D: BlockDevice>(
+    dev: &mut D,
+    lookup: &mut LookupCache,
     bs: &BootSector,
     fat_start: usize,
     data_start: usize,
     current_cluster: u32,
-    path: &[u8],
-) -> Option<()> {
-    if path.len() == 0 {
-        return None;
-    }
-
-    let mut working_cluster = if path[0] == b'/' { bs.root_cluster } else { current_cluster };
-
-    /* If path is exactly "/" it's a directory */
-    if path.len() == 1 && path[0] == b'/' {
-        return None;
-    }
-
-    let bytes_per_sector = bs.bytes_per_sector as usize;
-    let fat_size_bytes = bs.fat_size_sectors as usize * bytes_per_sector;
-    let fat_buf_size = core::cmp::min(FAT_MAX_SIZE, fat_size_bytes);
-    let mut fat_buf = [0u8; FAT_MAX_SIZE];
-    let r = read_fat_into(fd, bs, fat_start, &mut fat_buf[..fat_buf_size]);
-    if r < 0 || r as usize != fat_buf_size {
-        return None;
+    dir_name: &[u8],
+) -> Result<Option<(u32, Path)>, VolumeError> {
+    let working_cluster =
+        match find_directory(dev, lookup, bs, fat_start, data_start, current_cluster, dir_name)? {
+            Some(cluster) => cluster,
+            None => return Ok(None),
+        };
+    let mut path = Path::new();
+    directory_path(dev, bs, fat_start, data_start, working_cluster, &mut path)?;
+    if dir_name.is_empty() {
+        return Ok(Some((working_cluster, path)));
     }
 
-    let mut i = 0usize;
-    while i < path.len() {
-        if path[i] == b'/' {
-            i += 1;
-            continue;
-        }
-
-        let start = i;
-        while i < path.len() && path[i] != b'/' {
-            i += 1;
-        }
-        let comp = &path[start..i];
+    reset_cli();
+    list_dir(dev, bs, fat_start, data_start, working_cluster, path.as_bytes());
 
-        let mut lower_comp = [0u8; 13];
-        let comp_len = to_lowercase_ascii(comp, &mut lower_comp);
+    Ok(Some((working_cluster, path)))
+}
 
-        if comp_len == 1 && lower_comp[0] == b'.' {
-            continue;
+/* Path of directory `cluster` from the root, each level named in the
+ * parent its `..` entry points to. Long names when there are, else lower
+ * case short ones. The levels nearest the root are left out when the
+ * whole path doesn't fit */
+pub fn directory_path<D: BlockDevice>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    cluster: u32,
+    out: &mut Path,
+) -> Result<(), VolumeError> {
+    /* Built from its end, a level at a time */
+    let mut buf = [0u8; MAX_PATH_LEN];
+    let mut start = buf.len();
+    let mut dir = cluster;
+    let mut depth = 0;
+    while dir >= 2 && dir != bs.root_cluster {
+        depth += 1;
+        if depth > MAX_DEPTH {
+            /* `..` entries going round in circles */
+            return Err(VolumeError::Corrupt);
         }
-
-        if comp_len == 2 && lower_comp[0] == b'.' && lower_comp[1] == b'.' {
-            let found_parent = iterate_dir_entries::<u32, _>(
-                fd,
-                bs,
-                fat_start,
-                data_start,
-                working_cluster,
-                |entry, _last| {
-                    let name = &entry[0..8];
-                    let ext = &entry[8..11];
-
-                    let mut out = [0u8; 13];
-                    let name_len = build_short_name(name, ext, &mut out);
-                    let name_bytes = &out[..name_len];
-
-                    let mut lower_name = [0u8; 13];
-                    let lower_len = to_lowercase_ascii(name_bytes, &mut lower_name);
-
-                    if lower_len == comp_len && &lower_name[..lower_len] == &lower_comp[..comp_len] {
-                        let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
-                        let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
-                        let target_cluster = (cluster_high << 16) | (cluster_low & 0xFFFF);
-                        return Some(target_cluster);
-                    }
-                    None
-                },
-            );
-
-            if let Some(mut target_cluster) = found_parent {
-                if target_cluster < 2 {
-                    target_cluster = bs.root_cluster;
-                }
-                working_cluster = target_cluster;
-                continue;
+        let parent = iterate_dir_entries(dev, bs, fat_start, data_start, dir, |entry, _last| {
+            (&entry[0..11] == b"..         ").then(|| cached_entry(entry).cluster)
+        })?;
+        /* Cluster 0 stands for the root */
+        let parent = match parent {
+            Some(parent) if parent >= 2 => parent,
+            Some(_) => bs.root_cluster,
+            None => return Err(VolumeError::Corrupt),
+        };
+
+        let mut name = [0u8; LONG_NAME_MAX];
+        let found = iterate_named_entries(dev, bs, fat_start, data_start, parent, |entry, long| {
+            let found = cached_entry(entry);
+            if entry[0] == b'.' || !found.is_dir() || found.cluster != dir {
+                return None;
             }
-
-            return None;
+            Some(match long {
+                Some(long) => {
+                    name[..long.len()].copy_from_slice(long);
+                    long.len()
+                }
+                None => {
+                    let mut short = [0u8; 13];
+                    let len = lower_short_name(entry, &mut short);
+                    name[..len].copy_from_slice(&short[..len]);
+                    len
+                }
+            })
+        })?;
+        let Some(len) = found else {
+            return Err(VolumeError::Corrupt);
+        };
+        if len + 1 > start {
+            break;
         }
+        start -= len;
+        buf[start..start + len].copy_from_slice(&name[..len]);
+        start -= 1;
+        buf[start] = b'/';
+        dir = parent;
+    }
 
-        if i < path.len() {
-            let found = iterate_dir_entries::<u32, _>(
-                fd,
-                bs,
-                fat_start,
-                data_start,
-                working_cluster,
-                |entry, _last| {
-                    let attr = entry[11];
-                    let is_dir = (attr & 0x10) != 0;
-                    if !is_dir {
-                        return None;
-                    }
-
-                    let name = &entry[0..8];
-                    let ext = &entry[8..11];
-
-                    let mut out = [0u8; 13];
-                    let name_len = build_short_name(name, ext, &mut out);
-                    let name_bytes = &out[..name_len];
-
-                    let mut lower_name = [0u8; 13];
-                    let lower_len = to_lowercase_ascii(name_bytes, &mut lower_name);
-
-                    if lower_len == comp_len && &lower_name[..lower_len] == &lower_comp[..comp_len] {
-                        let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
-                        let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
-                        let target_cluster = (cluster_high << 16) | (cluster_low & 0xFFFF);
-                        return Some(target_cluster);
-                    }
-                    None
-                },
-            );
+    out.len = buf.len() - start;
+    out.buf[..out.len].copy_from_slice(&buf[start..]);
+    Ok(())
+}
 
-            if let Some(target_cluster) = found {
-                working_cluster = target_cluster;
-                continue;
-            }
+/* Entry of the file at `path`, Ok(None) when missing or a directory */
+pub fn find_file<D: BlockDevice>(
+    dev: &mut D,
+    lookup: &mut LookupCache,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    current_cluster: u32,
+    path: &[u8],
+) -> Result<Option<CachedEntry>, VolumeError> {
+    /* If path is empty or exactly "/" there is no file */
+    if path.is_empty() || path == b"/" {
+        return Ok(None);
+    }
 
-            return None;
+    let entry = match lookup.cached_path(current_cluster, path) {
+        Some(entry) => entry,
+        None => {
+            let (parent, name) = match path.iter().rposition(|&c| c == b'/') {
+                Some(0) => (&path[..1], &path[1..]),
+                Some(i) => (&path[..i], &path[i + 1..]),
+                None => (&path[..0], path),
+            };
+            let Some(dir) = find_directory(dev, lookup, bs, fat_start, data_start, current_cluster, parent)?
+            else {
+                return Ok(None);
+            };
+
+            let mut lower_name = [0u8; LONG_NAME_MAX];
+            let name_len = to_lowercase_ascii(name, &mut lower_name);
+            let lower_name = &lower_name[..name_len];
+            if matches!(lower_name, b"" | b"." | b"..") {
+                return Ok(None);
+            }
+            let Some(entry) = find_entry(dev, lookup, bs, fat_start, data_start, dir, lower_name)? else {
+                return Ok(None);
+            };
+            lookup.remember_path(current_cluster, path, entry);
+            entry
         }
+    };
+    Ok(Some(entry).filter(|entry| !entry.is_dir()))
+}
 
-        let found_file = iterate_dir_entries::<(u32, u32), _>(
-            fd,
-            bs,
-            fat_start,
-            data_start,
-            working_cluster,
-            |entry, _last| {
-                let attr = entry[11];
-                let is_dir = (attr & 0x10) != 0;
-                if is_dir {
-                    return None;
-                }
-
-                let name = &entry[0..8];
-                let ext = &entry[8..11];
-
-                let mut out = [0u8; 13];
-                let name_len = build_short_name(name, ext, &mut out);
-                let name_bytes = &out[..name_len];
-
-                let mut lower_name = [0u8; 13];
-                let lower_len = to_lowercase_ascii(name_bytes, &mut lower_name);
-
-                if lower_len == comp_len && &lower_name[..lower_len] == &lower_comp[..comp_len] {
-                    let cluster_low = u8_le_to_u16(&entry[26..28]) as u32;
-                    let cluster_high = u8_le_to_u16(&entry[20..22]) as u32;
-                    let start_cluster = (cluster_high << 16) | (cluster_low & 0xFFFF);
-                    let file_size = u8_to_u32_le(&entry[28..32]);
-                    return Some((start_cluster, file_size));
-                }
-                None
-            },
-        );
+/* A FAT directory tree for `tree`, directories found by their cluster */
+pub struct FatTree<'a, D> {
+    pub dev: &'a mut D,
+    pub bs: &'a BootSector,
+    pub fat_start: usize,
+    pub data_start: usize,
+    /* Where `read` reads file data */
+    pub chunk: &'a mut [u8],
+}
 
-        if let Some((mut start_cluster, mut file_size)) = found_file {
-            if start_cluster < 2 {
-                return Some(());
-            }
+impl<D: BlockDevice> TreeSource for FatTree<'_, D> {
+    type Dir = u32;
 
-            /* Read clusters and print bytes */
-            let sectors_per_cluster = bs.sectors_per_cluster as usize;
-            let cluster_size = bytes_per_sector * sectors_per_cluster;
-            if cluster_size == 0 || cluster_size > CLUSTER_MAX_SIZE {
+    fn scan(
+        &mut self,
+        dir: u32,
+        cb: &mut dyn FnMut(&TreeEntry<u32>) -> ControlFlow<()>,
+    ) -> Result<(), VolumeError> {
+        iterate_named_entries(self.dev, self.bs, self.fat_start, self.data_start, dir, |entry, long| {
+            if entry[0] == b'.' {
                 return None;
             }
+            let mut short = [0u8; 13];
+            let len = lower_short_name(entry, &mut short);
+            let short = &short[..len];
+            let found = cached_entry(entry);
+            let tree_entry = TreeEntry {
+                name: long.unwrap_or(short),
+                short,
+                node: found.cluster,
+                is_dir: found.is_dir(),
+                attributes: found.attr,
+                modified: (u8_le_to_u16(&entry[24..26]) as u32) << 16 | u8_le_to_u16(&entry[22..24]) as u32,
+                first_cluster: found.cluster,
+                size: found.size as u64,
+            };
+            cb(&tree_entry).is_break().then_some(())
+        })?;
+        Ok(())
+    }
 
-            let mut cluster_buf = [0u8; CLUSTER_MAX_SIZE];
-            let mut remaining = file_size as usize;
-            let mut cluster = start_cluster;
-
-            while remaining > 0 && !is_end_cluster(cluster) {
-                let rr = read_cluster_into(fd, bs, data_start, cluster, &mut cluster_buf[..cluster_size]);
-                if rr < 0 || rr as usize != cluster_size {
-                    return None;
-                }
+    fn read(&mut self, entry: &TreeEntry<u32>, out: &mut dyn FnMut(&[u8])) -> Result<(), VolumeError> {
+        let file = CachedEntry {
+            cluster: entry.node,
+            size: entry.size as u32,
+            attr: entry.attributes,
+        };
+        read_file(self.dev, self.bs, self.fat_start, self.data_start, &file, self.chunk, out)
+    }
+}
 
-                let to_print = core::cmp::min(remaining, cluster_size);
-                print_bytes(&cluster_buf[..to_print]);
-                remaining -= to_print;
+/* Offer the entries of directory `dir` to Tab, by long name or else by
+ * the lower case short name */
+pub fn complete_names<D: BlockDevice>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    dir: u32,
+    out: &mut Candidates,
+) -> Result<(), VolumeError> {
+    iterate_named_entries::<_, (), _>(dev, bs, fat_start, data_start, dir, |entry, long| {
+        let is_dir = entry[11] & ATTR_DIRECTORY != 0;
+        if entry[0] == b'.' {
+            return None;
+        }
+        if !long.is_some_and(|long| out.push(long, is_dir)) {
+            let mut lower_name = [0u8; 13];
+            let lower_len = lower_short_name(entry, &mut lower_name);
+            out.push(&lower_name[..lower_len], is_dir);
+        }
+        None
+    })?;
+    Ok(())
+}
 
-                if remaining == 0 {
-                    break;
-                }
+/* Hand the content of the file `entry` to `out`, a run of contiguous
+ * clusters at a time. Runs are read into `chunk` and cut to its length,
+ * mapped images are handed out in place */
+pub fn read_file<D: BlockDevice, W: FnMut(&[u8])>(
+    dev: &mut D,
+    bs: &BootSector,
+    fat_start: usize,
+    data_start: usize,
+    entry: &CachedEntry,
+    chunk: &mut [u8],
+    mut out: W,
+) -> Result<(), VolumeError> {
+    if entry.cluster < 2 {
+        return Ok(());
+    }
 
-                let next = fat_entry(&fat_buf[..fat_buf_size], cluster);
-                if next == 0 || is_end_cluster(next) {
-                    break;
-                }
-                cluster = next;
+    /* Read whole runs of contiguous clusters, hand their bytes to `out` */
+    let cluster_size = cluster_size(bs).ok_or(VolumeError::InvalidBootSector)?;
+
+    let mut guard = ChainGuard::new(bs);
+    let mut file = OpenFile::new(
+        entry.cluster,
+        entry.size as u64,
+        cluster_size as u64,
+        data_start as u64,
+        guard.clusters_count,
+    )?;
+
+    let mut pos = 0u64;
+    let mut next = |dev: &mut D, cluster| {
+        guard.enter(cluster)?;
+        next_cluster(dev, bs, fat_start, cluster)
+    };
+    while let Some((offset, len)) =
+        file.run_at(pos, chunk.len(), &mut |cluster| next(dev, cluster))?
+    {
+        /* A mapped image is handed out in place */
+        match dev.borrow(offset, len) {
+            Some(bytes) => out(bytes),
+            None => {
+                dev.read_at(offset, &mut chunk[..len])?;
+                out(&chunk[..len]);
             }
-
-            return Some(());
         }
-
-        return None;
+        pos += len as u64;
     }
 
-    None
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::boot_sector::parse_boot_sector;
+    use crate::device::{MappedMemDevice, MemDevice};
+    use crate::fixture::{ImageBuilder, Node};
+    use crate::format::FormatOptions;
+    use crate::tree::{Totals, TreeOptions, draw_tree};
+    use crate::volume::{FAT_EOC_MARK, Volume};
+    use std::boxed::Box;
+    use std::string::String;
+    use std::vec;
+    use std::vec::Vec;
 
     #[test]
     fn test_fat_entry_valid() {
@@ -628,8 +989,9 @@ mod tests {
         fat_buf[2] = 0x34;
         fat_buf[3] = 0x12;
 
+        /* The top 4 bits are reserved, only 28 bits come back */
         let result = fat_entry(&fat_buf, 0);
-        assert_eq!(result, 0x12345678);
+        assert_eq!(result, 0x02345678);
     }
 
     #[test]
@@ -707,9 +1069,421 @@ mod tests {
 
     #[test]
     fn test_fat_entry_all_ones() {
-        let mut fat_buf = [0xFFu8; 16];
+        let fat_buf = [0xFFu8; 16];
         let result = fat_entry(&fat_buf, 0);
         /* 0xFFFFFFFF & 0x0FFFFFFF = 0x0FFFFFFF */
         assert_eq!(result, 0x0FFFFFFF);
     }
+
+    fn small_boot_sector() -> BootSector {
+        BootSector {
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            reserved_sectors_count: 32,
+            fats_count: 2,
+            root_entry_count: 0,
+            fat_size_sectors: 1,
+            ext_flags: 0,
+            root_cluster: 2,
+            total_sectors: 32 + 2 + 100,
+            fs_info_sector: 1,
+            fat_type: FatType::Fat32,
+        }
+    }
+
+    #[test]
+    fn test_clusters_count_bounded_by_fat() {
+        let mut bs = small_boot_sector();
+        /* 100 data clusters but a 512 bytes FAT only describes 126 */
+        assert_eq!(clusters_count(&bs), 100);
+        bs.total_sectors = 32 + 2 + 1000;
+        assert_eq!(clusters_count(&bs), 126);
+    }
+
+    #[test]
+    fn test_chain_guard_rejects_loops_and_bad_clusters() {
+        let bs = small_boot_sector();
+        let mut guard = ChainGuard::new(&bs);
+
+        assert_eq!(guard.enter(0), Err(VolumeError::Corrupt));
+        assert_eq!(guard.enter(1), Err(VolumeError::Corrupt));
+        assert_eq!(guard.enter(102), Err(VolumeError::Corrupt));
+        assert_eq!(guard.enter(101), Ok(()));
+        /* Past as many clusters as the volume has, the chain loops */
+        for _ in 1..100 {
+            assert_eq!(guard.enter(2), Ok(()));
+        }
+        assert_eq!(guard.enter(3), Err(VolumeError::Corrupt));
+    }
+
+    /* Tree shared by the tests below, read back through the fat.rs API */
+    fn fixture(builder: ImageBuilder) -> (Vec<u8>, Vec<u8>) {
+        let big: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let tree = [
+            Node::Dir(
+                "sub",
+                &[
+                    Node::File("inner.txt", b"inner file\n"),
+                    Node::Dir("deeper", &[Node::File("leaf.txt", b"leaf")]),
+                ],
+            ),
+            Node::File("A long file name.txt", &big),
+            Node::Deleted("gone.txt", b"deleted"),
+            Node::File("empty.txt", b""),
+        ];
+        (builder.build(&tree), big)
+    }
+
+    fn open(data: &mut [u8]) -> (MemDevice<'_>, BootSector, usize, usize) {
+        let mut sector = [0u8; 512];
+        sector.copy_from_slice(&data[..512]);
+        let bs = parse_boot_sector(&sector).unwrap();
+        let (fat_start, data_start) = fat_regions(&bs);
+        (MemDevice { data }, bs, fat_start, data_start)
+    }
+
+    fn cat(data: &mut [u8], path: &[u8]) -> Result<Option<Vec<u8>>, VolumeError> {
+        let (mut dev, bs, fat_start, data_start) = open(data);
+        let mut lookup = Box::<LookupCache>::default();
+        let Some(entry) = find_file(&mut dev, &mut lookup, &bs, fat_start, data_start, bs.root_cluster, path)?
+        else {
+            return Ok(None);
+        };
+        let (mut out, mut chunk) = (Vec::new(), [0u8; 4096]);
+        read_file(&mut dev, &bs, fat_start, data_start, &entry, &mut chunk, |b| out.extend_from_slice(b))?;
+        Ok(Some(out))
+    }
+
+    #[test]
+    fn test_typed_fat_entry() {
+        /* FAT12 entries 2 and 3 are 0x123 and 0xFFF packed in 3 bytes */
+        let fat_buf = [0xF0, 0xFF, 0xFF, 0x23, 0xF1, 0xFF];
+        assert_eq!(typed_fat_entry(FatType::Fat12, &fat_buf, 2), 0x123);
+        assert_eq!(typed_fat_entry(FatType::Fat12, &fat_buf, 3), 0x0FFFFFFF);
+        assert_eq!(typed_fat_entry(FatType::Fat12, &fat_buf, 4), 0x0FFFFFFF);
+
+        let fat_buf = [0xF8, 0xFF, 0xFF, 0xFF, 0x34, 0x12, 0xF7, 0xFF];
+        assert_eq!(typed_fat_entry(FatType::Fat16, &fat_buf, 2), 0x1234);
+        assert_eq!(typed_fat_entry(FatType::Fat16, &fat_buf, 3), 0x0FFFFFF7);
+        assert_eq!(typed_fat_entry(FatType::Fat16, &fat_buf, 4), 0x0FFFFFFF);
+    }
+
+    #[test]
+    fn test_iterate_skips_long_and_deleted_entries() {
+        for builder in [ImageBuilder::new(), ImageBuilder::fat12(), ImageBuilder::fat16()] {
+            let (mut data, _) = fixture(builder);
+            let (mut dev, bs, fat_start, data_start) = open(&mut data);
+            let mut names = Vec::new();
+            iterate_dir_entries(&mut dev, &bs, fat_start, data_start, bs.root_cluster, |e, _| {
+                names.push(e[0..11].to_vec());
+                None::<()>
+            })
+            .unwrap();
+            assert_eq!(
+                names,
+                vec![b"SUB        ".to_vec(), b"ALONGF~1TXT".to_vec(), b"EMPTY   TXT".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_iterate_named_entries_gives_long_names() {
+        for builder in [ImageBuilder::new(), ImageBuilder::fat16()] {
+            let (mut data, _) = fixture(builder);
+            let (mut dev, bs, fat_start, data_start) = open(&mut data);
+            let mut names = Vec::new();
+            iterate_named_entries(&mut dev, &bs, fat_start, data_start, bs.root_cluster, |e, long| {
+                names.push((e[0..11].to_vec(), long.map(|l| l.to_vec())));
+                None::<()>
+            })
+            .unwrap();
+            assert_eq!(
+                names,
+                vec![
+                    (b"SUB        ".to_vec(), None),
+                    (b"ALONGF~1TXT".to_vec(), Some(b"A long file name.txt".to_vec())),
+                    (b"EMPTY   TXT".to_vec(), None),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_directory_path_names_each_level() {
+        for builder in [ImageBuilder::new(), ImageBuilder::fat12()] {
+            let (mut data, _) = fixture(builder);
+            let (mut dev, bs, fat_start, data_start) = open(&mut data);
+            let mut lookup = Box::<LookupCache>::default();
+            let root = bs.root_cluster;
+            let deeper = find_directory(&mut dev, &mut lookup, &bs, fat_start, data_start, root, b"SUB/./deeper/")
+                .unwrap()
+                .unwrap();
+            let mut path = |cluster| {
+                let mut path = Path::new();
+                directory_path(&mut dev, &bs, fat_start, data_start, cluster, &mut path).unwrap();
+                path.as_bytes().to_vec()
+            };
+            assert_eq!(path(deeper), b"/sub/deeper");
+            assert_eq!(path(root), b"/");
+        }
+    }
+
+    #[test]
+    fn test_tree_walks_the_fixture() {
+        for builder in [ImageBuilder::new(), ImageBuilder::fat12()] {
+            let (mut data, big) = fixture(builder);
+            let (mut dev, bs, fat_start, data_start) = open(&mut data);
+            let mut tree = FatTree {
+                dev: &mut dev,
+                bs: &bs,
+                fat_start,
+                data_start,
+                chunk: &mut [0u8; 4096],
+            };
+            let mut lines = Vec::new();
+            let drawn = draw_tree(&mut tree, bs.root_cluster, &TreeOptions::default(), &mut |line| {
+                lines.push((std::string::String::from_utf8(line.path.to_vec()).unwrap(), line.branches.len(), line.last))
+            })
+            .unwrap();
+            assert_eq!(
+                lines,
+                [
+                    ("/sub".into(), 0, false),
+                    ("/sub/inner.txt".into(), 1, false),
+                    ("/sub/deeper".into(), 1, true),
+                    ("/sub/deeper/leaf.txt".into(), 2, true),
+                    ("/A long file name.txt".into(), 0, false),
+                    ("/empty.txt".into(), 0, true),
+                ]
+            );
+            assert_eq!(
+                drawn,
+                Totals {
+                    dirs: 2,
+                    files: 4,
+                    bytes: 11 + 4 + big.len() as u64
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_directory_follows_paths() {
+        for builder in [ImageBuilder::new(), ImageBuilder::fat12(), ImageBuilder::fat16()] {
+            let (mut data, _) = fixture(builder);
+            let (mut dev, bs, fat_start, data_start) = open(&mut data);
+            let root = bs.root_cluster;
+            let mut lookup = Box::<LookupCache>::default();
+            let mut find = |from, path: &[u8]| {
+                find_directory(&mut dev, &mut lookup, &bs, fat_start, data_start, from, path).unwrap()
+            };
+
+            let sub = find(root, b"sub").unwrap();
+            let deeper = find(root, b"/SUB/deeper").unwrap();
+            assert_ne!(sub, deeper);
+            assert_eq!(find(deeper, b".."), Some(sub));
+            assert_eq!(find(sub, b"../sub/./deeper"), Some(deeper));
+            assert_eq!(find(sub, b".."), Some(root));
+            assert_eq!(find(root, b"missing"), None);
+            /* A file isn't a directory */
+            assert_eq!(find(root, b"empty.txt"), None);
+        }
+    }
+
+    #[test]
+    fn test_lookups_go_through_the_directory_index() {
+        let names: Vec<std::string::String> = (0..2000).map(|i| std::format!("F{i}.TXT")).collect();
+        let files: Vec<Node> = names.iter().map(|name| Node::File(name, name.as_bytes())).collect();
+        let tree = [Node::Dir("big", &files)];
+        let mut data = ImageBuilder::new().build(&tree);
+        let (mut dev, bs, fat_start, data_start) = open(&mut data);
+        let mut lookup = Box::<LookupCache>::default();
+
+        let cat = |lookup: &mut LookupCache, dev: &mut MemDevice, path: &[u8]| {
+            let entry = find_file(dev, lookup, &bs, fat_start, data_start, bs.root_cluster, path).unwrap()?;
+            let (mut out, mut chunk) = (Vec::new(), [0u8; 4096]);
+            read_file(dev, &bs, fat_start, data_start, &entry, &mut chunk, |b| out.extend_from_slice(b)).unwrap();
+            Some(out)
+        };
+        for i in (0..2000).step_by(97) {
+            let path = std::format!("big/f{i}.txt");
+            assert_eq!(cat(&mut lookup, &mut dev, path.as_bytes()), Some(names[i].as_bytes().to_vec()));
+        }
+        assert_eq!(cat(&mut lookup, &mut dev, b"big/nope.txt"), None);
+        /* The root and `big` were read whole once. The index found each
+         * file and `big`, remembered after that, only `nope.txt` missed */
+        assert_eq!(lookup.stats().scans, 2);
+        assert_eq!((lookup.stats().index_hits, lookup.stats().index_misses), (22, 1));
+        /* Then `big/f97.txt` itself is remembered */
+        let hits = lookup.stats().path_hits;
+        assert_eq!(cat(&mut lookup, &mut dev, b"big/f97.txt"), Some(b"F97.TXT".to_vec()));
+        assert_eq!(lookup.stats().path_hits, hits + 1);
+
+        /* Renamed behind the cache: only seen once it is invalidated */
+        let big = iterate_dir_entries(&mut dev, &bs, fat_start, data_start, bs.root_cluster, |e, _| {
+            (e[0..11] == *b"BIG        ").then(|| cached_entry(e).cluster)
+        })
+        .unwrap()
+        .unwrap();
+        let offset = iterate_dir_entries_at(&mut dev, &bs, fat_start, data_start, big, false, |e, _, offset| {
+            (e[0..11] == *b"F97     TXT").then_some(offset)
+        })
+        .unwrap()
+        .unwrap();
+        dev.write_at(offset, b"G97     TXT").unwrap();
+        assert_eq!(cat(&mut lookup, &mut dev, b"big/f97.txt"), Some(b"F97.TXT".to_vec()));
+        lookup.invalidate();
+        assert_eq!(cat(&mut lookup, &mut dev, b"big/f97.txt"), None);
+        assert_eq!(cat(&mut lookup, &mut dev, b"big/g97.txt"), Some(b"F97.TXT".to_vec()));
+    }
+
+    #[test]
+    fn test_long_names_are_indexed_too() {
+        /* Two names per file, more than the old index took. Each file uses
+         * three entries so long names straddle cluster starts */
+        let names: Vec<std::string::String> = (0..12500).map(|i| std::format!("{i:05} long name.txt")).collect();
+        let files: Vec<Node> = names.iter().map(|name| Node::File(name, name.as_bytes())).collect();
+        let tree = [Node::Dir("big", &files)];
+        let mut data = ImageBuilder::new().build(&tree);
+        let (mut dev, bs, fat_start, data_start) = open(&mut data);
+        let mut lookup = Box::<LookupCache>::default();
+
+        let mut cat = |path: &str| {
+            let entry = find_file(&mut dev, &mut lookup, &bs, fat_start, data_start, bs.root_cluster, path.as_bytes())
+                .unwrap()?;
+            let (mut out, mut chunk) = (Vec::new(), [0u8; 4096]);
+            read_file(&mut dev, &bs, fat_start, data_start, &entry, &mut chunk, |b| out.extend_from_slice(b)).unwrap();
+            Some(out)
+        };
+        for i in (0..12500).step_by(7) {
+            let content = Some(names[i].as_bytes().to_vec());
+            assert_eq!(cat(&std::format!("big/{i:05} LONG name.txt")), content);
+            assert_eq!(cat(&std::format!("/BIG/{i:05}L~1.TXT")), content);
+        }
+        assert_eq!(cat("big/nope.txt"), None);
+        assert_eq!(cat("big/00001 long name.tx"), None);
+        /* The root and `big` were read whole once, misses included */
+        assert_eq!(lookup.stats().scans, 2);
+    }
+
+    #[test]
+    fn test_read_file_over_contiguous_and_fragmented_chains() {
+        for builder in [
+            ImageBuilder::new(),
+            ImageBuilder::new().fragmented(),
+            ImageBuilder::fat12().fragmented(),
+            ImageBuilder::fat16().fragmented(),
+        ] {
+            let (mut data, big) = fixture(builder);
+            assert_eq!(cat(&mut data, b"alongf~1.txt").unwrap(), Some(big.clone()));
+            assert_eq!(cat(&mut data, b"/A LONG file name.TXT").unwrap(), Some(big));
+            assert_eq!(cat(&mut data, b"sub/inner.txt").unwrap(), Some(b"inner file\n".to_vec()));
+            assert_eq!(cat(&mut data, b"/sub/deeper/../deeper/leaf.txt").unwrap(), Some(b"leaf".to_vec()));
+            assert_eq!(cat(&mut data, b"empty.txt").unwrap(), Some(Vec::new()));
+            assert_eq!(cat(&mut data, b"gone.txt").unwrap(), None);
+            assert_eq!(cat(&mut data, b"sub").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_read_file_past_the_first_64k_of_the_fat() {
+        /* Cluster 20000 sits 80000 bytes into a FAT32 FAT */
+        for builder in [
+            ImageBuilder::new().first_cluster(20000),
+            ImageBuilder::new().first_cluster(70001).fragmented(),
+        ] {
+            let (mut data, big) = fixture(builder);
+            assert_eq!(cat(&mut data, b"alongf~1.txt").unwrap(), Some(big));
+            assert_eq!(cat(&mut data, b"sub/deeper/leaf.txt").unwrap(), Some(b"leaf".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_read_file_borrows_from_mapped_devices() {
+        for builder in [ImageBuilder::new().fragmented(), ImageBuilder::fat16()] {
+            let (mut data, big) = fixture(builder);
+            let (dev, bs, fat_start, data_start) = open(&mut data);
+            let mut dev = MappedMemDevice(dev);
+            let mut lookup = Box::<LookupCache>::default();
+            let mut read = |path: &[u8]| {
+                let entry = find_file(&mut dev, &mut lookup, &bs, fat_start, data_start, bs.root_cluster, path)
+                    .unwrap()
+                    .unwrap();
+                let (mut out, mut chunk) = (Vec::new(), [0u8; 4096]);
+                read_file(&mut dev, &bs, fat_start, data_start, &entry, &mut chunk, |b| out.extend_from_slice(b)).unwrap();
+                out
+            };
+            assert_eq!(read(b"alongf~1.txt"), big);
+            assert_eq!(read(b"sub/deeper/leaf.txt"), b"leaf");
+        }
+    }
+
+    #[test]
+    fn test_read_file_with_odd_geometry() {
+        let builder = ImageBuilder::new()
+            .options(FormatOptions {
+                bytes_per_sector: 1024,
+                sectors_per_cluster: Some(2),
+                reserved_sectors_count: 37,
+                fats_count: 3,
+                ..FormatOptions::default()
+            })
+            .size(140 * 1024 * 1024)
+            .fragmented();
+        let (mut data, big) = fixture(builder);
+        assert_eq!(cat(&mut data, b"alongf~1.txt").unwrap(), Some(big));
+        assert_eq!(cat(&mut data, b"sub/deeper/leaf.txt").unwrap(), Some(b"leaf".to_vec()));
+    }
+
+    #[test]
+    fn test_read_file_reports_looping_chain() {
+        let (mut data, _) = fixture(ImageBuilder::new());
+        let first = {
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            /* The long named file starts right after `sub` and its subdirectories */
+            let mut first = 0;
+            for cluster in 3..20 {
+                if vol.fat_entry(cluster).unwrap() == cluster + 1 {
+                    first = cluster;
+                    break;
+                }
+            }
+            vol.set_fat_entry(first + 1, first).unwrap();
+            first
+        };
+        assert_ne!(first, 0);
+        assert_eq!(cat(&mut data, b"alongf~1.txt"), Err(VolumeError::Corrupt));
+    }
+
+    #[test]
+    fn test_read_file_reports_broken_chains_past_the_first_clusters() {
+        /* Two full clusters of `many`, from 50000, looping on each other */
+        let names: Vec<String> = (0..30).map(|i| std::format!("f{i:02}.txt")).collect();
+        let files: Vec<Node> = names.iter().map(|name| Node::File(name, b"")).collect();
+        let mut data = ImageBuilder::new().first_cluster(50000).build(&[Node::Dir("many", &files)]);
+        {
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            assert_eq!(vol.fat_entry(50000).unwrap(), 50001);
+            vol.set_fat_entry(50001, 50000).unwrap();
+        }
+        assert_eq!(cat(&mut data, b"many/f29.txt").unwrap(), Some(Vec::new()));
+        assert_eq!(cat(&mut data, b"many/none.txt"), Err(VolumeError::Corrupt));
+
+        /* The long named file ends after its second cluster */
+        let (mut data, _) = fixture(ImageBuilder::new().first_cluster(50000).fragmented());
+        let first = {
+            let (mut dev, bs, fat_start, data_start) = open(&mut data);
+            let mut lookup = Box::<LookupCache>::default();
+            find_file(&mut dev, &mut lookup, &bs, fat_start, data_start, bs.root_cluster, b"alongf~1.txt")
+                .unwrap()
+                .unwrap()
+                .cluster
+        };
+        {
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            let second = vol.fat_entry(first).unwrap();
+            vol.set_fat_entry(second, FAT_EOC_MARK).unwrap();
+        }
+        assert_eq!(cat(&mut data, b"alongf~1.txt"), Err(VolumeError::Corrupt));
+    }
 }
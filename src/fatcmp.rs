@@ -0,0 +1,416 @@
+use crate::bitmap::{Bitmap, bitmap_bytes};
+use crate::check::{
+    CHECK_FAILED_EXIT_CODE, CheckError, CheckReport, check_volume, with_scratch,
+};
+use crate::cli::{print, print_hex_u32, print_no_ln, print_number};
+use crate::cache::{ImageDevice, ImageFile};
+use crate::device::BlockDevice;
+use crate::sys::print_bytes;
+use crate::volume::{Volume, VolumeError};
+use crate::walk::walk_tree;
+
+pub struct FatDifference<'a> {
+    pub cluster: u32,
+    pub active_fat: u8,
+    /* The copy disagreeing with the active FAT */
+    pub fat: u8,
+    pub active_value: u32,
+    pub value: u32,
+    /* Owner of the cluster in the active FAT, empty when nothing reaches it */
+    pub path: &'a [u8],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompareReport {
+    /* Differing (cluster, copy) pairs */
+    pub differences: u32,
+    /* Clusters with at least one difference */
+    pub clusters: u32,
+}
+
+/* Scratch bytes `compare_fats` needs for a volume of `clusters_count` clusters */
+pub fn compare_scratch_size(clusters_count: u32) -> usize {
+    2 * bitmap_bytes(clusters_count as usize + 2)
+}
+
+/* Mark in `diff` the clusters whose entry differs between FAT `a` and FAT `b` */
+fn diff_fats<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    a: u8,
+    b: u8,
+    diff: &mut Bitmap,
+    report: &mut CompareReport,
+) -> Result<(), VolumeError> {
+    for cluster in 2..vol.clusters_count + 2 {
+        let value_a = vol.fat_entry_in(a, cluster)?;
+        let value_b = vol.fat_entry_in(b, cluster)?;
+        if value_a != value_b && !diff.get(cluster as usize) {
+            diff.set(cluster as usize);
+            report.clusters += 1;
+        }
+    }
+    Ok(())
+}
+
+/* Hand every difference on `cluster` to `on_diff`, then forget about it */
+fn report_cluster<D: BlockDevice, F: FnMut(&FatDifference)>(
+    vol: &mut Volume<D>,
+    cluster: u32,
+    path: &[u8],
+    diff: &mut Bitmap,
+    report: &mut CompareReport,
+    on_diff: &mut F,
+) -> Result<(), VolumeError> {
+    diff.clear(cluster as usize);
+
+    let active = vol.active_fat;
+    let active_value = vol.fat_entry_in(active, cluster)?;
+    for fat in 0..vol.bs.fats_count {
+        if fat == active {
+            continue;
+        }
+        let value = vol.fat_entry_in(fat, cluster)?;
+        if value != active_value {
+            report.differences += 1;
+            on_diff(&FatDifference {
+                cluster,
+                active_fat: active,
+                fat,
+                active_value,
+                value,
+                path,
+            });
+        }
+    }
+    Ok(())
+}
+
+/* Report the differences on the chain at `start`, owned by `path` */
+fn report_chain<D: BlockDevice, F: FnMut(&FatDifference)>(
+    vol: &mut Volume<D>,
+    start: u32,
+    path: &[u8],
+    diff: &mut Bitmap,
+    report: &mut CompareReport,
+    on_diff: &mut F,
+) -> Result<(), VolumeError> {
+    let mut cluster = start;
+    for _ in 0..vol.clusters_count {
+        if !vol.is_valid_cluster(cluster) {
+            break;
+        }
+        if diff.get(cluster as usize) {
+            report_cluster(vol, cluster, path, diff, report, on_diff)?;
+        }
+        cluster = vol.fat_entry(cluster)?;
+    }
+    Ok(())
+}
+
+/* Diff the active FAT against every other copy, entry by entry. Differences
+ * come out grouped by the file owning the cluster in the active FAT, then the
+ * ones no file reaches. `scratch` must hold `compare_scratch_size` bytes */
+pub fn compare_fats<D: BlockDevice, F: FnMut(&FatDifference)>(
+    vol: &mut Volume<D>,
+    scratch: &mut [u8],
+    mut on_diff: F,
+) -> Result<CompareReport, CheckError> {
+    let bits = vol.clusters_count as usize + 2;
+    if scratch.len() < compare_scratch_size(vol.clusters_count) {
+        return Err(CheckError::ScratchTooSmall);
+    }
+    let (diff_storage, visited_storage) = scratch.split_at_mut(bitmap_bytes(bits));
+    let mut diff = Bitmap::new(diff_storage, bits).ok_or(CheckError::ScratchTooSmall)?;
+    let mut visited = Bitmap::new(visited_storage, bits).ok_or(CheckError::ScratchTooSmall)?;
+
+    let mut report = CompareReport::default();
+    let active = vol.active_fat;
+    for fat in 0..vol.bs.fats_count {
+        if fat != active {
+            diff_fats(vol, active, fat, &mut diff, &mut report)?;
+        }
+    }
+    if report.clusters == 0 {
+        return Ok(report);
+    }
+
+    let root = vol.bs.root_cluster;
+    report_chain(vol, root, b"/", &mut diff, &mut report, &mut on_diff)?;
+    walk_tree(vol, root, &mut visited, |vol, walked| {
+        let first = walked.entry.first_cluster;
+        report_chain(vol, first, walked.path, &mut diff, &mut report, &mut on_diff)
+    })?;
+
+    for cluster in 2..bits as u32 {
+        if diff.get(cluster as usize) {
+            report_cluster(vol, cluster, b"", &mut diff, &mut report, &mut on_diff)?;
+        }
+    }
+    Ok(report)
+}
+
+/* Check the directory tree against each FAT in turn, `on_score` gets every
+ * result. The copy with the fewest errors wins, the lowest index on ties */
+pub fn most_consistent_fat<D: BlockDevice, F: FnMut(u8, &CheckReport)>(
+    vol: &mut Volume<D>,
+    scratch: &mut [u8],
+    mut on_score: F,
+) -> Result<u8, CheckError> {
+    let active = vol.active_fat;
+    let mut best = (active, u32::MAX);
+
+    for fat in 0..vol.bs.fats_count {
+        vol.active_fat = fat;
+        let result = check_volume(vol, scratch, |_| {});
+        vol.active_fat = active;
+
+        let report = result?;
+        on_score(fat, &report);
+        if report.errors < best.1 {
+            best = (fat, report.errors);
+        }
+    }
+    Ok(best.0)
+}
+
+/* Copy FAT number `from` over every other one */
+pub fn reconcile_fats<D: BlockDevice>(vol: &mut Volume<D>, from: u8) -> Result<(), VolumeError> {
+    for fat in 0..vol.bs.fats_count {
+        if fat != from {
+            vol.copy_fat(from, fat)?;
+        }
+    }
+    Ok(())
+}
+
+/* __________ Shell __________ */
+fn print_difference(d: &FatDifference) {
+    print_no_ln("cluster ");
+    print_number(d.cluster as u64);
+    print_no_ln(": FAT #");
+    print_number(d.active_fat as u64 + 1);
+    print_no_ln(" ");
+    print_hex_u32(d.active_value);
+    print_no_ln(", FAT #");
+    print_number(d.fat as u64 + 1);
+    print_no_ln(" ");
+    print_hex_u32(d.value);
+    if d.path.is_empty() {
+        print(" (no owner)");
+    } else {
+        print_no_ln(" ");
+        print_bytes(d.path);
+        print("");
+    }
+}
+
+/* `fatcmp [-s]`: diff the FAT copies, `-s` copies the one agreeing best with
+ * the directory tree over the others. Returns 0 when they were identical */
+pub fn fatcmp_command(dev: &mut ImageDevice, image: &ImageFile, args: &[&[u8]]) -> usize {
+    let mut sync = false;
+    for &word in args {
+        match word {
+            b"-s" => sync = true,
+            _ => {
+                print("Usage: fatcmp [-s]");
+                return CHECK_FAILED_EXIT_CODE;
+            }
+        }
+    }
+
+    if !sync {
+        return run_fatcmp(dev, sync);
+    }
+    dev.with_writable(image, |dev| run_fatcmp(dev, sync)).unwrap_or(CHECK_FAILED_EXIT_CODE)
+}
+
+fn run_fatcmp(dev: &mut ImageDevice, sync: bool) -> usize {
+    let mut vol = match Volume::mount(dev) {
+        Ok(vol) => vol,
+        Err(_) => {
+            print("Failed to mount the volume");
+            return CHECK_FAILED_EXIT_CODE;
+        }
+    };
+    if vol.bs.fats_count < 2 {
+        print("The volume has a single FAT");
+        return 0;
+    }
+
+    let result = with_scratch(|scratch| {
+        let report = compare_fats(&mut vol, scratch, print_difference)?;
+        if report.clusters == 0 || !sync {
+            return Ok((report, None));
+        }
+
+        let best = most_consistent_fat(&mut vol, scratch, |fat, check| {
+            print_no_ln("FAT #");
+            print_number(fat as u64 + 1);
+            print_no_ln(": ");
+            print_number(check.errors as u64);
+            print(" errors against the directory tree");
+        })?;
+        reconcile_fats(&mut vol, best)?;
+        Ok((report, Some(best)))
+    });
+    if vol.flush().is_err() {
+        print("Failed to write the volume");
+        return CHECK_FAILED_EXIT_CODE;
+    }
+
+    match result {
+        Ok((report, best)) => {
+            if report.clusters == 0 {
+                print("FATs are identical");
+                return 0;
+            }
+            print_number(report.differences as u64);
+            print_no_ln(" differences over ");
+            print_number(report.clusters as u64);
+            print(" clusters");
+            if let Some(best) = best {
+                print_no_ln("Copied FAT #");
+                print_number(best as u64 + 1);
+                print(" over the others");
+            }
+            1
+        }
+        Err(CheckError::ScratchTooSmall) => {
+            print("Volume has too many clusters to be compared");
+            CHECK_FAILED_EXIT_CODE
+        }
+        Err(CheckError::Volume(_)) => {
+            print("Failed to access the volume");
+            CHECK_FAILED_EXIT_CODE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::check_scratch_size;
+    use crate::device::MemDevice;
+    use crate::dir_entry::build_dir_entry;
+    use crate::fixture::{ImageBuilder, Node};
+    use crate::format::{FormatOptions, format_volume};
+    use crate::volume::FAT_EOC_MARK;
+    use crate::helpers::u32_to_u8_le;
+    use std::vec;
+    use std::vec::Vec;
+
+    const SIZE: usize = 40 * 1024 * 1024;
+
+    /* Fresh volume holding FILE.TXT over clusters 3 and 4 */
+    fn image() -> Vec<u8> {
+        let mut data = vec![0u8; SIZE];
+        let mut dev = MemDevice { data: &mut data };
+        format_volume(&mut dev, SIZE as u64, &FormatOptions::default()).unwrap();
+        {
+            let mut vol = Volume::mount(dev).unwrap();
+            let entry = build_dir_entry(b"FILE    TXT", 0x20, 3, 1024, 0);
+            let root = vol.cluster_offset(2);
+            vol.dev.write_at(root, &entry).unwrap();
+            vol.set_fat_entry(3, 4).unwrap();
+            vol.set_fat_entry(4, 0x0FFFFFFF).unwrap();
+        }
+
+        /* Keep the FSInfo free count right so only the FATs matter */
+        let free = u32::from_le_bytes(data[512 + 488..512 + 492].try_into().unwrap()) - 2;
+        u32_to_u8_le(free, &mut data[512 + 488..512 + 492]);
+        data
+    }
+
+    /* Raw write of one entry of FAT `fat` */
+    fn poke(data: &mut [u8], fat: u8, cluster: u32, value: u32) {
+        let off = {
+            let vol = Volume::mount(MemDevice { data: &mut *data }).unwrap();
+            let fat_bytes = vol.bs.fat_size_sectors as usize * 512;
+            vol.fat_start as usize + fat as usize * fat_bytes + cluster as usize * 4
+        };
+        u32_to_u8_le(value, &mut data[off..off + 4]);
+    }
+
+    #[test]
+    fn identical_fats() {
+        let mut data = image();
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let mut scratch = vec![0u8; compare_scratch_size(vol.clusters_count)];
+        let report = compare_fats(&mut vol, &mut scratch, |_| panic!()).unwrap();
+        assert_eq!(report, CompareReport::default());
+    }
+
+    #[test]
+    fn differences_come_with_their_owner() {
+        let mut data = image();
+        poke(&mut data, 1, 4, 0);
+        poke(&mut data, 1, 100, 0x0FFFFFFF);
+
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let mut scratch = vec![0u8; compare_scratch_size(vol.clusters_count)];
+        let mut seen = Vec::new();
+        let report = compare_fats(&mut vol, &mut scratch, |d| {
+            seen.push((d.cluster, d.fat, d.active_value, d.value, d.path.to_vec()));
+        })
+        .unwrap();
+
+        assert_eq!(report.differences, 2);
+        assert_eq!(report.clusters, 2);
+        assert_eq!(
+            seen,
+            vec![
+                (4, 1, 0x0FFFFFFF, 0, b"/FILE.TXT".to_vec()),
+                (100, 1, 0, 0x0FFFFFFF, Vec::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reconciles_from_the_consistent_copy() {
+        let mut data = image();
+        /* The active FAT lost the end of the file */
+        poke(&mut data, 0, 4, 0);
+
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let mut scratch = vec![0u8; check_scratch_size(vol.clusters_count)];
+        let mut scores = Vec::new();
+        let best = most_consistent_fat(&mut vol, &mut scratch, |fat, r| scores.push((fat, r.errors)))
+            .unwrap();
+        assert_eq!(best, 1);
+        assert_eq!(scores[1], (1, 0));
+        assert!(scores[0].1 > 0);
+
+        reconcile_fats(&mut vol, best).unwrap();
+        let report = compare_fats(&mut vol, &mut scratch, |_| {}).unwrap();
+        assert_eq!(report.differences, 0);
+        let check = check_volume(&mut vol, &mut scratch, |_| {}).unwrap();
+        assert_eq!(check.errors, 0);
+    }
+
+    #[test]
+    fn compares_packed_fat12_and_fat16_entries() {
+        let tree = [Node::Dir("sub", &[Node::File("a.txt", &[1u8; 3000])])];
+        for builder in [ImageBuilder::fat12(), ImageBuilder::fat16()] {
+            let mut data = builder.build(&tree);
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            /* Only the second FAT loses the end of the file */
+            vol.mirrored = false;
+            vol.active_fat = 1;
+            let last = (2..vol.clusters_count + 2)
+                .filter(|&c| vol.fat_entry(c).unwrap() != 0)
+                .last()
+                .unwrap();
+            vol.set_fat_entry(last, 0).unwrap();
+            /* Its neighbour shares a byte with it on FAT12 and must stay equal */
+            vol.active_fat = 0;
+
+            let mut scratch = vec![0u8; compare_scratch_size(vol.clusters_count)];
+            let mut seen = Vec::new();
+            compare_fats(&mut vol, &mut scratch, |d| {
+                seen.push((d.cluster, d.active_value, d.value, d.path.to_vec()));
+            })
+            .unwrap();
+            assert_eq!(seen, vec![(last, FAT_EOC_MARK, 0, b"/SUB/A.TXT".to_vec())]);
+        }
+    }
+}
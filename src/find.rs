@@ -0,0 +1,330 @@
+/* `find`: the entries below a directory passing every test given, by
+ * path, hidden ones included. Sizes only apply to files */
+
+use crate::cli::{print, print_fat_date, print_no_ln, print_number};
+use crate::dir_entry::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM};
+use crate::helpers::parse_u64;
+use crate::pattern::glob_match;
+use crate::sys::print_bytes;
+use crate::tree::{TreeEntry, TreeLine, TreeOptions, TreeSource, visit_tree};
+use crate::volume::VolumeError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FindOptions<'a> {
+    /* Glob over the long or the short name, without case */
+    pub name: Option<&'a [u8]>,
+    /* Directories only when true, files only when false */
+    pub dirs: Option<bool>,
+    /* Both ends included */
+    pub min_size: u64,
+    pub max_size: u64,
+    /* Packed FAT dates, both ends included */
+    pub from: u16,
+    pub to: u16,
+    /* Attribute bits all set on a match */
+    pub attributes: u8,
+    /* Cluster, size and date before each path */
+    pub long: bool,
+}
+
+impl Default for FindOptions<'_> {
+    fn default() -> Self {
+        FindOptions {
+            name: None,
+            dirs: None,
+            min_size: 0,
+            max_size: u64::MAX,
+            from: 0,
+            to: u16::MAX,
+            attributes: 0,
+            long: false,
+        }
+    }
+}
+
+impl FindOptions<'_> {
+    pub fn matches<H>(&self, entry: &TreeEntry<H>) -> bool {
+        let is_dir = entry.is_dir;
+        let date = (entry.modified >> 16) as u16;
+        self.name.is_none_or(|name| {
+            glob_match(name, entry.name, true)
+                || (!entry.short.is_empty() && glob_match(name, entry.short, true))
+        }) && self.dirs.is_none_or(|dirs| dirs == is_dir)
+            && (is_dir && self.min_size == 0 && self.max_size == u64::MAX
+                || !is_dir && (self.min_size..=self.max_size).contains(&entry.size))
+            && (self.from..=self.to).contains(&date)
+            && entry.attributes & self.attributes == self.attributes
+    }
+}
+
+/* `12`, `4k`, `2M` or `1G`, in bytes */
+fn parse_size(word: &[u8]) -> Option<u64> {
+    let (digits, unit) = match word.last()? {
+        b'k' | b'K' => (&word[..word.len() - 1], 1 << 10),
+        b'M' => (&word[..word.len() - 1], 1 << 20),
+        b'G' => (&word[..word.len() - 1], 1 << 30),
+        _ => (word, 1),
+    };
+    parse_u64(digits)?.checked_mul(unit)
+}
+
+/* `YYYY-MM-DD` as a packed FAT date */
+fn parse_date(word: &[u8]) -> Option<u16> {
+    let [year, month, day] = [word.get(0..4)?, word.get(5..7)?, word.get(8..)?].map(parse_u64);
+    let (year, month, day) = (year?, month?, day?);
+    if word[4] != b'-' || word[7] != b'-' {
+        return None;
+    }
+    if !(1980..=2107).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16)
+}
+
+/* `a..b`, `a..`, `..b` or `a` alone, both ends included */
+fn parse_range<T>(word: &[u8], parse: fn(&[u8]) -> Option<T>, min: T, max: T) -> Option<(T, T)>
+where
+    T: Copy + PartialOrd,
+{
+    let Some(dots) = word.windows(2).position(|w| w == b"..") else {
+        let value = parse(word)?;
+        return Some((value, value));
+    };
+    let (lo, hi) = (&word[..dots], &word[dots + 2..]);
+    if lo.is_empty() && hi.is_empty() {
+        return None;
+    }
+    let lo = if lo.is_empty() { min } else { parse(lo)? };
+    let hi = if hi.is_empty() { max } else { parse(hi)? };
+    (lo <= hi).then_some((lo, hi))
+}
+
+/* `rhsda` letters as attribute bits */
+fn parse_attributes(word: &[u8]) -> Option<u8> {
+    word.iter().try_fold(0, |bits, &c| {
+        Some(
+            bits | match c {
+                b'r' => ATTR_READ_ONLY,
+                b'h' => ATTR_HIDDEN,
+                b's' => ATTR_SYSTEM,
+                b'd' => ATTR_DIRECTORY,
+                b'a' => ATTR_ARCHIVE,
+                _ => return None,
+            },
+        )
+    })
+}
+
+/* The tests of `find` and the directory, the current one when empty */
+pub fn parse_find_args<'a>(args: &[&'a [u8]]) -> Option<(FindOptions<'a>, &'a [u8])> {
+    let mut opts = FindOptions::default();
+    let mut path: Option<&[u8]> = None;
+    let mut words = args.iter();
+    while let Some(&word) = words.next() {
+        match word {
+            b"-name" => opts.name = Some(words.next()?),
+            b"-type" => {
+                opts.dirs = Some(match *words.next()? {
+                    b"d" => true,
+                    b"f" => false,
+                    _ => return None,
+                })
+            }
+            b"-size" => {
+                (opts.min_size, opts.max_size) =
+                    parse_range(words.next()?, parse_size, 0, u64::MAX)?
+            }
+            b"-mtime" => {
+                (opts.from, opts.to) = parse_range(words.next()?, parse_date, 0, u16::MAX)?
+            }
+            b"-attr" => opts.attributes = parse_attributes(words.next()?)?,
+            b"-l" => opts.long = true,
+            _ if path.is_none() && !word.starts_with(b"-") => path = Some(word),
+            _ => return None,
+        }
+    }
+    Some((opts, path.unwrap_or_default()))
+}
+
+/* `n` right aligned over `width` columns, then two spaces */
+fn print_column(n: u64, width: usize) {
+    let digits = n.checked_ilog10().unwrap_or(0) as usize + 1;
+    for _ in digits..width {
+        print_no_ln(" ");
+    }
+    print_number(n);
+    print_no_ln("  ");
+}
+
+fn print_match<H>(line: &TreeLine<H>, base: &[u8], long: bool) {
+    let entry = line.entry;
+    if long {
+        print_column(entry.first_cluster as u64, 10);
+        print_column(entry.size, 12);
+        print_fat_date((entry.modified >> 16) as u16, entry.modified as u16);
+        print_no_ln("  ");
+    }
+    if base != b"/" {
+        print_bytes(base);
+    }
+    print_bytes(line.path);
+    if entry.is_dir {
+        print_no_ln("/");
+    }
+    print("");
+}
+
+/* Print the entries below `root`, shown as `base`, passing `opts`.
+ * Returns how many did */
+pub fn find<S: TreeSource>(
+    src: &mut S,
+    root: S::Dir,
+    base: &[u8],
+    opts: &FindOptions,
+) -> Result<u64, VolumeError> {
+    let walk = TreeOptions {
+        hidden: true,
+        ..TreeOptions::default()
+    };
+    let mut found = 0;
+    visit_tree(src, root, &walk, &mut |line| {
+        if opts.matches(line.entry) {
+            found += 1;
+            print_match(line, base, opts.long);
+        }
+    })?;
+    print_number(found);
+    print(if found == 1 {
+        " entry found"
+    } else {
+        " entries found"
+    });
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::parse_boot_sector;
+    use crate::device::MemDevice;
+    use crate::fat::{FatTree, fat_regions};
+    use crate::fixture::{ImageBuilder, Node};
+    use std::vec::Vec;
+
+    #[test]
+    fn parses_tests_and_ranges() {
+        let (opts, path) = parse_find_args(&[
+            b"/docs", b"-name", b"*.txt", b"-type", b"f", b"-size", b"1k..2M",
+        ])
+        .unwrap();
+        assert_eq!(path, b"/docs");
+        assert_eq!(opts.name, Some(&b"*.txt"[..]));
+        assert_eq!(opts.dirs, Some(false));
+        assert_eq!((opts.min_size, opts.max_size), (1024, 2 << 20));
+
+        let (opts, path) = parse_find_args(&[
+            b"-size",
+            b"..10",
+            b"-mtime",
+            b"2024-02-29",
+            b"-attr",
+            b"rh",
+            b"-l",
+        ])
+        .unwrap();
+        assert_eq!(path, b"");
+        assert_eq!((opts.min_size, opts.max_size), (0, 10));
+        let day = (44 << 9) | (2 << 5) | 29;
+        assert_eq!((opts.from, opts.to), (day, day));
+        assert_eq!(opts.attributes, ATTR_READ_ONLY | ATTR_HIDDEN);
+        assert!(opts.long);
+
+        assert_eq!(
+            parse_find_args(&[b"-mtime", b"2024-01-01.."]).unwrap().0.to,
+            u16::MAX
+        );
+        assert!(parse_find_args(&[b"-size", b"2M..1k"]).is_none());
+        assert!(parse_find_args(&[b"-size", b".."]).is_none());
+        assert!(parse_find_args(&[b"-mtime", b"2024-13-01"]).is_none());
+        assert!(parse_find_args(&[b"-type", b"x"]).is_none());
+        assert!(parse_find_args(&[b"-attr", b"q"]).is_none());
+        assert!(parse_find_args(&[b"-name"]).is_none());
+        assert!(parse_find_args(&[b"a", b"b"]).is_none());
+        assert!(parse_find_args(&[b"-bogus"]).is_none());
+    }
+
+    #[test]
+    fn matches_every_test_given() {
+        let file = TreeEntry::<u32> {
+            name: b"Report 2024.TXT",
+            short: b"report~1.txt",
+            node: 9,
+            is_dir: false,
+            attributes: ATTR_ARCHIVE | ATTR_READ_ONLY,
+            modified: ((44 << 9) | (3 << 5) | 1) << 16,
+            first_cluster: 9,
+            size: 1500,
+        };
+        let dir = TreeEntry {
+            name: b"reports",
+            short: b"reports",
+            node: 10,
+            is_dir: true,
+            attributes: ATTR_DIRECTORY,
+            size: 0,
+            ..file
+        };
+        let find = |args: &[&[u8]]| {
+            let (opts, _) = parse_find_args(args).unwrap();
+            (opts.matches(&file), opts.matches(&dir))
+        };
+        assert_eq!(find(&[]), (true, true));
+        assert_eq!(find(&[b"-name", b"report*"]), (true, true));
+        assert_eq!(find(&[b"-name", b"*~1.txt"]), (true, false));
+        assert_eq!(find(&[b"-type", b"d"]), (false, true));
+        assert_eq!(find(&[b"-size", b"1k..2k"]), (true, false));
+        assert_eq!(find(&[b"-size", b"2k.."]), (false, false));
+        assert_eq!(find(&[b"-mtime", b"2024-03-01..2024-03-31"]), (true, true));
+        assert_eq!(find(&[b"-mtime", b"..2024-02-29"]), (false, false));
+        assert_eq!(find(&[b"-attr", b"r"]), (true, false));
+        assert_eq!(find(&[b"-attr", b"d"]), (false, true));
+    }
+
+    #[test]
+    fn finds_entries_of_an_image() {
+        let tree = [
+            Node::Dir("sub", &[Node::File("inner.txt", b"inner")]),
+            Node::File("A long file name.txt", &[1u8; 600]),
+            Node::File("empty.txt", b""),
+        ];
+        for builder in [ImageBuilder::new(), ImageBuilder::fat12()] {
+            let mut data = builder.build(&tree);
+            let mut sector = [0u8; 512];
+            sector.copy_from_slice(&data[..512]);
+            let bs = parse_boot_sector(&sector).unwrap();
+            let (fat_start, data_start) = fat_regions(&bs);
+            let mut dev = MemDevice { data: &mut data };
+            let mut src = FatTree {
+                dev: &mut dev,
+                bs: &bs,
+                fat_start,
+                data_start,
+                chunk: &mut [0u8; 4096],
+            };
+
+            /* The short name of a long one matches too */
+            let (opts, _) = parse_find_args(&[b"-name", b"*~1.TXT"]).unwrap();
+            let mut found = Vec::new();
+            visit_tree(&mut src, bs.root_cluster, &TreeOptions::default(), &mut |line| {
+                if opts.matches(line.entry) {
+                    found.push((line.path.to_vec(), line.entry.first_cluster != 0));
+                }
+            })
+            .unwrap();
+            assert_eq!(found, [(b"/A long file name.txt".to_vec(), true)]);
+
+            let (opts, _) = parse_find_args(&[b"-type", b"f", b"-size", b"..10"]).unwrap();
+            assert_eq!(find(&mut src, bs.root_cluster, b"/", &opts), Ok(2));
+        }
+    }
+}
@@ -0,0 +1,491 @@
+/* In-memory FAT images built from a tree description, so tests don't need
+ * `create_image.sh`, root or a loop mount */
+
+use crate::boot_sector::FatType;
+use crate::device::{BlockDevice, MemDevice};
+use crate::dir_entry::{
+    ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_LONG_NAME, DIR_ENTRY_SIZE, DOT_DOT_NAME, DOT_NAME,
+    ENTRY_DELETED, LFN_LAST_ENTRY, LFN_UNIT_OFFSETS, LFN_UNITS_PER_ENTRY, build_dir_entry,
+    is_legal_short_name, lfn_checksum, pack_short_name,
+};
+use crate::format::{FormatOptions, format_volume};
+use crate::helpers::{u16_to_u8_le, u32_to_u8_le};
+use crate::volume::{FAT_EOC_MARK, FSINFO_UNKNOWN, Volume};
+use std::vec;
+use std::vec::Vec;
+
+/* 2024-02-29 13:45:30, every entry gets it */
+pub const FIXTURE_TIME: u64 = 1709214330;
+
+/* A name that isn't a legal 8.3 one gets long name entries and a `~N`
+ * short name, like Windows does */
+pub enum Node<'a> {
+    File(&'a str, &'a [u8]),
+    Dir(&'a str, &'a [Node<'a>]),
+    /* Deleted file: entries marked 0xE5, data left in clusters freed in the FAT */
+    Deleted(&'a str, &'a [u8]),
+}
+
+pub struct ImageBuilder {
+    size: usize,
+    options: FormatOptions,
+    /* Leave a free cluster after every allocated one */
+    fragmented: bool,
+    /* FAT12/16 images get a hand written BPB, the formatter only does FAT32 */
+    small: Option<FatType>,
+    /* First cluster handed out to the tree, right after the root by default */
+    first: Option<u32>,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageBuilder {
+    /* 40 MiB, the smallest FAT32 volume with 512 byte clusters */
+    pub fn new() -> Self {
+        ImageBuilder {
+            size: 40 * 1024 * 1024,
+            options: FormatOptions {
+                sectors_per_cluster: Some(1),
+                ..FormatOptions::default()
+            },
+            fragmented: false,
+            small: None,
+            first: None,
+        }
+    }
+
+    /* 1.44 MB floppy */
+    pub fn fat12() -> Self {
+        ImageBuilder {
+            size: 2880 * 512,
+            small: Some(FatType::Fat12),
+            ..Self::new()
+        }
+    }
+
+    /* 16 MiB with 2 KiB clusters */
+    pub fn fat16() -> Self {
+        ImageBuilder {
+            size: 32768 * 512,
+            small: Some(FatType::Fat16),
+            ..Self::new()
+        }
+    }
+
+    pub fn size(mut self, bytes: usize) -> Self {
+        self.size = bytes;
+        self
+    }
+
+    pub fn options(mut self, options: FormatOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn fragmented(mut self) -> Self {
+        self.fragmented = true;
+        self
+    }
+
+    pub fn first_cluster(mut self, cluster: u32) -> Self {
+        self.first = Some(cluster);
+        self
+    }
+
+    pub fn build(&self, tree: &[Node]) -> Vec<u8> {
+        let mut data = vec![0u8; self.size];
+        match self.small {
+            Some(fat_type) => write_small_bpb(&mut data, fat_type),
+            None => {
+                format_volume(
+                    &mut MemDevice { data: &mut data },
+                    self.size as u64,
+                    &self.options,
+                )
+                .unwrap();
+            }
+        }
+
+        let vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let root = vol.bs.root_cluster;
+        let mut b = Builder {
+            vol,
+            /* The fixed root of FAT12/16 owns no cluster */
+            next: self.first.unwrap_or(core::cmp::max(root + 1, 2)),
+            step: if self.fragmented { 2 } else { 1 },
+            used: 0,
+        };
+
+        /* The formatter may have put the label in the first root slot */
+        let mut head = Vec::new();
+        if self.small.is_none() && &self.options.label != b"NO NAME    " {
+            let mut label = [0u8; 32];
+            let offset = b.vol.cluster_offset(root);
+            b.vol.dev.read_at(offset, &mut label).unwrap();
+            head.push(label);
+        }
+
+        let entries: usize = tree.iter().map(entries_for).sum();
+        let needed = clusters_for((head.len() + entries) * DIR_ENTRY_SIZE, b.vol.cluster_size);
+        let mut chain = Vec::new();
+        if root == 0 {
+            assert!(
+                entries <= b.vol.bs.root_entry_count as usize,
+                "fixture root doesn't fit in the fixed root directory"
+            );
+        } else {
+            chain.push(root);
+        }
+        if root != 0 && needed > 1 {
+            chain.extend(b.alloc(needed - 1));
+            /* The root's own cluster is already counted as used */
+            b.link(&chain);
+            b.used -= 1;
+        }
+        let children = b.children(tree);
+        b.write_dir(&chain, &head, tree, &children);
+
+        let used = b.used;
+        if let Some(mut info) = b.vol.read_fs_info().unwrap()
+            && info.free_count != FSINFO_UNKNOWN
+        {
+            info.free_count -= used;
+            b.vol.write_fs_info(info).unwrap();
+        }
+        data
+    }
+}
+
+/* Boot sector and FAT heads of a FAT12/16 image, see `ImageBuilder::fat12`
+ * and `ImageBuilder::fat16` */
+fn write_small_bpb(data: &mut [u8], fat_type: FatType) {
+    let (spc, reserved, root_entries, fat_size, media, fs_type) = match fat_type {
+        FatType::Fat12 => (1u8, 1u16, 224u16, 9u16, 0xF0u8, b"FAT12   "),
+        FatType::Fat16 => (4, 4, 512, 32, 0xF8, b"FAT16   "),
+        FatType::Fat32 => unreachable!("FAT32 images go through the formatter"),
+    };
+    let total = (data.len() / 512) as u16;
+
+    let bs = &mut data[..512];
+    bs[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    bs[3..11].copy_from_slice(b"FIXTURE ");
+    u16_to_u8_le(512, &mut bs[11..13]);
+    bs[13] = spc;
+    u16_to_u8_le(reserved, &mut bs[14..16]);
+    bs[16] = 2;
+    u16_to_u8_le(root_entries, &mut bs[17..19]);
+    u16_to_u8_le(total, &mut bs[19..21]);
+    bs[21] = media;
+    u16_to_u8_le(fat_size, &mut bs[22..24]);
+    u16_to_u8_le(18, &mut bs[24..26]);
+    u16_to_u8_le(2, &mut bs[26..28]);
+    bs[38] = 0x29;
+    u32_to_u8_le(0x1234_5678, &mut bs[39..43]);
+    bs[43..54].copy_from_slice(b"NO NAME    ");
+    bs[54..62].copy_from_slice(fs_type);
+    bs[510] = 0x55;
+    bs[511] = 0xAA;
+
+    /* Entry 0 holds the media byte, entry 1 an end of chain mark */
+    let head: &[u8] = match fat_type {
+        FatType::Fat12 => &[media, 0xFF, 0xFF],
+        _ => &[media, 0xFF, 0xFF, 0xFF],
+    };
+    for fat in 0..2 {
+        let at = (reserved as usize + fat * fat_size as usize) * 512;
+        data[at..at + head.len()].copy_from_slice(head);
+    }
+}
+
+fn clusters_for(bytes: usize, cluster_size: usize) -> usize {
+    bytes.div_ceil(cluster_size)
+}
+
+/* Short name for `name` not among `taken`, and whether it needs a long name */
+fn short_name(name: &str, taken: &[[u8; 11]]) -> ([u8; 11], bool) {
+    if let Some(packed) = pack_short_name(name.as_bytes())
+        && !taken.contains(&packed)
+    {
+        return (packed, false);
+    }
+
+    let keep = |c: &u8| *c != b' ' && *c != b'.';
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let mut basis = [b' '; 11];
+    for (dst, c) in basis[..6].iter_mut().zip(base.bytes().filter(keep)) {
+        *dst = c.to_ascii_uppercase();
+    }
+    for (dst, c) in basis[8..].iter_mut().zip(ext.bytes().filter(keep)) {
+        *dst = c.to_ascii_uppercase();
+    }
+    for c in basis.iter_mut() {
+        let mut probe = *b"A          ";
+        probe[1] = *c;
+        if *c != b' ' && !is_legal_short_name(&probe) {
+            *c = b'_';
+        }
+    }
+    let len = basis[..6].iter().position(|&c| c == b' ').unwrap_or(6).max(1);
+    if basis[0] == b' ' {
+        basis[0] = b'_';
+    }
+
+    for n in 1..1000u32 {
+        let tail = std::format!("~{n}");
+        let at = core::cmp::min(len, 8 - tail.len());
+        let mut candidate = basis;
+        candidate[at..8].fill(b' ');
+        candidate[at..at + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&candidate) {
+            return (candidate, true);
+        }
+    }
+    panic!("too many names sharing a basis in one fixture directory");
+}
+
+/* Long name entries of `name` for the short name `short`, in on-disk order */
+pub fn lfn_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; 32]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_UNITS_PER_ENTRY);
+    let checksum = lfn_checksum(short);
+
+    let mut entries = Vec::new();
+    for n in (1..=count).rev() {
+        let mut e = [0u8; 32];
+        e[0] = n as u8 | if n == count { LFN_LAST_ENTRY } else { 0 };
+        e[11] = ATTR_LONG_NAME;
+        e[13] = checksum;
+        for (i, &off) in LFN_UNIT_OFFSETS.iter().enumerate() {
+            let at = (n - 1) * LFN_UNITS_PER_ENTRY + i;
+            let unit = match at.cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[at],
+                core::cmp::Ordering::Equal => 0x0000,
+                core::cmp::Ordering::Greater => 0xFFFF,
+            };
+            u16_to_u8_le(unit, &mut e[off..off + 2]);
+        }
+        entries.push(e);
+    }
+    entries
+}
+
+struct Builder<'a> {
+    vol: Volume<MemDevice<'a>>,
+    /* Next cluster handed out */
+    next: u32,
+    step: u32,
+    /* Clusters linked in the FAT */
+    used: u32,
+}
+
+/* A child of a directory being built: its entries, long name ones first,
+ * and the clusters it owns */
+struct Slot {
+    raw: Vec<[u8; 32]>,
+    clusters: Vec<u32>,
+}
+
+impl Builder<'_> {
+    fn alloc(&mut self, count: usize) -> Vec<u32> {
+        let clusters: Vec<u32> = (0..count as u32).map(|i| self.next + i * self.step).collect();
+        self.next += count as u32 * self.step;
+        assert!(
+            self.vol.is_valid_cluster(self.next - 1),
+            "fixture tree doesn't fit in the volume"
+        );
+        clusters
+    }
+
+    fn link(&mut self, chain: &[u32]) {
+        for (i, &cluster) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).copied().unwrap_or(FAT_EOC_MARK);
+            self.vol.set_fat_entry(cluster, next).unwrap();
+        }
+        self.used += chain.len() as u32;
+    }
+
+    /* Spread `bytes` over `chain`, the rest of the last cluster zeroed */
+    fn write_chain(&mut self, chain: &[u32], bytes: &[u8]) {
+        let cs = self.vol.cluster_size;
+        assert!(bytes.len() <= chain.len() * cs, "fixture chain too short");
+        for (i, &cluster) in chain.iter().enumerate() {
+            let mut buf = vec![0u8; cs];
+            let start = core::cmp::min(i * cs, bytes.len());
+            let end = core::cmp::min(start + cs, bytes.len());
+            buf[..end - start].copy_from_slice(&bytes[start..end]);
+            self.vol.write_cluster(cluster, &buf).unwrap();
+        }
+    }
+
+    /* Entries of every node of `tree`, with clusters allocated, filled and
+     * linked for each of them */
+    fn children(&mut self, tree: &[Node]) -> Vec<Slot> {
+        let mut slots = Vec::new();
+        let mut taken: Vec<[u8; 11]> = Vec::new();
+        for node in tree {
+            let (name, attr, size, clusters, deleted) = match node {
+                Node::File(name, data) | Node::Deleted(name, data) => {
+                    let count = clusters_for(data.len(), self.vol.cluster_size);
+                    let clusters = self.alloc(count);
+                    let deleted = matches!(node, Node::Deleted(..));
+                    if !deleted {
+                        self.link(&clusters);
+                    }
+                    self.write_chain(&clusters, data);
+                    (*name, ATTR_ARCHIVE, data.len() as u32, clusters, deleted)
+                }
+                Node::Dir(name, children) => {
+                    let entries: usize = children.iter().map(entries_for).sum();
+                    let count = clusters_for((2 + entries) * DIR_ENTRY_SIZE, self.vol.cluster_size);
+                    let clusters = self.alloc(count);
+                    self.link(&clusters);
+                    (*name, ATTR_DIRECTORY, 0, clusters, false)
+                }
+            };
+
+            let (short, needs_lfn) = short_name(name, &taken);
+            taken.push(short);
+            let first = clusters.first().copied().unwrap_or(0);
+            let mut raw = if needs_lfn {
+                lfn_entries(name, &short)
+            } else {
+                Vec::new()
+            };
+            raw.push(build_dir_entry(&short, attr, first, size, FIXTURE_TIME));
+            if deleted {
+                raw.iter_mut().for_each(|e| e[0] = ENTRY_DELETED);
+            }
+            slots.push(Slot { raw, clusters });
+        }
+        slots
+    }
+
+    /* Write `head` then the entries of `children` over `chain`, an empty
+     * one being the fixed root, and fill the subdirectories in turn */
+    fn write_dir(&mut self, chain: &[u32], head: &[[u8; 32]], tree: &[Node], children: &[Slot]) {
+        let mut bytes: Vec<u8> = head.concat();
+        for slot in children {
+            bytes.extend_from_slice(&slot.raw.concat());
+        }
+        if chain.is_empty() {
+            let bps = self.vol.bs.bytes_per_sector as u64;
+            let root_start = self.vol.data_start - self.vol.bs.root_dir_sectors() as u64 * bps;
+            self.vol.dev.write_at(root_start, &bytes).unwrap();
+        } else {
+            self.write_chain(chain, &bytes);
+        }
+
+        /* `..` of a child of the root is 0 */
+        let parent = match chain.first() {
+            Some(&first) if first != self.vol.bs.root_cluster => first,
+            _ => 0,
+        };
+        for (node, slot) in tree.iter().zip(children) {
+            if let Node::Dir(_, grandchildren) = node {
+                let own = slot.clusters[0];
+                let dots = [
+                    build_dir_entry(DOT_NAME, ATTR_DIRECTORY, own, 0, FIXTURE_TIME),
+                    build_dir_entry(DOT_DOT_NAME, ATTR_DIRECTORY, parent, 0, FIXTURE_TIME),
+                ];
+                let entries = self.children(grandchildren);
+                self.write_dir(&slot.clusters, &dots, grandchildren, &entries);
+            }
+        }
+    }
+}
+
+/* Directory entries `node` takes, long name ones included */
+fn entries_for(node: &Node) -> usize {
+    let name = match node {
+        Node::File(name, _) | Node::Dir(name, _) | Node::Deleted(name, _) => name,
+    };
+    match pack_short_name(name.as_bytes()) {
+        Some(_) => 1,
+        None => 1 + name.encode_utf16().count().div_ceil(LFN_UNITS_PER_ENTRY),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::{check_scratch_size, check_volume};
+    use crate::dir_entry::LfnBuilder;
+    use crate::dir_entry::lfn_to_utf8;
+
+    fn check_clean(data: &mut [u8]) {
+        let mut vol = Volume::mount(MemDevice { data }).unwrap();
+        let mut scratch = vec![0u8; check_scratch_size(vol.clusters_count)];
+        let mut issues = 0;
+        let report = check_volume(&mut vol, &mut scratch, |_| issues += 1).unwrap();
+        assert_eq!((issues, report.errors), (0, 0));
+    }
+
+    #[test]
+    fn built_images_check_clean() {
+        let big = vec![b'x'; 3000];
+        let many: Vec<std::string::String> = (0..40).map(|i| std::format!("entry number {i}.txt")).collect();
+        let many: Vec<Node> = many.iter().map(|name| Node::File(name, b"")).collect();
+        let tree = [
+            Node::Dir("sub", &[Node::File("inner.txt", b"hello"), Node::Dir("deeper", &[])]),
+            Node::File("A long file name.txt", &big),
+            Node::Deleted("gone.txt", b"bye"),
+            Node::Dir("crowded", &many),
+        ];
+
+        check_clean(&mut ImageBuilder::new().build(&tree));
+        check_clean(&mut ImageBuilder::new().fragmented().build(&tree));
+        check_clean(
+            &mut ImageBuilder::new()
+                .options(FormatOptions {
+                    sectors_per_cluster: Some(2),
+                    reserved_sectors_count: 37,
+                    fats_count: 3,
+                    label: *b"FIXTURE    ",
+                    ..FormatOptions::default()
+                })
+                .size(80 * 1024 * 1024)
+                .build(&tree),
+        );
+    }
+
+    #[test]
+    fn small_images_have_the_requested_type() {
+        for (builder, fat_type) in [
+            (ImageBuilder::fat12(), FatType::Fat12),
+            (ImageBuilder::fat16(), FatType::Fat16),
+        ] {
+            let mut data = builder.build(&[Node::Dir("sub", &[Node::File("a.txt", b"a")])]);
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            assert_eq!(vol.bs.fat_type, fat_type);
+            assert_eq!(vol.bs.root_cluster, 0);
+            assert_eq!(vol.fat_entry(2).unwrap(), FAT_EOC_MARK);
+            assert_eq!(vol.fat_entry(3).unwrap(), FAT_EOC_MARK);
+            assert_eq!(vol.fat_entry(4).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn long_names_get_unique_short_names() {
+        let mut taken = Vec::new();
+        let (first, lfn) = short_name("Long name.txt", &taken);
+        assert_eq!((&first, lfn), (b"LONGNA~1TXT", true));
+        taken.push(first);
+        assert_eq!(short_name("Long name.txt", &taken).0, *b"LONGNA~2TXT");
+        assert_eq!(short_name("readme", &taken), (*b"README     ", false));
+
+        let entries = lfn_entries("Long name.txt", &first);
+        let mut b = LfnBuilder::new();
+        entries.iter().for_each(|e| assert_eq!(b.push(e), 0));
+        let units = b.finish(&first).unwrap().unwrap();
+        let mut out = [0u8; 32];
+        let len = lfn_to_utf8(units, &mut out);
+        assert_eq!(&out[..len], b"Long name.txt");
+    }
+}
@@ -0,0 +1,628 @@
+use crate::cli::{print, print_no_ln, print_number};
+use crate::device::{BlockDevice, DeviceError, FileDevice};
+use crate::exfat::format::format_exfat;
+use crate::helpers::{
+    fat_datetime, parse_u64, to_uppercase_ascii, u16_to_u8_le, u32_to_u8_le,
+};
+use crate::sys::{close, fsync, ftruncate, now_seconds, open_rw};
+
+/* A volume with fewer clusters than this is FAT16 by definition */
+pub const FAT32_MIN_CLUSTERS: u32 = 65525;
+pub const FAT32_MAX_CLUSTERS: u32 = 0x0FFFFFF5;
+
+const FSINFO_SECTOR: u16 = 1;
+const BACKUP_BOOT_SECTOR: u16 = 6;
+const ROOT_CLUSTER: u32 = 2;
+const MEDIA_FIXED: u8 = 0xF8;
+
+/* SDXC cards, above 32 GiB, come formatted as exFAT */
+const EXFAT_DEFAULT_ABOVE: u64 = 32 * 1024 * 1024 * 1024;
+
+static ZEROES: [u8; 16384] = [0u8; 16384];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FsType {
+    Fat32,
+    Exfat,
+}
+
+pub struct FormatOptions {
+    pub bytes_per_sector: u16,
+    /* None picks the Microsoft default for the volume size */
+    pub sectors_per_cluster: Option<u8>,
+    pub reserved_sectors_count: u16,
+    pub fats_count: u8,
+    /* Sectors before the volume when it lives in a partition */
+    pub hidden_sectors: u32,
+    pub label: [u8; 11],
+    pub volume_id: u32,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            bytes_per_sector: 512,
+            sectors_per_cluster: None,
+            reserved_sectors_count: 32,
+            fats_count: 2,
+            hidden_sectors: 0,
+            label: *b"NO NAME    ",
+            volume_id: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatSummary {
+    pub total_sectors: u32,
+    pub sectors_per_cluster: u8,
+    pub fat_size_sectors: u32,
+    pub clusters_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    InvalidOptions,
+    /* Not enough clusters for FAT32, use a smaller cluster size */
+    TooFewClusters,
+    TooManyClusters,
+    Device(DeviceError),
+}
+
+impl From<DeviceError> for FormatError {
+    fn from(e: DeviceError) -> Self {
+        FormatError::Device(e)
+    }
+}
+
+/* Cluster size in bytes from the Microsoft FAT32 table (fatgen103) */
+pub fn default_cluster_size(volume_bytes: u64) -> u32 {
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+
+    if volume_bytes <= 260 * MIB {
+        512
+    } else if volume_bytes <= 8 * GIB {
+        4096
+    } else if volume_bytes <= 16 * GIB {
+        8192
+    } else if volume_bytes <= 32 * GIB {
+        16384
+    } else {
+        32768
+    }
+}
+
+/* Label as stored on disk: uppercase and padded with spaces */
+pub fn make_label(name: &[u8]) -> [u8; 11] {
+    let mut label = [b' '; 11];
+    to_uppercase_ascii(name, &mut label);
+    label
+}
+
+pub fn compute_geometry(
+    volume_bytes: u64,
+    opts: &FormatOptions,
+) -> Result<FormatSummary, FormatError> {
+    let bps = opts.bytes_per_sector as u64;
+    if !matches!(bps, 512 | 1024 | 2048 | 4096) {
+        return Err(FormatError::InvalidOptions);
+    }
+
+    /* Boot sector, FSInfo and their backups at 6 and 7 must fit */
+    if opts.reserved_sectors_count < 8 || opts.fats_count == 0 {
+        return Err(FormatError::InvalidOptions);
+    }
+
+    let spc = match opts.sectors_per_cluster {
+        Some(spc) => spc as u64,
+        None => core::cmp::max(1, default_cluster_size(volume_bytes) as u64 / bps),
+    };
+    if spc == 0 || !spc.is_power_of_two() || spc > 128 || spc * bps > 65536 {
+        return Err(FormatError::InvalidOptions);
+    }
+
+    let total_sectors = volume_bytes / bps;
+    if total_sectors > u32::MAX as u64 {
+        return Err(FormatError::TooManyClusters);
+    }
+
+    let reserved = opts.reserved_sectors_count as u64;
+    let fats = opts.fats_count as u64;
+    if total_sectors <= reserved {
+        return Err(FormatError::TooFewClusters);
+    }
+
+    /* Smallest FAT covering every cluster left once the FATs are placed,
+     * plus the two reserved entries */
+    let entries_per_sector = bps / 4;
+    let fat_size = (total_sectors - reserved + 2 * spc).div_ceil(spc * entries_per_sector + fats);
+
+    let system_sectors = reserved + fats * fat_size;
+    if total_sectors <= system_sectors {
+        return Err(FormatError::TooFewClusters);
+    }
+    let clusters = (total_sectors - system_sectors) / spc;
+
+    if clusters < FAT32_MIN_CLUSTERS as u64 {
+        return Err(FormatError::TooFewClusters);
+    }
+    if clusters > FAT32_MAX_CLUSTERS as u64 {
+        return Err(FormatError::TooManyClusters);
+    }
+
+    Ok(FormatSummary {
+        total_sectors: total_sectors as u32,
+        sectors_per_cluster: spc as u8,
+        fat_size_sectors: fat_size as u32,
+        clusters_count: clusters as u32,
+    })
+}
+
+fn build_boot_sector(opts: &FormatOptions, summary: &FormatSummary, sector: &mut [u8]) {
+    sector.fill(0);
+
+    /* Jump over the BPB, then the OEM name */
+    sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+
+    u16_to_u8_le(opts.bytes_per_sector, &mut sector[11..13]);
+    sector[13] = summary.sectors_per_cluster;
+    u16_to_u8_le(opts.reserved_sectors_count, &mut sector[14..16]);
+    sector[16] = opts.fats_count;
+    /* Root entries count, total sectors 16 and FAT size 16 stay 0 on FAT32 */
+    sector[21] = MEDIA_FIXED;
+    u16_to_u8_le(63, &mut sector[24..26]);
+    u16_to_u8_le(255, &mut sector[26..28]);
+    u32_to_u8_le(opts.hidden_sectors, &mut sector[28..32]);
+    u32_to_u8_le(summary.total_sectors, &mut sector[32..36]);
+
+    u32_to_u8_le(summary.fat_size_sectors, &mut sector[36..40]);
+    /* Ext flags 0: every FAT is mirrored, version 0.0 */
+    u32_to_u8_le(ROOT_CLUSTER, &mut sector[44..48]);
+    u16_to_u8_le(FSINFO_SECTOR, &mut sector[48..50]);
+    u16_to_u8_le(BACKUP_BOOT_SECTOR, &mut sector[50..52]);
+
+    sector[64] = 0x80;
+    sector[66] = 0x29;
+    u32_to_u8_le(opts.volume_id, &mut sector[67..71]);
+    sector[71..82].copy_from_slice(&opts.label);
+    sector[82..90].copy_from_slice(b"FAT32   ");
+
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+}
+
+fn build_fsinfo_sector(summary: &FormatSummary, sector: &mut [u8]) {
+    sector.fill(0);
+
+    u32_to_u8_le(0x41615252, &mut sector[0..4]);
+    u32_to_u8_le(0x61417272, &mut sector[484..488]);
+    /* The root directory already takes one cluster */
+    u32_to_u8_le(summary.clusters_count - 1, &mut sector[488..492]);
+    u32_to_u8_le(ROOT_CLUSTER + 1, &mut sector[492..496]);
+    u32_to_u8_le(0xAA550000, &mut sector[508..512]);
+}
+
+pub fn zero_range<D: BlockDevice>(
+    dev: &mut D,
+    mut offset: u64,
+    mut len: u64,
+) -> Result<(), DeviceError> {
+    while len > 0 {
+        let chunk = core::cmp::min(len, ZEROES.len() as u64) as usize;
+        dev.write_at(offset, &ZEROES[..chunk])?;
+        offset += chunk as u64;
+        len -= chunk as u64;
+    }
+    Ok(())
+}
+
+/* Write an empty FAT32 file system on the first `volume_bytes` of `dev` */
+pub fn format_volume<D: BlockDevice>(
+    dev: &mut D,
+    volume_bytes: u64,
+    opts: &FormatOptions,
+) -> Result<FormatSummary, FormatError> {
+    let summary = compute_geometry(volume_bytes, opts)?;
+
+    if dev.size()? < summary.total_sectors as u64 * opts.bytes_per_sector as u64 {
+        return Err(FormatError::Device(DeviceError::OutOfBounds));
+    }
+
+    let bps = opts.bytes_per_sector as u64;
+    let mut sector = [0u8; 4096];
+    let sector = &mut sector[..bps as usize];
+
+    /* ---------- Reserved region ---------- */
+    zero_range(dev, 0, opts.reserved_sectors_count as u64 * bps)?;
+
+    build_boot_sector(opts, &summary, sector);
+    dev.write_at(0, sector)?;
+    dev.write_at(BACKUP_BOOT_SECTOR as u64 * bps, sector)?;
+
+    build_fsinfo_sector(&summary, sector);
+    dev.write_at(FSINFO_SECTOR as u64 * bps, sector)?;
+    dev.write_at((BACKUP_BOOT_SECTOR + FSINFO_SECTOR) as u64 * bps, sector)?;
+
+    /* ---------- FATs ---------- */
+    let fat_start = opts.reserved_sectors_count as u64 * bps;
+    let fat_bytes = summary.fat_size_sectors as u64 * bps;
+
+    /* Media descriptor, reserved entry, then end of chain for the root */
+    let mut head = [0u8; 12];
+    u32_to_u8_le(0x0FFFFF00 | MEDIA_FIXED as u32, &mut head[0..4]);
+    u32_to_u8_le(0x0FFFFFFF, &mut head[4..8]);
+    u32_to_u8_le(0x0FFFFFFF, &mut head[8..12]);
+
+    for i in 0..opts.fats_count as u64 {
+        let start = fat_start + i * fat_bytes;
+        zero_range(dev, start, fat_bytes)?;
+        dev.write_at(start, &head)?;
+    }
+
+    /* ---------- Root directory ---------- */
+    let data_start = fat_start + opts.fats_count as u64 * fat_bytes;
+    let cluster_size = summary.sectors_per_cluster as u64 * bps;
+    zero_range(dev, data_start, cluster_size)?;
+
+    if &opts.label != b"NO NAME    " {
+        let mut entry = [0u8; 32];
+        entry[0..11].copy_from_slice(&opts.label);
+        /* Volume id attribute */
+        entry[11] = 0x08;
+        let (date, time) = fat_datetime(now_seconds());
+        u16_to_u8_le(time, &mut entry[22..24]);
+        u16_to_u8_le(date, &mut entry[24..26]);
+        dev.write_at(data_start, &entry)?;
+    }
+
+    Ok(summary)
+}
+
+fn print_format_error(err: FormatError, fs: FsType) {
+    let name = match fs {
+        FsType::Fat32 => "FAT32",
+        FsType::Exfat => "exFAT",
+    };
+    match err {
+        FormatError::InvalidOptions => print("Invalid format options"),
+        FormatError::TooFewClusters => {
+            print_no_ln("Volume too small for ");
+            print_no_ln(name);
+            print(" with this cluster size");
+        }
+        FormatError::TooManyClusters => {
+            print_no_ln("Volume too large for ");
+            print_no_ln(name);
+            print(" with this cluster size");
+        }
+        FormatError::Device(_) => print("Failed to write the volume"),
+    }
+}
+
+/* `format <image> [size_mb] [-t fat32|exfat] [-c spc] [-s bps] [-r reserved] [-f fats] [-H hidden] [-n label] [-i id]`
+ * exFAT is the default above 32 GiB, it ignores -r and -f and keeps a
+ * single FAT. Returns the NUL terminated path of the image once it has
+ * been formatted, wherever it was among the options */
+pub fn format_command(args: &[&[u8]]) -> Option<[u8; 256]> {
+    let mut opts = FormatOptions {
+        volume_id: now_seconds() as u32,
+        ..FormatOptions::default()
+    };
+
+    let mut path = [0u8; 256];
+    let mut path_len = 0usize;
+    let mut size_mb: Option<u64> = None;
+    let mut fs: Option<FsType> = None;
+
+    let mut words = args.iter();
+    while let Some(&word) = words.next() {
+        if word.len() == 2 && word[0] == b'-' {
+            let value = match words.next() {
+                Some(&v) => v,
+                None => {
+                    print("Missing value for option");
+                    return None;
+                }
+            };
+
+            if word[1] == b'n' {
+                if value.len() > 11 {
+                    print("Label is at most 11 characters");
+                    return None;
+                }
+                opts.label = make_label(value);
+                continue;
+            }
+            if word[1] == b't' {
+                fs = match value {
+                    b"fat32" => Some(FsType::Fat32),
+                    b"exfat" => Some(FsType::Exfat),
+                    _ => {
+                        print("Unknown file system, use fat32 or exfat");
+                        return None;
+                    }
+                };
+                continue;
+            }
+
+            let n = match parse_u64(value) {
+                Some(n) => n,
+                None => {
+                    print("Invalid number");
+                    return None;
+                }
+            };
+            let ok = match word[1] {
+                b'c' if n <= 128 => {
+                    opts.sectors_per_cluster = Some(n as u8);
+                    true
+                }
+                b's' if n <= 4096 => {
+                    opts.bytes_per_sector = n as u16;
+                    true
+                }
+                b'r' if n <= u16::MAX as u64 => {
+                    opts.reserved_sectors_count = n as u16;
+                    true
+                }
+                b'f' if n <= 255 => {
+                    opts.fats_count = n as u8;
+                    true
+                }
+                /* Sectors before the volume, its partition's start */
+                b'H' if n <= u32::MAX as u64 => {
+                    opts.hidden_sectors = n as u32;
+                    true
+                }
+                b'i' if n <= u32::MAX as u64 => {
+                    opts.volume_id = n as u32;
+                    true
+                }
+                _ => false,
+            };
+            if !ok {
+                print("Invalid format option");
+                return None;
+            }
+            continue;
+        }
+
+        if path_len == 0 {
+            if word.len() >= path.len() {
+                print("Path too long");
+                return None;
+            }
+            path[..word.len()].copy_from_slice(word);
+            path_len = word.len();
+        } else if size_mb.is_none() {
+            size_mb = parse_u64(word);
+            if size_mb.is_none() {
+                print("Invalid size");
+                return None;
+            }
+        } else {
+            print(
+                "Usage: format <image> [size_mb] [-t fat32|exfat] [-c spc] [-s bps] [-r reserved] [-f fats] [-H hidden] [-n label] [-i id]",
+            );
+            return None;
+        }
+    }
+
+    if path_len == 0 {
+        print(
+            "Usage: format <image> [size_mb] [-t fat32|exfat] [-c spc] [-s bps] [-r reserved] [-f fats] [-H hidden] [-n label] [-i id]",
+        );
+        return None;
+    }
+
+    let fd = match open_rw(path.as_ptr(), size_mb.is_some()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            print_no_ln("Failed to open the image for writing: ");
+            print(e.description());
+            return None;
+        }
+    };
+    let mut dev = FileDevice::new(fd);
+
+    let volume_bytes = match size_mb {
+        Some(mb) => {
+            let bytes = mb * 1024 * 1024;
+            /* Block devices can't be resized, they just need to be large enough */
+            if ftruncate(fd, bytes).is_err() && dev.size().unwrap_or(0) < bytes {
+                print("Failed to resize the image");
+                close(fd);
+                return None;
+            }
+            bytes
+        }
+        None => match dev.size() {
+            Ok(s) => s,
+            Err(_) => {
+                print("Failed to get the image size");
+                close(fd);
+                return None;
+            }
+        },
+    };
+
+    let fs = fs.unwrap_or(if volume_bytes > EXFAT_DEFAULT_ABOVE { FsType::Exfat } else { FsType::Fat32 });
+    /* Clusters, cluster size and FAT size of either */
+    let result = match fs {
+        FsType::Fat32 => format_volume(&mut dev, volume_bytes, &opts).map(|summary| {
+            (
+                summary.clusters_count,
+                summary.sectors_per_cluster as u64 * opts.bytes_per_sector as u64,
+                summary.fat_size_sectors,
+            )
+        }),
+        FsType::Exfat => format_exfat(&mut dev, volume_bytes, &opts)
+            .map(|geo| (geo.cluster_count, geo.cluster_size(), geo.fat_length)),
+    };
+    /* The new size too, not only the data */
+    let synced = fsync(fd);
+    close(fd);
+    if result.is_ok() && synced.is_err() {
+        print("Failed to write the image");
+        return None;
+    }
+
+    match result {
+        Ok((clusters, cluster_size, fat_size)) => {
+            print_no_ln("Formatted ");
+            print_number(clusters as u64);
+            print_no_ln(" clusters of ");
+            print_number(cluster_size);
+            print_no_ln(" bytes, FAT size ");
+            print_number(fat_size as u64);
+            print(" sectors");
+            Some(path)
+        }
+        Err(e) => {
+            print_format_error(e, fs);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::{parse_boot_sector, verify_boot_sector_signature};
+    use crate::device::MemDevice;
+    use crate::helpers::u8_to_u32_le;
+    use std::vec;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn default_cluster_size_follows_table() {
+        assert_eq!(default_cluster_size(64 * MIB), 512);
+        assert_eq!(default_cluster_size(1024 * MIB), 4096);
+        assert_eq!(default_cluster_size(12 * 1024 * MIB), 8192);
+        assert_eq!(default_cluster_size(64 * 1024 * MIB), 32768);
+    }
+
+    #[test]
+    fn geometry_fat_covers_every_cluster() {
+        let opts = FormatOptions::default();
+        let g = compute_geometry(64 * MIB, &opts).unwrap();
+        assert_eq!(g.sectors_per_cluster, 1);
+        assert!(g.clusters_count >= FAT32_MIN_CLUSTERS);
+        assert!(g.fat_size_sectors as u64 * 128 >= g.clusters_count as u64 + 2);
+        let used = 32 + 2 * g.fat_size_sectors as u64 + g.clusters_count as u64;
+        assert!(used <= g.total_sectors as u64);
+    }
+
+    #[test]
+    fn geometry_rejects_too_few_clusters() {
+        let opts = FormatOptions {
+            sectors_per_cluster: Some(8),
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            compute_geometry(64 * MIB, &opts),
+            Err(FormatError::TooFewClusters)
+        );
+    }
+
+    #[test]
+    fn geometry_rejects_bad_options() {
+        let opts = FormatOptions {
+            sectors_per_cluster: Some(3),
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            compute_geometry(64 * MIB, &opts),
+            Err(FormatError::InvalidOptions)
+        );
+        let opts = FormatOptions {
+            reserved_sectors_count: 4,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            compute_geometry(64 * MIB, &opts),
+            Err(FormatError::InvalidOptions)
+        );
+    }
+
+    #[test]
+    fn format_writes_a_mountable_volume() {
+        let mut image = vec![0xAAu8; (40 * MIB) as usize];
+        let mut dev = MemDevice { data: &mut image };
+        let opts = FormatOptions {
+            label: make_label(b"test"),
+            volume_id: 0x12345678,
+            ..FormatOptions::default()
+        };
+        let summary = format_volume(&mut dev, 40 * MIB, &opts).unwrap();
+
+        let mut boot = [0u8; 512];
+        boot.copy_from_slice(&image[..512]);
+        assert!(verify_boot_sector_signature(&boot));
+        let bs = parse_boot_sector(&boot).unwrap();
+        assert_eq!(bs.bytes_per_sector, 512);
+        assert_eq!(bs.sectors_per_cluster, summary.sectors_per_cluster);
+        assert_eq!(bs.reserved_sectors_count, 32);
+        assert_eq!(bs.fats_count, 2);
+        assert_eq!(bs.fat_size_sectors, summary.fat_size_sectors);
+        assert_eq!(bs.root_cluster, 2);
+        assert_eq!(&image[71..82], b"TEST       ");
+        assert_eq!(u8_to_u32_le(&image[67..71]), 0x12345678);
+
+        /* Backup boot sector and FSInfo */
+        assert_eq!(&image[..512], &image[6 * 512..7 * 512]);
+        assert_eq!(u8_to_u32_le(&image[512..516]), 0x41615252);
+        assert_eq!(
+            u8_to_u32_le(&image[512 + 488..512 + 492]),
+            summary.clusters_count - 1
+        );
+
+        /* Both FATs start the same and are otherwise empty */
+        let fat_bytes = summary.fat_size_sectors as usize * 512;
+        for i in 0..2 {
+            let fat = &image[32 * 512 + i * fat_bytes..32 * 512 + (i + 1) * fat_bytes];
+            assert_eq!(u8_to_u32_le(&fat[0..4]), 0x0FFFFFF8);
+            assert_eq!(u8_to_u32_le(&fat[8..12]), 0x0FFFFFFF);
+            assert!(fat[12..].iter().all(|&b| b == 0));
+        }
+
+        /* Root holds only the label */
+        let data_start = 32 * 512 + 2 * fat_bytes;
+        assert_eq!(&image[data_start..data_start + 11], b"TEST       ");
+        assert_eq!(image[data_start + 11], 0x08);
+        assert!(
+            image[data_start + 32..data_start + 512]
+                .iter()
+                .all(|&b| b == 0)
+        );
+    }
+
+    #[test]
+    fn format_command_records_hidden_sectors() {
+        let path = std::env::temp_dir().join(std::format!("fat32-{}-hidden", std::process::id()));
+        let name = path.to_str().unwrap().as_bytes();
+        assert!(format_command(&[name, b"40", b"-H", b"2048", b"-i", b"7"]).is_some());
+
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(u8_to_u32_le(&image[28..32]), 2048);
+        assert_eq!(u8_to_u32_le(&image[67..71]), 7);
+        assert!(format_command(&[name, b"40", b"-H", b"4294967296"]).is_none());
+    }
+
+    #[test]
+    fn format_command_finds_the_path_after_options() {
+        let path = std::env::temp_dir().join(std::format!("fat32-{}-options-first", std::process::id()));
+        let name = path.to_str().unwrap().as_bytes();
+        let formatted = format_command(&[b"-t", b"fat32", name, b"40"]).unwrap();
+
+        assert_eq!(&formatted[..name.len() + 1], [name, b"\0"].concat());
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.len() as u64, 40 * MIB);
+        assert!(verify_boot_sector_signature(image[..512].try_into().unwrap()));
+    }
+}
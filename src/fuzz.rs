@@ -0,0 +1,174 @@
+/* Fuzz harnesses over an in-memory device. Each one takes the raw bytes of
+ * an image and must return, without panicking or reading out of bounds,
+ * whatever they are. The tests below drive them with a seeded mutator over
+ * a small populated image, the failing seed is printed on a panic */
+
+use crate::bitmap::{Bitmap, bitmap_bytes};
+use crate::boot_sector::{BootSector, parse_boot_sector};
+use crate::device::{BlockDevice, MemDevice};
+use crate::fat::{fat_regions, find_directory, find_file, iterate_dir_entries, read_file};
+use crate::lookup::LookupCache;
+use crate::fixture::{ImageBuilder, Node};
+use crate::undelete::scan_deleted;
+use crate::volume::Volume;
+use crate::walk::walk_tree;
+use std::boxed::Box;
+use std::vec;
+use std::vec::Vec;
+
+fn boot_sector(data: &mut [u8]) -> Option<BootSector> {
+    let mut sector = [0u8; 512];
+    MemDevice { data }.read_at(0, &mut sector).ok()?;
+    parse_boot_sector(&sector).ok()
+}
+
+pub fn fuzz_boot_sector(data: &mut [u8]) {
+    let _ = boot_sector(data);
+    let _ = Volume::mount(MemDevice { data });
+}
+
+pub fn fuzz_dir_iteration(data: &mut [u8]) {
+    if let Some(bs) = boot_sector(data) {
+        let (fat_start, data_start) = fat_regions(&bs);
+        let mut dev = MemDevice { data: &mut *data };
+        let _ = iterate_dir_entries(&mut dev, &bs, fat_start, data_start, bs.root_cluster, |_, _| {
+            None::<()>
+        });
+    }
+
+    if let Ok(mut vol) = Volume::mount(MemDevice { data }) {
+        let root = vol.bs.root_cluster;
+        let bits = vol.clusters_count as usize + 2;
+        let mut storage = vec![0u8; bitmap_bytes(bits)];
+        let mut visited = Bitmap::new(&mut storage, bits).unwrap();
+        let _ = walk_tree(&mut vol, root, &mut visited, |_, _| Ok(()));
+        let _ = scan_deleted(&mut vol, root, |_| false);
+    }
+}
+
+pub fn fuzz_path_lookup(data: &mut [u8], path: &[u8]) {
+    if let Some(bs) = boot_sector(data) {
+        let (fat_start, data_start) = fat_regions(&bs);
+        let mut dev = MemDevice { data };
+        let mut lookup = Box::<LookupCache>::default();
+        let _ = find_directory(&mut dev, &mut lookup, &bs, fat_start, data_start, bs.root_cluster, path);
+    }
+}
+
+pub fn fuzz_file_read(data: &mut [u8], path: &[u8]) {
+    if let Some(bs) = boot_sector(data) {
+        let (fat_start, data_start) = fat_regions(&bs);
+        let mut dev = MemDevice { data };
+        let mut read = 0usize;
+        let mut lookup = Box::<LookupCache>::default();
+        if let Ok(Some(entry)) = find_file(&mut dev, &mut lookup, &bs, fat_start, data_start, bs.root_cluster, path) {
+            let _ = read_file(&mut dev, &bs, fat_start, data_start, &entry, &mut [0u8; 4096], |bytes| {
+                read += bytes.len();
+            });
+        }
+    }
+}
+
+/* xorshift64, enough to spread mutations around */
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/* Root with sub/inner.txt, a long named file over two clusters and a
+ * deleted entry. Returns the image and the byte ranges worth mutating */
+fn seed_image() -> (Vec<u8>, Vec<(usize, usize)>) {
+    let mut data = ImageBuilder::new().build(&[
+        Node::Dir("sub", &[Node::File("inner.txt", b"inner")]),
+        Node::File("file.txt", &[b'x'; 700]),
+        Node::File("A long file name", b"long"),
+        Node::Deleted("gone.txt", b"gone"),
+    ]);
+
+    let vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+    let fat = vol.fat_start as usize;
+    let clusters = vol.cluster_offset(2) as usize;
+    let regions = vec![(0, 512), (fat, fat + 64), (clusters, clusters + 8 * vol.cluster_size)];
+    (data, regions)
+}
+
+/* Run `harness` on `rounds` mutants of the seed image, each one undone
+ * before the next */
+fn mutate_and_run(seed: u64, rounds: usize, mut harness: impl FnMut(&mut [u8])) {
+    let (mut data, regions) = seed_image();
+    let mut rng = Rng(seed);
+    let mut saved: Vec<(usize, u8)> = Vec::new();
+
+    for round in 0..rounds {
+        for _ in 0..1 + rng.below(8) {
+            let (start, end) = regions[rng.below(regions.len())];
+            let at = start + rng.below(end - start);
+            saved.push((at, data[at]));
+            data[at] = match rng.below(4) {
+                0 => 0x00,
+                1 => 0xFF,
+                2 => data[at] ^ (1 << rng.below(8)),
+                _ => rng.next() as u8,
+            };
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| harness(&mut data)));
+        assert!(result.is_ok(), "harness panicked at seed {seed:#x}, round {round}");
+
+        for (at, byte) in saved.drain(..).rev() {
+            data[at] = byte;
+        }
+    }
+}
+
+#[test]
+fn boot_sector_survives_mutations() {
+    mutate_and_run(0x9E3779B97F4A7C15, 400, fuzz_boot_sector);
+}
+
+#[test]
+fn dir_iteration_survives_mutations() {
+    mutate_and_run(0xD1B54A32D192ED03, 300, fuzz_dir_iteration);
+}
+
+#[test]
+fn path_lookup_survives_mutations() {
+    mutate_and_run(0x8CB92BA72F3D8DD7, 300, |data| {
+        fuzz_path_lookup(data, b"sub");
+        fuzz_path_lookup(data, b"/sub/../sub/.");
+    });
+}
+
+#[test]
+fn file_read_survives_mutations() {
+    mutate_and_run(0xA0761D6478BD642F, 300, |data| {
+        fuzz_file_read(data, b"file.txt");
+        fuzz_file_read(data, b"sub/inner.txt");
+        fuzz_file_read(data, b"alongf~1");
+    });
+}
+
+#[test]
+fn harnesses_survive_random_bytes() {
+    let mut rng = Rng(0xE7037ED1A0B428DB);
+    let mut data = vec![0u8; 256 * 1024];
+    for _ in 0..50 {
+        data.iter_mut().for_each(|b| *b = rng.next() as u8);
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        fuzz_boot_sector(&mut data);
+        fuzz_dir_iteration(&mut data);
+        fuzz_path_lookup(&mut data, b"a/b/c");
+        fuzz_file_read(&mut data, b"a.txt");
+    }
+}
@@ -0,0 +1,299 @@
+/* `grep`: the lines of files in the image matching a regular expression.
+ * A file is read a chunk at a time and its lines put together across
+ * chunks, so a line running over a cluster boundary is matched whole */
+
+use crate::cli::{print, print_no_ln, print_number};
+use crate::pattern::Regex;
+use crate::sys::print_bytes;
+use crate::tree::{TreeOptions, TreeSource, visit_tree_with};
+use crate::volume::VolumeError;
+
+/* Bytes of a line kept for matching and printing, the rest is dropped */
+const LINE_KEPT: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GrepOptions {
+    pub ignore_case: bool,
+    /* Line numbers after the path */
+    pub numbers: bool,
+    /* Every file below a directory */
+    pub recursive: bool,
+}
+
+/* The flags, the pattern and the path, `-i`, `-n` and `-r` can be joined
+ * and `--` ends them */
+pub fn parse_grep_args<'a>(args: &[&'a [u8]]) -> Option<(GrepOptions, &'a [u8], &'a [u8])> {
+    let mut opts = GrepOptions::default();
+    let mut words: [&[u8]; 2] = [&[]; 2];
+    let mut count = 0;
+    let mut flags = true;
+    for &word in args {
+        if flags && word == b"--" {
+            flags = false;
+            continue;
+        }
+        if flags && word.len() > 1 && word[0] == b'-' {
+            for &flag in &word[1..] {
+                match flag {
+                    b'i' => opts.ignore_case = true,
+                    b'n' => opts.numbers = true,
+                    b'r' => opts.recursive = true,
+                    _ => return None,
+                }
+            }
+            continue;
+        }
+        *words.get_mut(count)? = word;
+        count += 1;
+    }
+    (count == 2).then_some((opts, words[0], words[1]))
+}
+
+pub struct Grep<'a> {
+    regex: Regex<'a>,
+    opts: GrepOptions,
+    /* Matching lines and the files holding them so far */
+    pub lines: u64,
+    pub files: u64,
+}
+
+/* One file being searched */
+struct Search<'g, 'a> {
+    grep: &'g Grep<'a>,
+    base: &'g [u8],
+    path: &'g [u8],
+    line: [u8; LINE_KEPT],
+    len: usize,
+    number: u64,
+    /* A NUL in the first chunk: matches are counted, not printed */
+    binary: Option<bool>,
+    matched: u64,
+}
+
+impl Search<'_, '_> {
+    fn feed(&mut self, chunk: &[u8]) {
+        if self.binary.is_none() {
+            self.binary = Some(chunk.contains(&0));
+        }
+        for piece in chunk.split_inclusive(|&b| b == b'\n') {
+            let (body, ends) = match piece.split_last() {
+                Some((b'\n', body)) => (body, true),
+                _ => (piece, false),
+            };
+            let kept = body.len().min(LINE_KEPT - self.len);
+            self.line[self.len..self.len + kept].copy_from_slice(&body[..kept]);
+            self.len += kept;
+            if ends {
+                self.end_line();
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        self.number += 1;
+        let mut line = &self.line[..self.len];
+        if let Some((b'\r', body)) = line.split_last() {
+            line = body;
+        }
+        if self.grep.regex.is_match(line) {
+            self.matched += 1;
+            if self.binary == Some(true) {
+                if self.matched == 1 {
+                    print_no_ln("Binary file ");
+                    print_path(self.base, self.path);
+                    print(" matches");
+                }
+            } else {
+                print_path(self.base, self.path);
+                print_no_ln(":");
+                if self.grep.opts.numbers {
+                    print_number(self.number);
+                    print_no_ln(":");
+                }
+                print_bytes(line);
+                print("");
+            }
+        }
+        self.len = 0;
+    }
+}
+
+fn print_path(base: &[u8], path: &[u8]) {
+    if base != b"/" {
+        print_bytes(base);
+    }
+    print_bytes(path);
+}
+
+impl<'a> Grep<'a> {
+    /* None when the pattern is not a valid regular expression */
+    pub fn new(pattern: &'a [u8], opts: GrepOptions) -> Option<Self> {
+        Some(Grep {
+            regex: Regex::new(pattern, opts.ignore_case)?,
+            opts,
+            lines: 0,
+            files: 0,
+        })
+    }
+
+    /* Search the file whose bytes `read` hands over, shown as `base` then
+     * `path` */
+    pub fn file<R>(&mut self, base: &[u8], path: &[u8], read: R) -> Result<(), VolumeError>
+    where
+        R: FnOnce(&mut dyn FnMut(&[u8])) -> Result<(), VolumeError>,
+    {
+        let mut search = Search {
+            grep: self,
+            base,
+            path,
+            line: [0u8; LINE_KEPT],
+            len: 0,
+            number: 0,
+            binary: None,
+            matched: 0,
+        };
+        read(&mut |chunk| search.feed(chunk))?;
+        /* The last line may have no new line */
+        if search.len > 0 {
+            search.end_line();
+        }
+        let matched = search.matched;
+        self.lines += matched;
+        self.files += u64::from(matched > 0);
+        Ok(())
+    }
+
+    /* Search every file below `root`, hidden ones included, shown as `base` */
+    pub fn tree<S: TreeSource>(
+        &mut self,
+        src: &mut S,
+        root: S::Dir,
+        base: &[u8],
+    ) -> Result<(), VolumeError> {
+        let walk = TreeOptions {
+            hidden: true,
+            ..TreeOptions::default()
+        };
+        visit_tree_with(src, root, &walk, &mut |src, line| {
+            if line.entry.is_dir {
+                return Ok(());
+            }
+            self.file(base, line.path, |out| src.read(line.entry, out))
+        })?;
+        Ok(())
+    }
+
+    pub fn print_summary(&self) {
+        print_number(self.lines);
+        print_no_ln(if self.lines == 1 {
+            " matching line"
+        } else {
+            " matching lines"
+        });
+        if self.opts.recursive {
+            print_no_ln(" in ");
+            print_number(self.files);
+            print_no_ln(if self.files == 1 { " file" } else { " files" });
+        }
+        print("");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_sector::parse_boot_sector;
+    use crate::device::MemDevice;
+    use crate::fat::{FatTree, fat_regions};
+    use crate::fixture::{ImageBuilder, Node};
+
+    #[test]
+    fn parses_flags_pattern_and_path() {
+        let (opts, pattern, path) = parse_grep_args(&[b"-in", b"err.r", b"/logs", b"-r"]).unwrap();
+        assert_eq!(
+            opts,
+            GrepOptions {
+                ignore_case: true,
+                numbers: true,
+                recursive: true
+            }
+        );
+        assert_eq!((pattern, path), (&b"err.r"[..], &b"/logs"[..]));
+
+        let (_, pattern, _) = parse_grep_args(&[b"--", b"-x", b"file"]).unwrap();
+        assert_eq!(pattern, b"-x");
+        assert!(parse_grep_args(&[b"-q", b"x", b"file"]).is_none());
+        assert!(parse_grep_args(&[b"x"]).is_none());
+        assert!(parse_grep_args(&[b"x", b"y", b"z"]).is_none());
+    }
+
+    #[test]
+    fn joins_lines_across_chunks() {
+        let text = b"first line\r\nsecond needle\nneedle at the end";
+        for chunk in [1, 2, 3, 7, 64] {
+            let mut grep = Grep::new(b"needle", GrepOptions::default()).unwrap();
+            grep.file(b"", b"f", |out| {
+                text.chunks(chunk).for_each(out);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!((grep.lines, grep.files), (2, 1));
+
+            /* The pattern spans the cut between the two chunks */
+            let mut grep = Grep::new(b"^second needle$", GrepOptions::default()).unwrap();
+            grep.file(b"", b"f", |out| {
+                text.chunks(chunk).for_each(out);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(grep.lines, 1);
+        }
+
+        /* Past what is kept, a long line is matched on its start */
+        let mut long = std::vec![b'a'; LINE_KEPT * 2];
+        long.extend_from_slice(b"tail\nab");
+        let mut grep = Grep::new(b"^a+$", GrepOptions::default()).unwrap();
+        grep.file(b"", b"f", |out| {
+            long.chunks(1000).for_each(out);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(grep.lines, 1);
+    }
+
+    #[test]
+    fn searches_the_files_of_an_image() {
+        let binary: std::vec::Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let tree = [
+            Node::Dir(
+                "sub",
+                &[
+                    Node::File("inner.txt", b"inner file\n"),
+                    Node::Dir("deeper", &[Node::File("leaf.txt", b"leaf")]),
+                ],
+            ),
+            Node::File("A long file name.txt", &binary),
+            Node::File("empty.txt", b""),
+        ];
+        for builder in [ImageBuilder::new(), ImageBuilder::fat12()] {
+            let mut data = builder.build(&tree);
+            let mut sector = [0u8; 512];
+            sector.copy_from_slice(&data[..512]);
+            let bs = parse_boot_sector(&sector).unwrap();
+            let (fat_start, data_start) = fat_regions(&bs);
+            let mut dev = MemDevice { data: &mut data };
+            let mut src = FatTree {
+                dev: &mut dev,
+                bs: &bs,
+                fat_start,
+                data_start,
+                chunk: &mut [0u8; 4096],
+            };
+
+            /* Each file is read through the tree, the binary one is skipped */
+            let mut grep = Grep::new(b"^[il]", GrepOptions::default()).unwrap();
+            grep.tree(&mut src, bs.root_cluster, b"/").unwrap();
+            assert_eq!((grep.lines, grep.files), (2, 2));
+        }
+    }
+}
@@ -3,25 +3,97 @@ pub fn u8_le_to_u16(bytes: &[u8]) -> u16 {
     (bytes[1] as u16) << 8 | (bytes[0] as u16)
 }
 
-pub fn u8_le_to_u32(bytes: &[u8]) -> u32 {
-    (bytes[3] as u32) << 24 | (bytes[2] as u32) << 16 | (bytes[1] as u32) << 8 | (bytes[0] as u32)
-}
-
 pub fn u8_to_u32_le(bytes: &[u8]) -> u32 {
     (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
 }
 
+pub fn u8_to_u64_le(bytes: &[u8]) -> u64 {
+    u8_to_u32_le(bytes) as u64 | (u8_to_u32_le(&bytes[4..8]) as u64) << 32
+}
+
+pub fn u16_to_u8_le(value: u16, out: &mut [u8]) {
+    out[0] = value as u8;
+    out[1] = (value >> 8) as u8;
+}
+
+pub fn u32_to_u8_le(value: u32, out: &mut [u8]) {
+    out[0] = value as u8;
+    out[1] = (value >> 8) as u8;
+    out[2] = (value >> 16) as u8;
+    out[3] = (value >> 24) as u8;
+}
+
 pub fn to_lowercase_ascii(src: &[u8], dst: &mut [u8]) -> usize {
     let mut len = 0;
     let max = core::cmp::min(src.len(), dst.len());
     for i in 0..max {
         let c = src[i];
-        dst[i] = if c >= b'A' && c <= b'Z' { c + 32 } else { c };
+        dst[i] = if c.is_ascii_uppercase() { c + 32 } else { c };
         len += 1;
     }
     len
 }
 
+pub fn to_uppercase_ascii(src: &[u8], dst: &mut [u8]) -> usize {
+    let max = core::cmp::min(src.len(), dst.len());
+    for i in 0..max {
+        dst[i] = src[i].to_ascii_uppercase();
+    }
+    max
+}
+
+/* Decimal or `0x` prefixed hexadecimal number */
+pub fn parse_u64(bytes: &[u8]) -> Option<u64> {
+    let (digits, radix) = if bytes.len() > 2 && bytes[0] == b'0' && (bytes[1] == b'x' || bytes[1] == b'X') {
+        (&bytes[2..], 16)
+    } else {
+        (bytes, 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for &c in digits {
+        let d = (c as char).to_digit(radix)? as u64;
+        value = value.checked_mul(radix as u64)?.checked_add(d)?;
+    }
+    Some(value)
+}
+
+/* `value` in decimal over all of `out`, zero padded, higher digits are dropped */
+pub fn write_padded_decimal(mut value: u32, out: &mut [u8]) {
+    for c in out.iter_mut().rev() {
+        *c = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+}
+
+/* Unix seconds to the packed FAT (date, time) pair, clamped to 1980..=2107 */
+pub fn fat_datetime(secs: u64) -> (u16, u16) {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+
+    /* Civil from days, see Howard Hinnant's date algorithms */
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    if year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let year = core::cmp::min(year, 2107);
+
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = (((rem / 3600) as u16) << 11) | ((((rem / 60) % 60) as u16) << 5) | ((rem % 60) / 2) as u16;
+    (date, time)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -37,4 +109,37 @@ mod tests {
         let bytes = [0x78u8, 0x56u8, 0x34u8, 0x12u8]; /* 0x12345678 */
         assert_eq!(u8_to_u32_le(&bytes), 0x12345678);
     }
+
+    #[test]
+    fn u32_to_u8_le_round_trips() {
+        let mut bytes = [0u8; 4];
+        u32_to_u8_le(0x0FFFFFF8, &mut bytes);
+        assert_eq!(bytes, [0xF8, 0xFF, 0xFF, 0x0F]);
+        assert_eq!(u8_to_u32_le(&bytes), 0x0FFFFFF8);
+    }
+
+    #[test]
+    fn parse_u64_decimal_and_hex() {
+        assert_eq!(parse_u64(b"64"), Some(64));
+        assert_eq!(parse_u64(b"0x1234ABCD"), Some(0x1234ABCD));
+        assert_eq!(parse_u64(b"12a"), None);
+        assert_eq!(parse_u64(b""), None);
+    }
+
+    #[test]
+    fn fat_datetime_known_date() {
+        /* 2024-02-29 13:45:30 UTC */
+        let (date, time) = fat_datetime(1709214330);
+        assert_eq!(date, ((2024 - 1980) << 9) | (2 << 5) | 29);
+        assert_eq!(time, (13 << 11) | (45 << 5) | 15);
+    }
+
+    #[test]
+    fn write_padded_decimal_pads_and_truncates() {
+        let mut out = [0u8; 4];
+        write_padded_decimal(42, &mut out);
+        assert_eq!(&out, b"0042");
+        write_padded_decimal(123456, &mut out);
+        assert_eq!(&out, b"3456");
+    }
 }
@@ -0,0 +1,311 @@
+use crate::cli::{print, print_no_ln, print_number};
+use crate::dir_entry::ATTR_DIRECTORY;
+
+/* Slots of the name index, shared by every indexed directory. Loaded to
+ * 3/4 it still holds the 65536 names a directory has at most */
+pub const INDEX_SLOTS: usize = 90112;
+/* Filled up to 3/4, probes stay short */
+const INDEX_LOAD: usize = INDEX_SLOTS / 4 * 3;
+const INDEXED_DIRS: usize = 8;
+const PATH_SLOTS: usize = 32;
+const PATH_MAX: usize = 128;
+const EMPTY: u64 = 0;
+/* A slot holds a hash tag above the entry offset in 32 byte units, enough
+ * for the 8 TiB a FAT32 volume spans at most */
+const OFFSET_BITS: u32 = 38;
+const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+
+/* What a lookup needs of a short entry */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedEntry {
+    pub cluster: u32,
+    pub size: u32,
+    pub attr: u8,
+}
+
+impl CachedEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    /* Names found through a directory index */
+    pub index_hits: u64,
+    /* Names the index ruled out, or whose candidates were all someone else */
+    pub index_misses: u64,
+    /* Directories read whole, to index them or too large to */
+    pub scans: u64,
+    pub path_hits: u64,
+}
+
+#[derive(Clone, Copy)]
+struct PathSlot {
+    start: u32,
+    len: usize,
+    path: [u8; PATH_MAX],
+    entry: CachedEntry,
+}
+
+/* Name index of recently used directories and the paths resolved in them.
+ * The index maps a hash of the directory and a lower case short or long
+ * name to the byte offset of the short entry, a hit is confirmed by reading
+ * that entry and its long name back. Both are dropped whole by `invalidate` once the volume is written */
+pub struct LookupCache {
+    slots: [u64; INDEX_SLOTS],
+    used: usize,
+    dirs: [u32; INDEXED_DIRS],
+    dirs_len: usize,
+    /* Directory being indexed */
+    building: u32,
+    /* Last directory too large for the index, scanned on each lookup */
+    too_large: Option<u32>,
+    paths: [Option<PathSlot>; PATH_SLOTS],
+    /* Path slot replaced next */
+    next_path: usize,
+    stats: LookupStats,
+}
+
+impl Default for LookupCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LookupCache {
+    pub const fn new() -> Self {
+        LookupCache {
+            slots: [EMPTY; INDEX_SLOTS],
+            used: 0,
+            dirs: [0; INDEXED_DIRS],
+            dirs_len: 0,
+            building: 0,
+            too_large: None,
+            paths: [None; PATH_SLOTS],
+            next_path: 0,
+            stats: LookupStats {
+                index_hits: 0,
+                index_misses: 0,
+                scans: 0,
+                path_hits: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> LookupStats {
+        self.stats
+    }
+
+    /* Directories indexed and paths remembered */
+    pub fn occupancy(&self) -> (usize, usize) {
+        (self.dirs_len, self.paths.iter().flatten().count())
+    }
+
+    /* Forget everything, after a directory was changed */
+    pub fn invalidate(&mut self) {
+        self.clear_index();
+        self.too_large = None;
+        self.paths = [None; PATH_SLOTS];
+    }
+
+    fn clear_index(&mut self) {
+        self.slots.fill(EMPTY);
+        self.used = 0;
+        self.dirs_len = 0;
+    }
+
+    pub fn is_indexed(&self, dir: u32) -> bool {
+        self.dirs[..self.dirs_len].contains(&dir)
+    }
+
+    pub fn is_too_large(&self, dir: u32) -> bool {
+        self.too_large == Some(dir)
+    }
+
+    /* Offsets recorded under the hash of `name` in `dir`, one of them at
+     * most is the entry. What they led to is counted by `count_lookup` */
+    pub fn candidates(&self, dir: u32, name: &[u8]) -> impl Iterator<Item = u64> + '_ {
+        let hash = hash(dir, name);
+        let start = hash as usize % INDEX_SLOTS;
+        let tag = tag(hash);
+        let slots = &self.slots;
+        (0..INDEX_SLOTS)
+            .map(move |i| slots[(start + i) % INDEX_SLOTS])
+            .take_while(|&slot| slot != EMPTY)
+            .filter(move |&slot| slot & !OFFSET_MASK == tag)
+            .map(|slot| (slot & OFFSET_MASK) * 32)
+    }
+
+    /* A name looked up through the index, `found` when a candidate was it */
+    pub fn count_lookup(&mut self, found: bool) {
+        if found {
+            self.stats.index_hits += 1;
+        } else {
+            self.stats.index_misses += 1;
+        }
+    }
+
+    /* Start indexing `dir`, older directories make room when needed */
+    pub fn begin_index(&mut self, dir: u32) {
+        self.stats.scans += 1;
+        if self.dirs_len == INDEXED_DIRS || self.used > INDEX_LOAD / 2 {
+            self.clear_index();
+        }
+        self.building = dir;
+    }
+
+    /* Record the entry named `name` at `offset`, false once the index is
+     * full: the directory is then given up with `abandon_index` */
+    pub fn insert(&mut self, name: &[u8], offset: u64) -> bool {
+        if self.used >= INDEX_LOAD {
+            return false;
+        }
+        let hash = hash(self.building, name);
+        let mut slot = hash as usize % INDEX_SLOTS;
+        while self.slots[slot] != EMPTY {
+            slot = (slot + 1) % INDEX_SLOTS;
+        }
+        self.slots[slot] = tag(hash) | ((offset / 32) & OFFSET_MASK);
+        self.used += 1;
+        true
+    }
+
+    pub fn commit_index(&mut self) {
+        self.dirs[self.dirs_len] = self.building;
+        self.dirs_len += 1;
+    }
+
+    /* The directory has more entries than the index holds */
+    pub fn abandon_index(&mut self) {
+        self.clear_index();
+        self.too_large = Some(self.building);
+    }
+
+    fn path_slot(&self, start: u32, path: &[u8]) -> Option<&PathSlot> {
+        self.paths
+            .iter()
+            .flatten()
+            .find(|slot| slot.start == start && &slot.path[..slot.len] == path)
+    }
+
+    /* Entry `path` resolved to from directory `start` */
+    pub fn cached_path(&mut self, start: u32, path: &[u8]) -> Option<CachedEntry> {
+        let found = self.path_slot(start, path)?.entry;
+        self.stats.path_hits += 1;
+        Some(found)
+    }
+
+    pub fn remember_path(&mut self, start: u32, path: &[u8], entry: CachedEntry) {
+        if path.len() > PATH_MAX || self.path_slot(start, path).is_some() {
+            return;
+        }
+        let mut slot = PathSlot {
+            start,
+            len: path.len(),
+            path: [0u8; PATH_MAX],
+            entry,
+        };
+        slot.path[..path.len()].copy_from_slice(path);
+        self.paths[self.next_path] = Some(slot);
+        self.next_path = (self.next_path + 1) % PATH_SLOTS;
+    }
+}
+
+/* FNV-1a of the directory then the name */
+fn hash(dir: u32, name: &[u8]) -> u32 {
+    let mut h: u32 = 0x811C9DC5;
+    for &b in dir.to_le_bytes().iter().chain(name) {
+        h ^= b as u32;
+        h = h.wrapping_mul(0x01000193);
+    }
+    h
+}
+
+/* High bits of a slot for `hash`, never EMPTY */
+fn tag(hash: u32) -> u64 {
+    (((hash >> 6) | 1) as u64) << OFFSET_BITS
+}
+
+/* The lookup half of `cache stats` */
+pub fn print_lookup_stats(cache: &LookupCache) {
+    let stats = cache.stats();
+    let (dirs, paths) = cache.occupancy();
+    let counters = [
+        ("Index hits:   ", stats.index_hits),
+        ("Index misses: ", stats.index_misses),
+        ("Dir scans:    ", stats.scans),
+        ("Path hits:    ", stats.path_hits),
+        ("Indexed:      ", dirs as u64),
+    ];
+    for (label, value) in counters {
+        print_no_ln(label);
+        print_number(value);
+        print("");
+    }
+    print_no_ln("Paths:        ");
+    print_number(paths as u64);
+    print_no_ln(" of ");
+    print_number(PATH_SLOTS as u64);
+    print("");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::format;
+    use std::vec::Vec;
+
+    const FILE: CachedEntry = CachedEntry { cluster: 9, size: 4, attr: 0x20 };
+
+    #[test]
+    fn finds_names_of_indexed_directories() {
+        let mut cache = Box::<LookupCache>::default();
+        cache.begin_index(2);
+        assert!(cache.insert(b"a.txt", 64));
+        assert!(cache.insert(b"b.txt", 96));
+        cache.commit_index();
+        cache.begin_index(5);
+        assert!(cache.insert(b"a.txt", 1024));
+        cache.commit_index();
+
+        assert!(cache.is_indexed(2) && cache.is_indexed(5) && !cache.is_indexed(3));
+        assert_eq!(cache.candidates(2, b"a.txt").collect::<Vec<_>>(), [64]);
+        assert_eq!(cache.candidates(5, b"a.txt").collect::<Vec<_>>(), [1024]);
+        assert_eq!(cache.candidates(5, b"b.txt").count(), 0);
+
+        cache.invalidate();
+        assert!(!cache.is_indexed(2));
+    }
+
+    #[test]
+    fn gives_up_on_directories_larger_than_the_index() {
+        let mut cache = Box::<LookupCache>::default();
+        cache.begin_index(7);
+        let mut inserted = 0;
+        while cache.insert(format!("f{inserted}").as_bytes(), inserted as u64 * 32) {
+            inserted += 1;
+        }
+        assert_eq!(inserted, INDEX_LOAD);
+        cache.abandon_index();
+        assert!(cache.is_too_large(7) && !cache.is_indexed(7));
+    }
+
+    #[test]
+    fn remembers_resolved_paths() {
+        let mut cache = Box::<LookupCache>::default();
+        cache.remember_path(2, b"sub/a.txt", FILE);
+        assert_eq!(cache.cached_path(2, b"sub/a.txt"), Some(FILE));
+        assert_eq!(cache.cached_path(3, b"sub/a.txt"), None);
+
+        /* The oldest path makes room */
+        for i in 0..PATH_SLOTS {
+            cache.remember_path(2, format!("p{i}").as_bytes(), FILE);
+        }
+        assert_eq!(cache.cached_path(2, b"sub/a.txt"), None);
+        assert_eq!(cache.occupancy().1, PATH_SLOTS);
+        cache.invalidate();
+        assert_eq!(cache.occupancy(), (0, 0));
+    }
+}
@@ -1,27 +1,85 @@
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 #[cfg(test)]
 extern crate std;
 
+mod allocator;
+mod bitmap;
 mod boot_sector;
+mod cache;
+mod check;
 mod cli;
+mod device;
+mod dir_entry;
+mod exfat;
+mod extent;
 mod fat;
+mod fatcmp;
+mod find;
+#[cfg(test)]
+mod fixture;
+mod format;
+#[cfg(test)]
+mod fuzz;
+mod grep;
 mod helpers;
+mod lookup;
+mod partition;
+mod pattern;
 mod sys;
+mod tree;
+mod undelete;
+mod volume;
+mod walk;
 
 #[cfg(not(test))]
 use core::panic::PanicInfo;
 
-use crate::cli::{CLI_NAME, print, print_bytes_hex, print_line, print_no_ln, reset_cli};
-use crate::sys::{close, exit, open, print_bytes, read, read_at};
+use crate::cli::complete::{Candidates, Complete};
+use crate::cli::editor::{LINE_MAX, LineEditor, ReadLine, is_continuation};
+use crate::cli::commands::{ArgKind, COMMANDS, CommandId, find_command, help_command, print_unknown_command};
+use crate::cli::words::{print_token_error, tokenize};
+use crate::cli::options::{
+    IMAGE_VAR, Invocation, PATH_MAX, parse_options, print_option_error, print_usage, print_version,
+};
+use crate::cli::{CLI_NAME, print, print_bytes_hex, print_no_ln, reset_cli};
+#[cfg(not(test))]
+use crate::sys::init_process_args;
+use crate::sys::{close, exit, open, print_bytes, process_args};
+use allocator::free_command;
 use boot_sector::{BootSector, parse_boot_sector, verify_boot_sector_signature};
-use fat::{change_directory, list_root, read_file, list_dir};
-
-/* When not testing, we need this func to call main for aarch64 */
+use cache::{CACHE_BLOCK_SIZE, CACHE_BLOCKS, CacheBlocks, ImageDevice, ImageFile, print_cache_stats};
+use check::check_command;
+use device::{BlockDevice, FileDevice};
+use exfat::dir::{
+    ExfatPath, complete_names as complete_exfat_names, exfat_command, list_dir as list_exfat_dir,
+};
+use exfat::upcase::{UPCASE_ENTRIES, UpcaseTable};
+use exfat::{ExfatVolume, is_exfat};
+use fat::{
+    FatTree, READ_CHUNK_SIZE, change_directory, complete_names, directory_path, fat_regions, find_directory, find_file, list_dir, list_root, print_volume_error, read_file,
+};
+use lookup::{LookupCache, print_lookup_stats};
+use partition::{PartitionError, Window, find_partition};
+use fatcmp::fatcmp_command;
+use find::{find, parse_find_args};
+use grep::{Grep, parse_grep_args};
+use format::format_command;
+use undelete::{list_deleted_command, undelete_command};
+use tree::{parse_tree_args, print_tree};
+use walk::Path;
+
+/* When not testing, the C runtime's `_start` calls this with argc and
+ * argv from the process stack, we keep them and call main */
 #[cfg(not(test))]
 #[unsafe(no_mangle)]
-fn __libc_start_main() {
+extern "C" fn __libc_start_main(_main: usize, argc: usize, argv: *const *const u8) {
+    // Safety: `_start` passes argc and argv as the kernel laid them out.
+    //
+    // argv and envp stay on the stack for the whole process.
+    // SAFETY: see above, this runs first and once.
+    unsafe { init_process_args(argc, argv) };
     main();
 }
 
@@ -32,154 +90,642 @@ fn abort() {
     exit(1);
 }
 
-/* ---------- Main function ---------- */
-#[unsafe(no_mangle)]
-fn main() {
-    reset_cli();
+/* FAT volumes keep their geometry here, exFAT ones mount per command */
+enum Layout {
+    Fat {
+        bs: BootSector,
+        fat_start: usize,
+        data_start: usize,
+    },
+    Exfat,
+}
 
-    let path = b"disk.img\0";
-    let fd = open(path.as_ptr());
+struct Image {
+    file: ImageFile,
+    layout: Layout,
+}
 
-    if fd < 0 {
-        print("Error when opening image disk.img file, are you at the root directory?");
-        exit(1);
+/* The FAT name lookups of the shell, emptied at the same time as the
+ * cache. Too large for the stack, only `main` takes it, once */
+static mut LOOKUP: LookupCache = LookupCache::new();
+/* The blocks of the shell's sector cache, too large for the stack as well */
+static mut CACHE_STORAGE: CacheBlocks = [[0u8; CACHE_BLOCK_SIZE]; CACHE_BLOCKS];
+/* Where `cat`, `grep` and `tree` read file data into */
+static mut READ_CHUNK: [u8; READ_CHUNK_SIZE] = [0u8; READ_CHUNK_SIZE];
+/* Up-case table of a mounted exFAT volume, decompressed at each mount */
+static mut UPCASE: UpcaseTable = [0u16; UPCASE_ENTRIES];
+
+impl Image {
+    /* Cluster `cd /` goes to, 0 for exFAT and the fixed FAT12/16 root */
+    fn root_cluster(&self) -> u32 {
+        match &self.layout {
+            Layout::Fat { bs, .. } => bs.root_cluster,
+            Layout::Exfat => 0,
+        }
+    }
+}
+
+/* Prompts show this much of the current path, its end */
+const PROMPT_PATH_MAX: usize = 40;
+const PROMPT_MAX: usize = PROMPT_PATH_MAX + 16;
+
+/* `[fat32] /current/path: ` once an image is mounted, a long path cut
+ * from the front at a `/` when it can */
+fn build_prompt(path: Option<&[u8]>, out: &mut [u8; PROMPT_MAX]) -> usize {
+    let mut len = 0;
+    let mut push = |bytes: &[u8]| {
+        out[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len();
     };
+    push(CLI_NAME.as_bytes());
+    if let Some(mut path) = path {
+        push(b" ");
+        if path.len() > PROMPT_PATH_MAX {
+            let tail = &path[path.len() - PROMPT_PATH_MAX..];
+            let cut = tail
+                .iter()
+                .position(|&c| c == b'/')
+                .or_else(|| tail.iter().position(|&c| !is_continuation(c)))
+                .unwrap_or(0);
+            path = &tail[cut..];
+            push(b"...");
+        }
+        push(path);
+    }
+    push(b": ");
+    len
+}
+
+/* Tab completion from the mounted image, paths resolved like `cd` does */
+struct ShellCompleter<'a, 'b> {
+    image: Option<&'a Image>,
+    dev: &'a mut ImageDevice<'b>,
+    lookup: &'a mut LookupCache,
+    upcase: &'a mut UpcaseTable,
+    current_cluster: u32,
+    exfat_cwd: &'a ExfatPath,
+}
+
+impl Complete for ShellCompleter<'_, '_> {
+    fn commands(&mut self, out: &mut Candidates) {
+        for command in COMMANDS {
+            out.push(command.name.as_bytes(), false);
+            for alias in command.aliases {
+                out.push(alias.as_bytes(), false);
+            }
+        }
+    }
+
+    fn paths(&mut self, command: &[u8], dir: &[u8], out: &mut Candidates) {
+        match find_command(command).map(|c| c.completes) {
+            Some(ArgKind::Path) => {}
+            Some(ArgKind::Dir) => out.dirs_only(),
+            Some(ArgKind::Command) if dir.is_empty() => return self.commands(out),
+            _ => return,
+        }
+        let Some(img) = self.image else {
+            return;
+        };
+        let dev = &mut *self.dev;
+        /* Nothing to offer when the image can't be read, Tab rings */
+        let _ = match &img.layout {
+            Layout::Fat {
+                bs,
+                fat_start,
+                data_start,
+            } => find_directory(
+                dev,
+                self.lookup,
+                bs,
+                *fat_start,
+                *data_start,
+                self.current_cluster,
+                dir,
+            )
+            .and_then(|found| match found {
+                Some(cluster) => complete_names(dev, bs, *fat_start, *data_start, cluster, out),
+                None => Ok(()),
+            }),
+            Layout::Exfat => ExfatVolume::mount(dev, self.upcase)
+                .and_then(|mut vol| complete_exfat_names(&mut vol, self.exfat_cwd, dir, out)),
+        };
+    }
+}
+
+/* When the image didn't hold a volume at its start but has a partition
+ * table, the volume is likely in one of its partitions */
+fn print_partition_hint(dev: &mut ImageDevice) {
+    if (1..=4).any(|number| find_partition(dev, number).is_ok()) {
+        print("The image is partitioned, pick the volume with `--partition <n>`");
+    }
+}
+
+/* Open a NUL terminated image path, find the volume in it and locate its
+ * FATs. `dev` reads that image from now on, or no image when it fails.
+ * An exFAT volume decompresses its up-case table into `upcase` */
+fn mount_image(
+    dev: &mut ImageDevice,
+    upcase: &mut UpcaseTable,
+    path: &[u8],
+    partition: Option<u32>,
+    read_only: bool,
+) -> Option<Image> {
+    let fd = match open(path.as_ptr()) {
+        Ok(fd) => fd,
+        Err(e) => {
+            print_no_ln("Failed to open the image ");
+            print_bytes(&path[..path.iter().position(|&c| c == 0).unwrap_or(path.len())]);
+            print_no_ln(": ");
+            print(e.description());
+            print("Give its path as an argument, or create one with `format disk.img 64`");
+            return None;
+        }
+    };
+
+    /* ---------- Partition ---------- */
+    let window = match partition {
+        None => Window::WHOLE,
+        Some(number) => match find_partition(&mut FileDevice::new(fd), number) {
+            Ok(window) => window,
+            Err(e) => {
+                match e {
+                    PartitionError::Device(_) => print("Failed to read the partition table"),
+                    PartitionError::NoTable => print("The image has no partition table"),
+                    PartitionError::NotFound => print("No such partition on the image"),
+                }
+                close(fd);
+                return None;
+            }
+        },
+    };
+
+    let mut file = ImageFile {
+        fd,
+        path: [0u8; 257],
+        read_only,
+    };
+    let n = core::cmp::min(path.len(), file.path.len() - 1);
+    file.path[..n].copy_from_slice(&path[..n]);
+    dev.attach(fd, window);
 
     /* ---------- Boot sector ---------- */
     let mut boot_sector = [0u8; 512];
 
-    let r = read(fd as usize, boot_sector.as_mut_ptr(), 512);
-
-    if r != 512 {
+    if dev.read_at(0, &mut boot_sector).is_err() {
         print("Failed to read boot sector");
-        exit(1);
+        dev.detach();
+        close(fd);
+        return None;
+    }
+
+    if is_exfat(&boot_sector) {
+        let mut vol = match ExfatVolume::mount(&mut *dev, upcase) {
+            Ok(vol) => vol,
+            Err(e) => {
+                print_volume_error(e);
+                dev.detach();
+                close(fd);
+                return None;
+            }
+        };
+        let root = vol.root;
+        list_exfat_dir(&mut vol, &root, b"/");
+        return Some(Image {
+            file,
+            layout: Layout::Exfat,
+        });
     }
 
-    if verify_boot_sector_signature(&boot_sector) != true {
+    if !verify_boot_sector_signature(&boot_sector) {
         print("Boot sector signature is invalid");
         print_bytes_hex(&boot_sector[510..512]);
+        if partition.is_none() {
+            print_partition_hint(dev);
+        }
 
-        exit(1);
+        dev.detach();
+        close(fd);
+        return None;
     }
 
-    let bs: BootSector = parse_boot_sector(&boot_sector);
+    let bs: BootSector = match parse_boot_sector(&boot_sector) {
+        Ok(bs) => bs,
+        Err(_) => {
+            print("Boot sector geometry is invalid");
+            if partition.is_none() {
+                print_partition_hint(dev);
+            }
+            dev.detach();
+            close(fd);
+            return None;
+        }
+    };
 
     /* ---------- Localize FATs ---------- */
-    let fat_start = (bs.reserved_sectors_count as u32) * (bs.bytes_per_sector as u32);
-    let data_start =
-        fat_start + (bs.fats_count as u32) * bs.fat_size_sectors * bs.bytes_per_sector as u32;
+    let (fat_start, data_start) = fat_regions(&bs);
+
+    list_root(dev, &bs, fat_start, data_start);
+
+    Some(Image {
+        file,
+        layout: Layout::Fat {
+            bs,
+            fat_start,
+            data_start,
+        },
+    })
+}
+
+/* ---------- Main function ---------- */
+#[cfg_attr(not(test), unsafe(no_mangle))]
+fn main() {
+    let args = process_args();
+    let options = match parse_options(args.args(), args.env(IMAGE_VAR)) {
+        Ok(Invocation::Shell(options)) => options,
+        Ok(Invocation::Help) => {
+            print_usage();
+            exit(0);
+            return;
+        }
+        Ok(Invocation::Version) => {
+            print_version();
+            exit(0);
+            return;
+        }
+        Err(e) => {
+            print_option_error(e);
+            exit(2);
+            return;
+        }
+    };
+    let read_only = options.read_only;
+    let mut path = [0u8; PATH_MAX + 1];
+    path[..options.image.len()].copy_from_slice(options.image);
 
-    list_root(fd as usize, &bs, fat_start as usize, data_start as usize);
+    reset_cli();
 
-    let mut current_cluster = bs.root_cluster;
+    let (lookup, blocks, chunk, upcase) =
+        (&raw mut LOOKUP, &raw mut CACHE_STORAGE, &raw mut READ_CHUNK, &raw mut UPCASE);
+    // Safety: taking the shell's lookups, cache blocks, read chunk and
+    // up-case table out of the statics.
+    //
+    // main runs once, on the only thread, so these are the only references
+    // to them for the whole process.
+    // SAFETY: see above, nothing else names the statics.
+    let (lookup, blocks, chunk, upcase) = unsafe { (&mut *lookup, &mut *blocks, &mut *chunk, &mut *upcase) };
+    /* Reads of every command go through it, the commands writing borrow
+     * it too */
+    let mut dev = ImageDevice::new(blocks);
+    let mut image = mount_image(&mut dev, upcase, &path, options.partition, read_only);
+
+    let mut current_cluster = match &image {
+        Some(img) => img.root_cluster(),
+        None => 0,
+    };
+    let mut exfat_cwd = ExfatPath::new();
+    /* Path of the current directory from the root, and of the one before
+     * for `cd -` */
+    let mut cwd_path = Path::new();
+    let mut previous_path: Option<Path> = None;
 
-    print("Type 'exit' to quit or press Ctrl+C:");
+    /* Exit code of the last command that has one, returned on `exit` like a shell */
+    let mut last_status = 0usize;
 
-    #[cfg(not(test))]
-    loop {
-        print_no_ln(CLI_NAME);
+    print("Type 'help' for the commands, 'exit' to quit or press Ctrl+C:");
 
-        let mut buf = [0u8; 256];
-        /// Safety: calling the raw `read` syscall with a raw pointer.
-        ///
-        /// The buffer `buf` is a stack-local array with length `buf.len()`; we pass
-        /// its pointer and length to the syscall which will write at most that many
-        /// bytes. The syscall may perform arbitrary writes within that range.
-        // SAFETY: `buf.as_mut_ptr()` is valid for `buf.len()` bytes and `fd=0` (stdin)
-        // is a valid file descriptor for reading in this environment.
-        let bytes_read = unsafe { read(0, buf.as_mut_ptr(), buf.len()) };
+    let mut editor = LineEditor::new(args.env(b"HOME"));
 
-        if bytes_read >= 4 {
-            let input = &buf[..4];
-            if input[0] == b'e' && input[1] == b'x' && input[2] == b'i' && input[3] == b't' {
-                break;
+    loop {
+        let mut buf = [0u8; LINE_MAX];
+        let mut prompt = [0u8; PROMPT_MAX];
+        let prompt_len = build_prompt(image.is_some().then(|| cwd_path.as_bytes()), &mut prompt);
+        /* Ctrl+C, or the end of input (Ctrl+D or a closed pipe) */
+        let mut completer = ShellCompleter {
+            image: image.as_ref(),
+            dev: &mut dev,
+            lookup: &mut *lookup,
+            upcase: &mut *upcase,
+            current_cluster,
+            exfat_cwd: &exfat_cwd,
+        };
+        let len = match editor.read_line(&prompt[..prompt_len], &mut buf, &mut completer) {
+            ReadLine::Line(len) => len,
+            ReadLine::EndOfInput | ReadLine::Interrupted => break,
+        };
+
+        /* Names with spaces come quoted or escaped, as Tab completes them */
+        let words = match tokenize(&buf[..len]) {
+            Ok(words) => words,
+            Err(e) => {
+                print_token_error(e);
+                continue;
             }
-        }
-
-        if bytes_read <= 0 {
+        };
+        let (slices, count) = words.as_slices();
+        let Some((&name, args)) = slices[..count].split_first() else {
             continue;
-        }
-
-        let mut len = bytes_read as usize;
-        while len > 0
-            && (buf[len - 1] == b'\n'
-                || buf[len - 1] == b'\r'
-                || buf[len - 1] == 0
-                || buf[len - 1] == b' ')
-        {
-            len -= 1;
-        }
-        if len == 0 {
+        };
+        let Some(command) = find_command(name) else {
+            print_unknown_command(name);
+            continue;
+        };
+        if !command.accepts(args.len()) {
+            command.print_usage();
             continue;
         }
-
-        /* Handle `cd <dir>` command */
-        if len >= 3 && buf[0] == b'c' && buf[1] == b'd' && buf[2] == b' ' {
-            let arg = &buf[3..len];
-            match change_directory(
-                fd as usize,
-                &bs,
-                fat_start as usize,
-                data_start as usize,
-                current_cluster,
-                arg,
-            ) {
-                Some(cluster) => {
-                    current_cluster = cluster;
+        /* `cd -` goes back to the previous directory by its path */
+        let back;
+        let args = if command.id == CommandId::Cd && args[0] == b"-" {
+            match &previous_path {
+                Some(previous) => {
+                    back = previous.clone();
+                    &[back.as_bytes()][..]
                 }
                 None => {
-                    print("Folder not found");
+                    print("No previous directory");
+                    continue;
                 }
             }
+        } else {
+            args
+        };
+
+        /* `ls -R` draws the tree, taking the options of `tree` */
+        let id = match command.id {
+            CommandId::Ls if args.contains(&&b"-R"[..]) => CommandId::Tree,
+            id => id,
+        };
+        let valid = match id {
+            CommandId::Tree => parse_tree_args(args).is_some(),
+            CommandId::Find => parse_find_args(args).is_some(),
+            CommandId::Grep => parse_grep_args(args).is_some(),
+            /* The table allows the options of `ls -R`, without it a directory
+             * or `--deleted` comes alone */
+            CommandId::Ls => args.len() <= 1,
+            _ => true,
+        };
+        if !valid {
+            command.print_usage();
             continue;
         }
 
-        /* Handle `ls` with no arguments -> list current directory */
-        if len == 2 && buf[0] == b'l' && buf[1] == b's' {
-            reset_cli();
-            list_dir(fd as usize, &bs, fat_start as usize, data_start as usize, current_cluster, b"./");
-            continue;
+        match id {
+            CommandId::Exit => break,
+            CommandId::Help => {
+                last_status = help_command(args);
+                continue;
+            }
+            /* Format then mount the result */
+            CommandId::Format => {
+                if read_only {
+                    print("The image was opened with --read-only");
+                } else if let Some(path) = format_command(args) {
+                    if let Some(img) = image.take() {
+                        close(img.file.fd);
+                    }
+                    reset_cli();
+                    image = mount_image(&mut dev, upcase, &path, None, read_only);
+                    lookup.invalidate();
+                    if let Some(img) = &image {
+                        current_cluster = img.root_cluster();
+                    }
+                    exfat_cwd = ExfatPath::new();
+                    cwd_path = Path::new();
+                    previous_path = None;
+                }
+                continue;
+            }
+            _ => {}
         }
 
-        /* Handle `ls <path>` command (list without changing current directory) */
-        if len >= 3 && buf[0] == b'l' && buf[1] == b's' && buf[2] == b' ' {
-            let arg = &buf[3..len];
-            match change_directory(
-                fd as usize,
-                &bs,
-                fat_start as usize,
-                data_start as usize,
-                current_cluster,
-                arg,
-            ) {
-                Some(_cluster) => {
-                    /* `change_directory` already printed the directory listing. */
-                }
-                None => {
-                    print("Folder not found");
-                }
+        let img = match &image {
+            Some(img) => img,
+            None => {
+                print("No image mounted, create one with `format disk.img 64`");
+                continue;
             }
+        };
+        let file = img.file;
+        let dev = &mut dev;
+
+        if id == CommandId::Pwd {
+            print_bytes(cwd_path.as_bytes());
+            print("");
             continue;
         }
 
-        /* Handle `cat <file>` or `more <file>` command */
-        if (len >= 4 && buf[0] == b'c' && buf[1] == b'a' && buf[2] == b't' && buf[3] == b' ')
-            || (len >= 5 && buf[0] == b'm' && buf[1] == b'o' && buf[2] == b'r' && buf[3] == b'e' && buf[4] == b' ')
-        {
-            let arg = if buf[0] == b'c' { &buf[4..len] } else { &buf[5..len] };
-            match read_file(fd as usize, &bs, fat_start as usize, data_start as usize, current_cluster, arg) {
-                Some(()) => {}
-                None => print("File not found"),
+        /* How well the sector cache and the lookups do */
+        if id == CommandId::Cache {
+            if args[0] != b"stats" {
+                command.print_usage();
+                continue;
             }
+            match dev.cache() {
+                Some(cache) => print_cache_stats(cache),
+                None => print("The image is mapped in memory, it is read in place"),
+            }
+            print_lookup_stats(lookup);
             continue;
         }
 
-        /* Unknown command — show simple help */
-        print("Unknown command. Use `cd <dir>` or type `exit`.");
+        let (bs, fat_start, data_start) = match &img.layout {
+            Layout::Fat {
+                bs,
+                fat_start,
+                data_start,
+            } => (bs, *fat_start, *data_start),
+            Layout::Exfat => {
+                let before = cwd_path.clone();
+                exfat_command(dev, &file, upcase, &mut exfat_cwd, &mut cwd_path, id, args);
+                if id == CommandId::Cd && cwd_path.as_bytes() != before.as_bytes() {
+                    previous_path = Some(before);
+                }
+                continue;
+            }
+        };
+
+        match id {
+            CommandId::Cd => {
+                match change_directory(
+                    dev,
+                    lookup,
+                    bs,
+                    fat_start,
+                    data_start,
+                    current_cluster,
+                    args[0],
+                ) {
+                    Ok(Some((cluster, path))) => {
+                        current_cluster = cluster;
+                        if path.as_bytes() != cwd_path.as_bytes() {
+                            previous_path = Some(core::mem::replace(&mut cwd_path, path));
+                        }
+                    }
+                    Ok(None) => {
+                        print("Folder not found");
+                    }
+                    Err(e) => print_volume_error(e),
+                }
+            }
+            /* Deleted entries of the current directory */
+            CommandId::Ls if args.first() == Some(&&b"--deleted"[..]) => {
+                list_deleted_command(dev, current_cluster);
+            }
+            CommandId::Ls if args.is_empty() => {
+                reset_cli();
+                list_dir(dev, bs, fat_start, data_start, current_cluster, cwd_path.as_bytes());
+            }
+            /* List without changing the current directory */
+            CommandId::Ls => {
+                match change_directory(
+                    dev,
+                    lookup,
+                    bs,
+                    fat_start,
+                    data_start,
+                    current_cluster,
+                    args[0],
+                ) {
+                    Ok(Some(_)) => {
+                        /* `change_directory` already printed the directory listing. */
+                    }
+                    Ok(None) => {
+                        print("Folder not found");
+                    }
+                    Err(e) => print_volume_error(e),
+                }
+            }
+            CommandId::Tree => {
+                let Some((opts, path)) = parse_tree_args(args) else {
+                    continue;
+                };
+                match find_directory(dev, lookup, bs, fat_start, data_start, current_cluster, path) {
+                    Ok(Some(dir)) => {
+                        let mut shown = Path::new();
+                        let mut tree = FatTree {
+                            dev,
+                            bs,
+                            fat_start,
+                            data_start,
+                            chunk,
+                        };
+                        let drawn = directory_path(tree.dev, bs, fat_start, data_start, dir, &mut shown)
+                            .and_then(|()| print_tree(&mut tree, dir, shown.as_bytes(), &opts));
+                        if let Err(e) = drawn {
+                            print_volume_error(e);
+                        }
+                    }
+                    Ok(None) => print("Folder not found"),
+                    Err(e) => print_volume_error(e),
+                }
+            }
+            CommandId::Find => {
+                let Some((opts, path)) = parse_find_args(args) else {
+                    continue;
+                };
+                match find_directory(dev, lookup, bs, fat_start, data_start, current_cluster, path) {
+                    Ok(Some(dir)) => {
+                        let mut shown = Path::new();
+                        let mut tree = FatTree {
+                            dev,
+                            bs,
+                            fat_start,
+                            data_start,
+                            chunk,
+                        };
+                        let found = directory_path(tree.dev, bs, fat_start, data_start, dir, &mut shown)
+                            .and_then(|()| find(&mut tree, dir, shown.as_bytes(), &opts));
+                        if let Err(e) = found {
+                            print_volume_error(e);
+                        }
+                    }
+                    Ok(None) => print("Folder not found"),
+                    Err(e) => print_volume_error(e),
+                }
+            }
+            CommandId::Grep => {
+                let Some((opts, pattern, path)) = parse_grep_args(args) else {
+                    continue;
+                };
+                let Some(mut grep) = Grep::new(pattern, opts) else {
+                    print("Invalid pattern");
+                    continue;
+                };
+                let searched = match find_directory(dev, lookup, bs, fat_start, data_start, current_cluster, path) {
+                    Ok(Some(dir)) if opts.recursive => {
+                        let mut shown = Path::new();
+                        let mut tree = FatTree {
+                            dev,
+                            bs,
+                            fat_start,
+                            data_start,
+                            chunk,
+                        };
+                        directory_path(tree.dev, bs, fat_start, data_start, dir, &mut shown)
+                            .and_then(|()| grep.tree(&mut tree, dir, shown.as_bytes()))
+                    }
+                    Ok(Some(_)) => {
+                        print("That is a directory, search it with -r");
+                        continue;
+                    }
+                    Ok(None) => match find_file(dev, lookup, bs, fat_start, data_start, current_cluster, path) {
+                        Ok(Some(entry)) => {
+                            grep.file(b"", path, |out| read_file(dev, bs, fat_start, data_start, &entry, chunk, out))
+                        }
+                        Ok(None) => {
+                            print("File not found");
+                            continue;
+                        }
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e),
+                };
+                match searched {
+                    Ok(()) => grep.print_summary(),
+                    Err(e) => print_volume_error(e),
+                }
+            }
+            CommandId::Cat => {
+                match find_file(dev, lookup, bs, fat_start, data_start, current_cluster, args[0]) {
+                    Ok(Some(entry)) => {
+                        if let Err(e) = read_file(dev, bs, fat_start, data_start, &entry, chunk, print_bytes) {
+                            print_volume_error(e);
+                        }
+                    }
+                    Ok(None) => print("File not found"),
+                    Err(e) => print_volume_error(e),
+                }
+            }
+            /* Read only like `dosfsck -n` unless asked to repair */
+            CommandId::Check => {
+                last_status = check_command(dev, &file, args);
+                lookup.invalidate();
+            }
+            /* Diff the FAT copies and optionally resync them */
+            CommandId::Fatcmp => {
+                last_status = fatcmp_command(dev, &file, args);
+                lookup.invalidate();
+            }
+            /* Free space and where a run would fit */
+            CommandId::Free => {
+                last_status = free_command(dev, args);
+            }
+            /* The lost first character is asked for */
+            CommandId::Undelete => {
+                last_status = undelete_command(dev, &file, current_cluster, args[0]);
+                lookup.invalidate();
+            }
+            CommandId::Mkdir | CommandId::Write | CommandId::Rm | CommandId::Mv => {
+                print("Writing is only supported on exFAT volumes");
+            }
+            CommandId::Help | CommandId::Exit | CommandId::Format | CommandId::Cache | CommandId::Pwd => {}
+        }
+    }
+    if let Some(img) = image {
+        close(img.file.fd);
     }
-    close(fd as usize);
 
-    exit(0);
+    exit(last_status);
 }
 
 /* We need to implement this panic handler in no_std */
@@ -0,0 +1,168 @@
+use crate::device::{BlockDevice, DeviceError};
+use crate::helpers::{u8_to_u32_le, u8_to_u64_le};
+
+const SECTOR_SIZE: u64 = 512;
+const MBR_ENTRIES: usize = 4;
+const MBR_TABLE: usize = 446;
+const MBR_TYPE_GPT: u8 = 0xEE;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/* Entries read from the GPT array, what partitioning tools create */
+const GPT_MAX_ENTRIES: u32 = 128;
+
+/* Bytes of the image a volume lives in */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Window {
+    /* The whole image, a volume without a partition table */
+    pub const WHOLE: Window = Window {
+        offset: 0,
+        len: u64::MAX,
+    };
+    /* No bytes at all, where a device attached to no image reads */
+    pub const NONE: Window = Window { offset: 0, len: 0 };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Device(DeviceError),
+    /* Sector 0 holds no MBR */
+    NoTable,
+    /* The table has no partition by that number */
+    NotFound,
+}
+
+impl From<DeviceError> for PartitionError {
+    fn from(e: DeviceError) -> Self {
+        PartitionError::Device(e)
+    }
+}
+
+/* Partition `number` (from 1) of the MBR, or of the GPT behind a
+ * protective MBR. MBR numbers are the four primary slots */
+pub fn find_partition<D: BlockDevice>(dev: &mut D, number: u32) -> Result<Window, PartitionError> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    dev.read_at(0, &mut mbr)?;
+    if mbr[510] != 0x55 || mbr[511] != 0xAA {
+        return Err(PartitionError::NoTable);
+    }
+
+    let entry = |i: usize| &mbr[MBR_TABLE + 16 * i..MBR_TABLE + 16 * (i + 1)];
+    if (0..MBR_ENTRIES).any(|i| entry(i)[4] == MBR_TYPE_GPT) {
+        return find_gpt_partition(dev, number);
+    }
+
+    let index = number
+        .checked_sub(1)
+        .map(|i| i as usize)
+        .filter(|&i| i < MBR_ENTRIES);
+    let Some(e) = index.map(entry) else {
+        return Err(PartitionError::NotFound);
+    };
+    let start = u8_to_u32_le(&e[8..12]) as u64;
+    let sectors = u8_to_u32_le(&e[12..16]) as u64;
+    if e[4] == 0 || sectors == 0 {
+        return Err(PartitionError::NotFound);
+    }
+    Ok(Window {
+        offset: start * SECTOR_SIZE,
+        len: sectors * SECTOR_SIZE,
+    })
+}
+
+fn find_gpt_partition<D: BlockDevice>(dev: &mut D, number: u32) -> Result<Window, PartitionError> {
+    let mut header = [0u8; SECTOR_SIZE as usize];
+    dev.read_at(SECTOR_SIZE, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+    let entries_lba = u8_to_u64_le(&header[72..80]);
+    let entries = u8_to_u32_le(&header[80..84]).min(GPT_MAX_ENTRIES);
+    let entry_size = u8_to_u32_le(&header[84..88]) as u64;
+    if number == 0 || number > entries || !(128..=SECTOR_SIZE).contains(&entry_size) {
+        return Err(PartitionError::NotFound);
+    }
+
+    let mut e = [0u8; 128];
+    let at = entries_lba * SECTOR_SIZE + (number - 1) as u64 * entry_size;
+    dev.read_at(at, &mut e)?;
+    let first = u8_to_u64_le(&e[32..40]);
+    let last = u8_to_u64_le(&e[40..48]);
+    /* An all zero type GUID marks an unused entry */
+    if e[0..16].iter().all(|&b| b == 0) || last < first {
+        return Err(PartitionError::NotFound);
+    }
+    Ok(Window {
+        offset: first * SECTOR_SIZE,
+        len: (last - first + 1) * SECTOR_SIZE,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::helpers::u32_to_u8_le;
+    use std::vec;
+
+    fn mbr_entry(data: &mut [u8], slot: usize, kind: u8, start: u32, sectors: u32) {
+        let e = &mut data[MBR_TABLE + 16 * slot..MBR_TABLE + 16 * (slot + 1)];
+        e[4] = kind;
+        u32_to_u8_le(start, &mut e[8..12]);
+        u32_to_u8_le(sectors, &mut e[12..16]);
+    }
+
+    #[test]
+    fn finds_mbr_partitions() {
+        let mut data = vec![0u8; 4096];
+        let mut dev = MemDevice { data: &mut data };
+        assert_eq!(find_partition(&mut dev, 1), Err(PartitionError::NoTable));
+
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        mbr_entry(&mut data, 1, 0x0C, 2048, 100);
+        let mut dev = MemDevice { data: &mut data };
+        assert_eq!(
+            find_partition(&mut dev, 2),
+            Ok(Window {
+                offset: 2048 * 512,
+                len: 100 * 512,
+            })
+        );
+        assert_eq!(find_partition(&mut dev, 1), Err(PartitionError::NotFound));
+        assert_eq!(find_partition(&mut dev, 5), Err(PartitionError::NotFound));
+        assert_eq!(find_partition(&mut dev, 0), Err(PartitionError::NotFound));
+    }
+
+    #[test]
+    fn finds_gpt_partitions_behind_a_protective_mbr() {
+        let mut data = vec![0u8; 8192];
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        mbr_entry(&mut data, 0, MBR_TYPE_GPT, 1, u32::MAX);
+        let header = &mut data[512..1024];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72] = 2;
+        u32_to_u8_le(4, &mut header[80..84]);
+        u32_to_u8_le(128, &mut header[84..88]);
+        /* Second entry from LBA 34 to 99 */
+        let entry = &mut data[1024 + 128..1024 + 256];
+        entry[0] = 0xA2;
+        entry[32] = 34;
+        entry[40] = 99;
+
+        let mut dev = MemDevice { data: &mut data };
+        assert_eq!(
+            find_partition(&mut dev, 2),
+            Ok(Window {
+                offset: 34 * 512,
+                len: 66 * 512,
+            })
+        );
+        assert_eq!(find_partition(&mut dev, 1), Err(PartitionError::NotFound));
+        assert_eq!(find_partition(&mut dev, 5), Err(PartitionError::NotFound));
+    }
+}
@@ -0,0 +1,303 @@
+/* Patterns over bytes, ASCII letters compared without case when asked.
+ *
+ * Shell style wildcards for `find -name`: `*` any run, `?` one character,
+ * `[abc]`, `[a-z]`, `[!x]` or `[^x]` one of a set, and `\` takes the next
+ * byte as is.
+ *
+ * Regular expressions for `grep`, by backtracking: `.` one character, the
+ * same sets, `\d`, `\w` and `\s`, `*`, `+` and `?` after any of these,
+ * and `^` and `$` anchoring at the ends. No groups nor alternatives */
+
+/* Bytes of the UTF-8 character starting with `lead`, 1 when not a lead */
+fn char_len(lead: u8) -> usize {
+    match lead {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
+fn same(a: u8, b: u8, ignore_case: bool) -> bool {
+    a == b || (ignore_case && a.eq_ignore_ascii_case(&b))
+}
+
+/* Whether `c` is in the set starting after a `[` at `start`, and where
+ * the pattern goes on. None when the set is never closed */
+fn match_class(pattern: &[u8], start: usize, c: u8, ignore_case: bool) -> Option<(bool, usize)> {
+    let mut i = start;
+    let negate = matches!(pattern.get(i), Some(b'!' | b'^'));
+    if negate {
+        i += 1;
+    }
+    let in_range = |lo: u8, hi: u8| {
+        (lo..=hi).contains(&c)
+            || (ignore_case
+                && ((lo..=hi).contains(&c.to_ascii_lowercase())
+                    || (lo..=hi).contains(&c.to_ascii_uppercase())))
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *pattern.get(i)?;
+        if lo == b']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if lo == b'\\' {
+            i += 1;
+            lo = *pattern.get(i)?;
+        }
+        i += 1;
+        let mut hi = lo;
+        if pattern.get(i) == Some(&b'-') && pattern.get(i + 1).is_some_and(|&b| b != b']') {
+            hi = pattern[i + 1];
+            if hi == b'\\' {
+                i += 1;
+                hi = *pattern.get(i + 1)?;
+            }
+            i += 2;
+        }
+        matched |= in_range(lo, hi);
+    }
+}
+
+/* Pattern and text positions after matching what `pattern[p]` stands for
+ * against the start of `text[t..]`, when it does */
+fn match_one(
+    pattern: &[u8],
+    p: usize,
+    text: &[u8],
+    t: usize,
+    ignore_case: bool,
+) -> Option<(usize, usize)> {
+    let c = text[t];
+    match pattern[p] {
+        b'?' => Some((p + 1, (t + char_len(c)).min(text.len()))),
+        b'[' => match match_class(pattern, p + 1, c, ignore_case) {
+            Some((matched, next)) => matched.then_some((next, t + 1)),
+            /* Never closed, a plain `[` */
+            None => (c == b'[').then_some((p + 1, t + 1)),
+        },
+        b'\\' if p + 1 < pattern.len() => {
+            same(pattern[p + 1], c, ignore_case).then_some((p + 2, t + 1))
+        }
+        b => same(b, c, ignore_case).then_some((p + 1, t + 1)),
+    }
+}
+
+/* Whether all of `text` matches `pattern` */
+pub fn glob_match(pattern: &[u8], text: &[u8], ignore_case: bool) -> bool {
+    let (mut p, mut t) = (0, 0);
+    /* Past the last `*` seen, and the text it stopped at */
+    let mut star: Option<(usize, usize)> = None;
+    loop {
+        if t == text.len() {
+            return pattern[p..].iter().all(|&b| b == b'*');
+        }
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if p < pattern.len()
+            && let Some((next_p, next_t)) = match_one(pattern, p, text, t, ignore_case)
+        {
+            (p, t) = (next_p, next_t);
+            continue;
+        }
+        /* Let the last `*` take one more byte and try again from there */
+        let Some((after, taken)) = star else {
+            return false;
+        };
+        star = Some((after, taken + 1));
+        (p, t) = (after, taken + 1);
+    }
+}
+
+/* Byte after `.` took one character at `t`: continuation bytes go with it */
+fn skip_char(text: &[u8], t: usize) -> usize {
+    let mut next = t + 1;
+    while next < text.len() && is_continuation(text[next]) {
+        next += 1;
+    }
+    next
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Regex<'a> {
+    pattern: &'a [u8],
+    ignore_case: bool,
+}
+
+impl<'a> Regex<'a> {
+    /* None when a set is never closed, the pattern ends on a lone `\` or
+     * a repeat has nothing before it */
+    pub fn new(pattern: &'a [u8], ignore_case: bool) -> Option<Self> {
+        let regex = Regex {
+            pattern,
+            ignore_case,
+        };
+        let mut p = usize::from(pattern.first() == Some(&b'^'));
+        while p < pattern.len() {
+            if matches!(pattern[p], b'*' | b'+' | b'?') {
+                return None;
+            }
+            p = regex.atom_end(p)?;
+            if matches!(pattern.get(p), Some(b'*' | b'+' | b'?')) {
+                p += 1;
+            }
+        }
+        Some(regex)
+    }
+
+    /* Where the atom at `p` ends */
+    fn atom_end(&self, p: usize) -> Option<usize> {
+        match self.pattern[p] {
+            b'\\' if p + 1 < self.pattern.len() => Some(p + 2),
+            b'\\' => None,
+            b'[' => match_class(self.pattern, p + 1, 0, false).map(|(_, next)| next),
+            _ => Some(p + 1),
+        }
+    }
+
+    /* Where the text goes on after the atom at `p` matched at `t` */
+    fn match_atom(&self, p: usize, text: &[u8], t: usize) -> Option<usize> {
+        let &c = text.get(t)?;
+        let matched = match self.pattern[p] {
+            b'.' => return Some(skip_char(text, t)),
+            b'[' => match_class(self.pattern, p + 1, c, self.ignore_case)?.0,
+            b'\\' => match self.pattern[p + 1] {
+                b'd' => c.is_ascii_digit(),
+                b'w' => c.is_ascii_alphanumeric() || c == b'_',
+                b's' => c.is_ascii_whitespace(),
+                escaped => same(escaped, c, self.ignore_case),
+            },
+            b => same(b, c, self.ignore_case),
+        };
+        matched.then_some(t + 1)
+    }
+
+    /* Whether `pattern[p..]` matches from `text[t..]` on */
+    fn match_here(&self, p: usize, text: &[u8], t: usize) -> bool {
+        let pattern = self.pattern;
+        if p == pattern.len() {
+            return true;
+        }
+        if pattern[p] == b'$' && p + 1 == pattern.len() {
+            return t == text.len();
+        }
+        /* Checked by `new` */
+        let Some(end) = self.atom_end(p) else {
+            return false;
+        };
+        let (min, max, rest) = match pattern.get(end) {
+            Some(b'*') => (0, usize::MAX, end + 1),
+            Some(b'+') => (1, usize::MAX, end + 1),
+            Some(b'?') => (0, 1, end + 1),
+            _ => (1, 1, end),
+        };
+
+        /* As many as will match, then give them back one at a time */
+        let mut taken = t;
+        let mut count = 0;
+        while count < max
+            && let Some(next) = self.match_atom(p, text, taken)
+        {
+            taken = next;
+            count += 1;
+        }
+        while count >= min {
+            if self.match_here(rest, text, taken) {
+                return true;
+            }
+            if count == 0 {
+                break;
+            }
+            taken -= 1;
+            if pattern[p] == b'.' {
+                while taken > t && is_continuation(text[taken]) {
+                    taken -= 1;
+                }
+            }
+            count -= 1;
+        }
+        false
+    }
+
+    /* Whether the pattern matches somewhere in `text` */
+    pub fn is_match(&self, text: &[u8]) -> bool {
+        if self.pattern.first() == Some(&b'^') {
+            return self.match_here(1, text, 0);
+        }
+        (0..=text.len()).any(|t| self.match_here(0, text, t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_whole_names() {
+        assert!(glob_match(b"*.txt", b"notes.txt", false));
+        assert!(!glob_match(b"*.txt", b"notes.txt.bak", false));
+        assert!(glob_match(b"*.TXT", b"notes.txt", true));
+        assert!(!glob_match(b"*.TXT", b"notes.txt", false));
+        assert!(glob_match(b"a*b*c", b"axxbyybzc", false));
+        assert!(glob_match(b"*", b"", false));
+        assert!(!glob_match(b"?", b"", false));
+
+        /* `?` takes a whole character */
+        assert!(glob_match(b"caf?", "café".as_bytes(), false));
+        assert!(!glob_match(b"caf??", "café".as_bytes(), false));
+
+        assert!(glob_match(b"file[0-9].log", b"file7.log", false));
+        assert!(!glob_match(b"file[!0-9].log", b"file7.log", false));
+        assert!(glob_match(b"[^a]x", b"bx", false));
+        assert!(glob_match(b"[A-C]", b"b", true));
+        assert!(glob_match(b"[]]", b"]", false));
+        assert!(glob_match(b"a[-]b", b"a-b", false));
+        assert!(glob_match(b"a\\*", b"a*", false));
+        assert!(!glob_match(b"a\\*", b"ab", false));
+
+        /* A set never closed is a `[` */
+        assert!(glob_match(b"[ab", b"[ab", false));
+    }
+
+    #[test]
+    fn regexes_match_anywhere_in_a_line() {
+        let matches = |pattern: &str, text: &str| Regex::new(pattern.as_bytes(), false).unwrap().is_match(text.as_bytes());
+        assert!(matches("needle", "a needle in a haystack"));
+        assert!(!matches("needle", "a needl in a haystack"));
+        assert!(matches("^a ne", "a needle"));
+        assert!(!matches("^needle", "a needle"));
+        assert!(matches("stack$", "haystack"));
+        assert!(!matches("hay$", "haystack"));
+        assert!(matches("^$", ""));
+        assert!(matches("a.c", "abc"));
+        assert!(matches("^.$", "é"));
+        assert!(matches("colou?r", "color") && matches("colou?r", "colour"));
+        assert!(matches("^ab+c$", "abbbc") && !matches("^ab+c$", "ac"));
+        assert!(matches("^a.*b.*c$", "a--b--b--c"));
+        assert!(matches("^x.*é$", "x ééé"));
+        assert!(matches("[0-9][0-9]*-[a-z]+", "id 42-abc"));
+        assert!(matches("\\d\\d:\\d\\d", "at 12:45"));
+        assert!(matches("\\w+\\s\\w+", "two words"));
+        assert!(matches("a\\.b", "a.b") && !matches("a\\.b", "axb"));
+        assert!(matches("1\\+1", "1+1"));
+        assert!(matches("^$x", "$x"));
+
+        assert!(Regex::new(b"ERROR", true).unwrap().is_match(b"an error"));
+        assert!(Regex::new(b"[a-c]x", true).unwrap().is_match(b"Bx"));
+
+        assert!(Regex::new(b"*a", false).is_none());
+        assert!(Regex::new(b"a**", false).is_none());
+        assert!(Regex::new(b"[ab", false).is_none());
+        assert!(Regex::new(b"ab\\", false).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+/* Arguments and environment as the kernel lays them out on the process
+ * stack: argc, the argv pointers and a NULL, then the envp pointers and a
+ * NULL. The C runtime's `_start` hands `__libc_start_main` argc and argv
+ * the same way on x86_64 and aarch64, envp follows from them */
+#[derive(Clone, Copy)]
+pub struct ProcessArgs {
+    argc: usize,
+    argv: *const *const u8,
+    envp: *const *const u8,
+}
+
+/* Filled in by the entry point before `main` runs, empty under tests */
+static mut PROCESS_ARGS: ProcessArgs = ProcessArgs {
+    argc: 0,
+    argv: core::ptr::null(),
+    envp: core::ptr::null(),
+};
+
+/// Safety: same contract as `ProcessArgs::from_stack`.
+///
+/// Called once by the entry point, before anything reads the arguments.
+#[cfg(not(test))]
+pub unsafe fn init_process_args(argc: usize, argv: *const *const u8) {
+    // SAFETY: see above, the shell is single threaded and nothing reads
+    // PROCESS_ARGS yet.
+    unsafe { PROCESS_ARGS = ProcessArgs::from_stack(argc, argv) };
+}
+
+pub fn process_args() -> ProcessArgs {
+    // Safety: copying PROCESS_ARGS out.
+    //
+    // It is only written by `init_process_args`, before this runs.
+    // SAFETY: see above.
+    unsafe {
+        PROCESS_ARGS
+    }
+}
+
+impl ProcessArgs {
+    /// Safety: `argv` must come from the process stack.
+    ///
+    /// It must point at `argc` NUL terminated strings and a NULL, followed by
+    /// the NULL terminated envp array, all living for the whole process.
+    pub unsafe fn from_stack(argc: usize, argv: *const *const u8) -> Self {
+        // SAFETY: see above, envp starts right after argv and its NULL.
+        let envp = unsafe { argv.add(argc + 1) };
+        ProcessArgs { argc, argv, envp }
+    }
+
+    /* Arguments after the program name */
+    pub fn args(&self) -> impl Iterator<Item = &'static [u8]> + '_ {
+        (1..self.argc).map(|i| {
+            // Safety: reading one of the argv pointers.
+            //
+            // `i < argc`, `from_stack` was given that many valid pointers.
+            // SAFETY: see above.
+            unsafe {
+                c_str(*self.argv.add(i))
+            }
+        })
+    }
+
+    /* Value of the environment variable `name` */
+    pub fn env(&self, name: &[u8]) -> Option<&'static [u8]> {
+        if self.envp.is_null() {
+            return None;
+        }
+        let mut i = 0;
+        loop {
+            // Safety: walking envp up to its NULL.
+            //
+            // `from_stack` guarantees the array is NULL terminated.
+            // SAFETY: see above, `i` never passes the NULL.
+            let entry = unsafe { *self.envp.add(i) };
+            if entry.is_null() {
+                return None;
+            }
+            // SAFETY: non NULL envp entries are NUL terminated strings.
+            let var = unsafe { c_str(entry) };
+            if var.len() > name.len() && var.starts_with(name) && var[name.len()] == b'=' {
+                return Some(&var[name.len() + 1..]);
+            }
+            i += 1;
+        }
+    }
+}
+
+/// Safety: `ptr` must point at a NUL terminated string living for the
+/// whole process.
+unsafe fn c_str(ptr: *const u8) -> &'static [u8] {
+    let mut len = 0;
+    // SAFETY: see above, every byte up to the NUL is readable.
+    while unsafe { *ptr.add(len) } != 0 {
+        len += 1;
+    }
+    // SAFETY: the `len` bytes before the NUL were just read.
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_arguments_and_environment() {
+        let strings: [&[u8]; 5] = [
+            b"fat32\0",
+            b"-r\0",
+            b"a.img\0",
+            b"HOME=/root\0",
+            b"FAT32_IMAGE=b.img\0",
+        ];
+        let stack: [*const u8; 7] = [
+            strings[0].as_ptr(),
+            strings[1].as_ptr(),
+            strings[2].as_ptr(),
+            core::ptr::null(),
+            strings[3].as_ptr(),
+            strings[4].as_ptr(),
+            core::ptr::null(),
+        ];
+        // SAFETY: the array has the stack layout and the strings are static.
+        let args = unsafe { ProcessArgs::from_stack(3, stack.as_ptr()) };
+
+        let mut it = args.args();
+        assert_eq!(it.next(), Some(&b"-r"[..]));
+        assert_eq!(it.next(), Some(&b"a.img"[..]));
+        assert_eq!(it.next(), None);
+        assert_eq!(args.env(b"FAT32_IMAGE"), Some(&b"b.img"[..]));
+        assert_eq!(args.env(b"HOME"), Some(&b"/root"[..]));
+        assert_eq!(args.env(b"HOM"), None);
+    }
+}
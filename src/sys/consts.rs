@@ -1,11 +1,62 @@
 pub static RDONLY_0: usize = 0;
 
+pub static WRONLY_1: usize = 1;
+
+pub static RDWR_2: usize = 2;
+
+pub static CREAT_64: usize = 64;
+
+pub static APPEND_1024: usize = 1024;
+
+pub static FILE_MODE_644: usize = 0o644;
+
+pub static CLOCK_REALTIME: usize = 0;
+
 pub static AT_FDCWD: isize = -100;
 
 pub static STDOUT_FILENO: usize = 1;
 
-pub static SEEK_SET: usize = 0;
+pub static SEEK_END: usize = 2;
 
-pub static SEEK_CUR: usize = 1;
+/* File type bits of st_mode */
+pub static S_IFMT: u32 = 0o170000;
 
-pub static SEEK_END: usize = 2;
+pub static S_IFBLK: u32 = 0o060000;
+
+pub static PROT_READ: usize = 1;
+
+pub static PROT_WRITE: usize = 2;
+
+pub static MAP_SHARED: usize = 1;
+
+pub static MS_SYNC: usize = 4;
+
+pub static STDIN_FILENO: usize = 0;
+
+/* Terminal ioctls and termios bits, the generic values x86_64 and aarch64
+ * share */
+pub static TCGETS: usize = 0x5401;
+
+pub static TCSETS: usize = 0x5402;
+
+pub static BRKINT: u32 = 0o2;
+
+pub static INPCK: u32 = 0o20;
+
+pub static ISTRIP: u32 = 0o40;
+
+pub static ICRNL: u32 = 0o400;
+
+pub static IXON: u32 = 0o2000;
+
+pub static ISIG: u32 = 0o1;
+
+pub static ICANON: u32 = 0o2;
+
+pub static ECHO: u32 = 0o10;
+
+pub static IEXTEN: u32 = 0o100000;
+
+pub static VTIME: usize = 5;
+
+pub static VMIN: usize = 6;
@@ -0,0 +1,59 @@
+/* Error number of a failed syscall, the raw return value is its negation.
+ * The numbers are the same on x86_64 and aarch64 */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u16);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(2);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const EBADF: Errno = Errno(9);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EACCES: Errno = Errno(13);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EFBIG: Errno = Errno(27);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const EROFS: Errno = Errno(30);
+
+    /* What strerror would say, for the ones worth telling apart */
+    pub fn description(self) -> &'static str {
+        match self {
+            Errno::ENOENT => "No such file or directory",
+            Errno::EINTR => "Interrupted system call",
+            Errno::EIO => "Input/output error",
+            Errno::EBADF => "Bad file descriptor",
+            Errno::ENOMEM => "Cannot allocate memory",
+            Errno::EACCES => "Permission denied",
+            Errno::EEXIST => "File exists",
+            Errno::ENOTDIR => "Not a directory",
+            Errno::EISDIR => "Is a directory",
+            Errno::EINVAL => "Invalid argument",
+            Errno::EFBIG => "File too large",
+            Errno::ENOSPC => "No space left on device",
+            Errno::EROFS => "Read-only file system",
+            _ => "Unknown error",
+        }
+    }
+}
+
+/* Ok with the value a syscall returned, its error when negative */
+pub fn check(ret: isize) -> Result<usize, Errno> {
+    if ret < 0 {
+        Err(Errno(ret.unsigned_abs() as u16))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+/* Runs `call` again for as long as a signal interrupts it */
+pub fn retry(mut call: impl FnMut() -> isize) -> Result<usize, Errno> {
+    loop {
+        match check(call()) {
+            Err(Errno::EINTR) => continue,
+            result => return result,
+        }
+    }
+}
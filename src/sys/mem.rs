@@ -3,10 +3,10 @@ pub fn memset(dst: *mut u8, val: i32, count: usize) -> *mut u8 {
     let mut i = 0;
 
     while i < count {
-        /// Safety: writing via raw pointer arithmetic.
-        ///
-        /// `dst` must be valid for writes of `count` bytes. The caller is
-        /// responsible for ensuring the pointer points to a writable region.
+        // Safety: writing via raw pointer arithmetic.
+        //
+        // `dst` must be valid for writes of `count` bytes. The caller is
+        // responsible for ensuring the pointer points to a writable region.
         // SAFETY: we write at `dst.add(i)` which must be valid for the current index.
         unsafe {
             *dst.add(i) = val as u8;
@@ -21,9 +21,9 @@ pub fn memset(dst: *mut u8, val: i32, count: usize) -> *mut u8 {
 pub fn memcmp(a: *const u8, b: *const u8, count: usize) -> i32 {
     let mut i = 0;
     while i < count {
-        /// Safety: dereferencing raw pointers for comparison.
-        ///
-        /// Both `a` and `b` must be valid for reads of `count` bytes and non-null.
+        // Safety: dereferencing raw pointers for comparison.
+        //
+        // Both `a` and `b` must be valid for reads of `count` bytes and non-null.
         // SAFETY: `a.add(i)` and `b.add(i)` must be valid for the current index.
         let av = unsafe { *a.add(i) };
         let bv = unsafe { *b.add(i) };
@@ -34,3 +34,64 @@ pub fn memcmp(a: *const u8, b: *const u8, count: usize) -> i32 {
     }
     0
 }
+
+/* LLVM lowers slice equality to `bcmp`, which only needs zero / non zero */
+#[unsafe(no_mangle)]
+pub fn bcmp(a: *const u8, b: *const u8, count: usize) -> i32 {
+    memcmp(a, b, count)
+}
+
+#[unsafe(no_mangle)]
+pub fn memcpy(dst: *mut u8, src: *const u8, count: usize) -> *mut u8 {
+    let mut i = 0;
+
+    while i < count {
+        // Safety: copying via raw pointer arithmetic.
+        //
+        // `src` must be valid for reads and `dst` for writes of `count` bytes,
+        // the two regions must not overlap.
+        // SAFETY: `src.add(i)` and `dst.add(i)` must be valid for the current index.
+        unsafe {
+            *dst.add(i) = *src.add(i);
+        }
+        i += 1;
+    }
+
+    dst
+}
+
+#[unsafe(no_mangle)]
+pub fn memmove(dst: *mut u8, src: *const u8, count: usize) -> *mut u8 {
+    if (dst as usize) <= (src as usize) {
+        return memcpy(dst, src, count);
+    }
+
+    /* Destination after source: copy backwards so overlapping bytes are read first */
+    let mut i = count;
+    while i > 0 {
+        i -= 1;
+        // Safety: copying via raw pointer arithmetic, backwards.
+        //
+        // `src` must be valid for reads and `dst` for writes of `count` bytes.
+        // SAFETY: `src.add(i)` and `dst.add(i)` must be valid for the current index.
+        unsafe {
+            *dst.add(i) = *src.add(i);
+        }
+    }
+
+    dst
+}
+
+/* LLVM turns loops looking for a NUL, like the one of `c_str`, into `strlen` */
+#[unsafe(no_mangle)]
+pub fn strlen(s: *const u8) -> usize {
+    let mut len = 0;
+    // Safety: reading bytes up to the NUL.
+    //
+    // `s` must point at a NUL terminated string.
+    // SAFETY: every byte before the NUL, and the NUL itself, is readable.
+    while unsafe { *s.add(len) } != 0 {
+        len += 1;
+    }
+    len
+}
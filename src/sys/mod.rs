@@ -1,6 +1,14 @@
 pub mod consts;
 
+mod args;
+mod errno;
 mod mem;
+mod stat;
+mod termios;
+pub use args::*;
+pub use errno::*;
+pub use stat::*;
+pub use termios::*;
 
 /* __________ aarch64 __________ */
 #[cfg(target_arch = "aarch64")]
@@ -0,0 +1,31 @@
+use crate::sys::consts::{S_IFBLK, S_IFMT, SEEK_END};
+use crate::sys::{Errno, fstat_raw, lseek};
+
+/* The parts of `struct stat` the driver looks at */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub mode: u32,
+    pub size: u64,
+}
+
+impl FileStat {
+    pub fn is_block_device(&self) -> bool {
+        self.mode & S_IFMT == S_IFBLK
+    }
+}
+
+pub fn fstat(fd: usize) -> Result<FileStat, Errno> {
+    let (mode, size) = fstat_raw(fd)?;
+    Ok(FileStat { mode, size })
+}
+
+/* Size of the file or block device behind `fd`. A block device reports
+ * a size of 0, its end is found by seeking. All I/O is positioned so the
+ * file offset left there doesn't matter */
+pub fn file_size(fd: usize) -> Result<u64, Errno> {
+    let stat = fstat(fd)?;
+    if !stat.is_block_device() {
+        return Ok(stat.size);
+    }
+    lseek(fd, 0, SEEK_END).map(|end| end as u64)
+}
@@ -0,0 +1,55 @@
+use crate::sys::consts::{
+    BRKINT, ECHO, ICANON, ICRNL, IEXTEN, INPCK, ISIG, ISTRIP, IXON, TCGETS, TCSETS, VMIN, VTIME,
+};
+use crate::sys::{Errno, ioctl};
+
+/* The kernel's `struct termios`, not the larger one of the C library */
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub iflag: u32,
+    pub oflag: u32,
+    pub cflag: u32,
+    pub lflag: u32,
+    pub line: u8,
+    pub cc: [u8; 19],
+}
+
+impl Termios {
+    /* Bytes as they are typed: no echo, no line buffering and no signals
+     * from Ctrl-C, output processing stays so "\n" still starts a line */
+    pub fn raw(&self) -> Termios {
+        let mut raw = *self;
+        raw.iflag &= !(BRKINT | ICRNL | INPCK | ISTRIP | IXON);
+        raw.lflag &= !(ECHO | ICANON | IEXTEN | ISIG);
+        raw.cc[VMIN] = 1;
+        raw.cc[VTIME] = 0;
+        raw
+    }
+}
+
+/* Settings of the terminal behind `fd`, ENOTTY when it isn't one */
+pub fn tcgetattr(fd: usize) -> Result<Termios, Errno> {
+    let mut termios = Termios {
+        iflag: 0,
+        oflag: 0,
+        cflag: 0,
+        lflag: 0,
+        line: 0,
+        cc: [0; 19],
+    };
+    // Safety: TCGETS fills a `struct termios`.
+    //
+    // `termios` is one, laid out like the kernel's.
+    // SAFETY: see above, the pointer is valid for the whole call.
+    unsafe { ioctl(fd, TCGETS, &mut termios as *mut Termios as usize) }?;
+    Ok(termios)
+}
+
+pub fn tcsetattr(fd: usize, termios: &Termios) -> Result<(), Errno> {
+    // Safety: TCSETS reads a `struct termios`.
+    //
+    // `termios` is one, laid out like the kernel's.
+    // SAFETY: see above, the pointer is valid for the whole call.
+    unsafe { ioctl(fd, TCSETS, termios as *const Termios as usize) }.map(|_| ())
+}
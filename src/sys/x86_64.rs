@@ -1,4 +1,8 @@
-use crate::sys::consts::{AT_FDCWD, RDONLY_0, SEEK_CUR, SEEK_END, SEEK_SET, STDOUT_FILENO};
+use crate::sys::consts::{
+    APPEND_1024, AT_FDCWD, CLOCK_REALTIME, CREAT_64, FILE_MODE_644, MAP_SHARED, MS_SYNC, PROT_READ,
+    PROT_WRITE, RDONLY_0, RDWR_2, STDOUT_FILENO, WRONLY_1,
+};
+use crate::sys::{Errno, check, retry};
 
 pub mod syscalls {
     pub const EXIT: usize = 60;
@@ -7,14 +11,73 @@ pub mod syscalls {
     pub const CLOSE: usize = 3;
     pub const WRITE: usize = 1;
     pub const LSEEK: usize = 8;
+    pub const FTRUNCATE: usize = 77;
+    pub const CLOCK_GETTIME: usize = 228;
+    pub const PREAD64: usize = 17;
+    pub const PWRITE64: usize = 18;
+    pub const FSTAT: usize = 5;
+    pub const FSYNC: usize = 74;
+    pub const FDATASYNC: usize = 75;
+    pub const MMAP: usize = 9;
+    pub const MUNMAP: usize = 11;
+    pub const IOCTL: usize = 16;
+    pub const MSYNC: usize = 26;
 }
 
 /* __________ Syscalls __________ */
+#[inline(always)]
+pub fn syscall_6(n: usize, a0: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize) -> isize {
+    let ret: isize;
+    // Safety: inline assembly invoking `syscall` with six arguments, the
+    // fourth one goes in `r10` as `rcx` is clobbered by the instruction.
+    // SAFETY: asm block follows the syscall ABI and writes `ret` via `lateout("rax")`.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") n,
+            in("rdi") a0,
+            in("rsi") a1,
+            in("rdx") a2,
+            in("r10") a3,
+            in("r8") a4,
+            in("r9") a5,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack)
+        );
+    }
+    ret
+}
+
+#[inline(always)]
+pub fn syscall_4(n: usize, a0: usize, a1: usize, a2: usize, a3: usize) -> isize {
+    let ret: isize;
+    // Safety: inline assembly invoking `syscall` with four arguments, the
+    // fourth one goes in `r10` as `rcx` is clobbered by the instruction.
+    // SAFETY: asm block follows the syscall ABI and writes `ret` via `lateout("rax")`.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") n,
+            in("rdi") a0,
+            in("rsi") a1,
+            in("rdx") a2,
+            in("r10") a3,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack)
+        );
+    }
+    ret
+}
+
 #[inline(always)]
 pub fn syscall_3(n: usize, a0: usize, a1: usize, a2: usize) -> isize {
     let ret: isize;
-    /// Safety: inline assembly invoking the `syscall` instruction using the
-    /// x86_64 syscall ABI. Registers are constrained via operands.
+    // Safety: inline assembly invoking the `syscall` instruction using the
+    // x86_64 syscall ABI. Registers are constrained via operands.
     // SAFETY: asm block follows the syscall ABI and writes `ret` via `lateout("rax")`.
     unsafe {
         core::arch::asm!(
@@ -24,6 +87,28 @@ pub fn syscall_3(n: usize, a0: usize, a1: usize, a2: usize) -> isize {
             in("rsi") a1,
             in("rdx") a2,
             lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack)
+        );
+    }
+    ret
+}
+
+#[inline(always)]
+pub fn syscall_2(n: usize, a0: usize, a1: usize) -> isize {
+    let ret: isize;
+    // Safety: inline assembly invoking `syscall` with two arguments.
+    // SAFETY: asm block follows the syscall ABI and returns result in `ret`.
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            in("rax") n,
+            in("rdi") a0,
+            in("rsi") a1,
+            lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
             options(nostack)
         );
     }
@@ -33,7 +118,7 @@ pub fn syscall_3(n: usize, a0: usize, a1: usize, a2: usize) -> isize {
 #[inline(always)]
 pub fn syscall_1(n: usize, a0: usize) -> isize {
     let ret: isize;
-    /// Safety: inline assembly invoking `syscall` with one argument.
+    // Safety: inline assembly invoking `syscall` with one argument.
     // SAFETY: asm block follows the syscall ABI and returns result in `ret`.
     unsafe {
         core::arch::asm!(
@@ -41,6 +126,8 @@ pub fn syscall_1(n: usize, a0: usize) -> isize {
             in("rax") n,
             in("rdi") a0,
             lateout("rax") ret,
+            lateout("rcx") _,
+            lateout("r11") _,
             options(nostack)
         );
     }
@@ -52,53 +139,138 @@ pub fn exit(code: usize) {
     syscall_1(syscalls::EXIT, code);
 }
 
-pub fn lseek(fd: usize, offset: usize, whence: usize) -> isize {
-    syscall_3(syscalls::LSEEK, fd, offset, whence)
+pub fn lseek(fd: usize, offset: usize, whence: usize) -> Result<usize, Errno> {
+    check(syscall_3(syscalls::LSEEK, fd, offset, whence))
 }
 
-/// Safety: performs file operations using raw pointer `buffer` and seeks the
-/// underlying file descriptor. The caller must ensure `buffer` is valid for
-/// `len` bytes and that `fd` refers to an open file. The function will restore
-/// the previous file offset on return.
+/// Safety: reads up to `len` bytes at `offset` without moving the file
+/// offset. The caller must ensure `buffer` is valid for writes of `len`
+/// bytes and that `fd` refers to an open file.
 // SAFETY: Caller must guarantee the validity of `buffer` and `fd`.
-pub unsafe fn read_at(fd: usize, buffer: *mut u8, len: usize, offset: usize) -> isize {
-    let cur = lseek(fd, 0, SEEK_CUR);
-    if cur < 0 {
-        return cur;
-    }
+pub unsafe fn pread(fd: usize, buffer: *mut u8, len: usize, offset: u64) -> Result<usize, Errno> {
+    retry(|| syscall_4(syscalls::PREAD64, fd, buffer as usize, len, offset as usize))
+}
+
+/// Safety: same contract as `pread` but for writes, `buffer` must be valid
+/// for reads of `len` bytes.
+// SAFETY: Caller must guarantee the validity of `buffer` and `fd`.
+pub unsafe fn pwrite(fd: usize, buffer: *const u8, len: usize, offset: u64) -> Result<usize, Errno> {
+    retry(|| syscall_4(syscalls::PWRITE64, fd, buffer as usize, len, offset as usize))
+}
 
-    if lseek(fd, offset, SEEK_SET) < 0 {
-        return -1;
+/* Words of `struct stat` holding st_mode (in its low half) and st_size:
+ * st_dev, st_ino, st_nlink, then st_mode come first */
+const STAT_MODE_WORD: usize = 3;
+const STAT_SIZE_WORD: usize = 6;
+
+/* st_mode and st_size of `fd` */
+pub fn fstat_raw(fd: usize) -> Result<(u32, u64), Errno> {
+    /* sizeof(struct stat) is 144 */
+    let mut raw = [0u64; 18];
+    check(syscall_2(syscalls::FSTAT, fd, raw.as_mut_ptr() as usize))?;
+    Ok((raw[STAT_MODE_WORD] as u32, raw[STAT_SIZE_WORD]))
+}
+
+pub fn ftruncate(fd: usize, len: u64) -> Result<(), Errno> {
+    retry(|| syscall_2(syscalls::FTRUNCATE, fd, len as usize)).map(|_| ())
+}
+
+/* Data and metadata of `fd` on stable storage */
+pub fn fsync(fd: usize) -> Result<(), Errno> {
+    retry(|| syscall_1(syscalls::FSYNC, fd)).map(|_| ())
+}
+
+/* Same without the metadata a later read doesn't need, like timestamps */
+pub fn fdatasync(fd: usize) -> Result<(), Errno> {
+    retry(|| syscall_1(syscalls::FDATASYNC, fd)).map(|_| ())
+}
+
+/* Map `len` bytes of `fd` from its start, shared with the file so
+ * writes reach it and other descriptors see them */
+pub fn mmap(fd: usize, len: usize, writable: bool) -> Result<*mut u8, Errno> {
+    let prot = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+    check(syscall_6(syscalls::MMAP, 0, len, prot, MAP_SHARED, fd, 0)).map(|addr| addr as *mut u8)
+}
+
+/// Safety: `addr` and `len` must be a mapping returned by `mmap`, no
+/// reference into it may outlive this call.
+// SAFETY: Caller must guarantee the mapping is no longer borrowed.
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    check(syscall_2(syscalls::MUNMAP, addr as usize, len)).map(|_| ())
+}
+
+/* Write the dirty pages of a mapping back to its file and wait for it */
+pub fn msync(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    retry(|| syscall_3(syscalls::MSYNC, addr as usize, len, MS_SYNC)).map(|_| ())
+}
+
+/* Seconds since the epoch, 0 if the clock can't be read */
+pub fn now_seconds() -> u64 {
+    let mut ts = [0u64; 2];
+    let r = syscall_2(syscalls::CLOCK_GETTIME, CLOCK_REALTIME, ts.as_mut_ptr() as usize);
+    if r < 0 {
+        return 0;
     }
+    ts[0]
+}
 
-    let r = read(fd, buffer, len);
+pub fn open(path: *const u8) -> Result<usize, Errno> {
+    retry(|| {
+        syscall_3(
+            syscalls::OPEN_AT,
+            AT_FDCWD as usize,
+            path as usize,
+            RDONLY_0,
+        )
+    })
+}
 
-    lseek(fd, cur as usize, SEEK_SET);
+pub fn open_rw(path: *const u8, create: bool) -> Result<usize, Errno> {
+    let flags = if create { RDWR_2 | CREAT_64 } else { RDWR_2 };
+    retry(|| {
+        syscall_4(
+            syscalls::OPEN_AT,
+            AT_FDCWD as usize,
+            path as usize,
+            flags,
+            FILE_MODE_644,
+        )
+    })
+}
 
-    r
+/* Open for appending, created when missing */
+pub fn open_append(path: *const u8) -> Result<usize, Errno> {
+    retry(|| {
+        syscall_4(
+            syscalls::OPEN_AT,
+            AT_FDCWD as usize,
+            path as usize,
+            WRONLY_1 | CREAT_64 | APPEND_1024,
+            FILE_MODE_644,
+        )
+    })
 }
 
-pub fn open(path: *const u8) -> isize {
-    syscall_3(
-        syscalls::OPEN_AT,
-        AT_FDCWD as usize,
-        path as usize,
-        RDONLY_0,
-    )
+/// Safety: `arg` is handed to the driver of `fd` as is, when `request`
+/// takes a pointer it must be valid for what the request reads or writes.
+// SAFETY: Caller must guarantee `arg` matches `request`.
+pub unsafe fn ioctl(fd: usize, request: usize, arg: usize) -> Result<usize, Errno> {
+    retry(|| syscall_3(syscalls::IOCTL, fd, request, arg))
 }
 
-pub fn read(fd: usize, buffer: *mut u8, len: usize) -> isize {
-    syscall_3(syscalls::READ, fd, buffer as usize, len)
+pub fn read(fd: usize, buffer: *mut u8, len: usize) -> Result<usize, Errno> {
+    retry(|| syscall_3(syscalls::READ, fd, buffer as usize, len))
 }
 
 pub fn close(fd: usize) {
     syscall_1(syscalls::CLOSE, fd);
 }
 
-pub fn write(fd: usize, val: *const u8, len: usize) {
-    syscall_3(syscalls::WRITE, fd, val as usize, len);
+pub fn write(fd: usize, val: *const u8, len: usize) -> Result<usize, Errno> {
+    retry(|| syscall_3(syscalls::WRITE, fd, val as usize, len))
 }
 
+/* Nowhere to report a failed write to stdout */
 pub fn print_bytes(val: &[u8]) {
-    write(STDOUT_FILENO, val.as_ptr(), val.len());
+    let _ = write(STDOUT_FILENO, val.as_ptr(), val.len());
 }
@@ -0,0 +1,542 @@
+/* `tree`, `ls -R` and `find`: the entries below a directory, depth
+ * first. A directory is drawn a run of files at a time: the scan stops at
+ * each subdirectory and picks up after it once the subdirectory is done,
+ * so no scan holds the device while another one runs. Drawing scans each
+ * directory once more up front, for its last shown entry and its totals */
+
+use crate::cli::{print, print_branch, print_no_ln, print_number};
+use crate::dir_entry::ATTR_HIDDEN;
+use crate::helpers::parse_u64;
+use crate::sys::print_bytes;
+use crate::volume::VolumeError;
+use crate::walk::{MAX_DEPTH, Path};
+use core::ops::ControlFlow;
+
+/* Longest name kept while the scan that found it is left, UTF-8 */
+const NAME_MAX: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TreeOptions {
+    /* Levels below the starting directory, all when None */
+    pub max_depth: Option<usize>,
+    pub dirs_only: bool,
+    /* Entries with the hidden attribute or a name starting with `.` */
+    pub hidden: bool,
+    /* Files and bytes right in each directory, after its name */
+    pub totals: bool,
+}
+
+/* An entry as a file system hands it to the walk */
+#[derive(Clone, Copy)]
+pub struct TreeEntry<'a, H> {
+    pub name: &'a [u8],
+    /* Lower case 8.3 name on FAT, empty on exFAT */
+    pub short: &'a [u8],
+    /* Where its entries or its bytes are */
+    pub node: H,
+    pub is_dir: bool,
+    /* Read-only, hidden, system, directory and archive bits, the same
+     * on FAT and exFAT */
+    pub attributes: u8,
+    /* Packed FAT date in the high half, time in the low one */
+    pub modified: u32,
+    pub first_cluster: u32,
+    pub size: u64,
+}
+
+impl<H> TreeEntry<'_, H> {
+    pub fn is_hidden(&self) -> bool {
+        self.attributes & ATTR_HIDDEN != 0 || self.name.first() == Some(&b'.')
+    }
+}
+
+pub trait TreeSource {
+    /* What an entry is found by */
+    type Dir: Copy + PartialEq;
+
+    /* Every entry of `dir` in order, dot entries and labels left out,
+     * until `cb` breaks */
+    fn scan(
+        &mut self,
+        dir: Self::Dir,
+        cb: &mut dyn FnMut(&TreeEntry<Self::Dir>) -> ControlFlow<()>,
+    ) -> Result<(), VolumeError>;
+
+    /* The bytes of file `entry` in order, a chunk at a time */
+    fn read(&mut self, entry: &TreeEntry<Self::Dir>, out: &mut dyn FnMut(&[u8])) -> Result<(), VolumeError>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub dirs: u64,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/* An entry met by the walk, a line of the drawing */
+pub struct TreeLine<'a, H> {
+    pub entry: &'a TreeEntry<'a, H>,
+    /* From the starting directory, each name after a `/` */
+    pub path: &'a [u8],
+    /* Last shown entry of its directory, when drawing */
+    pub last: bool,
+    /* Whether each level above still has entries below this line */
+    pub branches: &'a [bool],
+    /* Files and bytes right in the directory, with `totals` */
+    pub totals: Option<Totals>,
+}
+
+/* Takes each entry with the source at hand */
+pub type SourceVisit<'s, S> = dyn FnMut(&mut S, &TreeLine<<S as TreeSource>::Dir>) -> Result<(), VolumeError> + 's;
+
+/* Where the walk hands the entries: files within the scan of their
+ * directory, or each entry once the scan let go of the source */
+enum Sink<'s, S: TreeSource> {
+    Scan(&'s mut dyn FnMut(&TreeLine<S::Dir>)),
+    Source(&'s mut SourceVisit<'s, S>),
+}
+
+#[derive(Clone, Copy)]
+struct Frame<H> {
+    dir: H,
+    /* Entries of the directory scanned so far */
+    done: usize,
+    /* Entries up to and including the last shown one */
+    end: usize,
+    path_len: usize,
+}
+
+/* An entry kept once its scan is left, names aside */
+struct Found<H> {
+    entry: TreeEntry<'static, H>,
+    name_len: usize,
+    short_len: usize,
+    last: bool,
+}
+
+impl TreeOptions {
+    fn shows<H>(&self, entry: &TreeEntry<H>) -> bool {
+        (self.hidden || !entry.is_hidden()) && (!self.dirs_only || entry.is_dir)
+    }
+}
+
+/* Where the shown entries of `dir` end, and the files right in it */
+fn survey<S: TreeSource>(src: &mut S, dir: S::Dir, opts: &TreeOptions) -> Result<(usize, Totals), VolumeError> {
+    let mut count = 0;
+    let mut end = 0;
+    let mut totals = Totals::default();
+    src.scan(dir, &mut |entry| {
+        count += 1;
+        if opts.shows(entry) {
+            end = count;
+        }
+        if !entry.is_dir && (opts.hidden || !entry.is_hidden()) {
+            totals.files += 1;
+            totals.bytes += entry.size;
+        }
+        ControlFlow::Continue(())
+    })?;
+    Ok((end, totals))
+}
+
+/* Hand the entries below directory `root` to `sink`, depth first.
+ * Without `draw` nothing is scanned ahead and no entry is the last. Returns
+ * what was met, hidden or filtered entries left out */
+fn walk<S: TreeSource>(
+    src: &mut S,
+    root: S::Dir,
+    opts: &TreeOptions,
+    draw: bool,
+    mut sink: Sink<S>,
+) -> Result<Totals, VolumeError> {
+    let mut met = Totals::default();
+    if opts.max_depth == Some(0) {
+        return Ok(met);
+    }
+    let end = if draw { survey(src, root, opts)?.0 } else { usize::MAX };
+    let mut stack = [Frame {
+        dir: root,
+        done: 0,
+        end,
+        path_len: 0,
+    }; MAX_DEPTH];
+    let mut branches = [false; MAX_DEPTH];
+    let mut path = Path::new();
+    let mut depth = 1;
+    let mut name = [0u8; NAME_MAX];
+    let mut short = [0u8; NAME_MAX];
+
+    while depth > 0 {
+        let top = depth - 1;
+        let frame = stack[top];
+        if frame.done >= frame.end {
+            depth -= 1;
+            continue;
+        }
+
+        /* Files up to the next subdirectory, or the next entry when the
+         * sink needs the source */
+        let mut index = 0;
+        let mut found: Option<Found<S::Dir>> = None;
+        src.scan(frame.dir, &mut |entry| {
+            index += 1;
+            if index <= frame.done || !opts.shows(entry) {
+                return ControlFlow::Continue(());
+            }
+            let last = index == frame.end;
+            let on_line = match &mut sink {
+                Sink::Scan(on_line) if !entry.is_dir => on_line,
+                _ => {
+                    let name_len = entry.name.len().min(NAME_MAX);
+                    name[..name_len].copy_from_slice(&entry.name[..name_len]);
+                    let short_len = entry.short.len().min(NAME_MAX);
+                    short[..short_len].copy_from_slice(&entry.short[..short_len]);
+                    found = Some(Found {
+                        entry: TreeEntry {
+                            name: &[],
+                            short: &[],
+                            ..*entry
+                        },
+                        name_len,
+                        short_len,
+                        last,
+                    });
+                    return ControlFlow::Break(());
+                }
+            };
+            met.files += 1;
+            met.bytes += entry.size;
+            path.len = frame.path_len;
+            path.push(entry.name);
+            on_line(&TreeLine {
+                entry,
+                path: path.as_bytes(),
+                last,
+                branches: &branches[..top],
+                totals: None,
+            });
+            if last { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        })?;
+        stack[top].done = index;
+
+        let Some(found) = found else {
+            /* Scanned to its end */
+            depth -= 1;
+            continue;
+        };
+        let entry = TreeEntry {
+            name: &name[..found.name_len],
+            short: &short[..found.short_len],
+            ..found.entry
+        };
+        /* A directory met again further down is shown, not entered */
+        let enter = entry.is_dir
+            && opts.max_depth.is_none_or(|max| depth < max)
+            && depth < MAX_DEPTH
+            && !stack[..depth].iter().any(|frame| frame.dir == entry.node);
+        let (end, totals) = if entry.is_dir && ((draw && enter) || opts.totals) {
+            survey(src, entry.node, opts)?
+        } else {
+            (usize::MAX, Totals::default())
+        };
+        if entry.is_dir {
+            met.dirs += 1;
+        } else {
+            met.files += 1;
+            met.bytes += entry.size;
+        }
+        path.len = frame.path_len;
+        path.push(entry.name);
+        let line = TreeLine {
+            entry: &entry,
+            path: path.as_bytes(),
+            last: found.last,
+            branches: &branches[..top],
+            totals: (entry.is_dir && opts.totals).then_some(totals),
+        };
+        match &mut sink {
+            Sink::Scan(on_line) => on_line(&line),
+            Sink::Source(on_line) => on_line(src, &line)?,
+        }
+        if enter {
+            branches[top] = !found.last;
+            stack[depth] = Frame {
+                dir: entry.node,
+                done: 0,
+                end: if draw { end } else { usize::MAX },
+                path_len: path.len,
+            };
+            depth += 1;
+        }
+    }
+    Ok(met)
+}
+
+/* The lines of the drawing below `root`, each knowing whether it is the
+ * last of its directory */
+pub fn draw_tree<S: TreeSource>(
+    src: &mut S,
+    root: S::Dir,
+    opts: &TreeOptions,
+    on_line: &mut dyn FnMut(&TreeLine<S::Dir>),
+) -> Result<Totals, VolumeError> {
+    walk(src, root, opts, true, Sink::Scan(on_line))
+}
+
+/* Every entry below `root` with its path, for searches */
+pub fn visit_tree<S: TreeSource>(
+    src: &mut S,
+    root: S::Dir,
+    opts: &TreeOptions,
+    on_line: &mut dyn FnMut(&TreeLine<S::Dir>),
+) -> Result<Totals, VolumeError> {
+    walk(src, root, opts, false, Sink::Scan(on_line))
+}
+
+/* Same with the source at hand for each entry, to read files on the way.
+ * A directory is scanned again for each of its entries */
+pub fn visit_tree_with<S: TreeSource>(
+    src: &mut S,
+    root: S::Dir,
+    opts: &TreeOptions,
+    on_line: &mut SourceVisit<S>,
+) -> Result<Totals, VolumeError> {
+    walk(src, root, opts, false, Sink::Source(on_line))
+}
+
+/* `tree` options, shared by `ls -R` which also takes `-R`. Returns them
+ * with the path, None on a bad argument */
+pub fn parse_tree_args<'a>(args: &[&'a [u8]]) -> Option<(TreeOptions, &'a [u8])> {
+    let mut opts = TreeOptions::default();
+    let mut path: Option<&[u8]> = None;
+    let mut words = args.iter();
+    while let Some(&word) = words.next() {
+        match word {
+            b"-R" => {}
+            b"-a" => opts.hidden = true,
+            b"-d" => opts.dirs_only = true,
+            b"-t" => opts.totals = true,
+            b"-L" => {
+                let levels = parse_u64(words.next()?)?;
+                opts.max_depth = Some(usize::try_from(levels).ok()?);
+            }
+            _ if path.is_none() => path = Some(word),
+            _ => return None,
+        }
+    }
+    Some((opts, path.unwrap_or_default()))
+}
+
+fn print_totals(totals: &Totals) {
+    print_number(totals.files);
+    print_no_ln(if totals.files == 1 {
+        " file, "
+    } else {
+        " files, "
+    });
+    print_number(totals.bytes);
+    print_no_ln(if totals.bytes == 1 { " byte" } else { " bytes" });
+}
+
+pub fn print_tree_line<H>(line: &TreeLine<H>) {
+    print_branch(line.entry.is_dir, line.last, line.branches);
+    print_bytes(line.entry.name);
+    if let Some(totals) = &line.totals {
+        print_no_ln("  (");
+        print_totals(totals);
+        print_no_ln(")");
+    }
+    print("");
+}
+
+/* Draw the tree below `root`, shown as `path` */
+pub fn print_tree<S: TreeSource>(
+    src: &mut S,
+    root: S::Dir,
+    path: &[u8],
+    opts: &TreeOptions,
+) -> Result<(), VolumeError> {
+    print_bytes(path);
+    print("");
+    let drawn = draw_tree(src, root, opts, &mut print_tree_line)?;
+    print("");
+    print_number(drawn.dirs);
+    print_no_ln(if drawn.dirs == 1 {
+        " directory"
+    } else {
+        " directories"
+    });
+    if !opts.dirs_only {
+        print_no_ln(", ");
+        print_totals(&drawn);
+    }
+    print("");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    /* Directory `i` holds `NODES[i]`: name, directory index or None, size */
+    const NODES: &[&[(&str, Option<usize>, u64)]] = &[
+        &[
+            ("a", Some(1), 0),
+            (".hidden", None, 5),
+            ("b.txt", None, 3),
+            ("c", Some(2), 0),
+        ],
+        &[("d", Some(3), 0), ("e.txt", None, 7)],
+        &[("f.txt", None, 1), (".g", Some(3), 0)],
+        &[("h.txt", None, 2)],
+    ];
+
+    struct Mock;
+
+    impl TreeSource for Mock {
+        type Dir = usize;
+
+        fn scan(
+            &mut self,
+            dir: usize,
+            cb: &mut dyn FnMut(&TreeEntry<usize>) -> ControlFlow<()>,
+        ) -> Result<(), VolumeError> {
+            for &(name, sub, size) in NODES[dir] {
+                let entry = TreeEntry {
+                    name: name.as_bytes(),
+                    short: &[],
+                    node: sub.unwrap_or(dir),
+                    is_dir: sub.is_some(),
+                    attributes: 0,
+                    modified: 0,
+                    first_cluster: 0,
+                    size,
+                };
+                if cb(&entry).is_break() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+
+        /* A file holds its name, a byte at a time */
+        fn read(&mut self, entry: &TreeEntry<usize>, out: &mut dyn FnMut(&[u8])) -> Result<(), VolumeError> {
+            for byte in entry.name.chunks(1) {
+                out(byte);
+            }
+            Ok(())
+        }
+    }
+
+    fn draw(opts: TreeOptions) -> (Vec<String>, Totals) {
+        let mut lines = Vec::new();
+        let drawn = draw_tree(&mut Mock, 0, &opts, &mut |line| {
+            let mut text = String::new();
+            for &more in line.branches {
+                text.push_str(if more { "| " } else { "  " });
+            }
+            text.push_str(if line.last { "`-" } else { "|-" });
+            text.push_str(core::str::from_utf8(line.entry.name).unwrap());
+            if let Some(totals) = line.totals {
+                text.push_str(&std::format!(" {}/{}", totals.files, totals.bytes));
+            }
+            lines.push(text);
+        })
+        .unwrap();
+        (lines, drawn)
+    }
+
+    #[test]
+    fn draws_connectors_for_the_last_shown_entries() {
+        let (lines, drawn) = draw(TreeOptions::default());
+        assert_eq!(
+            lines,
+            [
+                "|-a",
+                "| |-d",
+                "| | `-h.txt",
+                "| `-e.txt",
+                "|-b.txt",
+                "`-c",
+                "  `-f.txt"
+            ]
+        );
+        assert_eq!(
+            drawn,
+            Totals {
+                dirs: 3,
+                files: 4,
+                bytes: 13
+            }
+        );
+
+        /* `.g` is now the last entry of `c` */
+        let hidden = TreeOptions {
+            hidden: true,
+            ..TreeOptions::default()
+        };
+        let (lines, _) = draw(hidden);
+        assert_eq!(
+            &lines[4..],
+            [
+                "|-.hidden",
+                "|-b.txt",
+                "`-c",
+                "  |-f.txt",
+                "  `-.g",
+                "    `-h.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_by_depth_and_type() {
+        let opts = TreeOptions {
+            max_depth: Some(1),
+            dirs_only: true,
+            totals: true,
+            ..TreeOptions::default()
+        };
+        let (lines, drawn) = draw(opts);
+        assert_eq!(lines, ["|-a 1/7", "`-c 1/1"]);
+        assert_eq!(drawn.files, 0);
+
+        assert_eq!(
+            parse_tree_args(&[b"-R", b"-L", b"2", b"/x", b"-a"]).map(|(o, p)| (
+                o.max_depth,
+                o.hidden,
+                p
+            )),
+            Some((Some(2), true, &b"/x"[..]))
+        );
+        assert!(parse_tree_args(&[b"-L"]).is_none());
+        assert!(parse_tree_args(&[b"x", b"y"]).is_none());
+    }
+
+    #[test]
+    fn visits_with_the_source_at_hand() {
+        let opts = TreeOptions {
+            hidden: true,
+            ..TreeOptions::default()
+        };
+        let mut scanned = Vec::new();
+        visit_tree(&mut Mock, 0, &opts, &mut |line| scanned.push(line.path.to_vec())).unwrap();
+
+        let mut visited = Vec::new();
+        let mut read = Vec::new();
+        let met = visit_tree_with(&mut Mock, 0, &opts, &mut |src, line| {
+            visited.push(line.path.to_vec());
+            if !line.entry.is_dir {
+                src.read(line.entry, &mut |bytes| read.extend_from_slice(bytes))?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(visited, scanned);
+        assert_eq!(visited[..3], [b"/a".to_vec(), b"/a/d".to_vec(), b"/a/d/h.txt".to_vec()]);
+        assert_eq!(read, b"h.txte.txt.hiddenb.txtf.txth.txt");
+        assert_eq!(met.files, 6);
+    }
+}
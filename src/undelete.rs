@@ -0,0 +1,644 @@
+use crate::allocator::ClusterAllocator;
+use crate::cli::{print, print_fat_date, print_no_ln, print_number};
+use crate::cache::{ImageDevice, ImageFile};
+use crate::check::with_scratch;
+use crate::device::{BlockDevice, DeviceError};
+use crate::dir_entry::{
+    ATTR_VOLUME_ID, DIR_ENTRY_SIZE, DirEntry, ENTRY_DELETED, ENTRY_END, LFN_LAST_ENTRY,
+    LFN_MAX_ENTRIES, LFN_MAX_UNITS, LFN_UNIT_OFFSETS, LFN_UNITS_PER_ENTRY, is_legal_short_name,
+    is_lfn_entry, lfn_checksum, lfn_to_utf8, pack_short_name, parse_dir_entry,
+};
+use crate::fat::build_short_name;
+use crate::helpers::u8_le_to_u16;
+use crate::sys::{print_bytes, read};
+use crate::volume::{FAT_EOC_MARK, MAX_CLUSTER_SIZE, Volume, VolumeError};
+use crate::walk::has_entry;
+
+/* A deleted short entry with what survives of it */
+#[derive(Clone)]
+pub struct DeletedEntry {
+    pub entry: DirEntry,
+    /* Device offset of the short entry */
+    pub offset: u64,
+    pub date: u16,
+    pub time: u16,
+    /* Deleted long name entries right before it and sharing a checksum,
+     * nearest first */
+    lfn_offsets: [u64; LFN_MAX_ENTRIES],
+    lfn_count: usize,
+    lfn_checksum: u8,
+    pub long_name: [u16; LFN_MAX_UNITS],
+    pub long_name_len: usize,
+    /* First character the long name checksum agrees with, if any */
+    pub guess: Option<u8>,
+    /* The first cluster and the ones the size needs after it are still free */
+    pub recoverable: bool,
+}
+
+impl DeletedEntry {
+    /* Clusters a contiguous chain needs, directories get their first one back */
+    pub fn clusters_needed(&self, cluster_size: usize) -> u32 {
+        if self.entry.first_cluster == 0 {
+            0
+        } else if self.entry.is_dir() {
+            1
+        } else {
+            core::cmp::max(
+                1,
+                (self.entry.file_size as usize).div_ceil(cluster_size) as u32,
+            )
+        }
+    }
+
+    pub fn long_name(&self) -> &[u16] {
+        &self.long_name[..self.long_name_len]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UndeleteError {
+    Volume(VolumeError),
+    /* The name with the given first character isn't a legal 8.3 name */
+    InvalidName,
+    /* The directory already has an entry by that name */
+    NameTaken,
+    /* Some of the clusters were reused since */
+    Overwritten,
+    /* The free cluster map doesn't fit the scratch buffer */
+    TooManyClusters,
+}
+
+impl From<VolumeError> for UndeleteError {
+    fn from(e: VolumeError) -> Self {
+        UndeleteError::Volume(e)
+    }
+}
+
+impl From<DeviceError> for UndeleteError {
+    fn from(e: DeviceError) -> Self {
+        UndeleteError::Volume(e.into())
+    }
+}
+
+/* Whether the `count` clusters from `first` are all free */
+fn chain_is_free<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    first: u32,
+    count: u32,
+) -> Result<bool, VolumeError> {
+    for i in 0..count {
+        let cluster = match first.checked_add(i) {
+            Some(c) if vol.is_valid_cluster(c) => c,
+            _ => return Ok(false),
+        };
+        if vol.fat_entry(cluster)? != 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn is_recoverable<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    e: &DeletedEntry,
+) -> Result<bool, VolumeError> {
+    if e.entry.first_cluster == 0 {
+        /* Nothing to re-link, only an empty file comes back whole */
+        return Ok(e.entry.is_dir() || e.entry.file_size == 0);
+    }
+    let count = e.clusters_needed(vol.cluster_size);
+    chain_is_free(vol, e.entry.first_cluster, count)
+}
+
+/* First character making `name` legal and matching `checksum`, the long
+ * name's own first character tried before the others */
+fn guess_first_char(name: &[u8; 11], checksum: u8, long_name: &[u16]) -> Option<u8> {
+    let mut candidate = *name;
+    let hint = long_name
+        .first()
+        .filter(|&&u| u < 0x80)
+        .map(|&u| (u as u8).to_ascii_uppercase());
+    for c in hint.into_iter().chain(0x20u8..0x7F) {
+        candidate[0] = c;
+        if is_legal_short_name(&candidate) && lfn_checksum(&candidate) == checksum {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/* Build the entry for the short entry at `raw` from the deleted long
+ * name entries `run` (on-disk order) found before it */
+fn deleted_entry(
+    raw: &[u8],
+    offset: u64,
+    run: &[[u8; DIR_ENTRY_SIZE]],
+    run_offsets: &[u64],
+) -> DeletedEntry {
+    let mut e = DeletedEntry {
+        entry: parse_dir_entry(raw),
+        offset,
+        date: u8_le_to_u16(&raw[24..26]),
+        time: u8_le_to_u16(&raw[22..24]),
+        lfn_offsets: [0u64; LFN_MAX_ENTRIES],
+        lfn_count: 0,
+        lfn_checksum: 0,
+        long_name: [0u16; LFN_MAX_UNITS],
+        long_name_len: 0,
+        guess: None,
+        recoverable: false,
+    };
+
+    if let Some(nearest) = run.last() {
+        e.lfn_checksum = nearest[13];
+        for (lfn, &lfn_offset) in run.iter().rev().zip(run_offsets.iter().rev()) {
+            if lfn[13] != e.lfn_checksum {
+                break;
+            }
+            let base = e.lfn_count * LFN_UNITS_PER_ENTRY;
+            for (i, &off) in LFN_UNIT_OFFSETS.iter().enumerate() {
+                e.long_name[base + i] = u8_le_to_u16(&lfn[off..off + 2]);
+            }
+            e.lfn_offsets[e.lfn_count] = lfn_offset;
+            e.lfn_count += 1;
+        }
+        let units = e.lfn_count * LFN_UNITS_PER_ENTRY;
+        e.long_name_len = e.long_name[..units]
+            .iter()
+            .position(|&u| u == 0x0000 || u == 0xFFFF)
+            .unwrap_or(units);
+        e.guess = guess_first_char(&e.entry.name, e.lfn_checksum, e.long_name());
+    }
+    e
+}
+
+/* Call `on_entry` for each deleted file or directory of directory `dir`,
+ * 0 for the fixed root of FAT12/16, until it returns true */
+pub fn scan_deleted<D, F>(vol: &mut Volume<D>, dir: u32, mut on_entry: F) -> Result<(), VolumeError>
+where
+    D: BlockDevice,
+    F: FnMut(&DeletedEntry) -> bool,
+{
+    let mut cluster_buf = [0u8; MAX_CLUSTER_SIZE];
+    let mut run = [[0u8; DIR_ENTRY_SIZE]; LFN_MAX_ENTRIES];
+    let mut run_offsets = [0u64; LFN_MAX_ENTRIES];
+    let mut run_len = 0usize;
+    let mut cluster = dir;
+
+    for _ in 0..vol.clusters_count {
+        if !vol.is_valid_cluster(cluster) && !vol.is_fixed_root(cluster) {
+            break;
+        }
+        vol.read_dir_block(cluster, &mut cluster_buf)?;
+        let base = vol.dir_block_offset(cluster);
+
+        for i in 0..vol.dir_block_size(cluster) / DIR_ENTRY_SIZE {
+            let raw = &cluster_buf[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE];
+            let offset = base + (i * DIR_ENTRY_SIZE) as u64;
+            if raw[0] == ENTRY_END {
+                return Ok(());
+            }
+            if raw[0] != ENTRY_DELETED {
+                run_len = 0;
+                continue;
+            }
+            if is_lfn_entry(raw) {
+                /* Keep the nearest ones, a long name has at most that many */
+                if run_len == LFN_MAX_ENTRIES {
+                    run.copy_within(1.., 0);
+                    run_offsets.copy_within(1.., 0);
+                    run_len -= 1;
+                }
+                run[run_len].copy_from_slice(raw);
+                run_offsets[run_len] = offset;
+                run_len += 1;
+                continue;
+            }
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                run_len = 0;
+                continue;
+            }
+
+            let mut e = deleted_entry(raw, offset, &run[..run_len], &run_offsets[..run_len]);
+            run_len = 0;
+            e.recoverable = is_recoverable(vol, &e)?;
+            if on_entry(&e) {
+                return Ok(());
+            }
+        }
+        cluster = vol.next_dir_block(cluster)?;
+    }
+    Ok(())
+}
+
+/* Bring `deleted` back in directory `dir` with `first_char` as the first
+ * character of its name, re-linking a contiguous chain of its size through
+ * `free`. Returns the restored short name */
+pub fn undelete<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    free: &mut ClusterAllocator,
+    dir: u32,
+    deleted: &DeletedEntry,
+    first_char: u8,
+) -> Result<[u8; 11], UndeleteError> {
+    let mut name = deleted.entry.name;
+    name[0] = first_char.to_ascii_uppercase();
+    if !first_char.is_ascii() || !is_legal_short_name(&name) {
+        return Err(UndeleteError::InvalidName);
+    }
+    if has_entry(vol, dir, &name)? {
+        return Err(UndeleteError::NameTaken);
+    }
+    /* The directory may have changed since it was scanned */
+    if !is_recoverable(vol, deleted)? {
+        return Err(UndeleteError::Overwritten);
+    }
+
+    let first = deleted.entry.first_cluster;
+    let count = deleted.clusters_needed(vol.cluster_size);
+    for i in 0..count {
+        let next = if i + 1 < count {
+            first + i + 1
+        } else {
+            FAT_EOC_MARK
+        };
+        free.claim(vol, first + i, next)?;
+    }
+    vol.dev.write_at(deleted.offset, &name[..1])?;
+
+    /* The long name only comes back when it belongs to this short name */
+    if deleted.lfn_count > 0 && deleted.lfn_checksum == lfn_checksum(&name) {
+        for i in 0..deleted.lfn_count {
+            let mut ordinal = (i + 1) as u8;
+            if i + 1 == deleted.lfn_count {
+                ordinal |= LFN_LAST_ENTRY;
+            }
+            vol.dev.write_at(deleted.lfn_offsets[i], &[ordinal])?;
+        }
+    }
+
+    free.sync_fs_info(vol)?;
+    Ok(name)
+}
+
+/* Short name for display, `?` standing for the lost first character */
+fn display_name(name: &[u8; 11], out: &mut [u8]) -> usize {
+    let mut shown = *name;
+    shown[0] = b'?';
+    let len = build_short_name(&shown[0..8], &shown[8..11], out);
+    for c in out[..len].iter_mut() {
+        *c = c.to_ascii_lowercase();
+    }
+    len
+}
+
+fn print_deleted(e: &DeletedEntry) {
+    let mut name = [0u8; 4 * LFN_MAX_UNITS];
+    let len = display_name(&e.entry.name, &mut name);
+    print_bytes(&name[..len]);
+    if e.entry.is_dir() {
+        print_no_ln("/");
+    }
+    if e.long_name_len > 0 {
+        let len = lfn_to_utf8(e.long_name(), &mut name);
+        print_no_ln(" (");
+        print_bytes(&name[..len]);
+        print_no_ln(")");
+    }
+    print_no_ln("  ");
+    print_number(e.entry.file_size as u64);
+    print_no_ln(" bytes, cluster ");
+    print_number(e.entry.first_cluster as u64);
+    print_no_ln(", ");
+    print_fat_date(e.date, e.time);
+    if e.recoverable {
+        print(", recoverable");
+    } else {
+        print(", overwritten");
+    }
+}
+
+fn mount<D: BlockDevice>(dev: D) -> Option<Volume<D>> {
+    match Volume::mount(dev) {
+        Ok(vol) => Some(vol),
+        Err(_) => {
+            print("Failed to mount the volume");
+            None
+        }
+    }
+}
+
+/* `ls --deleted`: deleted entries of directory `dir` */
+pub fn list_deleted_command(dev: &mut ImageDevice, dir: u32) {
+    let mut vol = match mount(dev) {
+        Some(vol) => vol,
+        None => return,
+    };
+    let mut found = 0u32;
+    let result = scan_deleted(&mut vol, dir, |e| {
+        print_deleted(e);
+        found += 1;
+        false
+    });
+    if result.is_err() {
+        print("Failed to read the directory");
+    } else if found == 0 {
+        print("No deleted entries");
+    }
+}
+
+/* Whether `e` is the entry typed as `name`: its short name ignoring the
+ * first character, or its long name */
+fn matches(e: &DeletedEntry, name: &[u8]) -> bool {
+    if let Some(packed) = pack_short_name(name)
+        && packed[1..] == e.entry.name[1..]
+    {
+        return true;
+    }
+    let mut long = [0u8; 4 * LFN_MAX_UNITS];
+    let len = lfn_to_utf8(e.long_name(), &mut long);
+    len > 0 && long[..len].eq_ignore_ascii_case(name)
+}
+
+/* Ask for the lost first character, `guess` when the answer is empty.
+ * Short names are upper case, so is the answer */
+fn ask_first_char(e: &DeletedEntry) -> Option<u8> {
+    let mut name = [0u8; 16];
+    let len = display_name(&e.entry.name, &mut name);
+    print_no_ln("First character for ");
+    print_bytes(&name[..len]);
+    if let Some(c) = e.guess {
+        print_no_ln(" [");
+        print_bytes(&[c]);
+        print_no_ln("]");
+    }
+    print_no_ln(": ");
+
+    let mut answer = [0u8; 16];
+    let n = read(0, answer.as_mut_ptr(), answer.len()).unwrap_or(0);
+    if n == 0 || answer[0] == b'\n' || answer[0] == b'\r' {
+        e.guess
+    } else {
+        Some(answer[0].to_ascii_uppercase())
+    }
+}
+
+/* `undelete` on the mounted volume with the shell's scratch buffer as
+ * free cluster map, flushed once done */
+fn restore(
+    vol: &mut Volume<&mut ImageDevice>,
+    dir: u32,
+    deleted: &DeletedEntry,
+    first_char: u8,
+) -> Result<[u8; 11], UndeleteError> {
+    let clusters_count = vol.clusters_count;
+    let restored = with_scratch(|scratch| {
+        let mut free = ClusterAllocator::new(scratch, clusters_count)
+            .ok_or(UndeleteError::TooManyClusters)?;
+        free.read_fs_info(vol)?;
+        undelete(vol, &mut free, dir, deleted, first_char)
+    })?;
+    vol.flush()?;
+    Ok(restored)
+}
+
+/* `undelete <name>` in directory `dir`, writing through a descriptor
+ * reopened on the image. Returns the exit code */
+pub fn undelete_command(dev: &mut ImageDevice, image: &ImageFile, dir: u32, name: &[u8]) -> usize {
+    if name.is_empty() {
+        print("Usage: undelete <name>");
+        return 1;
+    }
+
+    let mut found: Option<DeletedEntry> = None;
+    {
+        let mut vol = match mount(&mut *dev) {
+            Some(vol) => vol,
+            None => return 1,
+        };
+        /* Prefer a recoverable match over an overwritten one */
+        let result = scan_deleted(&mut vol, dir, |e| {
+            if matches(e, name) && found.as_ref().is_none_or(|f| !f.recoverable) {
+                found = Some(e.clone());
+            }
+            found.as_ref().is_some_and(|f| f.recoverable)
+        });
+        if result.is_err() {
+            print("Failed to read the directory");
+            return 1;
+        }
+    }
+
+    let deleted = match found {
+        Some(e) => e,
+        None => {
+            print("No deleted entry by that name");
+            return 1;
+        }
+    };
+    if !deleted.recoverable {
+        print("Its clusters were reused, cannot recover");
+        return 1;
+    }
+    let first_char = match ask_first_char(&deleted) {
+        Some(c) => c,
+        None => {
+            print("No first character given");
+            return 1;
+        }
+    };
+
+    let written = dev.with_writable(image, |dev| match mount(dev) {
+        Some(mut vol) => match restore(&mut vol, dir, &deleted, first_char) {
+            Ok(restored) => {
+                let mut shown = [0u8; 16];
+                let len = build_short_name(&restored[0..8], &restored[8..11], &mut shown);
+                print_no_ln("Restored ");
+                print_bytes(&shown[..len]);
+                print("");
+                0
+            }
+            Err(UndeleteError::InvalidName) => {
+                print("Not a valid first character");
+                1
+            }
+            Err(UndeleteError::NameTaken) => {
+                print("An entry with that name already exists");
+                1
+            }
+            Err(UndeleteError::Overwritten) => {
+                print("Its clusters were reused, cannot recover");
+                1
+            }
+            Err(UndeleteError::TooManyClusters) => {
+                print("Volume has too many clusters to be mapped");
+                1
+            }
+            Err(UndeleteError::Volume(_)) => {
+                print("Failed to access the volume");
+                1
+            }
+        },
+        None => 1,
+    });
+    written.unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::allocator_scratch_size;
+    use crate::device::MemDevice;
+    use crate::dir_entry::{ATTR_ARCHIVE, build_dir_entry};
+    use crate::fixture::{ImageBuilder, Node};
+    use crate::format::{FormatOptions, format_volume};
+    use crate::helpers::u16_to_u8_le;
+    use std::vec;
+    use std::vec::Vec;
+
+    const SIZE: usize = 40 * 1024 * 1024;
+
+    fn lfn_entry(ordinal: u8, checksum: u8, units: &[u16]) -> [u8; 32] {
+        let mut e = [0xFFu8; 32];
+        e[0] = ordinal;
+        e[11] = 0x0F;
+        e[12] = 0;
+        e[13] = checksum;
+        e[26] = 0;
+        e[27] = 0;
+        for (i, &off) in LFN_UNIT_OFFSETS.iter().enumerate() {
+            let unit = match i.cmp(&units.len()) {
+                core::cmp::Ordering::Less => units[i],
+                core::cmp::Ordering::Equal => 0,
+                core::cmp::Ordering::Greater => 0xFFFF,
+            };
+            u16_to_u8_le(unit, &mut e[off..off + 2]);
+        }
+        e
+    }
+
+    /* `notes.txt` (long name `Notes.txt`) over clusters 3..=5, deleted */
+    fn deleted_image() -> Vec<u8> {
+        let mut data = vec![0u8; SIZE];
+        format_volume(
+            &mut MemDevice { data: &mut data },
+            SIZE as u64,
+            &FormatOptions::default(),
+        )
+        .unwrap();
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+
+        let name = *b"NOTES   TXT";
+        let units: Vec<u16> = "Notes.txt".encode_utf16().collect();
+        let mut lfn = lfn_entry(0x41, lfn_checksum(&name), &units);
+        lfn[0] = ENTRY_DELETED;
+        let size = 2 * vol.cluster_size as u32 + 10;
+        let mut short = build_dir_entry(&name, ATTR_ARCHIVE, 3, size, 1709214330);
+        short[0] = ENTRY_DELETED;
+
+        let root = vol.cluster_offset(2);
+        vol.dev.write_at(root, &lfn).unwrap();
+        vol.dev.write_at(root + 32, &short).unwrap();
+        data
+    }
+
+    fn scan(vol: &mut Volume<MemDevice>) -> Vec<DeletedEntry> {
+        let mut found = Vec::new();
+        scan_deleted(vol, 2, |e| {
+            found.push(e.clone());
+            false
+        })
+        .unwrap();
+        found
+    }
+
+    #[test]
+    fn lists_deleted_entries_with_a_guess() {
+        let mut data = deleted_image();
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let found = scan(&mut vol);
+
+        assert_eq!(found.len(), 1);
+        let e = &found[0];
+        assert_eq!(&e.entry.name[1..], b"OTES   TXT");
+        assert_eq!(e.entry.file_size, 2 * vol.cluster_size as u32 + 10);
+        assert_eq!(e.clusters_needed(vol.cluster_size), 3);
+        assert_eq!(
+            e.long_name(),
+            "Notes.txt".encode_utf16().collect::<Vec<_>>()
+        );
+        assert_eq!(e.guess, Some(b'N'));
+        assert!(e.recoverable);
+
+        /* Reusing one of its clusters makes it unrecoverable */
+        vol.set_fat_entry(4, FAT_EOC_MARK).unwrap();
+        assert!(!scan(&mut vol)[0].recoverable);
+    }
+
+    #[test]
+    fn restores_the_chain_and_long_name() {
+        let mut data = deleted_image();
+        let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+        let e = scan(&mut vol).remove(0);
+        let mut storage = vec![0u8; allocator_scratch_size(vol.clusters_count)];
+        let mut free = ClusterAllocator::new(&mut storage, vol.clusters_count).unwrap();
+        free.read_fs_info(&mut vol).unwrap();
+        let before = free.free_count().unwrap();
+
+        assert_eq!(
+            undelete(&mut vol, &mut free, 2, &e, b'?'),
+            Err(UndeleteError::InvalidName)
+        );
+        assert_eq!(undelete(&mut vol, &mut free, 2, &e, b'n').unwrap(), *b"NOTES   TXT");
+
+        assert_eq!(vol.fat_entry(3).unwrap(), 4);
+        assert_eq!(vol.fat_entry(4).unwrap(), 5);
+        assert_eq!(vol.fat_entry(5).unwrap(), FAT_EOC_MARK);
+        assert!(scan(&mut vol).is_empty());
+        let info = vol.read_fs_info().unwrap().unwrap();
+        assert_eq!((info.free_count, info.next_free), (before - 3, 6));
+
+        let root = vol.cluster_offset(2);
+        let mut entries = [0u8; 64];
+        vol.dev.read_at(root, &mut entries).unwrap();
+        assert_eq!(entries[0], 0x41);
+        assert_eq!(&entries[32..43], b"NOTES   TXT");
+
+        /* A second copy can't take the same name */
+        let mut again = e.clone();
+        again.entry.first_cluster = 0;
+        again.entry.file_size = 0;
+        assert_eq!(
+            undelete(&mut vol, &mut free, 2, &again, b'N'),
+            Err(UndeleteError::NameTaken)
+        );
+    }
+
+    #[test]
+    fn undeletes_in_the_fixed_root() {
+        let tree = [
+            Node::File("keep.txt", b"kept"),
+            Node::Deleted("gone.txt", b"deleted"),
+        ];
+        for builder in [ImageBuilder::fat12(), ImageBuilder::fat16()] {
+            let mut data = builder.build(&tree);
+            let mut vol = Volume::mount(MemDevice { data: &mut data }).unwrap();
+            let mut found = Vec::new();
+            scan_deleted(&mut vol, 0, |e| {
+                found.push(e.clone());
+                false
+            })
+            .unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(&found[0].entry.name[1..], b"ONE    TXT");
+            assert!(found[0].recoverable);
+
+            let mut storage = vec![0u8; allocator_scratch_size(vol.clusters_count)];
+            let mut free = ClusterAllocator::new(&mut storage, vol.clusters_count).unwrap();
+            assert_eq!(undelete(&mut vol, &mut free, 0, &found[0], b'g').unwrap(), *b"GONE    TXT");
+            let first = found[0].entry.first_cluster;
+            assert_eq!(vol.fat_entry(first).unwrap(), FAT_EOC_MARK);
+            assert!(has_entry(&mut vol, 0, b"GONE    TXT").unwrap());
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use crate::boot_sector::{BootSector, BootSectorError, FatType, parse_boot_sector};
+use crate::device::{BlockDevice, DeviceError};
+use crate::dir_entry::DIR_ENTRY_SIZE;
+use crate::helpers::{u32_to_u8_le, u8_to_u32_le};
+
+pub const FAT_ENTRY_MASK: u32 = 0x0FFFFFFF;
+pub const FAT_BAD_CLUSTER: u32 = 0x0FFFFFF7;
+pub const FAT_END_OF_CHAIN: u32 = 0x0FFFFFF8;
+/* Value written to end a chain */
+pub const FAT_EOC_MARK: u32 = 0x0FFFFFFF;
+/* Ext flags bit telling the FATs aren't kept in sync */
+const EXT_FLAGS_NO_MIRRORING: u16 = 0x0080;
+pub const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+pub const FSINFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+pub const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA550000;
+/* Free count or next free hint not known */
+pub const FSINFO_UNKNOWN: u32 = 0xFFFFFFFF;
+pub const MAX_SECTOR_SIZE: usize = 4096;
+pub const MAX_CLUSTER_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeError {
+    Device(DeviceError),
+    /* Missing signature or nonsensical BPB values */
+    InvalidBootSector,
+    /* The on-disk structures contradict each other */
+    Corrupt,
+}
+
+impl From<DeviceError> for VolumeError {
+    fn from(e: DeviceError) -> Self {
+        VolumeError::Device(e)
+    }
+}
+
+impl From<BootSectorError> for VolumeError {
+    fn from(_: BootSectorError) -> Self {
+        VolumeError::InvalidBootSector
+    }
+}
+
+/* Byte offset of the entry of `cluster` within a FAT */
+pub fn entry_offset(fat_type: FatType, cluster: u32) -> u64 {
+    let cluster = cluster as u64;
+    match fat_type {
+        FatType::Fat12 => cluster + cluster / 2,
+        FatType::Fat16 => cluster * 2,
+        FatType::Fat32 => cluster * 4,
+    }
+}
+
+/* FAT12/16 entry widened so that end of chain and bad cluster marks read
+ * like the FAT32 ones */
+pub fn widen_entry(fat_type: FatType, raw: u32) -> u32 {
+    let (bad, end) = match fat_type {
+        FatType::Fat12 => (0x0FF7, 0x0FF8),
+        FatType::Fat16 => (0xFFF7, 0xFFF8),
+        FatType::Fat32 => return raw & FAT_ENTRY_MASK,
+    };
+    if raw >= end {
+        FAT_EOC_MARK
+    } else if raw == bad {
+        FAT_BAD_CLUSTER
+    } else {
+        raw
+    }
+}
+
+/* The other way around, `value` as stored in a FAT12/16 entry */
+fn narrow_entry(fat_type: FatType, value: u32) -> u32 {
+    let mask = match fat_type {
+        FatType::Fat12 => 0x0FFF,
+        FatType::Fat16 => 0xFFFF,
+        FatType::Fat32 => return value & FAT_ENTRY_MASK,
+    };
+    if value >= FAT_END_OF_CHAIN {
+        mask
+    } else if value == FAT_BAD_CLUSTER {
+        mask - 8
+    } else {
+        value & mask
+    }
+}
+
+/* Allocation hints kept in the FSInfo sector */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FsInfo {
+    pub free_count: u32,
+    pub next_free: u32,
+}
+
+/* A mounted FAT volume: the device plus the geometry derived from its BPB */
+pub struct Volume<D: BlockDevice> {
+    pub dev: D,
+    pub bs: BootSector,
+    pub fat_start: u64,
+    pub data_start: u64,
+    pub cluster_size: usize,
+    /* Number of data clusters, valid cluster numbers are 2..clusters_count + 2 */
+    pub clusters_count: u32,
+    /* FAT read by `fat_entry`, the one the BPB marks active unless changed */
+    pub active_fat: u8,
+    /* Whether writes go to every FAT or only the active one */
+    pub mirrored: bool,
+    /* Last FAT sector read, sequential FAT walks mostly hit it */
+    fat_sector: [u8; MAX_SECTOR_SIZE],
+    fat_sector_index: u64,
+}
+
+impl<D: BlockDevice> Volume<D> {
+    pub fn mount(mut dev: D) -> Result<Self, VolumeError> {
+        let mut sector = [0u8; 512];
+        dev.read_at(0, &mut sector)?;
+
+        let bs = parse_boot_sector(&sector)?;
+
+        let bps = bs.bytes_per_sector as u64;
+        let spc = bs.sectors_per_cluster as u64;
+        let cluster_size = (bps * spc) as usize;
+
+        /* Clusters past what the FAT can describe don't exist */
+        let fat_bytes = bs.fat_size_sectors as u64 * bps;
+        let fat_entries = match bs.fat_type {
+            FatType::Fat12 => fat_bytes * 2 / 3,
+            FatType::Fat16 => fat_bytes / 2,
+            FatType::Fat32 => fat_bytes / 4,
+        };
+        let clusters_count = core::cmp::min(bs.data_clusters(), fat_entries.saturating_sub(2));
+        let clusters_count = core::cmp::min(clusters_count, (FAT_BAD_CLUSTER - 2) as u64) as u32;
+
+        if bs.fat_type == FatType::Fat32
+            && (bs.root_cluster < 2 || bs.root_cluster >= clusters_count + 2)
+        {
+            return Err(VolumeError::InvalidBootSector);
+        }
+
+        let fat_start = bs.reserved_sectors_count as u64 * bps;
+        let data_start = bs.system_sectors() * bps;
+
+        let mirrored = bs.ext_flags & EXT_FLAGS_NO_MIRRORING == 0;
+        let active_fat = if mirrored { 0 } else { (bs.ext_flags & 0x0F) as u8 };
+        if active_fat >= bs.fats_count {
+            return Err(VolumeError::InvalidBootSector);
+        }
+
+        Ok(Volume {
+            dev,
+            bs,
+            fat_start,
+            data_start,
+            cluster_size,
+            clusters_count,
+            active_fat,
+            mirrored,
+            fat_sector: [0u8; MAX_SECTOR_SIZE],
+            fat_sector_index: u64::MAX,
+        })
+    }
+
+    pub fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.clusters_count + 2
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.cluster_size as u64
+    }
+
+    /* Directories are read a block at a time: one of their clusters, or
+     * the whole fixed root of FAT12/16, which goes by cluster 0 */
+    pub fn is_fixed_root(&self, cluster: u32) -> bool {
+        cluster == 0 && self.bs.fat_type != FatType::Fat32
+    }
+
+    pub fn dir_block_offset(&self, cluster: u32) -> u64 {
+        if self.is_fixed_root(cluster) {
+            let bps = self.bs.bytes_per_sector as u64;
+            self.data_start - self.bs.root_dir_sectors() as u64 * bps
+        } else {
+            self.cluster_offset(cluster)
+        }
+    }
+
+    pub fn dir_block_size(&self, cluster: u32) -> usize {
+        if self.is_fixed_root(cluster) {
+            self.bs.root_entry_count as usize * DIR_ENTRY_SIZE
+        } else {
+            self.cluster_size
+        }
+    }
+
+    /* Corrupt when `buf` can't hold the block */
+    pub fn read_dir_block(&mut self, cluster: u32, buf: &mut [u8]) -> Result<(), VolumeError> {
+        if !self.is_fixed_root(cluster) {
+            return self.read_cluster(cluster, buf);
+        }
+        let size = self.dir_block_size(cluster);
+        if buf.len() < size {
+            return Err(VolumeError::Corrupt);
+        }
+        let offset = self.dir_block_offset(cluster);
+        self.dev.read_at(offset, &mut buf[..size])?;
+        Ok(())
+    }
+
+    /* Block after `cluster` in its directory, the fixed root has no other */
+    pub fn next_dir_block(&mut self, cluster: u32) -> Result<u32, VolumeError> {
+        if self.is_fixed_root(cluster) {
+            return Ok(FAT_EOC_MARK);
+        }
+        self.fat_entry(cluster)
+    }
+
+    /* Entry of `cluster` in the FAT number `fat_index` (0 is the active one) */
+    pub fn fat_entry_in(&mut self, fat_index: u8, cluster: u32) -> Result<u32, VolumeError> {
+        if fat_index >= self.bs.fats_count || !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+
+        let bps = self.bs.bytes_per_sector as u64;
+        let fat_type = self.bs.fat_type;
+        let byte = fat_index as u64 * self.bs.fat_size_sectors as u64 * bps
+            + entry_offset(fat_type, cluster);
+        let sector_index = byte / bps;
+        let off = (byte % bps) as usize;
+
+        /* A FAT12 entry may straddle two sectors, it skips the cache then */
+        let width = if fat_type == FatType::Fat32 { 4 } else { 2 };
+        let mut raw = [0u8; 4];
+        if let Some(bytes) = self.dev.borrow(self.fat_start + byte, width) {
+            /* Straight from a mapped image */
+            raw[..width].copy_from_slice(bytes);
+        } else if off + width > bps as usize {
+            self.dev.read_at(self.fat_start + byte, &mut raw[..width])?;
+        } else {
+            if sector_index != self.fat_sector_index {
+                let offset = self.fat_start + sector_index * bps;
+                self.fat_sector_index = u64::MAX;
+                self.dev
+                    .read_at(offset, &mut self.fat_sector[..bps as usize])?;
+                self.fat_sector_index = sector_index;
+            }
+            raw[..width].copy_from_slice(&self.fat_sector[off..off + width]);
+        }
+
+        let value = u8_to_u32_le(&raw);
+        let value = match fat_type {
+            FatType::Fat12 if cluster & 1 == 1 => value >> 4,
+            FatType::Fat12 => value & 0x0FFF,
+            _ => value,
+        };
+        Ok(widen_entry(fat_type, value))
+    }
+
+    pub fn fat_entry(&mut self, cluster: u32) -> Result<u32, VolumeError> {
+        self.fat_entry_in(self.active_fat, cluster)
+    }
+
+    /* Set the entry of `cluster` in every FAT (only the active one when they
+     * aren't mirrored), the reserved high bits of FAT32 are kept */
+    pub fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), VolumeError> {
+        if !self.is_valid_cluster(cluster) {
+            return Err(VolumeError::Corrupt);
+        }
+
+        let fat_type = self.bs.fat_type;
+        let fat_bytes = self.bs.fat_size_sectors as u64 * self.bs.bytes_per_sector as u64;
+        let stored = narrow_entry(fat_type, value);
+        for fat_index in 0..self.bs.fats_count as u64 {
+            if !self.mirrored && fat_index != self.active_fat as u64 {
+                continue;
+            }
+            let offset = self.fat_start + fat_index * fat_bytes + entry_offset(fat_type, cluster);
+            let width = if fat_type == FatType::Fat32 { 4 } else { 2 };
+            let mut raw = [0u8; 4];
+            self.dev.read_at(offset, &mut raw[..width])?;
+            let old = u8_to_u32_le(&raw);
+            let new = match fat_type {
+                /* Two entries share the middle byte */
+                FatType::Fat12 if cluster & 1 == 1 => (old & 0x000F) | (stored << 4),
+                FatType::Fat12 => (old & 0xF000) | stored,
+                FatType::Fat16 => stored,
+                FatType::Fat32 => (old & !FAT_ENTRY_MASK) | stored,
+            };
+            u32_to_u8_le(new, &mut raw);
+            self.dev.write_at(offset, &raw[..width])?;
+        }
+
+        self.fat_sector_index = u64::MAX;
+        Ok(())
+    }
+
+    /* Overwrite FAT number `to` with FAT number `from` */
+    pub fn copy_fat(&mut self, from: u8, to: u8) -> Result<(), VolumeError> {
+        if from >= self.bs.fats_count || to >= self.bs.fats_count {
+            return Err(VolumeError::Corrupt);
+        }
+
+        let bps = self.bs.bytes_per_sector as u64;
+        let fat_bytes = self.bs.fat_size_sectors as u64 * bps;
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        for i in 0..self.bs.fat_size_sectors as u64 {
+            let src = self.fat_start + from as u64 * fat_bytes + i * bps;
+            let dst = self.fat_start + to as u64 * fat_bytes + i * bps;
+            self.dev.read_at(src, &mut sector[..bps as usize])?;
+            self.dev.write_at(dst, &sector[..bps as usize])?;
+        }
+
+        self.fat_sector_index = u64::MAX;
+        Ok(())
+    }
+
+    /* Write back what the device keeps cached */
+    pub fn flush(&mut self) -> Result<(), VolumeError> {
+        self.dev.flush()?;
+        Ok(())
+    }
+
+    pub fn read_cluster(&mut self, cluster: u32, buf: &mut [u8]) -> Result<(), VolumeError> {
+        if !self.is_valid_cluster(cluster) || buf.len() < self.cluster_size {
+            return Err(VolumeError::Corrupt);
+        }
+        let offset = self.cluster_offset(cluster);
+        self.dev.read_at(offset, &mut buf[..self.cluster_size])?;
+        Ok(())
+    }
+
+    pub fn write_cluster(&mut self, cluster: u32, buf: &[u8]) -> Result<(), VolumeError> {
+        if !self.is_valid_cluster(cluster) || buf.len() < self.cluster_size {
+            return Err(VolumeError::Corrupt);
+        }
+        let offset = self.cluster_offset(cluster);
+        self.dev.write_at(offset, &buf[..self.cluster_size])?;
+        Ok(())
+    }
+
+    /* Read the FSInfo sector into `sector`, its number when the BPB names
+     * one and its signatures are right */
+    fn load_fs_info(&mut self, sector: &mut [u8]) -> Result<Option<u32>, VolumeError> {
+        let fs_info = self.bs.fs_info_sector;
+        if fs_info == 0 || fs_info == 0xFFFF || fs_info >= self.bs.reserved_sectors_count {
+            return Ok(None);
+        }
+        self.read_sector(fs_info as u32, sector)?;
+        let lead = u8_to_u32_le(&sector[0..4]);
+        let structure = u8_to_u32_le(&sector[484..488]);
+        if lead != FSINFO_LEAD_SIGNATURE || structure != FSINFO_STRUCT_SIGNATURE {
+            return Ok(None);
+        }
+        Ok(Some(fs_info as u32))
+    }
+
+    /* Free count and next free hint recorded in FSInfo, either may be
+     * FSINFO_UNKNOWN. None without a valid FSInfo sector */
+    pub fn read_fs_info(&mut self) -> Result<Option<FsInfo>, VolumeError> {
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        if self.load_fs_info(&mut sector)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(FsInfo {
+            free_count: u8_to_u32_le(&sector[488..492]),
+            next_free: u8_to_u32_le(&sector[492..496]),
+        }))
+    }
+
+    /* Record `info` in FSInfo, left alone when there is no valid one */
+    pub fn write_fs_info(&mut self, info: FsInfo) -> Result<(), VolumeError> {
+        let mut sector = [0u8; MAX_SECTOR_SIZE];
+        let Some(fs_info) = self.load_fs_info(&mut sector)? else {
+            return Ok(());
+        };
+        u32_to_u8_le(info.free_count, &mut sector[488..492]);
+        u32_to_u8_le(info.next_free, &mut sector[492..496]);
+        self.write_sector(fs_info, &sector)
+    }
+
+    pub fn read_sector(&mut self, sector: u32, buf: &mut [u8]) -> Result<(), VolumeError> {
+        let bps = self.bs.bytes_per_sector as usize;
+        if buf.len() < bps {
+            return Err(VolumeError::Corrupt);
+        }
+        self.dev
+            .read_at(sector as u64 * bps as u64, &mut buf[..bps])?;
+        Ok(())
+    }
+
+    pub fn write_sector(&mut self, sector: u32, buf: &[u8]) -> Result<(), VolumeError> {
+        let bps = self.bs.bytes_per_sector as usize;
+        if buf.len() < bps {
+            return Err(VolumeError::Corrupt);
+        }
+        self.dev.write_at(sector as u64 * bps as u64, &buf[..bps])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::MemDevice;
+    use crate::fixture::ImageBuilder;
+    use crate::format::{FormatOptions, format_volume};
+    use std::vec;
+
+    #[test]
+    fn mount_formatted_volume() {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        let mut dev = MemDevice { data: &mut image };
+        let summary = format_volume(&mut dev, 40 * 1024 * 1024, &FormatOptions::default()).unwrap();
+
+        let mut vol = Volume::mount(dev).unwrap();
+        assert_eq!(vol.clusters_count, summary.clusters_count);
+        assert_eq!(vol.cluster_size, 512);
+        assert_eq!(vol.fat_entry(2).unwrap(), FAT_ENTRY_MASK);
+        assert_eq!(vol.fat_entry_in(1, 2).unwrap(), FAT_ENTRY_MASK);
+        assert_eq!(vol.fat_entry(3).unwrap(), 0);
+        assert_eq!(
+            vol.fat_entry(summary.clusters_count + 2),
+            Err(VolumeError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn set_fat_entry_updates_every_copy() {
+        let mut image = vec![0u8; 40 * 1024 * 1024];
+        let mut dev = MemDevice { data: &mut image };
+        format_volume(&mut dev, 40 * 1024 * 1024, &FormatOptions::default()).unwrap();
+
+        let mut vol = Volume::mount(dev).unwrap();
+        /* Prime the cached sector, it must not go stale */
+        assert_eq!(vol.fat_entry(3).unwrap(), 0);
+        vol.set_fat_entry(3, 0xF0000004).unwrap();
+        assert_eq!(vol.fat_entry(3).unwrap(), 4);
+        assert_eq!(vol.fat_entry_in(1, 3).unwrap(), 4);
+    }
+
+    #[test]
+    fn fat12_entries_share_bytes_with_their_neighbours() {
+        let mut image = ImageBuilder::fat12().build(&[]);
+        let mut vol = Volume::mount(MemDevice { data: &mut image }).unwrap();
+        assert_eq!(vol.bs.fat_type, FatType::Fat12);
+
+        /* 341 and 342 straddle the first and second FAT sectors */
+        for cluster in [4, 5, 340, 341, 342] {
+            vol.set_fat_entry(cluster, 0xABC).unwrap();
+            vol.set_fat_entry(cluster + 1, FAT_BAD_CLUSTER).unwrap();
+            assert_eq!(vol.fat_entry(cluster).unwrap(), 0xABC);
+            assert_eq!(vol.fat_entry(cluster + 1).unwrap(), FAT_BAD_CLUSTER);
+            vol.set_fat_entry(cluster, FAT_EOC_MARK).unwrap();
+            assert_eq!(vol.fat_entry_in(1, cluster).unwrap(), FAT_EOC_MARK);
+            assert_eq!(vol.fat_entry(cluster + 1).unwrap(), FAT_BAD_CLUSTER);
+            vol.set_fat_entry(cluster + 1, 0).unwrap();
+        }
+        assert_eq!(widen_entry(FatType::Fat12, 0xFF8), FAT_EOC_MARK);
+        assert_eq!(narrow_entry(FatType::Fat16, FAT_BAD_CLUSTER), 0xFFF7);
+    }
+
+    #[test]
+    fn mount_rejects_garbage() {
+        let mut image = vec![0u8; 4096];
+        image[510] = 0x55;
+        image[511] = 0xAA;
+        let dev = MemDevice { data: &mut image };
+        assert!(matches!(
+            Volume::mount(dev),
+            Err(VolumeError::InvalidBootSector)
+        ));
+    }
+}
@@ -0,0 +1,289 @@
+use crate::bitmap::Bitmap;
+use crate::device::BlockDevice;
+use crate::dir_entry::{
+    DIR_ENTRY_SIZE, DirEntry, ENTRY_DELETED, ENTRY_END, LFN_MAX_UNITS, LfnBuilder, is_lfn_entry,
+    lfn_to_utf8, parse_dir_entry,
+};
+use crate::fat::build_short_name;
+use crate::volume::{MAX_CLUSTER_SIZE, Volume, VolumeError};
+
+pub const MAX_PATH_LEN: usize = 1024;
+/* Deeper directories are reported and not walked */
+pub const MAX_DEPTH: usize = 64;
+
+/* Slash separated path built while walking, cut when too long */
+#[derive(Clone)]
+pub struct Path {
+    pub buf: [u8; MAX_PATH_LEN],
+    pub len: usize,
+}
+
+impl Default for Path {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Path {
+    pub fn new() -> Self {
+        Path {
+            buf: [0u8; MAX_PATH_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, component: &[u8]) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = b'/';
+            self.len += 1;
+        }
+        let n = core::cmp::min(component.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&component[..n]);
+        self.len += n;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            b"/"
+        } else {
+            &self.buf[..self.len]
+        }
+    }
+}
+
+pub struct WalkEntry<'a> {
+    pub entry: &'a DirEntry,
+    /* From the root of the walk, long names when there are valid ones */
+    pub path: &'a [u8],
+}
+
+struct WalkFrame {
+    /* First cluster of the directory */
+    start: u32,
+    cluster: u32,
+    /* Next entry to look at in `cluster` */
+    index: usize,
+    path_len: usize,
+}
+
+/* Depth first walk over every entry below directory `start` (0 for the
+ * fixed root of FAT12/16), dot entries and volume labels left out.
+ * `visited` gets each directory cluster read,
+ * so a directory chain looping or shared with another one is cut there.
+ * A subdirectory pointing back at one of its ancestors is a corrupt volume */
+pub fn walk_tree<D, F>(
+    vol: &mut Volume<D>,
+    start: u32,
+    visited: &mut Bitmap,
+    mut on_entry: F,
+) -> Result<(), VolumeError>
+where
+    D: BlockDevice,
+    F: FnMut(&mut Volume<D>, &WalkEntry) -> Result<(), VolumeError>,
+{
+    let is_dir = vol.is_valid_cluster(start) || vol.is_fixed_root(start);
+    if !is_dir || visited.get(start as usize) {
+        return Ok(());
+    }
+    visited.set(start as usize);
+
+    let mut stack: [WalkFrame; MAX_DEPTH] = core::array::from_fn(|_| WalkFrame {
+        start: 0,
+        cluster: 0,
+        index: 0,
+        path_len: 0,
+    });
+    stack[0].start = start;
+    stack[0].cluster = start;
+    let mut depth = 1usize;
+
+    let mut path = Path::new();
+    let mut cluster_buf = [0u8; MAX_CLUSTER_SIZE];
+    let mut buffered = u32::MAX;
+    let mut lfn = LfnBuilder::new();
+    let mut name_buf = [0u8; 4 * LFN_MAX_UNITS];
+
+    while depth > 0 {
+        let top = depth - 1;
+        path.len = stack[top].path_len;
+
+        if stack[top].index == vol.dir_block_size(stack[top].cluster) / DIR_ENTRY_SIZE {
+            let next = vol.next_dir_block(stack[top].cluster)?;
+            if !vol.is_valid_cluster(next) || visited.get(next as usize) {
+                lfn.reset();
+                depth -= 1;
+                continue;
+            }
+            visited.set(next as usize);
+            stack[top].cluster = next;
+            stack[top].index = 0;
+        }
+
+        let cluster = stack[top].cluster;
+        if buffered != cluster {
+            vol.read_dir_block(cluster, &mut cluster_buf)?;
+            buffered = cluster;
+        }
+
+        let index = stack[top].index;
+        stack[top].index += 1;
+        let entry = &cluster_buf[index * DIR_ENTRY_SIZE..(index + 1) * DIR_ENTRY_SIZE];
+
+        if entry[0] == ENTRY_END {
+            lfn.reset();
+            depth -= 1;
+            continue;
+        }
+        if entry[0] == ENTRY_DELETED {
+            lfn.reset();
+            continue;
+        }
+        if is_lfn_entry(entry) {
+            lfn.push(entry);
+            continue;
+        }
+
+        let e = parse_dir_entry(entry);
+        let name_len = match lfn.finish(&e.name) {
+            Ok(Some(units)) => lfn_to_utf8(units, &mut name_buf),
+            _ => build_short_name(&e.name[0..8], &e.name[8..11], &mut name_buf),
+        };
+
+        if e.is_volume_id() || e.is_dot() {
+            continue;
+        }
+
+        path.push(&name_buf[..name_len]);
+        on_entry(
+            vol,
+            &WalkEntry {
+                entry: &e,
+                path: path.as_bytes(),
+            },
+        )?;
+
+        let first = e.first_cluster;
+        if e.is_dir() && stack[..depth].iter().any(|frame| frame.start == first) {
+            return Err(VolumeError::Corrupt);
+        }
+        if e.is_dir()
+            && depth < MAX_DEPTH
+            && vol.is_valid_cluster(first)
+            && !visited.get(first as usize)
+        {
+            visited.set(first as usize);
+            stack[depth] = WalkFrame {
+                start: first,
+                cluster: first,
+                index: 0,
+                path_len: path.len,
+            };
+            depth += 1;
+        }
+    }
+    Ok(())
+}
+
+/* Whether directory `dir` has an entry named `name` */
+pub fn has_entry<D: BlockDevice>(
+    vol: &mut Volume<D>,
+    dir: u32,
+    name: &[u8; 11],
+) -> Result<bool, VolumeError> {
+    let mut cluster = dir;
+    for _ in 0..vol.clusters_count {
+        let base = vol.dir_block_offset(cluster);
+        for i in 0..vol.dir_block_size(cluster) / DIR_ENTRY_SIZE {
+            let mut entry_name = [0u8; 11];
+            vol.dev
+                .read_at(base + (i * DIR_ENTRY_SIZE) as u64, &mut entry_name)?;
+            if entry_name[0] == ENTRY_END {
+                return Ok(false);
+            }
+            if &entry_name == name {
+                return Ok(true);
+            }
+        }
+        cluster = vol.next_dir_block(cluster)?;
+        if !vol.is_valid_cluster(cluster) {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmap::bitmap_bytes;
+    use crate::device::MemDevice;
+    use crate::dir_entry::{ATTR_DIRECTORY, DOT_DOT_NAME, DOT_NAME, build_dir_entry};
+    use crate::format::{FormatOptions, format_volume};
+    use std::vec;
+    use std::vec::Vec;
+
+    #[test]
+    fn walks_nested_directories_once() {
+        let size = 40 * 1024 * 1024;
+        let mut image = vec![0u8; size];
+        let mut dev = MemDevice { data: &mut image };
+        format_volume(&mut dev, size as u64, &FormatOptions::default()).unwrap();
+        let mut vol = Volume::mount(dev).unwrap();
+
+        let root = vol.cluster_offset(2);
+        let sub = vol.cluster_offset(3);
+        let entries: [(u64, [u8; 32]); 6] = [
+            (root, build_dir_entry(b"SUB        ", ATTR_DIRECTORY, 3, 0, 0)),
+            (root + 32, build_dir_entry(b"TOP     TXT", 0x20, 0, 0, 0)),
+            /* Shares the directory of SUB, must not be entered twice */
+            (root + 64, build_dir_entry(b"ALIAS      ", ATTR_DIRECTORY, 3, 0, 0)),
+            (sub, build_dir_entry(DOT_NAME, ATTR_DIRECTORY, 3, 0, 0)),
+            (sub + 32, build_dir_entry(DOT_DOT_NAME, ATTR_DIRECTORY, 0, 0, 0)),
+            (sub + 64, build_dir_entry(b"FILE    TXT", 0x20, 0, 0, 0)),
+        ];
+        for (offset, e) in entries.iter() {
+            vol.dev.write_at(*offset, e).unwrap();
+        }
+        vol.set_fat_entry(3, 0x0FFFFFFF).unwrap();
+
+        let bits = vol.clusters_count as usize + 2;
+        let mut storage = vec![0u8; bitmap_bytes(bits)];
+        let mut visited = Bitmap::new(&mut storage, bits).unwrap();
+        let mut paths: Vec<Vec<u8>> = Vec::new();
+        walk_tree(&mut vol, 2, &mut visited, |_, walked| {
+            paths.push(walked.path.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            paths,
+            vec![
+                b"/SUB".to_vec(),
+                b"/SUB/FILE.TXT".to_vec(),
+                b"/TOP.TXT".to_vec(),
+                b"/ALIAS".to_vec()
+            ]
+        );
+
+        /* Pointing back to the root is a loop in the directory graph */
+        let loop_entry = build_dir_entry(b"LOOP       ", ATTR_DIRECTORY, 2, 0, 0);
+        vol.dev.write_at(sub + 96, &loop_entry).unwrap();
+        let mut visited = Bitmap::new(&mut storage, bits).unwrap();
+        assert_eq!(
+            walk_tree(&mut vol, 2, &mut visited, |_, _| Ok(())),
+            Err(VolumeError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn path_is_cut_when_too_long() {
+        let mut path = Path::new();
+        assert_eq!(path.as_bytes(), b"/");
+        for _ in 0..200 {
+            path.push(b"component");
+        }
+        assert_eq!(path.len, MAX_PATH_LEN);
+        assert!(path.as_bytes().starts_with(b"/component/"));
+    }
+}